@@ -1,48 +1,72 @@
 use std::mem;
+use std::marker::PhantomData;
 use std::sync::atomic::{AtomicUsize};
-use std::sync::atomic::Ordering::{Acquire, Release, SeqCst, Relaxed};
+use std::sync::atomic::Ordering::Relaxed;
 use std::ops::Deref;
 
 use super::pool::*;
+use super::block_table;
+use super::sync::*;
+use codec::*;
 use LodestoneError;
 
 lazy_static! {
     pub static ref ARC_INNER_SIZE: usize = mem::size_of::<ArcByteSliceInner>();
 }
 
-/// ArcByteSlices are free floating and are not persisted
-pub struct ArcByteSlice {
+/// ArcByteSlices are free floating and are not persisted.
+/// They are neither Send nor Sync: dropping the last one frees into
+/// the pool, which only its own thread may do. They borrow the pool
+/// they came from, so they can't outlive it:
+///
+/// ```compile_fail
+/// use lodestone::allocator::Pool;
+///
+/// let arc = {
+///     let mut buf = vec![0u8; 0x4000];
+///     let pool = Pool::new(&mut buf);
+///     pool.malloc(b"gone with the pool").unwrap()
+/// };
+/// ```
+pub struct ArcByteSlice<'pool> {
     pub _ptr: *mut ArcByteSliceInner,
-    _pool: *const Pool,
+    // The pool's buffer lifetime is erased, 'pool keeps arcs from
+    // outliving the pool itself
+    _pool: *const Pool<'static>,
+    _borrow: PhantomData<&'pool ()>,
 }
 
 
 /// ArcByteSliceInners are persisted in the mem map
 pub struct ArcByteSliceInner {
     pub strong: AtomicUsize,
-    pub weak: AtomicUsize,
+    /// The block's crc32 with BLOCK_CHECKSUM_SET above it, or 0 if the
+    /// block has none, see Pool::set_block_checksums
+    pub checksum: AtomicUsize,
     pub size: usize,
 }
 
 /// Public Api for ArcByteSlice
-impl ArcByteSlice {
-    pub fn new(inner: &mut ArcByteSliceInner, pool: &Pool) -> ArcByteSlice {
-        inner.strong.fetch_add(1, Acquire);
+impl<'pool> ArcByteSlice<'pool> {
+    pub fn new(inner: &mut ArcByteSliceInner, pool: &'pool Pool) -> ArcByteSlice<'pool> {
+        pool._retain(inner);
         ArcByteSlice {
             _ptr: inner as *mut ArcByteSliceInner,
-            _pool: pool as *const Pool,
+            _pool: pool as *const Pool as *const Pool<'static>,
+            _borrow: PhantomData,
         }
     }
 
     pub fn get_ref_count(&self) -> usize {
-        self.inner().strong.load(Relaxed)
+        ref_count(&self.inner().strong)
     }
 
-    pub fn clone_to_persisted(&self) -> PersistedArcByteSlice {
+    pub(crate) fn clone_to_persisted(&self) -> PersistedArcByteSlice {
         let inner = self.inner();
         // Persisted counts as a strong reference
-        inner.strong.fetch_add(1, SeqCst);
+        self.pool()._retain(inner);
         unsafe {
+            (*self._pool)._mark_inner_dirty(&self);
             PersistedArcByteSlice {
                 arc_inner_index: (*self._pool)._inner_offset(&self),
                 id_tag: (*self._pool)._get_id_tag(&self),
@@ -50,6 +74,24 @@ impl ArcByteSlice {
         }
     }
 
+    /// A handle that doesn't keep the block alive, see WeakByteSlice
+    pub fn downgrade(&self) -> WeakByteSlice {
+        let pool = self.pool();
+        WeakByteSlice {
+            arc_inner_index: pool._inner_offset(self),
+            id_tag: pool._get_id_tag(self),
+        }
+    }
+
+    fn pool(&self) -> &Pool<'static> {
+        unsafe { &*self._pool }
+    }
+
+    /// Priviledged, should not be called outside allocator package
+    pub fn _belongs_to(&self, pool: &Pool) -> bool {
+        self._pool == pool as *const Pool as *const Pool<'static>
+    }
+
     /// Stolen from std::sync::arc https://doc.rust-lang.org/src/alloc/arc.rs.html
     #[inline]
     pub fn inner(&self) -> &ArcByteSliceInner {
@@ -82,24 +124,26 @@ impl ArcByteSlice {
 /// Public Api for ArcByteSliceInner
 impl ArcByteSliceInner {
     pub fn init(&mut self, size: usize) {
-        self.strong.store(0, SeqCst);
-        self.weak.store(0, SeqCst);
+        // Nobody else can see the block yet
+        self.strong.store(0, Relaxed);
+        self.checksum.store(0, Relaxed);
         self.size = size;
     }
 }
 
-impl Clone for ArcByteSlice {
-    fn clone(&self) -> ArcByteSlice {
-        self.inner().strong.fetch_add(1, Acquire);
+impl<'pool> Clone for ArcByteSlice<'pool> {
+    fn clone(&self) -> ArcByteSlice<'pool> {
+        self.pool()._retain(self.inner());
         ArcByteSlice {
             _ptr: self._ptr,
             _pool: self._pool,
+            _borrow: PhantomData,
         }
     }
 }
 
 /// Deref for ArcByteSlice -- No DerefMut since map contents are Read Only.
-impl Deref for ArcByteSlice {
+impl<'pool> Deref for ArcByteSlice<'pool> {
     type Target = [u8];
 
     fn deref<'a>(&'a self) -> &'a [u8] {
@@ -109,10 +153,10 @@ impl Deref for ArcByteSlice {
     }
 }
 
-impl  Drop for ArcByteSlice {
+impl<'pool> Drop for ArcByteSlice<'pool> {
     fn drop(&mut self) {
         let inner = self.inner();
-        if inner.strong.fetch_sub(1, Release) == 1 {
+        if self.pool()._release(inner) == 0 {
             // This was the last strong ref, let's release
             unsafe {
                 (*self._pool).free(self);
@@ -126,14 +170,17 @@ impl  Drop for ArcByteSlice {
 /// However, you must always manually release the PersistedArcByteSlice
 /// since releasing requires reference to a pool. The Drop impl will panic
 /// if you forget to release the persist.
+///
+/// This is the crate's own handle, laid out as tree nodes store their
+/// children. Code outside the crate uses Handle.
 #[derive(Debug)]
-pub struct PersistedArcByteSlice {
-    pub arc_inner_index: usize,
+pub(crate) struct PersistedArcByteSlice {
+    arc_inner_index: usize,
     id_tag: usize,
 }
 
 impl PersistedArcByteSlice {
-    pub fn clone_to_arc_byte_slice(&self, pool: &Pool) -> Result<ArcByteSlice, LodestoneError> {
+    pub fn clone_to_arc_byte_slice<'p>(&self, pool: &'p Pool) -> Result<ArcByteSlice<'p>, LodestoneError> {
         pool.clone_persisted_to_arc(self)
     }
 
@@ -141,6 +188,19 @@ impl PersistedArcByteSlice {
         self.id_tag
     }
 
+    /// Priviledged, should not be called outside allocator package
+    pub(crate) fn _arc_inner_index(&self) -> usize {
+        self.arc_inner_index
+    }
+
+    /// A handle that doesn't keep the block alive, see WeakByteSlice
+    pub fn downgrade(&self) -> WeakByteSlice {
+        WeakByteSlice {
+            arc_inner_index: self.arc_inner_index,
+            id_tag: self.id_tag,
+        }
+    }
+
     pub fn clone(&self, pool: &Pool) -> Result<PersistedArcByteSlice, LodestoneError> {
         try!(self.retain(pool));
         Ok(PersistedArcByteSlice {
@@ -150,14 +210,25 @@ impl PersistedArcByteSlice {
     }
 
     pub fn retain(&self, pool: &Pool) -> Result<(), LodestoneError> {
+        if block_table::is_logical(self.arc_inner_index) {
+            return pool._retain_logical(self);
+        }
         let arc = try!(pool.clone_persisted_to_arc(self));
-        arc.inner().strong.fetch_add(1, Acquire);
+        pool._retain(arc.inner());
+        pool._mark_inner_dirty(&arc);
         Ok(())
     }
 
     pub fn release(&mut self, pool: &Pool) -> Result<bool, LodestoneError> {
+        if block_table::is_logical(self.arc_inner_index) {
+            let freed = try!(pool._release_logical(self));
+            self.id_tag = 0;
+            self.arc_inner_index = BUFFER_END;
+            return Ok(freed);
+        }
         let arc = try!(pool.clone_persisted_to_arc(self));
-        let remaining_count = arc.inner().strong.fetch_sub(1, Release) - 1;
+        let remaining_count = pool._release(arc.inner());
+        pool._mark_inner_dirty(&arc);
         self.id_tag = 0;
         self.arc_inner_index = BUFFER_END;
         // The last ref is the arc which will call free if necessary
@@ -165,6 +236,189 @@ impl PersistedArcByteSlice {
     }
 }
 
+/// An owned handle to a block for code outside the crate, e.g. to keep
+/// a bitmap or a raw block of its own. It holds a count on the block
+/// like PersistedArcByteSlice but has nothing to reach into, and every
+/// use checks it against the pool like a Reference read out of a block,
+/// so a stale or forged handle is an error rather than a read of
+/// whatever memory it names. Handles must be released with their pool.
+#[derive(Debug)]
+pub struct Handle {
+    persisted: PersistedArcByteSlice,
+}
+
+impl Handle {
+    /// Hold a count on arc's block
+    pub fn new(arc: &ArcByteSlice) -> Handle {
+        Handle { persisted: arc.clone_to_persisted() }
+    }
+
+    /// Take over the count a stored reference holds, see Pool::make_reference
+    pub fn from_reference(reference: &Reference, pool: &Pool) -> Result<Handle, LodestoneError> {
+        Ok(Handle { persisted: try!(pool.take_reference(reference)) })
+    }
+
+    /// The reference to store in a block, which then holds this
+    /// handle's count
+    pub fn into_reference(self) -> Reference {
+        self.reference()
+    }
+
+    /// Names the block without a count, e.g. to compare handles
+    pub fn reference(&self) -> Reference {
+        Reference::from_persisted(&self.persisted)
+    }
+
+    pub fn get<'p>(&self, pool: &'p Pool) -> Result<ArcByteSlice<'p>, LodestoneError> {
+        pool.resolve(&self.reference())
+    }
+
+    pub fn try_clone(&self, pool: &Pool) -> Result<Handle, LodestoneError> {
+        self.get(pool).map(|arc| Handle::new(&arc))
+    }
+
+    /// Give up the count. Returns whether the block was freed.
+    pub fn release(self, pool: &Pool) -> Result<bool, LodestoneError> {
+        try!(pool.take_reference(&self.reference())).release(pool)
+    }
+
+    pub fn downgrade(&self) -> WeakByteSlice {
+        self.persisted.downgrade()
+    }
+
+    /// Priviledged, should not be called outside allocator package
+    pub(crate) fn _persisted(&self) -> &PersistedArcByteSlice {
+        &self.persisted
+    }
+}
+
+/// A handle to a block that doesn't hold a count on it, for caches and
+/// secondary indexes that shouldn't keep blocks alive. Upgrading gives an
+/// ArcByteSlice while anything else still holds the block, and None once
+/// it was freed, even if its memory was handed out again since: the id
+/// tag is checked like a persisted handle's. Nothing is counted for weak
+/// handles, so they cost the block nothing and needn't be released.
+/// Like PersistedArcByteSlice it must be upgraded with its own pool.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WeakByteSlice {
+    arc_inner_index: usize,
+    id_tag: usize,
+}
+
+impl WeakByteSlice {
+    /// Weak handles can be stored in blocks as references that own no
+    /// count. Nothing is checked until the handle is upgraded.
+    pub fn from_reference(reference: &Reference) -> WeakByteSlice {
+        WeakByteSlice {
+            arc_inner_index: reference.arc_inner_index(),
+            id_tag: reference.generation(),
+        }
+    }
+
+    pub fn reference(&self) -> Reference {
+        Reference::new(self.arc_inner_index, self.id_tag)
+    }
+
+    pub fn upgrade<'pool>(&self, pool: &'pool Pool) -> Option<ArcByteSlice<'pool>> {
+        let (inner, id_tag) = match pool._weak_target(&self.reference()) {
+            Some(target) => target,
+            None => return None,
+        };
+        if !pool._retain_if_live(inner) {
+            return None;
+        }
+        let arc = ArcByteSlice {
+            _ptr: inner as *mut ArcByteSliceInner,
+            _pool: pool as *const Pool as *const Pool<'static>,
+            _borrow: PhantomData,
+        };
+        // The block can be freed and handed out again between the tag
+        // check and the retain. Then the count is on somebody else's
+        // block, and dropping the arc gives it back.
+        if pool._get_id_tag(&arc) != id_tag {
+            return None;
+        }
+        Some(arc)
+    }
+}
+
+pub const REFERENCE_SIZE: usize = 16;
+
+/// A reference to a block that can be stored inside another block,
+/// e.g. to build graphs out of tree values. The id tag of the target
+/// acts as a generation so stale references are caught when resolved.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reference {
+    arc_inner_index: usize,
+    generation: usize,
+}
+
+impl Reference {
+    /// Nothing is checked until the reference is resolved
+    pub fn new(arc_inner_index: usize, generation: usize) -> Reference {
+        Reference {
+            arc_inner_index: arc_inner_index,
+            generation: generation,
+        }
+    }
+
+    pub(crate) fn from_persisted(persisted: &PersistedArcByteSlice) -> Reference {
+        Reference {
+            arc_inner_index: persisted.arc_inner_index,
+            generation: persisted.id_tag,
+        }
+    }
+
+    /// Little endian index followed by generation
+    pub fn to_bytes(&self) -> [u8; REFERENCE_SIZE] {
+        let mut bytes = [0u8; REFERENCE_SIZE];
+        write_word_le(&mut bytes, 0, self.arc_inner_index);
+        write_word_le(&mut bytes, WORD, self.generation);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Reference, LodestoneError> {
+        if bytes.len() != REFERENCE_SIZE {
+            return Err(LodestoneError::InvalidReference("Encoded reference has the wrong length"));
+        }
+        Ok(Reference {
+            arc_inner_index: read_word_le(bytes, 0),
+            generation: read_word_le(bytes, WORD),
+        })
+    }
+
+    pub fn arc_inner_index(&self) -> usize {
+        self.arc_inner_index
+    }
+
+    pub fn generation(&self) -> usize {
+        self.generation
+    }
+
+    /// Whether this names a block table slot rather than a block, see
+    /// Pool::make_logical_reference
+    pub fn is_logical(&self) -> bool {
+        block_table::is_logical(self.arc_inner_index)
+    }
+
+    /// Priviledged, should not be called outside allocator package
+    pub fn _with_arc_inner_index(&self, index: usize) -> Reference {
+        Reference {
+            arc_inner_index: index,
+            generation: self.generation,
+        }
+    }
+
+    /// Priviledged, should not be called outside allocator package.
+    /// The pool must have validated the index first.
+    pub(crate) fn _to_persisted(&self) -> PersistedArcByteSlice {
+        PersistedArcByteSlice {
+            arc_inner_index: self.arc_inner_index,
+            id_tag: self.generation,
+        }
+    }
+}
+
 // TODO: Adding the drop flag causes a segfault when assigning to the array
 // of children. Figure this out so I can re-enable this check
 // impl Drop for PersistedArcByteSlice {
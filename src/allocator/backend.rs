@@ -0,0 +1,75 @@
+use LodestoneError;
+
+/// Where a pool's bytes live. The pool addresses blocks as offsets into
+/// one contiguous region, so a backend has to present one: memory it
+/// owns, an mmapped file, or memory the embedder manages some other way.
+/// Backends that fetch pages on demand would need a paging layer in front
+/// of the pool, which doesn't exist yet.
+/// Pools can move between threads, taking their backend along.
+pub trait StorageBackend: Send {
+    /// Start of the region. Must stay put for the backend's lifetime.
+    fn as_mut_ptr(&mut self) -> *mut u8;
+
+    fn len(&self) -> usize;
+
+    /// How many of the len bytes are really backed by memory or disk.
+    /// Less than len for sparse backends.
+    fn materialized(&self) -> usize {
+        self.len()
+    }
+
+    /// Make the given range durable. Memory has nothing to do.
+    fn flush(&self, _offset: usize, _len: usize) -> Result<(), LodestoneError> {
+        Ok(())
+    }
+
+    /// Give the physical storage behind a page aligned range back, the
+    /// range reading as zeroes afterwards. Returns how many bytes were
+    /// actually released. Memory keeps its pages.
+    fn punch_hole(&self, _offset: usize, _len: usize) -> Result<usize, LodestoneError> {
+        Ok(0)
+    }
+}
+
+/// Punch a hole in a file for a file backed StorageBackend, see
+/// StorageBackend::punch_hole. The file keeps its size.
+#[cfg(all(target_os = "linux", feature = "hole-punching"))]
+pub fn punch_file_hole(file: &::std::fs::File, offset: usize, len: usize) -> Result<usize, LodestoneError> {
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+    use libc;
+
+    let fd = file.as_raw_fd();
+    let allocated = || unsafe {
+        let mut stat: libc::stat = mem::zeroed();
+        if libc::fstat(fd, &mut stat) == 0 { Some(stat.st_blocks as usize * 512) } else { None }
+    };
+    let before = try!(allocated().ok_or(LodestoneError::Storage("Couldn't stat the pool file")));
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    if unsafe { libc::fallocate(fd, mode, offset as libc::off_t, len as libc::off_t) } != 0 {
+        return Err(LodestoneError::Storage("The filesystem refused to punch a hole"));
+    }
+    let after = try!(allocated().ok_or(LodestoneError::Storage("Couldn't stat the pool file")));
+    Ok(before.saturating_sub(after))
+}
+
+/// Anonymous heap memory owned by the pool
+pub struct HeapBackend {
+    bytes: Vec<u8>,
+}
+
+impl HeapBackend {
+    pub fn new(size: usize) -> HeapBackend {
+        HeapBackend { bytes: vec![0; size] }
+    }
+}
+
+impl StorageBackend for HeapBackend {
+    fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.bytes.as_mut_ptr()
+    }
+
+    fn len(&self) -> usize {
+        self.bytes.len()
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::BTreeSet;
+
+/// The free blocks of a pool, grouped by size so an allocation goes
+/// straight to blocks that can hold it instead of walking the skip list.
+/// Bin k holds blocks spanning [2^k, 2^(k+1)) bytes, headers included,
+/// which puts page sized blocks in a bin of their own, apart from the
+/// small ones left by keys and values. Within a bin blocks are kept in
+/// offset order, so the lowest fitting block still wins, same as a walk
+/// from the start would have found. Nothing here is persisted: the skip
+/// list is the record, and the bins are rebuilt from it on open.
+
+const BINS: usize = 64;
+
+pub struct FreeBins {
+    bins: Vec<BTreeSet<usize>>,
+}
+
+fn bin_for(span: usize) -> usize {
+    debug_assert!(span > 0);
+    BINS - 1 - span.leading_zeros() as usize
+}
+
+impl FreeBins {
+    pub fn new() -> FreeBins {
+        FreeBins {
+            bins: (0..BINS).map(|_| BTreeSet::new()).collect(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for bin in self.bins.iter_mut() {
+            bin.clear();
+        }
+    }
+
+    pub fn insert(&mut self, offset: usize, span: usize) {
+        self.bins[bin_for(span)].insert(offset);
+    }
+
+    pub fn remove(&mut self, offset: usize, span: usize) {
+        self.bins[bin_for(span)].remove(&offset);
+    }
+
+    /// The lowest free block spanning at least span bytes. Blocks in the
+    /// bins above span's own are all big enough, so only span's own bin
+    /// is searched, asking span_of how big its blocks are.
+    pub fn find<F>(&self, span: usize, span_of: F) -> Option<usize>
+        where F: Fn(usize) -> usize {
+        let first = bin_for(span);
+        let fitting = self.bins[first].iter().cloned().find(|&offset| span_of(offset) >= span);
+        self.bins[first + 1..].iter()
+            .filter_map(|bin| bin.iter().next().cloned())
+            .chain(fitting)
+            .min()
+    }
+
+    /// The lowest free block of any size
+    pub fn lowest(&self) -> Option<usize> {
+        self.bins.iter().filter_map(|bin| bin.iter().next().cloned()).min()
+    }
+
+    pub fn len(&self) -> usize {
+        self.bins.iter().map(|bin| bin.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_find_lowest_fitting() {
+        let mut spans = HashMap::new();
+        let mut bins = FreeBins::new();
+        for &(offset, span) in [(0, 48), (100, 96), (300, 4096), (9000, 80), (20000, 1 << 20)].iter() {
+            bins.insert(offset, span);
+            spans.insert(offset, span);
+        }
+        let span_of = |offset: usize| spans[&offset];
+        assert_eq!(Some(0), bins.find(40, &span_of));
+        // 48 is in the same bin as 64, but too small
+        assert_eq!(Some(100), bins.find(64, &span_of));
+        assert_eq!(Some(300), bins.find(97, &span_of));
+        assert_eq!(Some(300), bins.find(4096, &span_of));
+        assert_eq!(Some(20000), bins.find(4097, &span_of));
+        assert_eq!(None, bins.find((1 << 20) + 1, &span_of));
+        assert_eq!(Some(0), bins.lowest());
+
+        bins.remove(0, 48);
+        bins.remove(300, 4096);
+        assert_eq!(Some(100), bins.lowest());
+        assert_eq!(Some(100), bins.find(80, &span_of));
+        assert_eq!(Some(20000), bins.find(97, &span_of));
+        assert_eq!(3, bins.len());
+        bins.clear();
+        assert_eq!(None, bins.lowest());
+    }
+}
@@ -0,0 +1,272 @@
+use codec::*;
+use LodestoneError;
+
+/// Stable logical ids for blocks that may move. A logical reference
+/// names a slot in the pool's block table (a block of its own, pinned as
+/// BLOCK_TABLE_PIN) instead of a place in the buffer, and the slot says
+/// where the block is now. Moving the block (Pool::relocate) only
+/// rewrites its slot, so whatever stores the logical reference keeps
+/// working, for the cost of one lookup per resolve.
+///
+/// Logical references are told apart by their index: block indexes are
+/// word aligned, a logical one has LOGICAL_TAG in its low bits and the
+/// slot above them. Its generation is the slot's, bumped every time the
+/// slot is reused, so a reference to a slot since given away is caught.
+///
+/// The slot counts the logical references to it and holds one strong
+/// count on the block, dropped once the last logical reference is.
+///
+/// Layout: magic, capacity, the first free slot and how many are live,
+/// then per slot the block's index and id tag, the slot's generation and
+/// its count. Free slots hold the next free slot in place of an index.
+
+pub const BLOCK_TABLE_PIN: &'static str = "block table";
+pub const DEFAULT_BLOCK_TABLE_SLOTS: usize = 64;
+
+const LOGICAL_TAG: usize = 2;
+const TAG_BITS: usize = 3;
+const NO_SLOT: usize = !0;
+const BLOCK_TABLE_MAGIC: usize = 0x6c6f_6765_7462_6c6b;
+
+const MAGIC_AT: usize = 0;
+const CAPACITY_AT: usize = WORD;
+const FREE_HEAD_AT: usize = 2 * WORD;
+const LIVE_AT: usize = 3 * WORD;
+const HEADER_SIZE: usize = 4 * WORD;
+
+const SLOT_SIZE: usize = 4 * WORD;
+const PHYSICAL: usize = 0;
+const TAG: usize = WORD;
+const GENERATION: usize = 2 * WORD;
+const COUNT: usize = 3 * WORD;
+
+pub fn is_logical(arc_inner_index: usize) -> bool {
+    arc_inner_index & ((1 << TAG_BITS) - 1) == LOGICAL_TAG
+}
+
+pub fn logical_index(slot: usize) -> usize {
+    slot << TAG_BITS | LOGICAL_TAG
+}
+
+pub fn slot_of(arc_inner_index: usize) -> usize {
+    arc_inner_index >> TAG_BITS
+}
+
+/// Bytes a table of capacity slots takes
+pub fn table_size(capacity: usize) -> usize {
+    HEADER_SIZE + capacity * SLOT_SIZE
+}
+
+/// Where a live slot's block is
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Slot {
+    pub arc_inner_index: usize,
+    pub id_tag: usize,
+    pub generation: usize,
+    pub count: usize,
+}
+
+/// A block table, read and written in place
+pub struct BlockTable<'a> {
+    bytes: &'a mut [u8],
+}
+
+impl<'a> BlockTable<'a> {
+    /// Lay out an empty table over bytes, as many slots as fit
+    pub fn init(bytes: &'a mut [u8]) -> BlockTable<'a> {
+        let capacity = (bytes.len() - HEADER_SIZE) / SLOT_SIZE;
+        let mut table = BlockTable { bytes: bytes };
+        table.write(MAGIC_AT, BLOCK_TABLE_MAGIC);
+        table.write(CAPACITY_AT, capacity);
+        table.write(LIVE_AT, 0);
+        table.write(FREE_HEAD_AT, if capacity == 0 { NO_SLOT } else { 0 });
+        for slot in 0..capacity {
+            let next = if slot + 1 == capacity { NO_SLOT } else { slot + 1 };
+            table.write_slot(slot, PHYSICAL, next);
+            table.write_slot(slot, TAG, 0);
+            table.write_slot(slot, GENERATION, 0);
+            table.write_slot(slot, COUNT, 0);
+        }
+        table
+    }
+
+    pub fn open(bytes: &'a mut [u8]) -> Result<BlockTable<'a>, LodestoneError> {
+        if bytes.len() < HEADER_SIZE || read_word_le(bytes, MAGIC_AT) != BLOCK_TABLE_MAGIC {
+            return Err(LodestoneError::Corruption("Block table has a bad magic number"));
+        }
+        if table_size(read_word_le(bytes, CAPACITY_AT)) > bytes.len() {
+            return Err(LodestoneError::Corruption("Block table is smaller than its capacity"));
+        }
+        Ok(BlockTable { bytes: bytes })
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.read(CAPACITY_AT)
+    }
+
+    pub fn live(&self) -> usize {
+        self.read(LIVE_AT)
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.read(FREE_HEAD_AT) == NO_SLOT
+    }
+
+    /// The live slot a logical reference names, if its generation matches
+    pub fn lookup(&self, arc_inner_index: usize, generation: usize) -> Option<Slot> {
+        let slot = slot_of(arc_inner_index);
+        if !is_logical(arc_inner_index) || slot >= self.capacity() {
+            return None;
+        }
+        let found = self.slot(slot);
+        if found.count == 0 || found.generation != generation {
+            return None;
+        }
+        Some(found)
+    }
+
+    /// Give a free slot to the block, counting one logical reference.
+    /// Returns the slot's logical index and generation, None if full.
+    pub fn assign(&mut self, arc_inner_index: usize, id_tag: usize) -> Option<(usize, usize)> {
+        let slot = self.read(FREE_HEAD_AT);
+        if slot == NO_SLOT {
+            return None;
+        }
+        let next = self.read_slot(slot, PHYSICAL);
+        self.write(FREE_HEAD_AT, next);
+        let generation = self.read_slot(slot, GENERATION) + 1;
+        self.write_slot(slot, PHYSICAL, arc_inner_index);
+        self.write_slot(slot, TAG, id_tag);
+        self.write_slot(slot, GENERATION, generation);
+        self.write_slot(slot, COUNT, 1);
+        let live = self.live();
+        self.write(LIVE_AT, live + 1);
+        Some((logical_index(slot), generation))
+    }
+
+    /// Point the slot at the block's new place
+    pub fn moved(&mut self, arc_inner_index: usize, to: usize, id_tag: usize) {
+        let slot = slot_of(arc_inner_index);
+        self.write_slot(slot, PHYSICAL, to);
+        self.write_slot(slot, TAG, id_tag);
+    }
+
+    pub fn retain(&mut self, arc_inner_index: usize) {
+        let slot = slot_of(arc_inner_index);
+        let count = self.read_slot(slot, COUNT);
+        self.write_slot(slot, COUNT, count + 1);
+    }
+
+    /// Drop a logical reference. When it was the last one the slot is
+    /// freed, and the block it held is handed back to be released.
+    pub fn release(&mut self, arc_inner_index: usize) -> Option<Slot> {
+        let slot = slot_of(arc_inner_index);
+        let held = self.slot(slot);
+        self.write_slot(slot, COUNT, held.count - 1);
+        if held.count > 1 {
+            return None;
+        }
+        let head = self.read(FREE_HEAD_AT);
+        self.write_slot(slot, PHYSICAL, head);
+        self.write_slot(slot, TAG, 0);
+        self.write(FREE_HEAD_AT, slot);
+        let live = self.live();
+        self.write(LIVE_AT, live - 1);
+        Some(held)
+    }
+
+    /// Copy every slot into a fresh table with at least as many slots
+    pub fn copy_into(&self, other: &mut BlockTable) {
+        let capacity = self.capacity();
+        debug_assert!(other.capacity() >= capacity && other.live() == 0);
+        other.bytes[HEADER_SIZE..table_size(capacity)].copy_from_slice(&self.bytes[HEADER_SIZE..table_size(capacity)]);
+        // The old free list ends where the new slots begin
+        let mut free = Vec::new();
+        for slot in 0..capacity {
+            if self.read_slot(slot, COUNT) == 0 {
+                free.push(slot);
+            }
+        }
+        free.extend(capacity..other.capacity());
+        for pair in free.windows(2) {
+            other.write_slot(pair[0], PHYSICAL, pair[1]);
+        }
+        if let Some(&last) = free.last() {
+            other.write_slot(last, PHYSICAL, NO_SLOT);
+        }
+        other.write(FREE_HEAD_AT, free.first().cloned().unwrap_or(NO_SLOT));
+        other.write(LIVE_AT, self.live());
+    }
+
+    /// Every live slot, with its logical index
+    pub fn live_slots(&self) -> Vec<(usize, Slot)> {
+        (0..self.capacity())
+            .map(|slot| (logical_index(slot), self.slot(slot)))
+            .filter(|&(_, ref found)| found.count > 0)
+            .collect()
+    }
+
+    fn slot(&self, slot: usize) -> Slot {
+        Slot {
+            arc_inner_index: self.read_slot(slot, PHYSICAL),
+            id_tag: self.read_slot(slot, TAG),
+            generation: self.read_slot(slot, GENERATION),
+            count: self.read_slot(slot, COUNT),
+        }
+    }
+
+    fn read(&self, at: usize) -> usize {
+        read_word_le(self.bytes, at)
+    }
+
+    fn write(&mut self, at: usize, value: usize) {
+        write_word_le(self.bytes, at, value);
+    }
+
+    fn read_slot(&self, slot: usize, field: usize) -> usize {
+        self.read(HEADER_SIZE + slot * SLOT_SIZE + field)
+    }
+
+    fn write_slot(&mut self, slot: usize, field: usize, value: usize) {
+        self.write(HEADER_SIZE + slot * SLOT_SIZE + field, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slots_are_reused_with_new_generations() {
+        let mut bytes = vec![0u8; table_size(2)];
+        let mut table = BlockTable::init(&mut bytes);
+        let (a, a_gen) = table.assign(64, 7).unwrap();
+        let (b, _) = table.assign(128, 8).unwrap();
+        assert!(is_logical(a) && !is_logical(64));
+        assert!(table.is_full());
+        assert_eq!(None, table.assign(256, 9));
+
+        table.retain(a);
+        table.moved(a, 512, 10);
+        assert_eq!(Some(Slot { arc_inner_index: 512, id_tag: 10, generation: a_gen, count: 2 }),
+            table.lookup(a, a_gen));
+        assert_eq!(None, table.release(a));
+        assert_eq!(Some(512), table.release(a).map(|s| s.arc_inner_index));
+        assert_eq!(None, table.lookup(a, a_gen));
+
+        // The freed slot comes back under a new generation
+        let (c, c_gen) = table.assign(1024, 11).unwrap();
+        assert_eq!(a, c);
+        assert!(c_gen != a_gen);
+        assert_eq!(None, table.lookup(a, a_gen));
+
+        let mut bigger = vec![0u8; table_size(4)];
+        let mut grown = BlockTable::init(&mut bigger);
+        table.copy_into(&mut grown);
+        assert_eq!(2, grown.live());
+        assert_eq!(table.live_slots(), grown.live_slots());
+        assert!(grown.assign(2048, 12).is_some() && grown.assign(4096, 13).is_some());
+        assert!(grown.is_full());
+        assert_eq!(Some(128), grown.lookup(b, 1).map(|s| s.arc_inner_index));
+    }
+}
@@ -0,0 +1,58 @@
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+
+use super::sync::*;
+
+/// Off by default. When enabled on a pool, roughly one in every `one_in`
+/// allocations ignores the free bins and walks the skip list from the
+/// start of the pool instead, the walk the bins are rebuilt with on open.
+/// Cheap enough to leave on in a staging or canary deployment so that
+/// path keeps getting exercised.
+#[derive(Debug)]
+pub struct Chaos {
+    one_in: usize,
+    state: AtomicUsize,
+    forced_slow_paths: AtomicUsize,
+}
+
+impl Chaos {
+    pub fn new(one_in: usize, seed: usize) -> Chaos {
+        assert!(one_in > 0, "Chaos must strike at most once per allocation");
+        Chaos {
+            one_in: one_in,
+            state: AtomicUsize::new(xorshift_seed(seed)),
+            forced_slow_paths: AtomicUsize::new(0),
+        }
+    }
+
+    /// How many allocations have been pushed onto the slow path
+    pub fn forced_slow_paths(&self) -> usize {
+        self.forced_slow_paths.load(Relaxed)
+    }
+
+    /// Roll the dice, counting a hit
+    pub fn strike(&self) -> bool {
+        let hit = roll(&self.state) % self.one_in == 0;
+        if hit {
+            self.forced_slow_paths.fetch_add(1, Relaxed);
+        }
+        hit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strike_rate() {
+        let always = Chaos::new(1, 0);
+        assert!((0..10).all(|_| always.strike()));
+        assert_eq!(10, always.forced_slow_paths());
+
+        let rarely = Chaos::new(100, 42);
+        let hits = (0..10000).filter(|_| rarely.strike()).count();
+        assert!(hits > 50 && hits < 200, "{} hits", hits);
+        assert_eq!(hits, rarely.forced_slow_paths());
+    }
+}
@@ -0,0 +1,121 @@
+use std::cmp;
+use std::collections::BTreeMap;
+
+/// Tracks what a pool has written since its last flush, so a flush
+/// only asks the backend to sync what changed. Ranges are merged as
+/// they're marked, and handed out in offset order so the backend sees
+/// sequential IO, cut into extents of at most max_extent bytes.
+
+pub const DEFAULT_MAX_FLUSH_EXTENT: usize = 1 << 20;
+
+/// Counters for tuning flushes
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FlushStats {
+    pub flushes: usize,
+    /// Dirty ranges marked, before coalescing
+    pub ranges: usize,
+    pub bytes: usize,
+    /// Calls to StorageBackend::flush
+    pub calls: usize,
+}
+
+pub struct FlushState {
+    /// start -> end, never overlapping or touching
+    dirty: BTreeMap<usize, usize>,
+    marked: usize,
+    max_extent: usize,
+    stats: FlushStats,
+}
+
+impl FlushState {
+    pub fn new() -> FlushState {
+        FlushState {
+            dirty: BTreeMap::new(),
+            marked: 0,
+            max_extent: DEFAULT_MAX_FLUSH_EXTENT,
+            stats: FlushStats::default(),
+        }
+    }
+
+    pub fn set_max_extent(&mut self, bytes: usize) {
+        assert!(bytes > 0, "Flush extents can't be empty");
+        self.max_extent = bytes;
+    }
+
+    pub fn stats(&self) -> &FlushStats {
+        &self.stats
+    }
+
+    pub fn mark(&mut self, start: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
+        self.marked += 1;
+        let (mut start, mut end) = (start, start + len);
+        // A range starting before this one might reach into it
+        if let Some((&s, &e)) = self.dirty.range(..start + 1).next_back() {
+            if e >= start {
+                start = s;
+                end = cmp::max(end, e);
+            }
+        }
+        let swallowed: Vec<usize> = self.dirty.range(start..end + 1).map(|(&s, _)| s).collect();
+        for s in swallowed {
+            end = cmp::max(end, self.dirty.remove(&s).unwrap());
+        }
+        self.dirty.insert(start, end);
+    }
+
+    /// What to flush, as (offset, len) in offset order
+    pub fn extents(&self) -> Vec<(usize, usize)> {
+        let mut extents = Vec::new();
+        for (&start, &end) in self.dirty.iter() {
+            let mut at = start;
+            while at < end {
+                let len = cmp::min(self.max_extent, end - at);
+                extents.push((at, len));
+                at += len;
+            }
+        }
+        extents
+    }
+
+    /// Record a successful flush of the given extents and start over
+    pub fn flushed(&mut self, extents: &[(usize, usize)]) {
+        self.stats.flushes += 1;
+        self.stats.ranges += self.marked;
+        self.stats.calls += extents.len();
+        self.stats.bytes += extents.iter().fold(0, |sum, &(_, len)| sum + len);
+        self.dirty.clear();
+        self.marked = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coalescing() {
+        let mut state = FlushState::new();
+        state.mark(100, 10);
+        state.mark(0, 10);
+        // Touching and overlapping ranges merge
+        state.mark(10, 5);
+        state.mark(105, 20);
+        state.mark(95, 5);
+        state.mark(200, 0);
+        assert_eq!(vec![(0, 15), (95, 30)], state.extents());
+
+        // Swallowing several ranges at once
+        state.mark(12, 100);
+        assert_eq!(vec![(0, 125)], state.extents());
+
+        state.set_max_extent(50);
+        assert_eq!(vec![(0, 50), (50, 50), (100, 25)], state.extents());
+        let extents = state.extents();
+        state.flushed(&extents);
+        assert_eq!(FlushStats { flushes: 1, ranges: 6, bytes: 125, calls: 3 }, *state.stats());
+        assert!(state.extents().is_empty());
+    }
+}
@@ -0,0 +1,207 @@
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Instant;
+
+use super::sharded::Sharded;
+
+/// Bytes read and written through a pool, in total and over the last
+/// minute and hour, for rate limiting and spotting IO amplification
+/// from inside the process. Logical bytes are block contents, physical
+/// bytes add the block headers, flushed bytes are what was handed to
+/// the backend (Pool::flush).
+///
+/// Windows are rings of buckets, one second wide for the minute and a
+/// minute wide for the hour, so a window covers between its length and
+/// one bucket less. Buckets are reset by whoever first records into them
+/// in a new period without a lock, and a write racing the reset can be
+/// lost: rates are close, not exact. Totals are exact.
+///
+/// Reads are counted on every resolve, so their counters are sharded
+/// (see sharded) and a snapshot adds the shards up.
+
+const MINUTE_BUCKETS: usize = 60;
+const HOUR_BUCKETS: usize = 60;
+
+/// One counter, read through IoStats::snapshot
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct IoRate {
+    pub total: usize,
+    pub last_minute: usize,
+    pub last_hour: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IoSnapshot {
+    pub logical_read: IoRate,
+    pub logical_written: IoRate,
+    pub physical_read: IoRate,
+    pub physical_written: IoRate,
+    pub flushed: IoRate,
+}
+
+struct Bucket {
+    period: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+struct Window {
+    seconds_per_bucket: usize,
+    buckets: Vec<Bucket>,
+}
+
+impl Window {
+    fn new(buckets: usize, seconds_per_bucket: usize) -> Window {
+        Window {
+            seconds_per_bucket: seconds_per_bucket,
+            // Period 0 is the first, !0 is one that never comes
+            buckets: (0..buckets).map(|_| Bucket { period: AtomicUsize::new(!0), bytes: AtomicUsize::new(0) }).collect(),
+        }
+    }
+
+    fn add(&self, bytes: usize, second: usize) {
+        let period = second / self.seconds_per_bucket;
+        let bucket = &self.buckets[period % self.buckets.len()];
+        if bucket.period.swap(period, Relaxed) != period {
+            bucket.bytes.store(0, Relaxed);
+        }
+        bucket.bytes.fetch_add(bytes, Relaxed);
+    }
+
+    fn sum(&self, second: usize) -> usize {
+        let period = second / self.seconds_per_bucket;
+        self.buckets.iter()
+            .filter(|b| {
+                let p = b.period.load(Relaxed);
+                p <= period && period - p < self.buckets.len()
+            })
+            .map(|b| b.bytes.load(Relaxed))
+            .sum()
+    }
+}
+
+struct Counter {
+    total: AtomicUsize,
+    minute: Window,
+    hour: Window,
+}
+
+impl Counter {
+    fn new() -> Counter {
+        Counter {
+            total: AtomicUsize::new(0),
+            minute: Window::new(MINUTE_BUCKETS, 1),
+            hour: Window::new(HOUR_BUCKETS, 60),
+        }
+    }
+
+    fn add(&self, bytes: usize, second: usize) {
+        self.total.fetch_add(bytes, Relaxed);
+        self.minute.add(bytes, second);
+        self.hour.add(bytes, second);
+    }
+
+    fn rate(&self, second: usize) -> IoRate {
+        IoRate {
+            total: self.total.load(Relaxed),
+            last_minute: self.minute.sum(second),
+            last_hour: self.hour.sum(second),
+        }
+    }
+}
+
+struct ReadCounters {
+    logical: Counter,
+    physical: Counter,
+}
+
+pub struct IoStats {
+    started: Instant,
+    reads: Sharded<ReadCounters>,
+    logical_written: Counter,
+    physical_written: Counter,
+    flushed: Counter,
+}
+
+impl IoStats {
+    pub fn new() -> IoStats {
+        IoStats {
+            started: Instant::now(),
+            reads: Sharded::new(|| ReadCounters { logical: Counter::new(), physical: Counter::new() }),
+            logical_written: Counter::new(),
+            physical_written: Counter::new(),
+            flushed: Counter::new(),
+        }
+    }
+
+    /// A block was read, len bytes of it contents
+    pub fn read(&self, len: usize, overhead: usize) {
+        let second = self.second();
+        let reads = self.reads.local();
+        reads.logical.add(len, second);
+        reads.physical.add(len + overhead, second);
+    }
+
+    /// A block was written, len bytes of it contents
+    pub fn written(&self, len: usize, overhead: usize) {
+        let second = self.second();
+        self.logical_written.add(len, second);
+        self.physical_written.add(len + overhead, second);
+    }
+
+    pub fn flushed(&self, bytes: usize) {
+        self.flushed.add(bytes, self.second());
+    }
+
+    pub fn snapshot(&self) -> IoSnapshot {
+        self.snapshot_at(self.second())
+    }
+
+    fn snapshot_at(&self, second: usize) -> IoSnapshot {
+        IoSnapshot {
+            logical_read: self.read_rate(second, |r| &r.logical),
+            logical_written: self.logical_written.rate(second),
+            physical_read: self.read_rate(second, |r| &r.physical),
+            physical_written: self.physical_written.rate(second),
+            flushed: self.flushed.rate(second),
+        }
+    }
+
+    fn read_rate<F>(&self, second: usize, counter: F) -> IoRate
+        where F: Fn(&ReadCounters) -> &Counter {
+        self.reads.iter().map(|r| counter(r).rate(second)).fold(IoRate::default(), |sum, rate| IoRate {
+            total: sum.total + rate.total,
+            last_minute: sum.last_minute + rate.last_minute,
+            last_hour: sum.last_hour + rate.last_hour,
+        })
+    }
+
+    fn second(&self) -> usize {
+        self.started.elapsed().as_secs() as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_windows_roll_over() {
+        let stats = IoStats::new();
+        stats.logical_written.add(100, 0);
+        stats.logical_written.add(50, 30);
+        assert_eq!(IoRate { total: 150, last_minute: 150, last_hour: 150 }, stats.snapshot_at(30).logical_written);
+        // The first second has left the minute, not the hour
+        assert_eq!(IoRate { total: 150, last_minute: 50, last_hour: 150 }, stats.snapshot_at(60).logical_written);
+        // A bucket reused a minute later starts over
+        stats.logical_written.add(7, 60);
+        assert_eq!(57, stats.snapshot_at(60).logical_written.last_minute);
+        assert_eq!(157, stats.snapshot_at(60).logical_written.last_hour);
+        // Both windows pass, the total stays
+        assert_eq!(IoRate { total: 157, last_minute: 0, last_hour: 0 }, stats.snapshot_at(2 * 3600).logical_written);
+
+        stats.read(10, 24);
+        stats.flushed(4096);
+        let now = stats.snapshot();
+        assert_eq!((10, 34, 4096), (now.logical_read.last_minute, now.physical_read.last_minute, now.flushed.total));
+    }
+}
@@ -0,0 +1,112 @@
+use checksum::crc32;
+use codec::*;
+
+/// Crash safe commits. The metadata block holds ROOT_RECORDS records of
+/// the committed root, each with a sequence number and a checksum. A
+/// commit overwrites the record that isn't the newest, after everything
+/// the new root reaches has been written, so a crash part way through
+/// the record leaves a torn record that fails its checksum, and the one
+/// before it still names a whole tree. Recovery takes the newest record
+/// whose checksum holds (Pool::journaled_root).
+
+pub const ROOT_RECORDS: usize = 2;
+
+/// A root as a commit left it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JournaledRoot {
+    /// 0 for an empty tree
+    pub index: usize,
+    pub generation: usize,
+    pub tx_id: usize,
+    pub entries: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RootRecord {
+    /// 0 for a record that was never written
+    sequence: usize,
+    root: JournaledRoot,
+    checksum: u32,
+}
+
+impl RootRecord {
+    pub fn empty() -> RootRecord {
+        RootRecord {
+            sequence: 0,
+            root: JournaledRoot { index: 0, generation: 0, tx_id: 0, entries: 0 },
+            checksum: 0,
+        }
+    }
+
+    fn new(sequence: usize, root: JournaledRoot) -> RootRecord {
+        RootRecord {
+            sequence: sequence,
+            root: root,
+            checksum: checksum(sequence, &root),
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        self.sequence != 0 && self.checksum == checksum(self.sequence, &self.root)
+    }
+}
+
+/// Which of records is the newest valid one
+fn newest(records: &[RootRecord; ROOT_RECORDS]) -> Option<usize> {
+    (0..ROOT_RECORDS)
+        .filter(|&i| records[i].is_valid())
+        .max_by_key(|&i| records[i].sequence)
+}
+
+pub fn latest(records: &[RootRecord; ROOT_RECORDS]) -> Option<JournaledRoot> {
+    newest(records).map(|i| records[i].root)
+}
+
+/// Record root in place of the oldest record, leaving the newest alone
+pub fn record(records: &mut [RootRecord; ROOT_RECORDS], root: JournaledRoot) {
+    let (slot, sequence) = match newest(records) {
+        Some(i) => ((i + 1) % ROOT_RECORDS, records[i].sequence + 1),
+        None => (0, 1),
+    };
+    records[slot] = RootRecord::new(sequence, root);
+}
+
+fn checksum(sequence: usize, root: &JournaledRoot) -> u32 {
+    let mut bytes = [0u8; 5 * WORD];
+    write_word_le(&mut bytes, 0, sequence);
+    write_word_le(&mut bytes, WORD, root.index);
+    write_word_le(&mut bytes, 2 * WORD, root.generation);
+    write_word_le(&mut bytes, 3 * WORD, root.tx_id);
+    write_word_le(&mut bytes, 4 * WORD, root.entries);
+    crc32(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn root(tx_id: usize) -> JournaledRoot {
+        JournaledRoot { index: 64 * tx_id, generation: tx_id + 10, tx_id: tx_id, entries: tx_id }
+    }
+
+    #[test]
+    fn test_torn_record_falls_back() {
+        let mut records = [RootRecord::empty(); ROOT_RECORDS];
+        assert_eq!(None, latest(&records));
+        record(&mut records, root(1));
+        record(&mut records, root(2));
+        assert_eq!(Some(root(2)), latest(&records));
+
+        // The third commit goes over the first, and tears
+        record(&mut records, root(3));
+        assert_eq!(Some(root(3)), latest(&records));
+        records[0].root.index += 8;
+        assert_eq!(Some(root(2)), latest(&records));
+
+        // The next commit replaces the torn record, not the good one
+        record(&mut records, root(4));
+        assert_eq!(Some(root(4)), latest(&records));
+        records[0].checksum ^= 1;
+        assert_eq!(Some(root(2)), latest(&records));
+    }
+}
@@ -0,0 +1,129 @@
+use checksum::crc32;
+use codec::*;
+
+/// Every commit bumps the pool's generation and extends a hash chain,
+/// hash(n) = crc32(hash(n-1) ++ root(n)). Someone holding on to the
+/// Lineage they last saw can later tell whether the pool still descends
+/// from it, e.g. to catch a pool file that was restored from an older
+/// copy or written by someone else on a synced filesystem.
+/// The last LINEAGE_LINKS links are kept in the metadata block.
+
+pub const LINEAGE_LINKS: usize = 8;
+const ABSENT: usize = !0;
+
+/// Where a pool's commit history stood at some point
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Lineage {
+    pub generation: usize,
+    pub hash: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineageCheck {
+    /// The same history, possibly with later commits on top
+    Continuous,
+    /// The pool is at an earlier generation than the one seen
+    RolledBack,
+    /// The pool's history doesn't pass through the one seen
+    Diverged,
+    /// Too many commits since, the link that was seen is no longer kept
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Link {
+    generation: usize,
+    root: usize,
+    hash: u32,
+}
+
+impl Link {
+    pub fn absent() -> Link {
+        Link { generation: ABSENT, root: 0, hash: 0 }
+    }
+
+    /// Generation 0, before anything was committed
+    pub fn origin() -> Link {
+        Link { generation: 0, root: 0, hash: 0 }
+    }
+
+    pub fn next(&self, root: usize) -> Link {
+        Link {
+            generation: self.generation + 1,
+            root: root,
+            hash: chain(self.hash, root),
+        }
+    }
+
+    pub fn lineage(&self) -> Lineage {
+        Lineage {
+            generation: self.generation,
+            hash: self.hash,
+        }
+    }
+}
+
+fn chain(previous: u32, root: usize) -> u32 {
+    let mut bytes = [0u8; 12];
+    write_u32_le(&mut bytes, 0, previous);
+    write_word_le(&mut bytes, 4, root);
+    crc32(&bytes)
+}
+
+/// Check seen against the ring of recent links, whose newest is at generation.
+/// The links after the seen one are re-hashed, so an edited link is caught too.
+pub fn check(links: &[Link; LINEAGE_LINKS], generation: usize, seen: &Lineage) -> LineageCheck {
+    if seen.generation > generation {
+        return LineageCheck::RolledBack;
+    }
+    if generation - seen.generation >= LINEAGE_LINKS {
+        return LineageCheck::Unknown;
+    }
+    let mut previous = links[seen.generation % LINEAGE_LINKS];
+    if previous.generation != seen.generation || previous.hash != seen.hash {
+        return LineageCheck::Diverged;
+    }
+    for g in seen.generation + 1..generation + 1 {
+        let link = links[g % LINEAGE_LINKS];
+        if link.generation != g || link.hash != chain(previous.hash, link.root) {
+            return LineageCheck::Diverged;
+        }
+        previous = link;
+    }
+    LineageCheck::Continuous
+}
+
+#[cfg(test)]
+mod tests {
+    use allocator::*;
+
+    #[test]
+    fn test_lineage() {
+        let mut buf = vec![0u8; 0x2000];
+        let pool = Pool::new(&mut buf);
+        let origin = pool.lineage();
+        assert_eq!(0, origin.generation);
+
+        let first = pool.record_commit(64);
+        pool.record_commit(128);
+        let third = pool.record_commit(64);
+        assert_eq!(third, pool.lineage());
+        assert_eq!(3, third.generation);
+        // Same root, different history
+        assert!(first.hash != third.hash);
+
+        assert_eq!(LineageCheck::Continuous, pool.check_lineage(&origin));
+        assert_eq!(LineageCheck::Continuous, pool.check_lineage(&first));
+        assert_eq!(LineageCheck::Continuous, pool.check_lineage(&third));
+        let ahead = Lineage { generation: 4, hash: third.hash };
+        assert_eq!(LineageCheck::RolledBack, pool.check_lineage(&ahead));
+        let other = Lineage { generation: 1, hash: first.hash ^ 1 };
+        assert_eq!(LineageCheck::Diverged, pool.check_lineage(&other));
+
+        for root in 0..LINEAGE_LINKS {
+            pool.record_commit(root * 64);
+        }
+        assert_eq!(LineageCheck::Unknown, pool.check_lineage(&first));
+        assert_eq!(LineageCheck::Continuous, pool.check_lineage(&pool.lineage()));
+    }
+}
@@ -1,5 +1,36 @@
 pub use self::pool::*;
 pub use self::arc::*;
+pub use self::value_log::*;
+pub use self::chaos::Chaos;
+pub use self::range_lock::*;
+pub use self::backend::*;
+pub use self::sync::{RefCounting, RefCountPolicy, RefCountError, RefCountStats, MAX_REF_COUNT};
+pub use self::flush::{FlushStats, DEFAULT_MAX_FLUSH_EXTENT};
+pub use self::io_stats::{IoRate, IoSnapshot};
+pub use self::journal::{JournaledRoot, ROOT_RECORDS};
+pub use self::sharded::ShardedCounter;
+pub use self::superblock::{Superblock, FORMAT_VERSION, SUPERBLOCK_SIZE};
+pub use self::lineage::{Lineage, LineageCheck, LINEAGE_LINKS};
+pub use self::pins::{PIN_SLOTS, PIN_NAME_SIZE};
+pub use self::tiers::{TieredPools, Tier, MigrationReport};
+pub use self::progress::{CompactionProgress, CompactionPhase, ProgressHandle};
+pub use self::block_table::BLOCK_TABLE_PIN;
 
 pub mod pool;
+pub mod bins;
+pub mod block_table;
 pub mod arc;
+pub mod sync;
+pub mod value_log;
+pub mod chaos;
+pub mod range_lock;
+pub mod backend;
+pub mod io_stats;
+pub mod journal;
+pub mod sharded;
+pub mod superblock;
+pub mod lineage;
+pub mod flush;
+pub mod pins;
+pub mod tiers;
+pub mod progress;
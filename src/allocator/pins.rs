@@ -0,0 +1,48 @@
+use super::arc::{Reference, REFERENCE_SIZE};
+
+/// Roots pinned under a name, kept in the metadata block so they outlive
+/// the process that pinned them. A pin holds a strong count on its block,
+/// and sweep_unreachable treats every pin as a root, so a backup or
+/// export can pick up where it left off after a crash without the
+/// version it was reading being reclaimed underneath it.
+
+pub const PIN_SLOTS: usize = 8;
+pub const PIN_NAME_SIZE: usize = 32;
+
+#[derive(Clone, Copy)]
+pub struct Pin {
+    /// 0 for an empty slot
+    name_len: u8,
+    name: [u8; PIN_NAME_SIZE],
+    reference: [u8; REFERENCE_SIZE],
+}
+
+impl Pin {
+    pub fn empty() -> Pin {
+        Pin {
+            name_len: 0,
+            name: [0; PIN_NAME_SIZE],
+            reference: [0; REFERENCE_SIZE],
+        }
+    }
+
+    pub fn new(name: &str, reference: &Reference) -> Pin {
+        let mut pin = Pin::empty();
+        pin.name_len = name.len() as u8;
+        pin.name[..name.len()].copy_from_slice(name.as_bytes());
+        pin.reference = reference.to_bytes();
+        pin
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.name_len == 0
+    }
+
+    pub fn name(&self) -> &[u8] {
+        &self.name[..self.name_len as usize]
+    }
+
+    pub fn reference(&self) -> Reference {
+        Reference::from_bytes(&self.reference).unwrap()
+    }
+}
@@ -1,34 +1,124 @@
-use std::{mem, fmt, slice};
-use std::sync::atomic::AtomicUsize;
-use std::sync::atomic::Ordering::SeqCst;
+use std::{cmp, mem, fmt, process, ptr, slice, vec};
+use std::marker::PhantomData;
+use std::collections::HashSet;
+use std::sync::atomic::{self, AtomicUsize, Ordering};
+use std::sync::atomic::Ordering::{Relaxed, SeqCst};
 
 use super::arc::*;
+use super::bins::FreeBins;
+use super::block_table::{self, BlockTable, BLOCK_TABLE_PIN, DEFAULT_BLOCK_TABLE_SLOTS};
+use super::sync::*;
+use super::chaos::Chaos;
+use super::range_lock::*;
+use super::backend::*;
+use super::flush::*;
+use super::io_stats::{IoSnapshot, IoStats};
+use super::journal::{self, JournaledRoot, RootRecord, ROOT_RECORDS};
+use super::lineage::{self, Lineage, LineageCheck, Link, LINEAGE_LINKS};
+use super::pins::{Pin, PIN_SLOTS, PIN_NAME_SIZE};
+use super::progress::{CompactionPhase, ProgressHandle};
+use super::superblock::{Superblock, SUPERBLOCK_SIZE};
+use checksum::crc32;
+use codec::*;
 use LodestoneError;
 
 pub const PAGE_SIZE: usize = 4096;
 pub const BUFFER_END: usize = !0 as usize;
+/// Marks a buffer as holding a pool, see Pool::open
+const POOL_MAGIC: u64 = 0x6c6f_6465_706f_6f6c;
+/// Tells a block's checksum apart from a block without one
+const BLOCK_CHECKSUM_SET: usize = 1 << 32;
 
 lazy_static! {
-    pub static ref HEADER_SIZE: usize = mem::size_of::<SkipListEntry>();
+    pub static ref HEADER_SIZE: usize = SKIP_LIST_HEADER_SIZE;
     pub static ref FIRST_OR_SINGLE_CONTENT_SIZE: usize = PAGE_SIZE - *HEADER_SIZE;
     pub static ref OVERHEAD: usize = *HEADER_SIZE + *ARC_INNER_SIZE;
 }
 
-pub struct Pool {
+/// A pool is tied to the borrow of the buffer it was made with, so the
+/// buffer can't be dropped or moved while the pool is still using it.
+/// Pools over a backend own their memory, and borrow nothing.
+pub struct Pool<'buf> {
     buffer: *mut u8,
     buffer_size: usize,
+    deterministic: bool,
+    chaos: Option<Chaos>,
+    range_locks: RangeLocks,
+    // Only set if the pool owns its memory
+    backend: Option<Box<StorageBackend>>,
+    // Only used with a backend, there's nothing to flush otherwise. Every
+    // call into the backend is made holding it.
+    flush_state: Guarded<FlushState>,
+    // A pool of its own, inside a block of this one
+    scratch: Option<Box<Pool<'buf>>>,
+    ref_counting: RefCounting,
+    ref_count_policy: RefCountPolicy,
+    ref_count_stats: Guarded<RefCountStats>,
+    // Guarded so it's only ever called from one thread at a time
+    ref_count_handler: Guarded<Option<Box<Fn(RefCountError) + Send>>>,
+    // Whether freed pages are handed back to the backend
+    punch_holes: bool,
+    // Whether malloc and mark_written checksum blocks
+    block_checksums: bool,
+    // Whether persist hands out logical references
+    logical_references: bool,
+    io_stats: IoStats,
+    reclaimed: AtomicUsize,
+    // Rebuilt from the skip list whenever a pool is attached. Held across
+    // every change to the skip list and the metadata block, which makes
+    // it the allocation lock of a thread safe pool.
+    free_bins: Guarded<FreeBins>,
+    // Held across every read and change of the block table, taken
+    // before the allocation lock
+    block_table_lock: Guarded<()>,
+    _buffer: PhantomData<&'buf mut [u8]>,
 }
 
-#[derive(Debug)]
+// Nothing in a pool is tied to the thread that made it, so it can be
+// moved. It isn't Sync, allocation isn't synchronized, unless it's built
+// with the thread-safe feature: then allocation, freeing and the metadata
+// (the lifetime counters included, so malloc_inner and free_block bump
+// them before letting go) go through the free_bins lock, the backend through the flush_state
+// lock, and ref counts are atomic (new_single_threaded, the only way to
+// get RefCounting::Plain, isn't there). Walks of the block list hold the
+// free_bins lock too, so they never see a block half split or merged.
+unsafe impl<'buf> Send for Pool<'buf> {}
+#[cfg(feature = "thread-safe")]
+unsafe impl<'buf> Sync for Pool<'buf> {}
+
 struct Metadata {
-    // TODO rip this out and replace with a free list
-    // We probably want to keep 2 free lists -- A one-page
-    // list and a larger objects list to avoid fragmentation
+    magic: u64,
+    // The lowest free block, or BUFFER_END. Allocation goes by the free
+    // bins, this is only kept up to date for tools reading the image.
     lowest_known_free_index: usize,
     next_id_tag: AtomicUsize,
+    generation: usize,
+    links: [Link; LINEAGE_LINKS],
+    // Block holding the scratch region, or BUFFER_END
+    scratch_region: usize,
+    pins: [Pin; PIN_SLOTS],
+    lifetime: LifetimeStats,
+    // Written by journal_root. Images from before it have zeroes or
+    // garbage here, which never passes a record's checksum.
+    root_records: [RootRecord; ROOT_RECORDS],
 }
 
-impl fmt::Debug for Pool {
+// The superblock goes after the metadata, in the last bytes of the page
+const _: () = assert!(SKIP_LIST_HEADER_SIZE + mem::size_of::<Metadata>() + SUPERBLOCK_SIZE <= PAGE_SIZE,
+    "Metadata no longer leaves room for the superblock");
+
+impl fmt::Debug for Metadata {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Spelled out as derive printed it before std dropped the
+        // AtomicUsize(..) around atomics
+        f.debug_struct("Metadata")
+            .field("lowest_known_free_index", &self.lowest_known_free_index)
+            .field("next_id_tag", &format_args!("AtomicUsize({})", self.next_id_tag.load(SeqCst)))
+            .finish()
+    }
+}
+
+impl<'buf> fmt::Debug for Pool<'buf> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Pool")
             .field("buffer_size", &self.buffer_size)
@@ -38,32 +128,328 @@ impl fmt::Debug for Pool {
     }
 }
 
-impl Pool {
-    pub fn new(buf: &mut [u8]) -> Pool {
-        let ptr: *mut u8 = buf.as_mut_ptr();
-        let p = Pool {
-            buffer: ptr,
-            buffer_size: buf.len(),
-        };
+impl<'buf> Pool<'buf> {
+    pub fn new(buf: &'buf mut [u8]) -> Pool<'buf> {
+        let p = Pool::attach(buf);
         {
             let metadata = p.get_metadata_block();
+            metadata.magic = POOL_MAGIC;
             metadata.lowest_known_free_index = 0;
             metadata.next_id_tag = AtomicUsize::new(1);
+            metadata.generation = 0;
+            metadata.links = [Link::absent(); LINEAGE_LINKS];
+            metadata.links[0] = Link::origin();
+            metadata.scratch_region = BUFFER_END;
+            metadata.pins = [Pin::empty(); PIN_SLOTS];
+            metadata.lifetime = LifetimeStats::default();
+            metadata.root_records = [RootRecord::empty(); ROOT_RECORDS];
         }
         let last_skip_index = p.buffer_size - PAGE_SIZE;
         // Init head of skip list
         p.make_skip_entry(SkipListStart(0), BUFFER_END, last_skip_index, true);
         // Last page is metadata and not usable as a full page-aligned chunk anyway
         p.make_skip_entry(SkipListStart(last_skip_index), 0, BUFFER_END, false);
+        p.free_bins.lock().insert(0, last_skip_index);
+        p.write_superblock(Superblock::current(0));
+        p
+    }
+
+    /// Reattach to the pool an earlier Pool::new left in buf, as it was
+    /// last written. Nothing is initialized: the image is checked (the
+    /// metadata and a walk of every block header) and refused with
+    /// InvalidReference if it isn't a pool, or with Format if its
+    /// superblock says this build can't read it. The scratch region, if
+    /// any, is reset, see reserve_scratch.
+    pub fn open(buf: &'buf mut [u8]) -> Result<Pool<'buf>, LodestoneError> {
+        if buf.len() < 2 * PAGE_SIZE {
+            return Err(LodestoneError::InvalidReference("Buffer is too small to hold a pool"));
+        }
+        let mut p = Pool::attach(buf);
+        // Before anything is read in a layout the image may not have
+        try!(try!(p.superblock()).check());
+        try!(p.check_image());
+        p.rebuild_free_bins();
+        p.reset_scratch();
+        Ok(p)
+    }
+
+    fn attach(buf: &'buf mut [u8]) -> Pool<'buf> {
+        let ptr: *mut u8 = buf.as_mut_ptr();
+        Pool {
+            buffer: ptr,
+            buffer_size: buf.len(),
+            deterministic: false,
+            chaos: None,
+            range_locks: RangeLocks::new(),
+            backend: None,
+            flush_state: Guarded::new(FlushState::new()),
+            scratch: None,
+            ref_counting: RefCounting::Atomic,
+            ref_count_policy: RefCountPolicy::Saturate,
+            ref_count_stats: Guarded::new(RefCountStats::default()),
+            ref_count_handler: Guarded::new(None),
+            punch_holes: false,
+            block_checksums: false,
+            logical_references: false,
+            io_stats: IoStats::new(),
+            reclaimed: AtomicUsize::new(0),
+            free_bins: Guarded::new(FreeBins::new()),
+            block_table_lock: Guarded::new(()),
+            _buffer: PhantomData,
+        }
+    }
+
+    /// A pool whose buffer contents are a pure function of the operations
+    /// performed on it: the buffer starts zeroed and freed memory is zeroed
+    /// again, so no stale bytes survive in padding or free space. Useful for
+    /// reproducible tests and for comparing pool images.
+    pub fn new_deterministic(buf: &'buf mut [u8]) -> Pool<'buf> {
+        for b in buf.iter_mut() {
+            *b = 0;
+        }
+        let mut p = Pool::new(buf);
+        p.deterministic = true;
+        p
+    }
+
+    /// A pool whose ref counts aren't atomic, see RefCounting::Plain.
+    /// Its scratch region, if any, counts the same way. Not available
+    /// with the thread-safe feature, where pools are Sync.
+    #[cfg(not(feature = "thread-safe"))]
+    pub fn new_single_threaded(buf: &'buf mut [u8]) -> Pool<'buf> {
+        let mut p = Pool::new(buf);
+        p.ref_counting = RefCounting::Plain;
+        p
+    }
+
+    /// A pool living in, and owning, the given backend
+    pub fn with_backend(mut backend: Box<StorageBackend>) -> Pool<'static> {
+        let mut p = {
+            let buf = unsafe { slice::from_raw_parts_mut(backend.as_mut_ptr(), backend.len()) };
+            Pool::new(buf)
+        };
+        p.backend = Some(backend);
+        // Everything was just initialized
+        let size = p.buffer_size;
+        p.mark_dirty(0, size);
         p
     }
 }
 
-#[derive(Debug)]
-struct SkipListEntry {
-    prev: usize, // absolute buffer offset of previous SKE
-    id_tag: usize, // 0 if the given memory is free, unique id otherwise
-    next: usize, // absolute buffer offset of next SKE
+/// How much of a pool is spoken for, at each level
+#[derive(Debug, Clone, PartialEq)]
+pub struct Usage {
+    /// Bytes held by live blocks, headers included
+    pub allocated: usize,
+    /// The size the pool was created with
+    pub reserved: usize,
+    /// Bytes the backend actually holds, which is less than reserved
+    /// for backends that only materialize pages once they're written
+    pub materialized: usize,
+    /// Bytes given back to the backend by punching holes, over the pool's life
+    pub reclaimed: usize,
+}
+
+/// A pool's history, for sizing pools from what they really went through.
+/// Kept in the metadata block, so it lasts as long as the pool does and
+/// is as durable as the last flush.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LifetimeStats {
+    /// Bytes and blocks currently allocated, headers included
+    pub live_bytes: usize,
+    pub live_blocks: usize,
+    /// The most that were ever allocated at once
+    pub peak_live_bytes: usize,
+    pub peak_live_blocks: usize,
+    /// Blocks holding a type, made with make_new or clone (tree nodes)
+    pub typed_allocations: usize,
+    /// Blocks holding plain bytes, made with malloc (keys and values)
+    pub byte_allocations: usize,
+    pub frees: usize,
+    /// Mallocs malloc_parts had to split, for want of a free block big
+    /// enough. Growing means fragmentation is starting to bite.
+    pub split_allocations: usize,
+}
+
+/// What sweep_unreachable found
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SweepReport {
+    pub reachable_blocks: usize,
+    pub freed_blocks: usize,
+    /// Including block headers
+    pub freed_bytes: usize,
+}
+
+/// What compact did
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompactReport {
+    pub moved_blocks: usize,
+    /// Including block headers
+    pub moved_bytes: usize,
+    /// Blocks behind logical references left in place because something
+    /// besides the block table holds them
+    pub busy_blocks: usize,
+    pub largest_free_before: usize,
+    pub largest_free_after: usize,
+}
+
+/// A block in use that no root reaches, see unreachable_blocks
+#[derive(Debug, Clone, PartialEq)]
+pub struct Unreachable {
+    pub reference: Reference,
+    pub bytes: Vec<u8>,
+}
+
+/// Offset independent picture of a pool, for golden tests that should
+/// survive changes to block placement and struct sizes
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolSnapshot {
+    pub blocks: Vec<SnapshotBlock>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SnapshotBlock {
+    Free,
+    /// Live blocks are numbered in allocation order, starting from 0
+    Live { id: usize, size: usize, ref_count: usize },
+}
+
+/// Offset independent description of a block, for comparing pools
+/// structurally without depending on where things were placed
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockShape {
+    pub capacity: usize,
+    pub is_free: bool,
+}
+
+/// Everything the pool knows about a block, for diagnostics and tooling.
+/// Blocks carry no type information, so what's inside is up to the caller.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockInfo {
+    /// Where the block's header starts in the buffer
+    pub offset: usize,
+    /// Bytes available for data
+    pub capacity: usize,
+    /// Bytes in use, 0 for free blocks
+    pub size: usize,
+    /// The id tag, 0 for free blocks
+    pub generation: usize,
+    pub ref_count: usize,
+    pub is_free: bool,
+}
+
+/// Walks the blocks of a pool in address order
+pub struct BlockIter<'a> {
+    pool: &'a Pool<'a>,
+    next_index: usize,
+}
+
+impl <'a> Iterator for BlockIter<'a> {
+    type Item = BlockInfo;
+
+    fn next(&mut self) -> Option<BlockInfo> {
+        if self.next_index == BUFFER_END {
+            return None;
+        }
+        let (idx, entry) = self.pool.index_to_skip_list_header(SkipListStart(self.next_index));
+        self.next_index = entry.next();
+        if entry.next() == BUFFER_END {
+            // The metadata page isn't a block
+            return None;
+        }
+        let is_free = entry.id_tag() == 0;
+        let inner = self.pool.index_to_arc_inner(SkipListStart(idx));
+        Some(BlockInfo {
+            offset: idx,
+            capacity: entry.next() - idx - *OVERHEAD,
+            size: if is_free { 0 } else { inner.size },
+            generation: entry.id_tag(),
+            ref_count: if is_free { 0 } else { ref_count(&inner.strong) },
+            is_free: is_free,
+        })
+    }
+}
+
+/// prev (absolute buffer offset of the previous header), id_tag (0 if the
+/// block is free, a unique id otherwise) and next, each a word
+pub const SKIP_LIST_HEADER_SIZE: usize = 3 * WORD;
+const PREV_AT: usize = 0;
+const ID_TAG_AT: usize = WORD;
+const NEXT_AT: usize = 2 * WORD;
+
+/// A block's skip list header, read and written in place through the codec
+struct SkipListHeader<'a> {
+    at: *mut u8,
+    _buffer: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> SkipListHeader<'a> {
+    fn bytes(&self) -> &'a mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.at, SKIP_LIST_HEADER_SIZE) }
+    }
+
+    fn prev(&self) -> usize {
+        read_word_le(self.bytes(), PREV_AT)
+    }
+
+    fn set_prev(&self, prev: usize) {
+        write_word_le(self.bytes(), PREV_AT, prev);
+    }
+
+    fn id_tag(&self) -> usize {
+        read_word_le(self.bytes(), ID_TAG_AT)
+    }
+
+    fn set_id_tag(&self, id_tag: usize) {
+        write_word_le(self.bytes(), ID_TAG_AT, id_tag);
+    }
+
+    fn next(&self) -> usize {
+        read_word_le(self.bytes(), NEXT_AT)
+    }
+
+    fn set_next(&self, next: usize) {
+        write_word_le(self.bytes(), NEXT_AT, next);
+    }
+}
+
+/// A header's id tag seen as a Counter, so malloc claims blocks through
+/// sync::claim. The word is little endian bytes rather than an atomic;
+/// claims only happen under the free bins lock, which is what makes the
+/// compare and swap one.
+struct IdTagWord<'h, 'a: 'h>(&'h SkipListHeader<'a>);
+
+impl<'h, 'a> Counter for IdTagWord<'h, 'a> {
+    fn load(&self, _: Ordering) -> usize {
+        self.0.id_tag()
+    }
+
+    fn store(&self, val: usize, _: Ordering) {
+        self.0.set_id_tag(val)
+    }
+
+    fn fetch_add(&self, val: usize, _: Ordering) -> usize {
+        let old = self.0.id_tag();
+        self.0.set_id_tag(old.wrapping_add(val));
+        old
+    }
+
+    fn fetch_sub(&self, val: usize, _: Ordering) -> usize {
+        let old = self.0.id_tag();
+        self.0.set_id_tag(old.wrapping_sub(val));
+        old
+    }
+
+    fn compare_exchange(&self, current: usize, new: usize, _: Ordering, _: Ordering)
+        -> Result<usize, usize> {
+        let old = self.0.id_tag();
+        if old == current {
+            self.0.set_id_tag(new);
+            Ok(old)
+        } else {
+            Err(old)
+        }
+    }
 }
 
 use self::IndexType::*;
@@ -74,15 +460,449 @@ enum IndexType {
     SkipListStart(usize),
 }
 
+/// Which LifetimeStats counter an allocation counts against
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Allocation {
+    Typed,
+    Bytes,
+    Scratch,
+}
+
 /// Public interface
-impl Pool {
-    pub fn make_new<T>(&self) -> Result<ArcByteSlice, LodestoneError> {
+impl<'buf> Pool<'buf> {
+    /// Total size of the backing buffer in bytes
+    pub fn size(&self) -> usize {
+        self.buffer_size
+    }
+
+    pub fn usage(&self) -> Usage {
+        let allocating = self.free_bins.lock();
+        let allocated = self.blocks(&allocating)
+            .filter(|b| !b.is_free)
+            .map(|b| b.capacity + *OVERHEAD)
+            .fold(0, |sum, n| sum + n);
+        Usage {
+            allocated: allocated,
+            reserved: self.buffer_size,
+            materialized: match self.backend {
+                Some(ref backend) => {
+                    let _backend = self.flush_state.lock();
+                    backend.materialized()
+                },
+                None => self.buffer_size,
+            },
+            reclaimed: self.reclaimed.load(Relaxed),
+        }
+    }
+
+    /// Punch holes for the whole pages of freed blocks, so a file backed
+    /// pool takes less disk without being compacted. Backends that can't
+    /// give pages back ignore it.
+    pub fn set_punch_holes(&mut self, punch: bool) {
+        self.punch_holes = punch;
+    }
+
+    /// Ask the backend, if the pool has one, to make everything written
+    /// since the last flush durable. Blocks are tracked as they are
+    /// allocated and freed, and persisted ref counts as they change.
+    /// Writes into an existing block through deref_as_mut have to be
+    /// reported with mark_written.
+    pub fn flush(&self) -> Result<(), LodestoneError> {
+        let backend = match self.backend {
+            Some(ref backend) => backend,
+            None => return Ok(()),
+        };
+        // The metadata changes with nearly every allocation
+        self.mark_dirty(self.buffer_size - PAGE_SIZE, PAGE_SIZE);
+        let mut state = self.flush_state.lock();
+        let extents = state.extents();
+        for &(offset, len) in extents.iter() {
+            // On failure everything stays dirty for the next attempt
+            try!(backend.flush(offset, len));
+            self.io_stats.flushed(len);
+        }
+        state.flushed(&extents);
+        Ok(())
+    }
+
+    /// Flushes sync adjacent dirty ranges with one backend call, up to
+    /// this many bytes at a time
+    pub fn set_max_flush_extent(&mut self, bytes: usize) {
+        self.flush_state.lock().set_max_extent(bytes);
+    }
+
+    pub fn flush_stats(&self) -> FlushStats {
+        self.flush_state.lock().stats().clone()
+    }
+
+    /// Report an in-place write to the arc's block, so the next flush
+    /// covers it. With block checksums on, the block is checksummed again.
+    pub fn mark_written(&self, arc: &ArcByteSlice) {
+        let index = self.arc_to_arc_inner_index(arc);
+        let offset = self.index_to_data_offset(index);
+        let len = self.index_to_arc_inner(index).size;
+        self.mark_dirty(offset, len);
+        self.io_stats.written(len, 0);
+        if self.block_checksums {
+            self.seal_block(index);
+        }
+    }
+
+    /// Checksum the bytes of every block malloc writes from now on, and
+    /// of every block mark_written reports. Blocks written in place lose
+    /// their checksum (deref_as_mut) until they're reported. Whatever
+    /// carries a checksum is verified by clone_persisted_to_arc, on or
+    /// off, so a reopened pool catches bit rot in what was checksummed
+    /// with Corruption instead of walking garbage.
+    pub fn set_block_checksums(&mut self, on: bool) {
+        self.block_checksums = on;
+    }
+
+    /// Have the structures built in this pool (tree nodes, message
+    /// buffers) hold what they point at through logical references from
+    /// now on, so compact can move those blocks. Each one costs a block
+    /// table slot, and a table lookup whenever it's followed.
+    pub fn set_logical_references(&mut self, on: bool) {
+        self.logical_references = on;
+    }
+
+    pub fn logical_references(&self) -> bool {
+        self.logical_references
+    }
+
+    /// Bytes read and written through the pool since it was attached,
+    /// with rates, see io_stats. Blocks count as read when a persisted
+    /// reference to them is resolved, and as written when they're
+    /// allocated and whenever mark_written reports a change.
+    pub fn io_stats(&self) -> IoSnapshot {
+        self.io_stats.snapshot()
+    }
+
+    /// What the pool image was written by. open refuses images this
+    /// build can't read, or without a superblock.
+    pub fn superblock(&self) -> Result<Superblock, LodestoneError> {
+        let bytes = unsafe { slice::from_raw_parts(self.superblock_ptr(), SUPERBLOCK_SIZE) };
+        Superblock::decode(bytes)
+    }
+
+    /// Record the B of the tree kept in the pool
+    pub fn record_node_capacity(&self, capacity: usize) {
+        let superblock = self.superblock().unwrap_or(Superblock::current(0));
+        self.write_superblock(Superblock { node_capacity: capacity as u32, ..superblock });
+    }
+
+    /// Free every block that can't be reached from roots, whatever its
+    /// ref count says. trace lists the references inside a block's bytes
+    /// (e.g. node::references for tree nodes); references that don't
+    /// resolve are ignored, so a tracer that guesses wrong keeps blocks
+    /// alive rather than freeing live ones. This is the backstop for ref
+    /// count bugs and allocations leaked by a crash, so every live handle,
+    /// including ArcByteSlices held in memory, must be among the roots.
+    /// The pool is locked throughout, so trace mustn't use it.
+    pub fn sweep_unreachable<F>(&self, roots: &[Reference], trace: F) -> SweepReport
+        where F: Fn(&[u8]) -> Vec<Reference> {
+        self.sweep_unreachable_with_progress(roots, trace, &ProgressHandle::new())
+    }
+
+    /// sweep_unreachable, reporting to progress as it goes. Cancelled, it
+    /// stops between frees and reports what it freed until then.
+    pub fn sweep_unreachable_with_progress<F>(&self, roots: &[Reference], trace: F, progress: &ProgressHandle)
+        -> SweepReport
+        where F: Fn(&[u8]) -> Vec<Reference> {
+        progress.set_phase(CompactionPhase::Tracing);
+        let _table = self.block_table_lock.lock();
+        let mut pending = roots.to_vec();
+        pending.extend(self.block_table_roots());
+        let mut bins = self.free_bins.lock();
+        let (reachable, doomed) = self.find_unreachable(&bins, pending, trace);
+        let mut report = SweepReport {
+            reachable_blocks: reachable,
+            ..SweepReport::default()
+        };
+        progress.set_phase(CompactionPhase::Freeing);
+        for block in doomed.iter() {
+            if progress.should_stop() {
+                return report;
+            }
+            self.free_block(&mut bins, SkipListStart(block.offset));
+            report.freed_blocks += 1;
+            report.freed_bytes += block.capacity + *OVERHEAD;
+            progress.update(0, report.freed_bytes);
+        }
+        progress.set_phase(CompactionPhase::Done);
+        report
+    }
+
+    /// The blocks sweep_unreachable would free, with copies of their
+    /// bytes, and left in place so anything worth saving can be saved
+    /// before the sweep. Nothing is retained or freed.
+    pub fn unreachable_blocks<F>(&self, roots: &[Reference], trace: F) -> Vec<Unreachable>
+        where F: Fn(&[u8]) -> Vec<Reference> {
+        let _table = self.block_table_lock.lock();
+        let mut pending = roots.to_vec();
+        pending.extend(self.block_table_roots());
+        let bins = self.free_bins.lock();
+        self.find_unreachable(&bins, pending, trace).1.iter()
+            .map(|block| {
+                let index = block.offset + *HEADER_SIZE;
+                Unreachable {
+                    reference: Reference::new(index, block.generation),
+                    bytes: self.index_to_byte_slice(ArcByteSliceStart(index)).to_vec(),
+                }
+            })
+            .collect()
+    }
+
+    /// Set aside size bytes for temporaries (sort buffers, staging for
+    /// compaction, ...), allocated from scratch() instead of the pool.
+    /// Nothing persisted may refer to a scratch block: the whole region
+    /// is thrown away by reset_scratch, which is what opening the pool
+    /// again should do so temporaries leaked by a crash don't linger.
+    pub fn reserve_scratch(&mut self, size: usize) -> Result<(), LodestoneError> {
+        if self.scratch.is_some() {
+            return Err(LodestoneError::UserError("The pool already has a scratch region"));
+        }
+        if size < 2 * PAGE_SIZE {
+            return Err(LodestoneError::UserError("A scratch region needs at least two pages"));
+        }
+        let (idx, inner) = try!(self.malloc_inner(size, Allocation::Scratch));
+        // Held by the pool itself for as long as it lives
+        self._retain(inner);
+        self.get_metadata_block().scratch_region = self.index_to_arc_offset(idx);
+        let mut scratch = Pool::new(self.index_to_buffer_region(idx));
+        scratch.ref_counting = self.ref_counting;
+        self.scratch = Some(Box::new(scratch));
+        Ok(())
+    }
+
+    pub fn scratch(&self) -> Option<&Pool<'buf>> {
+        self.scratch.as_ref().map(|p| &**p)
+    }
+
+    /// Drop everything in the scratch region at once. Any ArcByteSlice
+    /// still pointing into it is left dangling.
+    pub fn reset_scratch(&mut self) {
+        let region = self.get_metadata_block().scratch_region;
+        if region != BUFFER_END {
+            let buf = self.index_to_buffer_region(ArcByteSliceStart(region));
+            let mut scratch = Pool::new(buf);
+            scratch.ref_counting = self.ref_counting;
+            self.scratch = Some(Box::new(scratch));
+        }
+    }
+
+    /// Keep arc's block alive under name, across restarts, until unpinned
+    pub fn pin_root(&self, name: &str, arc: &ArcByteSlice) -> Result<(), LodestoneError> {
+        if name.is_empty() || name.len() > PIN_NAME_SIZE {
+            return Err(LodestoneError::UserError("Pin names must be 1 to 32 bytes long"));
+        }
+        if self.pinned(name).is_some() {
+            return Err(LodestoneError::UserError("A root is already pinned under that name"));
+        }
+        let _allocating = self.free_bins.lock();
+        let metadata = self.get_metadata_block();
+        match metadata.pins.iter().position(|p| p.is_empty()) {
+            Some(slot) => {
+                metadata.pins[slot] = Pin::new(name, &self.make_reference(arc));
+                Ok(())
+            },
+            None => Err(LodestoneError::UserError("Every pin slot is taken")),
+        }
+    }
+
+    pub fn pinned(&self, name: &str) -> Option<Reference> {
+        let _allocating = self.free_bins.lock();
+        self.get_metadata_block().pins.iter()
+            .find(|p| !p.is_empty() && p.name() == name.as_bytes())
+            .map(|p| p.reference())
+    }
+
+    pub fn pinned_names(&self) -> Vec<String> {
+        let _allocating = self.free_bins.lock();
+        self.get_metadata_block().pins.iter()
+            .filter(|p| !p.is_empty())
+            .map(|p| String::from_utf8_lossy(p.name()).into_owned())
+            .collect()
+    }
+
+    /// Give up the pin's hold on its block, freeing it if nothing else holds it
+    pub fn unpin(&self, name: &str) -> Result<(), LodestoneError> {
+        let reference = {
+            let _allocating = self.free_bins.lock();
+            let metadata = self.get_metadata_block();
+            let slot = match metadata.pins.iter().position(|p| !p.is_empty() && p.name() == name.as_bytes()) {
+                Some(slot) => slot,
+                None => return Err(LodestoneError::UserError("Nothing is pinned under that name")),
+            };
+            let reference = metadata.pins[slot].reference();
+            metadata.pins[slot] = Pin::empty();
+            reference
+        };
+        // Freeing takes the lock again
+        let mut persisted = try!(self.take_reference(&reference));
+        try!(persisted.release(self));
+        Ok(())
+    }
+
+    /// Point an existing pin at arc in place, then give up its hold on
+    /// the block it pinned before. Readers of the pin see either block,
+    /// never neither.
+    pub fn repin(&self, name: &str, arc: &ArcByteSlice) -> Result<(), LodestoneError> {
+        let reference = {
+            let _allocating = self.free_bins.lock();
+            let metadata = self.get_metadata_block();
+            let slot = match metadata.pins.iter().position(|p| !p.is_empty() && p.name() == name.as_bytes()) {
+                Some(slot) => slot,
+                None => return Err(LodestoneError::UserError("Nothing is pinned under that name")),
+            };
+            let reference = metadata.pins[slot].reference();
+            metadata.pins[slot] = Pin::new(name, &self.make_reference(arc));
+            reference
+        };
+        let mut persisted = try!(self.take_reference(&reference));
+        try!(persisted.release(self));
+        Ok(())
+    }
+
+    pub fn lifetime_stats(&self) -> LifetimeStats {
+        let _allocating = self.free_bins.lock();
+        self.get_metadata_block().lifetime
+    }
+
+    /// Bump the generation and extend the commit hash chain with the new root
+    pub fn record_commit(&self, root: usize) -> Lineage {
+        let _allocating = self.free_bins.lock();
+        let metadata = self.get_metadata_block();
+        let link = metadata.links[metadata.generation % LINEAGE_LINKS].next(root);
+        metadata.generation += 1;
+        metadata.links[metadata.generation % LINEAGE_LINKS] = link;
+        link.lineage()
+    }
+
+    pub fn lineage(&self) -> Lineage {
+        let _allocating = self.free_bins.lock();
+        let metadata = self.get_metadata_block();
+        metadata.links[metadata.generation % LINEAGE_LINKS].lineage()
+    }
+
+    /// Whether the pool still descends from a lineage seen earlier
+    pub fn check_lineage(&self, seen: &Lineage) -> LineageCheck {
+        let _allocating = self.free_bins.lock();
+        let metadata = self.get_metadata_block();
+        lineage::check(&metadata.links, metadata.generation, seen)
+    }
+
+    /// Make root the committed root, so a crash at any point recovers
+    /// either it or the root committed before it (see journal). When
+    /// sync, everything written so far is flushed first and the record
+    /// after; otherwise the writes are only ordered, which is enough for
+    /// a crash of the process but not of the machine.
+    pub fn journal_root(&self, root: JournaledRoot, sync: bool) -> Result<(), LodestoneError> {
+        if sync {
+            try!(self.flush());
+        }
+        atomic::fence(SeqCst);
+        {
+            let _allocating = self.free_bins.lock();
+            journal::record(&mut self.get_metadata_block().root_records, root);
+        }
+        atomic::fence(SeqCst);
+        if sync {
+            try!(self.flush());
+        }
+        Ok(())
+    }
+
+    /// The root of the last commit that was journaled whole
+    pub fn journaled_root(&self) -> Option<JournaledRoot> {
+        let _allocating = self.free_bins.lock();
+        journal::latest(&self.get_metadata_block().root_records)
+    }
+
+    /// Occasionally force allocations down their slow path, see Chaos
+    pub fn enable_chaos(&mut self, chaos: Chaos) {
+        self.chaos = Some(chaos);
+    }
+
+    pub fn chaos(&self) -> Option<&Chaos> {
+        self.chaos.as_ref()
+    }
+
+    pub fn ref_counting(&self) -> RefCounting {
+        self.ref_counting
+    }
+
+    /// Saturate by default
+    pub fn set_ref_count_policy(&mut self, policy: RefCountPolicy) {
+        self.ref_count_policy = policy;
+    }
+
+    /// Called with every ref count error, before the policy is applied
+    pub fn on_ref_count_error<F>(&mut self, handler: F)
+        where F: Fn(RefCountError) + Send + 'static {
+        *self.ref_count_handler.lock() = Some(Box::new(handler));
+    }
+
+    pub fn ref_count_stats(&self) -> RefCountStats {
+        *self.ref_count_stats.lock()
+    }
+
+    /// Advisory lock over the key range [start, end), for writers
+    /// coordinating among themselves. See RangeLocks.
+    pub fn lock_range<'a>(&'a self, start: &[u8], end: &[u8]) -> RangeLockGuard<'a> {
+        self.range_locks.lock(start, end)
+    }
+
+    pub fn try_lock_range<'a>(&'a self, start: &[u8], end: &[u8]) -> Option<RangeLockGuard<'a>> {
+        self.range_locks.try_lock(start, end)
+    }
+
+    /// All blocks, free and allocated, in address order, as they were
+    /// when the walk took the allocation lock
+    pub fn iter_blocks(&self) -> vec::IntoIter<BlockInfo> {
+        let allocating = self.free_bins.lock();
+        self.blocks(&allocating).collect::<Vec<_>>().into_iter()
+    }
+
+    pub fn snapshot(&self) -> PoolSnapshot {
+        let blocks: Vec<BlockInfo> = self.iter_blocks().collect();
+        let mut generations: Vec<usize> = blocks.iter()
+            .filter(|b| !b.is_free)
+            .map(|b| b.generation)
+            .collect();
+        generations.sort();
+        PoolSnapshot {
+            blocks: blocks.iter()
+                .map(|b| if b.is_free {
+                    SnapshotBlock::Free
+                } else {
+                    SnapshotBlock::Live {
+                        id: generations.binary_search(&b.generation).unwrap(),
+                        size: b.size,
+                        ref_count: b.ref_count,
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    /// The blocks of the pool in order, without their offsets
+    pub fn shape(&self) -> Vec<BlockShape> {
+        self.iter_blocks()
+            .map(|b| BlockShape {
+                capacity: b.capacity,
+                is_free: b.is_free,
+            })
+            .collect()
+    }
+
+    pub fn make_new<'a, T>(&'a self) -> Result<ArcByteSlice<'a>, LodestoneError> {
         let size = mem::size_of::<T>();
-        let (_, inner) = try!(self.malloc_inner(size));
+        let (_, inner) = try!(self.malloc_inner(size, Allocation::Typed));
         Ok(ArcByteSlice::new(inner, self))
     }
 
-    pub fn clone<T>(&self, from: &T) -> Result<ArcByteSlice, LodestoneError> {
+    pub fn clone<'a, T>(&'a self, from: &T) -> Result<ArcByteSlice<'a>, LodestoneError> {
         let dest = try!(self.make_new::<T>());
         let arc_index = self.arc_to_arc_inner_index(&dest);
         let dest_slice = self.index_to_byte_slice_mut(arc_index);
@@ -94,14 +914,53 @@ impl Pool {
         Ok(dest)
     }
 
-    pub fn malloc(&self, data: &[u8]) -> Result<ArcByteSlice, LodestoneError> {
+    /// Copy a block of this pool straight into dest. Only the block headers
+    /// are new (including a fresh id tag), the contents are copied as is.
+    pub fn copy_block<'d>(&self, arc: &ArcByteSlice, dest: &'d Pool) -> Result<ArcByteSlice<'d>, LodestoneError> {
+        let arc_index = self.arc_to_arc_inner_index(arc);
+        dest.malloc(self.index_to_byte_slice(arc_index))
+    }
+
+    pub fn malloc<'a>(&'a self, data: &[u8]) -> Result<ArcByteSlice<'a>, LodestoneError> {
         let size = data.len();
-        let (idx, inner) = try!(self.malloc_inner(size));
+        let (idx, inner) = try!(self.malloc_inner(size, Allocation::Bytes));
         let dest = self.index_to_byte_slice_mut(idx);
         dest.clone_from_slice(data);
+        if self.block_checksums {
+            inner.checksum.store(BLOCK_CHECKSUM_SET | crc32(data) as usize, SeqCst);
+        }
         Ok(ArcByteSlice::new(inner, self))
     }
 
+    /// malloc, or when no free block is big enough for all of data, as
+    /// many blocks as it takes, biggest first. The parts are in order.
+    pub fn malloc_parts<'a>(&'a self, data: &[u8]) -> Result<Vec<ArcByteSlice<'a>>, LodestoneError> {
+        match self.malloc(data) {
+            Err(LodestoneError::OutOfMemory(_)) => (),
+            whole => return whole.map(|arc| vec![arc]),
+        }
+        let mut parts = Vec::new();
+        let mut rest = data;
+        while !rest.is_empty() {
+            // Whatever was taken already is freed again with parts
+            let room = self.largest_free_block();
+            if room == 0 {
+                return Err(LodestoneError::OutOfMemory("malloc_parts"));
+            }
+            let (part, after) = rest.split_at(cmp::min(room, rest.len()));
+            parts.push(try!(self.malloc(part)));
+            rest = after;
+        }
+        let _allocating = self.free_bins.lock();
+        self.get_metadata_block().lifetime.split_allocations += 1;
+        Ok(parts)
+    }
+
+    /// The most bytes a single malloc could get right now
+    pub fn largest_free_block(&self) -> usize {
+        self.iter_blocks().filter(|b| b.is_free).map(|b| b.capacity).max().unwrap_or(0)
+    }
+
     pub fn free(&self, arc: &ArcByteSlice) {
         let arc_index = self.arc_to_arc_inner_index(arc);
         self.free_inner(arc_index)
@@ -120,99 +979,578 @@ impl Pool {
 
     pub unsafe fn deref_as_mut<'a, T>(&'a self, arc: &'a ArcByteSlice) -> &'a mut T {
         let arc_index = self.arc_to_arc_inner_index(arc);
+        self.index_to_arc_inner(arc_index).checksum.store(0, SeqCst);
         let offset = self.index_to_data_offset(arc_index);
         mem::transmute(self.buffer.offset(offset as isize))
     }
 
-    pub fn clone_persisted_to_arc(&self, persisted: &PersistedArcByteSlice) -> Result<ArcByteSlice, LodestoneError> {
-        let index = ArcByteSliceStart(persisted.arc_inner_index);
-        let (_, header) = self.index_to_skip_list_header(index);
-        if header.id_tag == persisted.get_id_tag() {
-            let inner = self.index_to_arc_inner(index);
-            Ok(ArcByteSlice::new(inner, self))
+    /// Make a reference to the arc's block that can be stored inside
+    /// another block. The reference holds a strong count on the block
+    /// until it is given back with take_reference and released.
+    pub fn make_reference(&self, arc: &ArcByteSlice) -> Reference {
+        Reference::from_persisted(&arc.clone_to_persisted())
+    }
+
+    /// Like make_reference, but the reference stays good when the block
+    /// is moved with relocate, see block_table. The block table holds
+    /// the block for as long as any logical reference to it is kept.
+    pub fn make_logical_reference(&self, arc: &ArcByteSlice) -> Result<Reference, LodestoneError> {
+        let _table = self.block_table_lock.lock();
+        let mut table_arc = match try!(self.block_table_arc()) {
+            Some(table_arc) => table_arc,
+            None => try!(self.make_block_table()),
+        };
+        if try!(BlockTable::open(self.arc_bytes_mut(&table_arc))).is_full() {
+            table_arc = try!(self.grow_block_table(&table_arc));
+        }
+        // The table's hold on the block
+        let held = arc.clone_to_persisted();
+        let assigned = try!(BlockTable::open(self.arc_bytes_mut(&table_arc)))
+            .assign(held._arc_inner_index(), held.get_id_tag());
+        self.mark_written(&table_arc);
+        match assigned {
+            Some((index, generation)) => Ok(Reference::new(index, generation)),
+            None => Err(LodestoneError::Corruption("Block table has no free slot after growing")),
+        }
+    }
+
+    /// Move the block behind a logical reference to wherever malloc
+    /// would put it now, e.g. lower in the pool to compact it. Logical
+    /// references follow it; ArcByteSlices resolved before the move
+    /// keep the old copy alive until they are dropped.
+    pub fn relocate(&self, reference: &Reference) -> Result<(), LodestoneError> {
+        if !reference.is_logical() {
+            return Err(LodestoneError::UserError("Only blocks behind a logical reference can be relocated"));
+        }
+        let old = try!(self.resolve(reference));
+        let moved = try!(self.copy_block(&old, self));
+        let held = moved.clone_to_persisted();
+        let _table = self.block_table_lock.lock();
+        let (table_arc, slot) = try!(self.find_slot(reference));
+        try!(BlockTable::open(self.arc_bytes_mut(&table_arc)))
+            .moved(reference.arc_inner_index(), held._arc_inner_index(), held.get_id_tag());
+        self.mark_written(&table_arc);
+        // The table's hold on the old copy
+        let mut old_held = Reference::new(slot.arc_inner_index, slot.id_tag)._to_persisted();
+        try!(old_held.release(self));
+        Ok(())
+    }
+
+    /// Move the blocks behind logical references toward the front of the
+    /// pool, highest first, so the free space between them merges into
+    /// one block at the tail. Every move is a relocate: logical references
+    /// follow the block and it gets a new id tag. Blocks something else
+    /// holds a count on (an ArcByteSlice, a physical reference) stay put,
+    /// since their holders can't be redirected, and so does everything
+    /// only physical references name.
+    pub fn compact(&self) -> Result<CompactReport, LodestoneError> {
+        self.compact_with_progress(&ProgressHandle::new())
+    }
+
+    /// compact, reporting to progress as it goes. Cancelled, it stops
+    /// between moves and reports what it moved until then.
+    pub fn compact_with_progress(&self, progress: &ProgressHandle) -> Result<CompactReport, LodestoneError> {
+        let mut report = CompactReport {
+            largest_free_before: self.largest_free_block(),
+            ..CompactReport::default()
+        };
+        progress.set_phase(CompactionPhase::Tracing);
+        let mut slots = {
+            let _table = self.block_table_lock.lock();
+            match try!(self.block_table_arc()) {
+                Some(table_arc) => try!(BlockTable::open(self.arc_bytes_mut(&table_arc))).live_slots(),
+                None => Vec::new(),
+            }
+        };
+        slots.sort_by(|a, b| b.1.arc_inner_index.cmp(&a.1.arc_inner_index));
+
+        progress.set_phase(CompactionPhase::Moving);
+        let mut cancelled = false;
+        for (logical, slot) in slots {
+            if progress.should_stop() {
+                cancelled = true;
+                break;
+            }
+            let size = match self._weak_target(&Reference::new(slot.arc_inner_index, slot.id_tag)) {
+                Some((inner, _)) if ref_count(&inner.strong) == 1 => inner.size,
+                Some(_) => {
+                    report.busy_blocks += 1;
+                    continue;
+                },
+                // Freed or moved since the table was read
+                None => continue,
+            };
+            let span = byte_align(size) + *OVERHEAD;
+            if !self.lowest_fit(span).map_or(false, |offset| offset + *HEADER_SIZE < slot.arc_inner_index) {
+                continue;
+            }
+            try!(self.relocate(&Reference::new(logical, slot.generation)));
+            report.moved_blocks += 1;
+            report.moved_bytes += span;
+            progress.update(report.moved_blocks, 0);
+        }
+        if !cancelled {
+            progress.set_phase(CompactionPhase::Done);
+        }
+        report.largest_free_after = self.largest_free_block();
+        Ok(report)
+    }
+
+    /// A handle on arc's block to store in another block: a logical one
+    /// if the pool was asked for them, see set_logical_references
+    pub(crate) fn persist(&self, arc: &ArcByteSlice) -> Result<PersistedArcByteSlice, LodestoneError> {
+        if self.logical_references {
+            Ok(try!(self.make_logical_reference(arc))._to_persisted())
         } else {
-            Err(LodestoneError::InvalidReference(
-                "Can't convert to Arc. Persisted reference is no longer valid."
-            ))
+            Ok(arc.clone_to_persisted())
+        }
+    }
+
+    /// Follow a reference read out of a block
+    pub fn resolve<'a>(&'a self, reference: &Reference) -> Result<ArcByteSlice<'a>, LodestoneError> {
+        let persisted = try!(self.take_reference(reference));
+        self.clone_persisted_to_arc(&persisted)
+    }
+
+    /// Turn a stored reference back into the persisted handle that owns
+    /// its strong count, e.g. to release it.
+    pub(crate) fn take_reference(&self, reference: &Reference) -> Result<PersistedArcByteSlice, LodestoneError> {
+        if reference.is_logical() {
+            try!(self.logical_to_physical(reference));
+            return Ok(reference._to_persisted());
+        }
+        if !self.in_bounds(reference) {
+            return Err(LodestoneError::InvalidReference("Reference points outside of the pool"));
+        }
+        let persisted = reference._to_persisted();
+        if persisted.get_id_tag() == 0 {
+            return Err(LodestoneError::InvalidReference("Reference points to free memory"));
         }
+        try!(self.check_persisted(&persisted));
+        Ok(persisted)
+    }
+
+    pub(crate) fn clone_persisted_to_arc<'a>(&'a self, persisted: &PersistedArcByteSlice) -> Result<ArcByteSlice<'a>, LodestoneError> {
+        let inner = try!(self.check_persisted(persisted));
+        self.io_stats.read(inner.size, *OVERHEAD);
+        Ok(ArcByteSlice::new(inner, self))
     }
 }
 
 /// Private interface
-impl Pool {
-    fn malloc_inner<'a>(&'a self, size: usize) -> Result<(IndexType, &'a mut ArcByteSliceInner), LodestoneError> {
+impl<'buf> Pool<'buf> {
+    /// How many blocks roots (and the pins) reach, and the blocks in use
+    /// that they don't, see sweep_unreachable
+    fn find_unreachable<F>(&self, allocating: &FreeBins, mut pending: Vec<Reference>, trace: F) -> (usize, Vec<BlockInfo>)
+        where F: Fn(&[u8]) -> Vec<Reference> {
+        let mut reachable = HashSet::new();
+        pending.extend(self.get_metadata_block().pins.iter().filter(|p| !p.is_empty()).map(|p| p.reference()));
+        while let Some(reference) = pending.pop() {
+            let index = reference.arc_inner_index();
+            if reachable.contains(&index) || !self.in_bounds(&reference) {
+                continue;
+            }
+            let (_, header) = self.index_to_skip_list_header(ArcByteSliceStart(index));
+            if header.id_tag() == 0 || header.id_tag() != reference.generation() {
+                continue;
+            }
+            reachable.insert(index);
+            pending.extend(trace(self.index_to_byte_slice(ArcByteSliceStart(index))));
+        }
+
+        let scratch_region = self.get_metadata_block().scratch_region;
+        let doomed: Vec<BlockInfo> = self.blocks(allocating)
+            .filter(|b| !b.is_free)
+            .filter(|b| {
+                let index = b.offset + *HEADER_SIZE;
+                !reachable.contains(&index) && index != scratch_region
+            })
+            .collect();
+        (reachable.len(), doomed)
+    }
+
+    /// The physical references behind the block table's live slots. The
+    /// table holds whatever logical references name, so those blocks are
+    /// reached through it whether or not they're traced. Called with the
+    /// block table lock held, and before the allocation lock.
+    fn block_table_roots(&self) -> Vec<Reference> {
+        match self.block_table_arc() {
+            Ok(Some(table_arc)) => match BlockTable::open(self.arc_bytes_mut(&table_arc)) {
+                Ok(table) => table.live_slots().iter()
+                    .map(|&(_, slot)| Reference::new(slot.arc_inner_index, slot.id_tag))
+                    .collect(),
+                Err(_) => Vec::new(),
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    /// Walk the blocks in address order, for callers already holding the
+    /// allocation lock
+    fn blocks<'a>(&'a self, _allocating: &FreeBins) -> BlockIter<'a> {
+        BlockIter {
+            pool: self,
+            next_index: 0,
+        }
+    }
+
+    /// The arc behind a persisted handle, if the handle is still good
+    fn check_persisted<'a>(&'a self, persisted: &PersistedArcByteSlice) -> Result<&'a mut ArcByteSliceInner, LodestoneError> {
+        if block_table::is_logical(persisted._arc_inner_index()) {
+            let physical = try!(self.logical_to_physical(&Reference::from_persisted(persisted)));
+            return self.check_persisted(&physical);
+        }
+        if !self.in_bounds(&Reference::from_persisted(persisted)) {
+            return Err(LodestoneError::InvalidReference("Persisted reference points outside the pool"));
+        }
+        let index = ArcByteSliceStart(persisted._arc_inner_index());
+        let (_, header) = self.index_to_skip_list_header(index);
+        if header.id_tag() != persisted.get_id_tag() {
+            return Err(LodestoneError::InvalidReference(
+                "Can't convert to Arc. Persisted reference is no longer valid."
+            ));
+        }
+        let inner = self.index_to_arc_inner(index);
+        if ref_count(&inner.strong) == POISONED_REF_COUNT {
+            return Err(LodestoneError::InvalidReference("Block was poisoned by a ref count error"));
+        }
+        let checksum = inner.checksum.load(SeqCst);
+        if checksum != 0 && checksum != BLOCK_CHECKSUM_SET | crc32(self.index_to_byte_slice(index)) as usize {
+            return Err(LodestoneError::Corruption("Block checksum mismatch"));
+        }
+        Ok(inner)
+    }
+
+    /// Where the block behind a logical reference is now
+    fn logical_to_physical(&self, reference: &Reference) -> Result<PersistedArcByteSlice, LodestoneError> {
+        let _table = self.block_table_lock.lock();
+        let (_, slot) = try!(self.find_slot(reference));
+        Ok(Reference::new(slot.arc_inner_index, slot.id_tag)._to_persisted())
+    }
+
+    /// The block table and the live slot reference names. Callers hold
+    /// the table lock.
+    fn find_slot<'a>(&'a self, reference: &Reference) -> Result<(ArcByteSlice<'a>, block_table::Slot), LodestoneError> {
+        if let Some(table_arc) = try!(self.block_table_arc()) {
+            let slot = try!(BlockTable::open(self.arc_bytes_mut(&table_arc)))
+                .lookup(reference.arc_inner_index(), reference.generation());
+            if let Some(slot) = slot {
+                return Ok((table_arc, slot));
+            }
+        }
+        Err(LodestoneError::InvalidReference("Logical reference names no live block"))
+    }
+
+    /// The pool's block table, if one has been made
+    fn block_table_arc<'a>(&'a self) -> Result<Option<ArcByteSlice<'a>>, LodestoneError> {
+        match self.pinned(BLOCK_TABLE_PIN) {
+            Some(reference) => self.resolve(&reference).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn make_block_table<'a>(&'a self) -> Result<ArcByteSlice<'a>, LodestoneError> {
+        let table_arc = try!(self.malloc(&vec![0; block_table::table_size(DEFAULT_BLOCK_TABLE_SLOTS)]));
+        BlockTable::init(self.arc_bytes_mut(&table_arc));
+        self.mark_written(&table_arc);
+        try!(self.pin_root(BLOCK_TABLE_PIN, &table_arc));
+        Ok(table_arc)
+    }
+
+    /// Swap the table for one with twice the slots
+    fn grow_block_table<'a>(&'a self, old: &ArcByteSlice) -> Result<ArcByteSlice<'a>, LodestoneError> {
+        let capacity = try!(BlockTable::open(self.arc_bytes_mut(old))).capacity();
+        let table_arc = try!(self.malloc(&vec![0; block_table::table_size(2 * capacity)]));
+        {
+            let old_table = try!(BlockTable::open(self.arc_bytes_mut(old)));
+            let mut new_table = BlockTable::init(self.arc_bytes_mut(&table_arc));
+            old_table.copy_into(&mut new_table);
+        }
+        self.mark_written(&table_arc);
+        try!(self.repin(BLOCK_TABLE_PIN, &table_arc));
+        Ok(table_arc)
+    }
+
+    fn arc_bytes_mut<'a>(&'a self, arc: &ArcByteSlice) -> &'a mut [u8] {
+        let index = self.arc_to_arc_inner_index(arc);
+        self.index_to_arc_inner(index).checksum.store(0, SeqCst);
+        self.index_to_byte_slice_mut(index)
+    }
+
+    fn seal_block(&self, index: IndexType) {
+        let checksum = BLOCK_CHECKSUM_SET | crc32(self.index_to_byte_slice(index)) as usize;
+        self.index_to_arc_inner(index).checksum.store(checksum, SeqCst);
+    }
+
+    /// Anything read out of a block is untrusted, so make sure it at
+    /// least lands on an arc inside the usable part of the buffer
+    fn in_bounds(&self, reference: &Reference) -> bool {
+        let index = reference.arc_inner_index();
+        index % 8 == 0
+            && index >= *HEADER_SIZE
+            && index.checked_add(*ARC_INNER_SIZE).map_or(false, |end| end <= self.buffer_size - PAGE_SIZE)
+    }
+
+    fn malloc_inner<'a>(&'a self, size: usize, kind: Allocation) -> Result<(IndexType, &'a mut ArcByteSliceInner), LodestoneError> {
         let chunked_size = byte_align(size) + *OVERHEAD;
         let metadata = self.get_metadata_block();
-        // Try to claim a block
-        let (free_block_index, entry) = self.next_free_block_larger_than(chunked_size,
-            SkipListStart(metadata.lowest_known_free_index));
+        let mut bins = self.free_bins.lock();
+        let free_block_index = if self.chaos.as_ref().map_or(false, |c| c.strike()) {
+            // Pretend the bins are lost and walk the skip list, the way
+            // they're rebuilt on open
+            self.next_free_block_larger_than(chunked_size, SkipListStart(0)).0
+        } else {
+            bins.find(chunked_size, |idx| self.block_span(idx)).unwrap_or(BUFFER_END)
+        };
         if free_block_index == BUFFER_END {
             return Err(LodestoneError::OutOfMemory("malloc_inner"));
         }
+        // Try to claim a block
+        let (_, entry) = self.index_to_skip_list_header(SkipListStart(free_block_index));
+        let next_index = free_block_index + chunked_size;
+        let following_index = entry.next();
+        if next_index > following_index {
+            return Err(LodestoneError::StructureCorrupt("Free block is smaller than its skip list entry claims"));
+        }
         // Claim as non-free
-        entry.id_tag = metadata.next_id_tag.fetch_add(1, SeqCst);
+        if claim(&IdTagWord(&entry), &metadata.next_id_tag).is_none() {
+            return Err(LodestoneError::StructureCorrupt("Free block already carries an id tag"));
+        }
+        bins.remove(free_block_index, following_index - free_block_index);
 
-        let next_index = free_block_index + chunked_size;
-        let following_index = entry.next;
-        assert!(next_index <= following_index);
-        // If we split a block, then we need to make a new entry
-        if next_index < following_index {
+        // If we split a block, then we need to make a new entry. Leftovers
+        // too small to hold their own header stay attached to this block.
+        if next_index + *OVERHEAD < following_index {
             self.make_skip_entry(SkipListStart(next_index),
                 free_block_index, following_index, true);
+            bins.insert(next_index, following_index - next_index);
             let (_, following_entry) = self.index_to_skip_list_header(SkipListStart(following_index));
-            following_entry.prev = next_index;
-            entry.next = next_index;
+            following_entry.set_prev(next_index);
+            entry.set_next(next_index);
+            self.mark_dirty(next_index, *HEADER_SIZE);
+            self.mark_dirty(following_index, *HEADER_SIZE);
         }
-
-        // Update known free index if necessary (only necessary if we've used the lowest)
-        if free_block_index == metadata.lowest_known_free_index {
-            let (idx, _) = self.next_free_block_larger_than(0, SkipListStart(free_block_index));
-            metadata.lowest_known_free_index = idx;
+        // Header, arc and the data the caller is about to write
+        self.mark_dirty(free_block_index, chunked_size);
+        self.io_stats.written(size, chunked_size - size);
+        {
+            let stats = &mut metadata.lifetime;
+            stats.live_bytes += entry.next() - free_block_index;
+            stats.live_blocks += 1;
+            stats.peak_live_bytes = cmp::max(stats.peak_live_bytes, stats.live_bytes);
+            stats.peak_live_blocks = cmp::max(stats.peak_live_blocks, stats.live_blocks);
+            match kind {
+                Allocation::Typed => stats.typed_allocations += 1,
+                Allocation::Bytes => stats.byte_allocations += 1,
+                Allocation::Scratch => (),
+            }
         }
 
+        metadata.lowest_known_free_index = bins.lowest().unwrap_or(BUFFER_END);
+
         let inner = self.index_to_arc_inner(SkipListStart(free_block_index));
         inner.init(size);
         Ok((SkipListStart(free_block_index), inner))
     }
 
     fn free_inner(&self, index: IndexType) {
+        let mut bins = self.free_bins.lock();
+        self.free_block(&mut bins, index)
+    }
+
+    /// free_inner, with the allocation lock already held
+    fn free_block(&self, bins: &mut FreeBins, index: IndexType) {
         let metadata = self.get_metadata_block();
         let (this_idx, header) = self.index_to_skip_list_header(index);
-        let prev_idx = header.prev;
-        let next_idx = header.next;
+        let prev_idx = header.prev();
+        let next_idx = header.next();
 
-        header.id_tag = 0; // Mark as free
-        // Update known free index if necessary
-        if this_idx < metadata.lowest_known_free_index {
-            metadata.lowest_known_free_index = this_idx;
+        // Freeing a free block again changes nothing, and isn't counted
+        if header.id_tag() != 0 {
+            metadata.lifetime.live_bytes -= next_idx - this_idx;
+            metadata.lifetime.live_blocks -= 1;
+            metadata.lifetime.frees += 1;
+        } else {
+            bins.remove(this_idx, next_idx - this_idx);
         }
+        header.set_id_tag(0); // Mark as free
+        self.mark_dirty(this_idx, *HEADER_SIZE);
 
         if next_idx != BUFFER_END {
             let (_, next) = self.index_to_skip_list_header(SkipListStart(next_idx));
-            if next.id_tag == 0 {
+            if next.id_tag() == 0 {
                 // Merge with the next item, by encompassing it
-                let next_next_idx = next.next;
-                header.next = next_next_idx;
+                let next_next_idx = next.next();
+                bins.remove(next_idx, next_next_idx - next_idx);
+                header.set_next(next_next_idx);
                 // Update the prev of the next_next_idx
                 if next_next_idx != BUFFER_END {
                     let (_, next_next) = self.index_to_skip_list_header(SkipListStart(next_next_idx));
-                    next_next.prev = this_idx;
+                    next_next.set_prev(this_idx);
+                    self.mark_dirty(next_next_idx, *HEADER_SIZE);
                 }
             }
         }
-        if prev_idx != BUFFER_END {
-            let (_, prev) = self.index_to_skip_list_header(SkipListStart(prev_idx));
-            if prev.id_tag == 0 {
-                // Merge by swallowing this item with the previous item
-                let next_idx = header.next;
-                prev.next = next_idx;
-                // Update the prev of the following item
-                if next_idx != BUFFER_END {
-                    let (_, next) = self.index_to_skip_list_header(SkipListStart(next_idx));
-                    next.prev = prev_idx;
-                }
+        if prev_idx != BUFFER_END {
+            let (_, prev) = self.index_to_skip_list_header(SkipListStart(prev_idx));
+            if prev.id_tag() == 0 {
+                // Merge by swallowing this item with the previous item
+                let next_idx = header.next();
+                bins.remove(prev_idx, this_idx - prev_idx);
+                prev.set_next(next_idx);
+                self.mark_dirty(prev_idx, *HEADER_SIZE);
+                // Update the prev of the following item
+                if next_idx != BUFFER_END {
+                    let (_, next) = self.index_to_skip_list_header(SkipListStart(next_idx));
+                    next.set_prev(prev_idx);
+                    self.mark_dirty(next_idx, *HEADER_SIZE);
+                }
+            }
+        }
+        let merged_idx = if self.is_free(prev_idx) { prev_idx } else { this_idx };
+        bins.insert(merged_idx, self.block_span(merged_idx));
+        metadata.lowest_known_free_index = bins.lowest().unwrap_or(BUFFER_END);
+        if self.deterministic {
+            self.zero_free_block(merged_idx);
+        }
+        if self.punch_holes {
+            self.punch_free_block(merged_idx);
+        }
+    }
+
+    /// Hand the whole pages after the header of the given free block back
+    /// to the backend. Failing to is harmless, the pages just stay.
+    fn punch_free_block(&self, idx: usize) {
+        let backend = match self.backend {
+            Some(ref backend) => backend,
+            None => return,
+        };
+        let (_, header) = self.index_to_skip_list_header(SkipListStart(idx));
+        let start = (idx + *HEADER_SIZE + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        let end = header.next() / PAGE_SIZE * PAGE_SIZE;
+        if start < end {
+            let _backend = self.flush_state.lock();
+            if let Ok(bytes) = backend.punch_hole(start, end - start) {
+                self.reclaimed.fetch_add(bytes, Relaxed);
+            }
+        }
+    }
+
+    /// Where the superblock starts, the last bytes of the buffer
+    fn superblock_ptr(&self) -> *mut u8 {
+        unsafe { self.buffer.offset((self.buffer_size - SUPERBLOCK_SIZE) as isize) }
+    }
+
+    fn write_superblock(&self, superblock: Superblock) {
+        let bytes = superblock.encode();
+        unsafe {
+            ptr::copy_nonoverlapping(bytes.as_ptr(), self.superblock_ptr(), SUPERBLOCK_SIZE);
+        }
+        self.mark_dirty(self.buffer_size - SUPERBLOCK_SIZE, SUPERBLOCK_SIZE);
+    }
+
+    /// Where malloc would put a block spanning span bytes, headers included
+    fn lowest_fit(&self, span: usize) -> Option<usize> {
+        self.free_bins.lock().find(span, |idx| self.block_span(idx))
+    }
+
+    /// Bytes from the block's header to the next one's
+    fn block_span(&self, idx: usize) -> usize {
+        self.index_to_skip_list_header(SkipListStart(idx)).1.next() - idx
+    }
+
+    /// Sort the free blocks into bins again, from the skip list
+    fn rebuild_free_bins(&self) {
+        let mut bins = self.free_bins.lock();
+        let free: Vec<BlockInfo> = self.blocks(&bins).filter(|b| b.is_free).collect();
+        bins.clear();
+        for block in free {
+            bins.insert(block.offset, block.capacity + *OVERHEAD);
+        }
+    }
+
+    fn is_free(&self, idx: usize) -> bool {
+        idx != BUFFER_END && self.index_to_skip_list_header(SkipListStart(idx)).1.id_tag() == 0
+    }
+
+    /// Zero everything after the header of the given free block
+    fn zero_free_block(&self, idx: usize) {
+        let (_, header) = self.index_to_skip_list_header(SkipListStart(idx));
+        let start = idx + *HEADER_SIZE;
+        self.mark_dirty(start, header.next() - start);
+        unsafe {
+            let data = slice::from_raw_parts_mut(self.byte_index_to_live_ptr(start), header.next() - start);
+            for b in data.iter_mut() {
+                *b = 0;
+            }
+        }
+    }
+
+    fn ref_count_error(&self, error: RefCountError, inner: &ArcByteSliceInner) {
+        match error {
+            RefCountError::Underflow(_) => self.ref_count_stats.lock().underflows += 1,
+            RefCountError::Overflow(_) => self.ref_count_stats.lock().overflows += 1,
+        }
+        if let Some(ref handler) = *self.ref_count_handler.lock() {
+            handler(error);
+        }
+        match self.ref_count_policy {
+            RefCountPolicy::Saturate => inner.strong.store(match error {
+                RefCountError::Underflow(_) => 0,
+                RefCountError::Overflow(_) => MAX_REF_COUNT,
+            }, SeqCst),
+            RefCountPolicy::Poison => {
+                inner.strong.store(POISONED_REF_COUNT, SeqCst);
+                self.ref_count_stats.lock().poisoned += 1;
+            },
+            RefCountPolicy::Abort => process::abort(),
+        }
+    }
+
+    fn inner_to_offset(&self, inner: &ArcByteSliceInner) -> usize {
+        inner as *const ArcByteSliceInner as usize - self.buffer as usize
+    }
+
+    fn mark_dirty(&self, start: usize, len: usize) {
+        if self.backend.is_some() {
+            self.flush_state.lock().mark(start, len);
+        }
+    }
+
+    /// Whether the buffer holds a pool that open can trust: the magic is
+    /// there, and the skip list runs from the start of the buffer to the
+    /// metadata page with links that agree in both directions
+    fn check_image(&self) -> Result<(), LodestoneError> {
+        let metadata = self.get_metadata_block();
+        if metadata.magic != POOL_MAGIC {
+            return Err(LodestoneError::InvalidReference("Buffer doesn't hold a pool"));
+        }
+        let last_skip_index = self.buffer_size - PAGE_SIZE;
+        let next_id_tag = metadata.next_id_tag.load(SeqCst);
+        let (mut prev, mut index) = (BUFFER_END, 0);
+        while index != last_skip_index {
+            let (_, entry) = self.index_to_skip_list_header(SkipListStart(index));
+            if entry.prev() != prev {
+                return Err(LodestoneError::InvalidReference("Skip list links don't agree"));
+            }
+            if entry.id_tag() >= next_id_tag {
+                return Err(LodestoneError::InvalidReference("Block was tagged after the pool's last tag"));
+            }
+            if entry.next() < index + *OVERHEAD || entry.next() > last_skip_index || entry.next() % 8 != 0 {
+                return Err(LodestoneError::InvalidReference("Skip list runs outside the buffer"));
             }
+            prev = index;
+            index = entry.next();
         }
+        let (_, last) = self.index_to_skip_list_header(SkipListStart(last_skip_index));
+        if last.prev() != prev || last.next() != BUFFER_END {
+            return Err(LodestoneError::InvalidReference("Skip list doesn't end at the metadata page"));
+        }
+        let hint = metadata.lowest_known_free_index;
+        if hint != BUFFER_END && hint > last_skip_index {
+            return Err(LodestoneError::InvalidReference("Free block hint is outside the buffer"));
+        }
+        Ok(())
     }
 
     /// Get the metadata block, which always lives in the last page of the array
@@ -232,6 +1570,16 @@ impl Pool {
         }
     }
 
+    /// A block's bytes, borrowed for as long as the whole buffer is, to
+    /// build a pool inside. The block must stay allocated that long.
+    fn index_to_buffer_region(&self, index: IndexType) -> &'buf mut [u8] {
+        let size = self.index_to_arc_inner(index).size;
+        let offset = self.index_to_data_offset(index);
+        unsafe {
+            slice::from_raw_parts_mut(self.buffer.offset(offset as isize), size)
+        }
+    }
+
     /// Get the byte_slice corresponding to an index
     fn index_to_byte_slice_mut<'a>(&'a self, index: IndexType) -> &'a mut [u8] {
         let size = self.index_to_arc_inner(index).size;
@@ -259,19 +1607,19 @@ impl Pool {
     }
 
     /// Overhead must already be factored into size
-    fn next_free_block_larger_than<'a>(&'a self, size: usize, start_index: IndexType) -> (usize, &'a mut SkipListEntry) {
-        let (idx, mut entry) = self.index_to_skip_list_header(start_index);
-        if entry.id_tag == 0
-           && (entry.next - idx) >= size {
+    fn next_free_block_larger_than<'a>(&'a self, size: usize, start_index: IndexType) -> (usize, SkipListHeader<'a>) {
+        let (idx, entry) = self.index_to_skip_list_header(start_index);
+        if entry.id_tag() == 0
+           && (entry.next() - idx) >= size {
             (idx, entry)
-        } else if entry.next != BUFFER_END {
-            self.next_free_block_larger_than(size, SkipListStart(entry.next))
+        } else if entry.next() != BUFFER_END {
+            self.next_free_block_larger_than(size, SkipListStart(entry.next()))
         } else {
             (BUFFER_END, entry)
         }
     }
 
-    fn live_ptr_to_arc(&self, ptr: *const u8) -> Result<ArcByteSlice, LodestoneError> {
+    fn live_ptr_to_arc<'a>(&'a self, ptr: *const u8) -> Result<ArcByteSlice<'a>, LodestoneError> {
         let index = DataStart(self.live_ptr_to_byte_index(ptr));
         let inner = self.index_to_arc_inner(index);
         Ok(ArcByteSlice::new(inner, self))
@@ -314,17 +1662,111 @@ impl Pool {
     }
 
     /// Find the skip list entry that precedes the given index's data
-    fn index_to_skip_list_header<'a>(&'a self, index: IndexType) -> (usize, &'a mut SkipListEntry) {
+    fn index_to_skip_list_header<'a>(&'a self, index: IndexType) -> (usize, SkipListHeader<'a>) {
         let offset = match index {
             ArcByteSliceStart(i) => i - *HEADER_SIZE,
             DataStart(i) => i - *OVERHEAD,
             SkipListStart(i) => i,
         };
-        unsafe {
-            (offset, mem::transmute(self.buffer.offset(offset as isize)))
+        debug_assert!(offset + SKIP_LIST_HEADER_SIZE <= self.buffer_size);
+        let header = SkipListHeader {
+            at: unsafe { self.buffer.offset(offset as isize) },
+            _buffer: PhantomData,
+        };
+        (offset, header)
+    }
+
+    /// Priviledged, should not be called outside allocator package
+    pub fn _retain(&self, inner: &ArcByteSliceInner) {
+        if ref_count(&inner.strong) == POISONED_REF_COUNT {
+            return;
+        }
+        self.ref_counting.retain(&inner.strong);
+        if ref_count(&inner.strong) > MAX_REF_COUNT {
+            self.ref_count_error(RefCountError::Overflow(self.inner_to_offset(inner)), inner);
+        }
+    }
+
+    /// Priviledged, should not be called outside allocator package.
+    /// Retain a block nothing may be holding, see WeakByteSlice::upgrade.
+    /// Returns whether it was still alive.
+    pub fn _retain_if_live(&self, inner: &ArcByteSliceInner) -> bool {
+        if !self.ref_counting.retain_if_live(&inner.strong) {
+            return false;
+        }
+        if ref_count(&inner.strong) > MAX_REF_COUNT {
+            self.ref_count_error(RefCountError::Overflow(self.inner_to_offset(inner)), inner);
+        }
+        true
+    }
+
+    /// Priviledged, should not be called outside allocator package.
+    /// The arc a weak handle points at and the id tag it must keep, if
+    /// the block is still the one the handle was taken from.
+    pub fn _weak_target<'a>(&'a self, reference: &Reference) -> Option<(&'a mut ArcByteSliceInner, usize)> {
+        let reference = if reference.is_logical() {
+            match self.logical_to_physical(reference) {
+                Ok(physical) => Reference::from_persisted(&physical),
+                Err(_) => return None,
+            }
+        } else {
+            *reference
+        };
+        if !self.in_bounds(&reference) || reference.generation() == 0 {
+            return None;
+        }
+        let index = ArcByteSliceStart(reference.arc_inner_index());
+        let (_, header) = self.index_to_skip_list_header(index);
+        if header.id_tag() != reference.generation() {
+            return None;
+        }
+        Some((self.index_to_arc_inner(index), reference.generation()))
+    }
+
+    /// Priviledged, should not be called outside allocator package.
+    /// Returns the count left, never 0 after an error so the block
+    /// isn't freed because of one.
+    pub fn _release(&self, inner: &ArcByteSliceInner) -> usize {
+        match ref_count(&inner.strong) {
+            POISONED_REF_COUNT => POISONED_REF_COUNT,
+            0 => {
+                self.ref_count_error(RefCountError::Underflow(self.inner_to_offset(inner)), inner);
+                cmp::max(1, ref_count(&inner.strong))
+            },
+            _ => self.ref_counting.release(&inner.strong),
+        }
+    }
+
+    /// Priviledged, should not be called outside allocator package
+    pub(crate) fn _retain_logical(&self, persisted: &PersistedArcByteSlice) -> Result<(), LodestoneError> {
+        let _table = self.block_table_lock.lock();
+        let (table_arc, _) = try!(self.find_slot(&Reference::from_persisted(persisted)));
+        try!(BlockTable::open(self.arc_bytes_mut(&table_arc))).retain(persisted._arc_inner_index());
+        self.mark_written(&table_arc);
+        Ok(())
+    }
+
+    /// Priviledged, should not be called outside allocator package.
+    /// Returns whether the block was freed with the last logical reference.
+    pub(crate) fn _release_logical(&self, persisted: &PersistedArcByteSlice) -> Result<bool, LodestoneError> {
+        let _table = self.block_table_lock.lock();
+        let (table_arc, _) = try!(self.find_slot(&Reference::from_persisted(persisted)));
+        let released = try!(BlockTable::open(self.arc_bytes_mut(&table_arc))).release(persisted._arc_inner_index());
+        self.mark_written(&table_arc);
+        match released {
+            // The table's hold on the block
+            Some(slot) => Reference::new(slot.arc_inner_index, slot.id_tag)._to_persisted().release(self),
+            None => Ok(false),
         }
     }
 
+    /// Priviledged, should not be called outside allocator package
+    /// For ref count changes that are persisted
+    pub fn _mark_inner_dirty(&self, arc: &ArcByteSlice) {
+        let offset = self._inner_offset(arc);
+        self.mark_dirty(offset, *ARC_INNER_SIZE);
+    }
+
     /// Priviledged, should not be called outside allocator package
     pub fn _inner_offset(&self, arc: &ArcByteSlice) -> usize {
         let inner_index = self.arc_to_arc_inner_index(arc);
@@ -335,18 +1777,19 @@ impl Pool {
     pub fn _get_id_tag(&self, arc: &ArcByteSlice) -> usize {
         let inner_index = self.arc_to_arc_inner_index(arc);
         let (_, header) = self.index_to_skip_list_header(inner_index);
-        header.id_tag
+        header.id_tag()
     }
 
     fn make_skip_entry(&self, index: IndexType, prev: usize, next: usize, is_free: bool) {
         let (_, entry) = self.index_to_skip_list_header(index);
-        entry.prev = prev;
-        entry.next = next;
-        entry.id_tag = if is_free {
+        entry.set_prev(prev);
+        entry.set_next(next);
+        let id_tag = if is_free {
             0
         } else {
-            self.get_metadata_block().next_id_tag.fetch_add(1, SeqCst)
+            next_tag(&self.get_metadata_block().next_id_tag)
         };
+        entry.set_id_tag(id_tag);
     }
 
     fn get_debug_blocks<'a>(&'a self) -> Vec<_B> {
@@ -354,8 +1797,8 @@ impl Pool {
         let mut next_index: usize = 0;
         loop {
             let (idx, entry) = self.index_to_skip_list_header(SkipListStart(next_index));
-            next_index = entry.next;
-            let prev_index = entry.prev;
+            next_index = entry.next();
+            let prev_index = entry.prev();
             if next_index == BUFFER_END {
                 break
             }
@@ -364,7 +1807,7 @@ impl Pool {
                 capacity: next_index - idx - *OVERHEAD,
                 next: next_index,
                 prev: prev_index,
-                is_free: entry.id_tag == 0,
+                is_free: entry.id_tag() == 0,
             });
         }
         ret
@@ -389,6 +1832,7 @@ struct _B {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use allocator::{CompactionProgress, IoRate};
 
     #[test]
     #[should_panic(expected="malloc_inner")]
@@ -398,6 +1842,39 @@ mod tests {
         p.malloc(&[42; 0x2000][..]).unwrap();
     }
 
+    #[test]
+    fn test_open_existing_image() {
+        let mut buf = vec![0u8; 0x4000];
+        let kept = {
+            let p = Pool::new(&mut buf);
+            let a = p.malloc(b"kept").unwrap();
+            let b = p.malloc(b"freed").unwrap();
+            p.pin_root("kept", &a).unwrap();
+            p.free(&b);
+            p.make_reference(&a)
+        };
+        {
+            let p = Pool::open(&mut buf).unwrap();
+            assert_eq!(Some(kept), p.pinned("kept"));
+            assert_eq!(&b"kept"[..], &p.resolve(&kept).unwrap()[..]);
+            // Still allocates where it left off
+            let _more = p.malloc(b"more").unwrap();
+            assert_eq!(2, p.lifetime_stats().live_blocks);
+        }
+
+        let mut junk = vec![0xabu8; 0x4000];
+        match Pool::open(&mut junk) {
+            Err(LodestoneError::InvalidReference(_)) => (),
+            other => panic!("Expected InvalidReference, got {:?}", other),
+        }
+        assert!(Pool::open(&mut buf[..0x1000]).is_err());
+        // A block tagged with a tag the pool never handed out
+        for b in buf[8..16].iter_mut() {
+            *b = 0xff;
+        }
+        assert!(Pool::open(&mut buf).is_err());
+    }
+
     #[test]
     fn test_printing_empty() {
         let mut buf: [u8; 0x2000] = [0; 0x2000];
@@ -414,77 +1891,833 @@ mod tests {
 
     #[test]
     fn test_small_alloc_free() {
+        use super::SnapshotBlock::*;
         let mut buf: [u8; 0x4000] = [0; 0x4000];
         let p = Pool::new(&mut buf[..]);
         let data = [0x1, 0x2, 0x3, 0x4];
 
         let arc_ts1 = p.malloc(&data[..]).unwrap();
-
-        assert_eq!(
-            "Pool { buffer_size: 16384, \
-                metadata: Metadata { lowest_known_free_index: 56, next_id_tag: AtomicUsize(3) }, \
-                blocks: [\
-                    _B { start: 0, capacity: 8, next: 56, prev: 18446744073709551615, is_free: false }, \
-                    _B { start: 56, capacity: 12184, next: 12288, prev: 0, is_free: true }\
-                ] }",
-            format!("{:?}", p)
-        );
-
+        assert_eq!(vec![Live { id: 0, size: 4, ref_count: 1 }, Free], p.snapshot().blocks);
         assert_eq!([0x1, 0x2, 0x3, 0x4], arc_ts1[0..4]);
 
         let arc_ts2 = p.malloc(&data[..]).unwrap();
         assert_eq!(
-            "Pool { buffer_size: 16384, \
-                metadata: Metadata { lowest_known_free_index: 112, next_id_tag: AtomicUsize(4) }, \
-                blocks: [\
-                    _B { start: 0, capacity: 8, next: 56, prev: 18446744073709551615, is_free: false }, \
-                    _B { start: 56, capacity: 8, next: 112, prev: 0, is_free: false }, \
-                    _B { start: 112, capacity: 12128, next: 12288, prev: 56, is_free: true }\
-                ] }",
-            format!("{:?}", p)
+            vec![Live { id: 0, size: 4, ref_count: 1 }, Live { id: 1, size: 4, ref_count: 1 }, Free],
+            p.snapshot().blocks
         );
 
         p.free(&arc_ts1);
+        assert_eq!(vec![Free, Live { id: 0, size: 4, ref_count: 1 }, Free], p.snapshot().blocks);
 
-        assert_eq!(
-            "Pool { buffer_size: 16384, \
-                metadata: Metadata { lowest_known_free_index: 0, next_id_tag: AtomicUsize(4) }, \
-                blocks: [\
-                    _B { start: 0, capacity: 8, next: 56, prev: 18446744073709551615, is_free: true }, \
-                    _B { start: 56, capacity: 8, next: 112, prev: 0, is_free: false }, \
-                    _B { start: 112, capacity: 12128, next: 12288, prev: 56, is_free: true }\
-                ] }",
-            format!("{:?}", p)
-        );
-
+        // Freeing the middle block merges all three
         p.free(&arc_ts2);
+        assert_eq!(vec![Free], p.snapshot().blocks);
+    }
+
+    #[test]
+    fn test_alloc_does_not_leave_sliver() {
+        let mut buf: [u8; 0x4000] = [0; 0x4000];
+        let p = Pool::new(&mut buf[..]);
+
+        {
+            // Leaves 8 bytes at the end of the block, which can't hold a header
+            let _arc = p.malloc(&[7u8; 12232][..]).unwrap();
+            let blocks = p.get_debug_blocks();
+            assert_eq!(1, blocks.len());
+            assert_eq!(12240, blocks[0].capacity);
+            assert!(!blocks[0].is_free);
+        }
+        let blocks = p.get_debug_blocks();
+        assert_eq!(1, blocks.len());
+        assert!(blocks[0].is_free);
+    }
+
+    #[test]
+    fn test_alloc_splits_only_leftovers_that_fit_a_block() {
+        let mut buf: [u8; 0x4000] = [0; 0x4000];
+        let p = Pool::new(&mut buf[..]);
+        let capacity = p.get_debug_blocks()[0].capacity;
+
+        {
+            // Room for a header, an arc and 8 bytes: split off
+            let _arc = p.malloc(&vec![7u8; capacity - *OVERHEAD - 8][..]).unwrap();
+            let blocks = p.get_debug_blocks();
+            assert_eq!(2, blocks.len());
+            assert!(blocks[1].is_free);
+            assert_eq!(8, blocks[1].capacity);
+        }
+        {
+            // Room for a header and an arc only: kept
+            let _arc = p.malloc(&vec![7u8; capacity - *OVERHEAD][..]).unwrap();
+            let blocks = p.get_debug_blocks();
+            assert_eq!(1, blocks.len());
+            assert_eq!(capacity, blocks[0].capacity);
+        }
+        assert_eq!(1, p.get_debug_blocks().len());
+    }
+
+    #[test]
+    fn test_references() {
+        let mut buf: [u8; 0x4000] = [0; 0x4000];
+        let p = Pool::new(&mut buf[..]);
+
+        let reference = {
+            let target = p.malloc(&[1, 2, 3]).unwrap();
+            p.make_reference(&target)
+        };
+        // The reference keeps the target alive
+        let bytes = reference.to_bytes();
+        let decoded = Reference::from_bytes(&bytes).unwrap();
+        assert_eq!(reference, decoded);
+        assert_eq!([1, 2, 3], *p.resolve(&decoded).unwrap());
+
+        // Junk never resolves
+        assert!(Reference::from_bytes(&bytes[1..]).is_err());
+        let mut junk = bytes;
+        junk[0] = junk[0].wrapping_add(3);
+        assert!(p.resolve(&Reference::from_bytes(&junk).unwrap()).is_err());
+        junk[0] = 0xff;
+        junk[7] = 0xff;
+        assert!(p.resolve(&Reference::from_bytes(&junk).unwrap()).is_err());
+
+        // Once released, the reference is stale
+        let mut owner = p.take_reference(&decoded).unwrap();
+        assert!(owner.release(&p).unwrap());
+        assert!(p.resolve(&decoded).is_err());
+    }
+
+    #[test]
+    fn test_deterministic_layout() {
+        let mut clean = vec![0u8; 0x4000];
+        let mut dirty = vec![0xABu8; 0x4000];
+        {
+            let run = |p: &Pool| {
+                let a = p.malloc(&[1, 2, 3]).unwrap();
+                let b = p.malloc(&[4; 100]).unwrap();
+                let _c = p.make_new::<[u64; 5]>().unwrap();
+                drop(a);
+                let _d = p.malloc(&[5, 6]).unwrap();
+                drop(b);
+                p.shape()
+            };
+            let p1 = Pool::new_deterministic(&mut clean[..]);
+            let p2 = Pool::new_deterministic(&mut dirty[..]);
+            let shape = run(&p1);
+            assert_eq!(shape, run(&p2));
+            assert_eq!(
+                vec![
+                    BlockShape { capacity: 8, is_free: false },
+                    BlockShape { capacity: 104, is_free: true },
+                    BlockShape { capacity: 40, is_free: false },
+                    BlockShape { capacity: 11944, is_free: true },
+                ],
+                shape
+            );
+        }
+        // Same operations, same bytes, whatever was in the buffer before
+        assert!(clean == dirty);
+    }
+
+    #[test]
+    fn test_chaos_slow_path() {
+        let mut buf = vec![0u8; 0x4000];
+        let mut p = Pool::new(&mut buf[..]);
+        p.enable_chaos(Chaos::new(1, 7));
+        {
+            let a = p.malloc(&[1; 10]).unwrap();
+            let b = p.malloc(&[2; 10]).unwrap();
+            let c = p.malloc(&[3; 10]).unwrap();
+            drop(b);
+            // Skipping the bins still finds the hole, and doesn't disturb anything
+            let d = p.malloc(&[4; 10]).unwrap();
+            assert_eq!(&[1; 10], &a[..]);
+            assert_eq!(&[3; 10], &c[..]);
+            assert_eq!(&[4; 10], &d[..]);
+            assert_eq!(4, p.chaos().unwrap().forced_slow_paths());
+        }
+        assert_eq!(1, p.shape().len());
+    }
+
+    #[cfg(feature = "thread-safe")]
+    #[test]
+    fn test_concurrent_allocation() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let p = Arc::new(Pool::with_backend(Box::new(HeapBackend::new(0x100000))));
+        let span = |p: &Pool| p.shape().iter().fold(0, |sum, b| sum + b.capacity + *OVERHEAD);
+        let total = span(&p);
+        // Walks run alongside, and never see a half split or merged block
+        let walker = {
+            let p = p.clone();
+            thread::spawn(move || {
+                for _ in 0..200 {
+                    assert_eq!(total, span(&p));
+                }
+            })
+        };
+        let threads: Vec<_> = (0..4u8).map(|t| {
+            let p = p.clone();
+            thread::spawn(move || {
+                let mut kept = Vec::new();
+                for i in 0..500 {
+                    let a = p.malloc(&vec![t; 8 + i % 200]).unwrap();
+                    if i % 3 == 0 {
+                        kept.push(a);
+                    }
+                    if kept.len() > 20 {
+                        kept.remove(0);
+                    }
+                }
+                assert!(kept.iter().all(|a| a.iter().all(|&b| b == t)));
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        walker.join().unwrap();
+        assert_eq!(0, p.lifetime_stats().live_blocks);
+        assert_eq!(2000, p.lifetime_stats().frees);
+        assert_eq!(1, p.shape().len());
+    }
+
+    #[test]
+    fn test_free_bins() {
+        let mut buf = vec![0u8; 0x40000];
+        let holes = {
+            let p = Pool::new(&mut buf);
+            // Fragment the pool with holes of every size, each kept apart
+            // by a live block
+            let mut kept = Vec::new();
+            for i in 0..200 {
+                let hole = p.malloc(&vec![0; 8 * (i % 40 + 1)]).unwrap();
+                kept.push(p.malloc(b"wall").unwrap());
+                p.free(&hole);
+            }
+            for i in 0..100 {
+                let size = 8 * (i * 7 % 60 + 1);
+                // Wherever a walk of the skip list would have put it
+                let expected = p.next_free_block_larger_than(byte_align(size) + *OVERHEAD, SkipListStart(0)).0;
+                let a = p.malloc(&vec![1; size]).unwrap();
+                assert_eq!(expected, p.index_to_skip_list_header(p.arc_to_arc_inner_index(&a)).0);
+                kept.push(a);
+            }
+            // Left allocated in the image
+            ::std::mem::forget(kept);
+            p.iter_blocks().filter(|b| b.is_free).count()
+        };
+        // Reopened, the bins are rebuilt from the skip list
+        let p = Pool::open(&mut buf).unwrap();
+        assert_eq!(holes, p.free_bins.lock().len());
+        let expected = p.next_free_block_larger_than(PAGE_SIZE, SkipListStart(0)).0;
+        let a = p.malloc(&[2; PAGE_SIZE]).unwrap();
+        assert_eq!(expected + *HEADER_SIZE, p.make_reference(&a).arc_inner_index());
+    }
 
+    #[test]
+    fn test_copy_block() {
+        let mut src_buf = vec![0u8; 0x4000];
+        let mut dest_buf = vec![0u8; 0x4000];
+        let src = Pool::new(&mut src_buf[..]);
+        let dest = Pool::new(&mut dest_buf[..]);
+        let _filler = dest.malloc(&[0; 100]).unwrap();
+        let original = src.malloc(b"hello world").unwrap();
+
+        let copy = src.copy_block(&original, &dest).unwrap();
+        assert_eq!(b"hello world", &copy[..]);
+        // The copy belongs to dest, and lives on without the original
+        let mut persisted = copy.clone_to_persisted();
+        drop(original);
+        assert_eq!(b"hello world", &persisted.clone_to_arc_byte_slice(&dest).unwrap()[..]);
+        persisted.release(&dest).unwrap();
+        assert!(dest.copy_block(&copy, &src).is_ok());
+    }
+
+    #[test]
+    fn test_iter_blocks() {
+        let mut buf = vec![0u8; 0x4000];
+        let p = Pool::new(&mut buf[..]);
+        let a = p.malloc(&[1; 3]).unwrap();
+        let b = p.malloc(&[2; 20]).unwrap();
+        let _b2 = b.clone();
+        drop(a);
+        let blocks: Vec<BlockInfo> = p.iter_blocks().collect();
         assert_eq!(
-            "Pool { buffer_size: 16384, \
-                metadata: Metadata { lowest_known_free_index: 0, next_id_tag: AtomicUsize(4) }, \
-                blocks: [\
-                    _B { start: 0, capacity: 12240, next: 12288, prev: 18446744073709551615, is_free: true }\
-                ] }",
-            format!("{:?}", p)
+            vec![
+                BlockInfo { offset: 0, capacity: 8, size: 0, generation: 0, ref_count: 0, is_free: true },
+                BlockInfo { offset: 56, capacity: 24, size: 20, generation: 3, ref_count: 2, is_free: false },
+                BlockInfo { offset: 128, capacity: 12112, size: 0, generation: 0, ref_count: 0, is_free: true },
+            ],
+            blocks
         );
     }
 
+    #[test]
+    fn test_backends() {
+        use std::sync::Arc;
+        use std::sync::atomic::AtomicUsize;
+        use std::thread;
+
+        let p = Pool::with_backend(Box::new(HeapBackend::new(0x4000)));
+        assert_eq!(0x4000, p.size());
+        {
+            let a = p.malloc(b"owned").unwrap();
+            assert_eq!(b"owned", &a[..]);
+        }
+        assert!(p.flush().is_ok());
+
+        struct Counting {
+            inner: HeapBackend,
+            flushes: Arc<AtomicUsize>,
+        }
+        impl StorageBackend for Counting {
+            fn as_mut_ptr(&mut self) -> *mut u8 { self.inner.as_mut_ptr() }
+            fn len(&self) -> usize { self.inner.len() }
+            fn flush(&self, offset: usize, len: usize) -> Result<(), LodestoneError> {
+                assert_eq!((0, 0x2000), (offset, len));
+                self.flushes.fetch_add(1, SeqCst);
+                Ok(())
+            }
+        }
+        let flushes = Arc::new(AtomicUsize::new(0));
+        let p = Pool::with_backend(Box::new(Counting {
+            inner: HeapBackend::new(0x2000),
+            flushes: flushes.clone(),
+        }));
+        // Pools that own their memory can be handed to another thread
+        thread::spawn(move || p.flush().unwrap()).join().unwrap();
+        assert_eq!(1, flushes.load(SeqCst));
+    }
+
+    #[test]
+    fn test_usage() {
+        let mut buf = vec![0u8; 0x4000];
+        let p = Pool::new(&mut buf);
+        assert_eq!(Usage { allocated: 0, reserved: 0x4000, materialized: 0x4000, reclaimed: 0 }, p.usage());
+        let a = p.malloc(&[1; 8]).unwrap();
+        let b = p.malloc(&[2; 100]).unwrap();
+        assert_eq!(2 * *OVERHEAD + 8 + 104, p.usage().allocated);
+        drop(a);
+        drop(b);
+        assert_eq!(0, p.usage().allocated);
+
+        // Only the pages that were touched
+        struct Sparse(HeapBackend);
+        impl StorageBackend for Sparse {
+            fn as_mut_ptr(&mut self) -> *mut u8 { self.0.as_mut_ptr() }
+            fn len(&self) -> usize { self.0.len() }
+            fn materialized(&self) -> usize { 2 * PAGE_SIZE }
+        }
+        let p = Pool::with_backend(Box::new(Sparse(HeapBackend::new(0x10000))));
+        assert_eq!(Usage { allocated: 0, reserved: 0x10000, materialized: 2 * PAGE_SIZE, reclaimed: 0 }, p.usage());
+    }
+
+    #[test]
+    fn test_lifetime_stats() {
+        let mut buf = vec![0u8; 0x4000];
+        let p = Pool::new(&mut buf);
+        let a = p.malloc(&[1; 100]).unwrap();
+        let b = p.malloc(&[2; 8]).unwrap();
+        let peak = p.usage().allocated;
+        drop(a);
+        let node = p.make_new::<u64>().unwrap();
+        drop(b);
+        assert_eq!(LifetimeStats {
+            live_bytes: p.usage().allocated,
+            live_blocks: 1,
+            peak_live_bytes: peak,
+            peak_live_blocks: 2,
+            typed_allocations: 1,
+            byte_allocations: 2,
+            frees: 2,
+            split_allocations: 0,
+        }, p.lifetime_stats());
+        drop(node);
+        assert_eq!((0, 0), (p.lifetime_stats().live_bytes, p.lifetime_stats().live_blocks));
+    }
+
+    #[test]
+    fn test_punch_holes() {
+        use std::sync::{Arc, Mutex};
+
+        struct Holey {
+            inner: HeapBackend,
+            holes: Arc<Mutex<Vec<(usize, usize)>>>,
+        }
+        impl StorageBackend for Holey {
+            fn as_mut_ptr(&mut self) -> *mut u8 { self.inner.as_mut_ptr() }
+            fn len(&self) -> usize { self.inner.len() }
+            fn punch_hole(&self, offset: usize, len: usize) -> Result<usize, LodestoneError> {
+                self.holes.lock().unwrap().push((offset, len));
+                Ok(len)
+            }
+        }
+        let holes = Arc::new(Mutex::new(Vec::new()));
+        let mut p = Pool::with_backend(Box::new(Holey { inner: HeapBackend::new(0x10000), holes: holes.clone() }));
+        p.set_punch_holes(true);
+
+        let a = p.malloc(&[1; 100]).unwrap();
+        let b = p.malloc(&[2; 100]).unwrap();
+        let c = p.malloc(&[3; 3 * PAGE_SIZE]).unwrap();
+        let d = p.malloc(&[4; 100]).unwrap();
+        // Too small to span a whole page past its header
+        drop(b);
+        assert!(holes.lock().unwrap().is_empty());
+
+        // Merged with b, and still fenced in by d
+        drop(c);
+        let (offset, len) = holes.lock().unwrap()[0];
+        assert_eq!(1, holes.lock().unwrap().len());
+        assert_eq!((0, 0), (offset % PAGE_SIZE, len % PAGE_SIZE));
+        assert!(len >= 2 * PAGE_SIZE && offset + len <= p.iter_blocks().find(|b| !b.is_free && b.offset > offset).unwrap().offset);
+        assert_eq!(len, p.usage().reclaimed);
+        assert_eq!(&[4; 100][..], &d[..]);
+        drop(a);
+    }
+
+    #[test]
+    fn test_flush_coalescing() {
+        use std::sync::{Arc, Mutex};
+
+        struct Recording {
+            inner: HeapBackend,
+            extents: Arc<Mutex<Vec<(usize, usize)>>>,
+        }
+        impl StorageBackend for Recording {
+            fn as_mut_ptr(&mut self) -> *mut u8 { self.inner.as_mut_ptr() }
+            fn len(&self) -> usize { self.inner.len() }
+            fn flush(&self, offset: usize, len: usize) -> Result<(), LodestoneError> {
+                self.extents.lock().unwrap().push((offset, len));
+                Ok(())
+            }
+        }
+        let extents = Arc::new(Mutex::new(Vec::new()));
+        let mut p = Pool::with_backend(Box::new(Recording {
+            inner: HeapBackend::new(0x4000),
+            extents: extents.clone(),
+        }));
+        // A new pool is dirty all over
+        p.flush().unwrap();
+        assert_eq!(vec![(0, 0x4000)], *extents.lock().unwrap());
+        extents.lock().unwrap().clear();
+
+        // Two neighbouring blocks go out together, in offset order
+        p.set_max_flush_extent(0x800);
+        let a = p.malloc(&[1; 8]).unwrap();
+        let b = p.malloc(&[2; 8]).unwrap();
+        p.flush().unwrap();
+        assert_eq!(vec![(0, 2 * (*OVERHEAD + 8) + *HEADER_SIZE), (0x3000, 0x800), (0x3800, 0x800)],
+            *extents.lock().unwrap());
+        extents.lock().unwrap().clear();
+
+        // Nothing changed, only the metadata goes out
+        p.flush().unwrap();
+        assert_eq!(vec![(0x3000, 0x800), (0x3800, 0x800)], *extents.lock().unwrap());
+        extents.lock().unwrap().clear();
+
+        // Persisting a reference changes b's count
+        let mut persisted = b.clone_to_persisted();
+        p.flush().unwrap();
+        assert_eq!((p._inner_offset(&b), *ARC_INNER_SIZE), extents.lock().unwrap()[0]);
+        persisted.release(&p).unwrap();
+        drop(a);
+        drop(b);
+
+        let stats = p.flush_stats();
+        assert_eq!(4, stats.flushes);
+        assert_eq!(stats.calls, 1 + 3 + 2 + 3);
+    }
+
+    #[test]
+    fn test_scratch() {
+        let mut buf = vec![0u8; 0x8000];
+        let mut p = Pool::new(&mut buf);
+        assert!(p.scratch().is_none());
+        assert!(p.reserve_scratch(PAGE_SIZE).is_err());
+        p.reserve_scratch(0x3000).unwrap();
+        assert!(p.reserve_scratch(0x3000).is_err());
+        assert_eq!(0x3000 + *OVERHEAD, p.usage().allocated);
+
+        let kept = Handle::new(&p.malloc(b"kept").unwrap());
+        {
+            let scratch = p.scratch().unwrap();
+            // Temporaries can fill the region without touching the pool
+            let mut temporaries = Vec::new();
+            while let Ok(t) = scratch.malloc(&[7; 512]) {
+                temporaries.push(t);
+            }
+            assert!(temporaries.len() > 2);
+            mem::forget(temporaries);
+            assert!(scratch.malloc(&[7; 512]).is_err());
+        }
+        assert_eq!(0x3000 + 8 + 2 * *OVERHEAD, p.usage().allocated);
+
+        // Leaked temporaries are gone after a reset
+        p.reset_scratch();
+        assert!(p.scratch().unwrap().iter_blocks().all(|b| b.is_free));
+        assert_eq!(b"kept", &kept.get(&p).unwrap()[..]);
+    }
+
+    #[test]
+    fn test_sweep_unreachable() {
+        let mut buf = vec![0u8; 0x4000];
+        let p = Pool::new(&mut buf);
+        let trace = |bytes: &[u8]| Reference::from_bytes(bytes).into_iter().collect();
+
+        // root -> child, plus a block whose handle was leaked
+        let child = p.malloc(b"child").unwrap();
+        let root = p.malloc(&p.make_reference(&child).to_bytes()).unwrap();
+        mem::forget(p.malloc(&[9; 100]).unwrap());
+        let root_reference = Reference::from_persisted(&root.clone_to_persisted());
+        mem::forget(root);
+
+        // Listed with its bytes first, and left alone
+        let unreachable = p.unreachable_blocks(&[root_reference], &trace);
+        assert_eq!(1, unreachable.len());
+        assert_eq!(vec![9; 100], unreachable[0].bytes);
+        assert_eq!(3, p.lifetime_stats().live_blocks);
+
+        let report = p.sweep_unreachable(&[root_reference], &trace);
+        assert_eq!(SweepReport { reachable_blocks: 2, freed_blocks: 1, freed_bytes: 104 + *OVERHEAD }, report);
+        assert_eq!(b"child", &child[..]);
+
+        // Bogus references keep nothing alive and break nothing
+        let bogus = Reference::from_bytes(&[1; 16]).unwrap();
+        let report = p.sweep_unreachable(&[bogus], &trace);
+        assert_eq!(0, report.reachable_blocks);
+        assert_eq!(2, report.freed_blocks);
+        assert!(p.iter_blocks().all(|b| b.is_free));
+        mem::forget(child);
+    }
+
+    #[test]
+    fn test_sweep_cancelled() {
+        let mut buf = vec![0u8; 0x4000];
+        let p = Pool::new(&mut buf);
+        let root = p.malloc(b"root").unwrap();
+        let root_reference = p.make_reference(&root);
+        for _ in 0..3 {
+            mem::forget(p.malloc(&[9; 100]).unwrap());
+        }
+
+        // Cancelled while tracing, so nothing is freed
+        let progress = ProgressHandle::new();
+        let report = p.sweep_unreachable_with_progress(&[root_reference], |_| {
+            progress.cancel();
+            Vec::new()
+        }, &progress);
+        assert_eq!(0, report.freed_blocks);
+        assert_eq!(CompactionPhase::Cancelled, progress.progress().phase);
+        assert_eq!(4, p.lifetime_stats().live_blocks);
+
+        let progress = ProgressHandle::new();
+        p.sweep_unreachable_with_progress(&[root_reference], |_| Vec::new(), &progress);
+        assert_eq!(CompactionProgress { blocks_moved: 0, bytes_reclaimed: 3 * (104 + *OVERHEAD), phase: CompactionPhase::Done },
+            progress.progress());
+        assert_eq!(b"root", &root[..]);
+    }
+
+    #[test]
+    #[cfg(not(feature = "thread-safe"))]
+    fn test_single_threaded_ref_counts() {
+        let mut buf = vec![0u8; 0x4000];
+        let mut p = Pool::new_single_threaded(&mut buf);
+        assert_eq!(RefCounting::Plain, p.ref_counting());
+        let arc = p.malloc(b"plain").unwrap();
+        let other = arc.clone();
+        assert_eq!(2, arc.get_ref_count());
+        let mut persisted = other.clone_to_persisted();
+        drop(other);
+        assert_eq!(2, arc.get_ref_count());
+        persisted.release(&p).unwrap();
+        drop(arc);
+        assert!(p.iter_blocks().all(|b| b.is_free));
+
+        p.reserve_scratch(2 * PAGE_SIZE).unwrap();
+        assert_eq!(RefCounting::Plain, p.scratch().unwrap().ref_counting());
+    }
+
+    #[test]
+    fn test_ref_count_errors() {
+        use std::sync::{Arc, Mutex};
+        let mut buf = vec![0u8; 0x4000];
+        let mut p = Pool::new(&mut buf);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let s = seen.clone();
+        p.on_ref_count_error(move |e| s.lock().unwrap().push(e));
+
+        // Released once too often, saturates and isn't freed twice
+        let a = p.malloc(b"a").unwrap();
+        let offset = p._inner_offset(&a);
+        p._release(a.inner());
+        assert_eq!(1, p._release(a.inner()));
+        assert_eq!(0, a.get_ref_count());
+        assert_eq!(vec![RefCountError::Underflow(offset)], *seen.lock().unwrap());
+
+        // Too many retains, pinned at the maximum
+        let b = p.malloc(b"b").unwrap();
+        b.inner().strong.store(MAX_REF_COUNT, SeqCst);
+        let b2 = b.clone();
+        assert_eq!(MAX_REF_COUNT, b.get_ref_count());
+        assert_eq!(RefCountStats { underflows: 1, overflows: 1, poisoned: 0 }, p.ref_count_stats());
+        mem::forget((a, b, b2));
+
+        // A poisoned block can't be resolved any more
+        p.set_ref_count_policy(RefCountPolicy::Poison);
+        let c = p.malloc(b"c").unwrap();
+        let persisted = c.clone_to_persisted();
+        p._release(c.inner());
+        p._release(c.inner());
+        p._release(c.inner());
+        assert!(p.clone_persisted_to_arc(&persisted).is_err());
+        assert_eq!(1, p.ref_count_stats().poisoned);
+        assert_eq!(3, seen.lock().unwrap().len());
+        mem::forget(persisted);
+        mem::forget(c);
+    }
+
+    #[test]
+    fn test_pins() {
+        let mut buf = vec![0u8; 0x4000];
+        let p = Pool::new(&mut buf);
+        let backup = p.malloc(b"root of backup").unwrap();
+        p.pin_root("backup-2024", &backup).unwrap();
+        assert!(p.pin_root("backup-2024", &backup).is_err());
+        assert!(p.pin_root("", &backup).is_err());
+        assert!(p.pin_root(&"x".repeat(PIN_NAME_SIZE + 1), &backup).is_err());
+        let reference = p.make_reference(&backup);
+        assert_eq!(Some(reference), p.pinned("backup-2024"));
+        p.take_reference(&reference).unwrap().release(&p).unwrap();
+        drop(backup);
+
+        // The pin alone keeps the block alive, and is a root for sweeps
+        assert_eq!(vec!["backup-2024".to_string()], p.pinned_names());
+        assert_eq!(b"root of backup", &p.resolve(&reference).unwrap()[..]);
+        let report = p.sweep_unreachable(&[], |_| Vec::new());
+        assert_eq!((1, 0), (report.reachable_blocks, report.freed_blocks));
+
+        p.unpin("backup-2024").unwrap();
+        assert!(p.unpin("backup-2024").is_err());
+        assert!(p.pinned_names().is_empty());
+        assert!(p.iter_blocks().all(|b| b.is_free));
+    }
+
+    #[test]
+    fn test_io_stats() {
+        let mut buf = vec![0u8; 0x4000];
+        let p = Pool::new(&mut buf);
+        let value = p.malloc(&[1; 100]).unwrap();
+        let reference = p.make_reference(&value);
+        p.resolve(&reference).unwrap();
+        p.resolve(&reference).unwrap();
+        p.mark_written(&value);
+        let stats = p.io_stats();
+        assert_eq!(IoRate { total: 200, last_minute: 200, last_hour: 200 }, stats.logical_read);
+        assert_eq!(200 + 2 * *OVERHEAD, stats.physical_read.total);
+        assert_eq!(200, stats.logical_written.total);
+        assert_eq!(200 + byte_align(100) - 100 + *OVERHEAD, stats.physical_written.last_minute);
+        assert_eq!(0, stats.flushed.total);
+        p.take_reference(&reference).unwrap().release(&p).unwrap();
+    }
+
+    #[test]
+    fn test_weak_byte_slices() {
+        let mut buf = vec![0u8; 0x4000];
+        let p = Pool::new(&mut buf);
+        let value = p.malloc(b"cached").unwrap();
+        let weak = value.downgrade();
+        assert_eq!(1, value.get_ref_count());
+        {
+            let upgraded = weak.upgrade(&p).unwrap();
+            assert_eq!(&b"cached"[..], &upgraded[..]);
+            assert_eq!(2, value.get_ref_count());
+        }
+        assert_eq!(1, value.get_ref_count());
+
+        // Weak handles round trip through references and persisted handles
+        let stored = WeakByteSlice::from_reference(&Reference::from_bytes(&weak.reference().to_bytes()).unwrap());
+        assert!(stored.upgrade(&p).is_some());
+        let mut persisted = value.clone_to_persisted();
+        assert_eq!(weak, persisted.downgrade());
+
+        // Gone with the last strong reference, and stays gone when the
+        // block is reused
+        drop(value);
+        assert!(weak.upgrade(&p).is_some());
+        persisted.release(&p).unwrap();
+        assert!(weak.upgrade(&p).is_none());
+        let reused = p.malloc(b"cached").unwrap();
+        assert_eq!(weak.reference().arc_inner_index(), reused.downgrade().reference().arc_inner_index());
+        assert!(weak.upgrade(&p).is_none());
+        assert_eq!(1, reused.get_ref_count());
+    }
+
+    #[test]
+    fn test_handles() {
+        let mut buf = vec![0u8; 0x4000];
+        let p = Pool::new(&mut buf);
+        let handle = Handle::new(&p.malloc(b"owned").unwrap());
+        assert_eq!(&b"owned"[..], &handle.get(&p).unwrap()[..]);
+        let copy = handle.try_clone(&p).unwrap();
+        assert_eq!(2, handle.get(&p).unwrap().get_ref_count() - 1);
+
+        // Through a block and back, carrying the count
+        let stored = copy.into_reference().to_bytes();
+        let copy = Handle::from_reference(&Reference::from_bytes(&stored).unwrap(), &p).unwrap();
+        assert!(!copy.release(&p).unwrap());
+        let weak = handle.downgrade();
+        assert!(handle.release(&p).unwrap());
+        assert!(weak.upgrade(&p).is_none());
+
+        // Stale and forged handles are caught
+        let stale = Handle::from_reference(&Reference::from_bytes(&stored).unwrap(), &p);
+        assert!(stale.is_err());
+        assert!(Handle::from_reference(&Reference::new(3, 1), &p).is_err());
+        assert!(Handle::from_reference(&Reference::new(1 << 40, 1), &p).is_err());
+        assert!(Handle::from_reference(&Reference::new(usize::max_value() - 7, 1), &p).is_err());
+    }
+
+    #[test]
+    fn test_malloc_parts() {
+        let mut buf = vec![0u8; 0x4000];
+        let p = Pool::new(&mut buf);
+        let blocks: Vec<ArcByteSlice> = (0..12).map(|i| p.malloc(&[i as u8; 600]).unwrap()).collect();
+        let kept: Vec<ArcByteSlice> = blocks.into_iter().enumerate().filter(|&(i, _)| i % 2 == 0).map(|(_, b)| b).collect();
+        let room = p.largest_free_block();
+        let data: Vec<u8> = (0..room + 700).map(|i| i as u8).collect();
+        assert!(p.malloc(&data).is_err());
+
+        let parts = p.malloc_parts(&data).unwrap();
+        assert!(parts.len() > 1);
+        assert_eq!(data, parts.iter().flat_map(|part| part.iter().cloned()).collect::<Vec<u8>>());
+        assert_eq!(1, p.lifetime_stats().split_allocations);
+        assert_eq!(1, p.malloc_parts(b"fits").unwrap().len());
+
+        // Nothing is left behind when it can't fit at all
+        drop(parts);
+        let used = p.lifetime_stats().live_blocks;
+        assert!(p.malloc_parts(&vec![0; 0x4000]).is_err());
+        assert_eq!(used, p.lifetime_stats().live_blocks);
+        assert_eq!(1, p.lifetime_stats().split_allocations);
+        drop(kept);
+    }
+
+    #[test]
+    fn test_block_checksums() {
+        let mut buf = vec![0u8; 0x4000];
+        let mut p = Pool::new(&mut buf);
+        let plain = p.make_reference(&p.malloc(b"unchecked").unwrap());
+        p.set_block_checksums(true);
+        let value = p.make_reference(&p.malloc(b"some value").unwrap());
+        let counter = p.make_reference(&p.malloc(&[0; 8]).unwrap());
+        let flip = |reference: &Reference| p.index_to_byte_slice_mut(ArcByteSliceStart(reference.arc_inner_index()))[1] ^= 0x10;
+
+        // Bit rot in a checksummed block is caught, not in an unchecked one
+        flip(&value);
+        match p.resolve(&value) {
+            Err(LodestoneError::Corruption(_)) => (),
+            other => panic!("Expected Corruption, got {:?}", other.map(|v| v.to_vec())),
+        }
+        flip(&plain);
+        assert_eq!(b"u~checked", &p.resolve(&plain).unwrap()[..]);
+
+        // Writing in place drops the checksum, reporting the write restores it
+        let arc = p.resolve(&counter).unwrap();
+        *arc.deref_as_mut::<u64>() = 7;
+        assert_eq!(7, *p.resolve(&counter).unwrap().deref_as::<u64>());
+        p.mark_written(&arc);
+        assert!(p.index_to_arc_inner(ArcByteSliceStart(counter.arc_inner_index())).checksum.load(SeqCst) != 0);
+        flip(&counter);
+        assert!(p.resolve(&counter).is_err());
+    }
+
+    #[test]
+    fn test_logical_references() {
+        let mut buf = vec![0u8; 0x10000];
+        let p = Pool::new(&mut buf);
+        let filler = p.malloc(&[1; 200]).unwrap();
+        let value = p.malloc(b"moves around").unwrap();
+        let logical = p.make_logical_reference(&value).unwrap();
+        let before = p.make_reference(&value);
+        assert!(logical.is_logical() && !before.is_logical());
+        p.take_reference(&before).unwrap().release(&p).unwrap();
+        drop(value);
+
+        // Only the table holds it now; moving it into the freed space
+        // leaves the logical reference good and the physical one stale
+        drop(filler);
+        p.relocate(&logical).unwrap();
+        assert_eq!(b"moves around", &p.resolve(&logical).unwrap()[..]);
+        assert!(p.resolve(&before).is_err());
+        assert!(p.relocate(&before).is_err());
+        let report = p.sweep_unreachable(&[], |_| Vec::new());
+        assert_eq!((2, 0), (report.reachable_blocks, report.freed_blocks));
+
+        // The table grows past its first size
+        let many: Vec<Reference> = (0..DEFAULT_BLOCK_TABLE_SLOTS + 1)
+            .map(|i| p.make_logical_reference(&p.malloc(&[i as u8]).unwrap()).unwrap())
+            .collect();
+        assert_eq!(b"moves around", &p.resolve(&logical).unwrap()[..]);
+        assert_eq!(&[3u8][..], &p.resolve(&many[3]).unwrap()[..]);
+
+        // Releasing the last logical reference frees the block
+        let mut persisted = p.take_reference(&logical).unwrap();
+        let mut copy = persisted.clone(&p).unwrap();
+        assert!(!persisted.release(&p).unwrap());
+        assert!(copy.release(&p).unwrap());
+        assert!(p.resolve(&logical).is_err());
+        for reference in many {
+            p.take_reference(&reference).unwrap().release(&p).unwrap();
+        }
+        p.unpin(BLOCK_TABLE_PIN).unwrap();
+        assert!(p.iter_blocks().all(|b| b.is_free));
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut buf = vec![0u8; 0x10000];
+        let p = Pool::new(&mut buf);
+        let mut gaps = Vec::new();
+        let logical: Vec<Reference> = (0..8u8).map(|i| {
+            gaps.push(p.malloc(&[0; 300]).unwrap());
+            p.make_logical_reference(&p.malloc(&[i; 100]).unwrap()).unwrap()
+        }).collect();
+        let busy = p.resolve(&logical[6]).unwrap();
+        drop(gaps);
+
+        let progress = ProgressHandle::new();
+        let report = p.compact_with_progress(&progress).unwrap();
+        assert_eq!(1, report.busy_blocks);
+        assert!(report.moved_blocks > 0);
+        assert_eq!(CompactionPhase::Done, progress.progress().phase);
+        assert!(report.largest_free_after > report.largest_free_before);
+        assert_eq!(report.largest_free_after, p.largest_free_block());
+        for (i, reference) in logical.iter().enumerate() {
+            assert_eq!(&[i as u8; 100][..], &p.resolve(reference).unwrap()[..]);
+        }
+
+        // Once let go, the busy block moves too, and then nothing is left to do
+        drop(busy);
+        let report = p.compact().unwrap();
+        assert_eq!((1, 0), (report.moved_blocks, report.busy_blocks));
+        assert_eq!(1, p.iter_blocks().filter(|b| b.is_free).count());
+        let settled = p.compact().unwrap();
+        assert_eq!((0, 0), (settled.moved_blocks, settled.busy_blocks));
+        let cancelled = ProgressHandle::new();
+        cancelled.cancel();
+        assert_eq!(0, p.compact_with_progress(&cancelled).unwrap().moved_blocks);
+        assert_eq!(CompactionPhase::Cancelled, cancelled.progress().phase);
+    }
+
     #[test]
     fn test_large_alloc() {
+        use super::SnapshotBlock::*;
         let mut buf: [u8; 0x4000] = [0; 0x4000];
         let p = Pool::new(&mut buf[..]);
 
         // Take up > 1 page
         let arc_ts1 = p.malloc(&[42u8; 0x2000][..]).unwrap();
-
-        assert_eq!(
-            "Pool { buffer_size: 16384, \
-                metadata: Metadata { lowest_known_free_index: 8240, next_id_tag: AtomicUsize(3) }, \
-                blocks: [\
-                    _B { start: 0, capacity: 8192, next: 8240, prev: 18446744073709551615, is_free: false }, \
-                    _B { start: 8240, capacity: 4000, next: 12288, prev: 0, is_free: true }\
-                ] }",
-            format!("{:?}", p)
-        );
+        assert_eq!(vec![Live { id: 0, size: 0x2000, ref_count: 1 }, Free], p.snapshot().blocks);
+        assert_eq!(0x2000, arc_ts1.len());
     }
 }
@@ -0,0 +1,147 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::atomic::Ordering::SeqCst;
+
+/// Progress of a long running compaction or GC (sweep_unreachable,
+/// Pool::compact, ValueLog::gc, TieredPools::migrate_cold), readable from
+/// any thread while it runs, and a way to ask it to stop. Operations
+/// only stop between steps that leave everything consistent (a block
+/// freed or moved, a record rewritten, a reference migrated), so a
+/// cancelled run just leaves the rest of the work for the next one.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompactionPhase {
+    NotStarted,
+    /// Finding out what is still reachable
+    Tracing,
+    /// Copying live blocks somewhere else
+    Moving,
+    /// Freeing what nothing reaches
+    Freeing,
+    Done,
+    /// Stopped early by ProgressHandle::cancel
+    Cancelled,
+}
+
+impl CompactionPhase {
+    fn from_usize(n: usize) -> CompactionPhase {
+        match n {
+            1 => CompactionPhase::Tracing,
+            2 => CompactionPhase::Moving,
+            3 => CompactionPhase::Freeing,
+            4 => CompactionPhase::Done,
+            5 => CompactionPhase::Cancelled,
+            _ => CompactionPhase::NotStarted,
+        }
+    }
+
+    fn to_usize(self) -> usize {
+        match self {
+            CompactionPhase::NotStarted => 0,
+            CompactionPhase::Tracing => 1,
+            CompactionPhase::Moving => 2,
+            CompactionPhase::Freeing => 3,
+            CompactionPhase::Done => 4,
+            CompactionPhase::Cancelled => 5,
+        }
+    }
+}
+
+/// A reading of a ProgressHandle
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompactionProgress {
+    pub blocks_moved: usize,
+    pub bytes_reclaimed: usize,
+    pub phase: CompactionPhase,
+}
+
+struct Shared {
+    blocks_moved: AtomicUsize,
+    bytes_reclaimed: AtomicUsize,
+    phase: AtomicUsize,
+    cancelled: AtomicBool,
+}
+
+/// Clones share the same progress, so one can be handed to the operation
+/// and another kept to poll and cancel it. Use a new handle per run.
+#[derive(Clone)]
+pub struct ProgressHandle {
+    shared: Arc<Shared>,
+}
+
+impl ProgressHandle {
+    pub fn new() -> ProgressHandle {
+        ProgressHandle {
+            shared: Arc::new(Shared {
+                blocks_moved: AtomicUsize::new(0),
+                bytes_reclaimed: AtomicUsize::new(0),
+                phase: AtomicUsize::new(CompactionPhase::NotStarted.to_usize()),
+                cancelled: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    pub fn progress(&self) -> CompactionProgress {
+        CompactionProgress {
+            blocks_moved: self.shared.blocks_moved.load(SeqCst),
+            bytes_reclaimed: self.shared.bytes_reclaimed.load(SeqCst),
+            phase: CompactionPhase::from_usize(self.shared.phase.load(SeqCst)),
+        }
+    }
+
+    /// Ask the operation to stop at its next consistent point. It still
+    /// returns normally, with what it got done.
+    pub fn cancel(&self) {
+        self.shared.cancelled.store(true, SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.shared.cancelled.load(SeqCst)
+    }
+
+    /// For operations reporting in
+    pub fn set_phase(&self, phase: CompactionPhase) {
+        self.shared.phase.store(phase.to_usize(), SeqCst);
+    }
+
+    /// For operations reporting in, the totals so far
+    pub fn update(&self, blocks_moved: usize, bytes_reclaimed: usize) {
+        self.shared.blocks_moved.store(blocks_moved, SeqCst);
+        self.shared.bytes_reclaimed.store(bytes_reclaimed, SeqCst);
+    }
+
+    /// For operations at a consistent point: whether to stop there.
+    /// Stopping is recorded as the phase.
+    pub fn should_stop(&self) -> bool {
+        let stop = self.is_cancelled();
+        if stop {
+            self.set_phase(CompactionPhase::Cancelled);
+        }
+        stop
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_progress_across_threads() {
+        let handle = ProgressHandle::new();
+        assert_eq!(CompactionProgress { blocks_moved: 0, bytes_reclaimed: 0, phase: CompactionPhase::NotStarted },
+            handle.progress());
+        let worker = handle.clone();
+        thread::spawn(move || {
+            worker.set_phase(CompactionPhase::Moving);
+            worker.update(3, 120);
+            assert!(!worker.should_stop());
+        }).join().unwrap();
+        assert_eq!(CompactionProgress { blocks_moved: 3, bytes_reclaimed: 120, phase: CompactionPhase::Moving },
+            handle.progress());
+
+        handle.cancel();
+        assert!(handle.clone().should_stop());
+        assert_eq!(CompactionPhase::Cancelled, handle.progress().phase);
+    }
+}
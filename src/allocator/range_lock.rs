@@ -0,0 +1,124 @@
+use std::sync::{Condvar, Mutex};
+
+/// Advisory locks over half-open key ranges [start, end). Nothing in the
+/// pool or tree checks them: they exist so that cooperating writers can
+/// serialize work on overlapping ranges while disjoint ranges proceed
+/// in parallel.
+pub struct RangeLocks {
+    held: Mutex<Held>,
+    released: Condvar,
+}
+
+struct Held {
+    next_id: usize,
+    ranges: Vec<(usize, Vec<u8>, Vec<u8>)>,
+}
+
+impl Held {
+    fn conflicts(&self, start: &[u8], end: &[u8]) -> bool {
+        self.ranges.iter().any(|&(_, ref s, ref e)| overlaps(start, end, s, e))
+    }
+
+    fn insert(&mut self, start: &[u8], end: &[u8]) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.ranges.push((id, start.to_vec(), end.to_vec()));
+        id
+    }
+}
+
+/// Releases the range when dropped
+pub struct RangeLockGuard<'a> {
+    locks: &'a RangeLocks,
+    id: usize,
+}
+
+impl RangeLocks {
+    pub fn new() -> RangeLocks {
+        RangeLocks {
+            held: Mutex::new(Held { next_id: 0, ranges: Vec::new() }),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Block until no overlapping range is held, then take [start, end)
+    pub fn lock<'a>(&'a self, start: &[u8], end: &[u8]) -> RangeLockGuard<'a> {
+        let mut held = self.held.lock().unwrap();
+        while held.conflicts(start, end) {
+            held = self.released.wait(held).unwrap();
+        }
+        RangeLockGuard { locks: self, id: held.insert(start, end) }
+    }
+
+    /// Take [start, end) if nothing overlapping is held
+    pub fn try_lock<'a>(&'a self, start: &[u8], end: &[u8]) -> Option<RangeLockGuard<'a>> {
+        let mut held = self.held.lock().unwrap();
+        if held.conflicts(start, end) {
+            None
+        } else {
+            Some(RangeLockGuard { locks: self, id: held.insert(start, end) })
+        }
+    }
+
+    pub fn num_held(&self) -> usize {
+        self.held.lock().unwrap().ranges.len()
+    }
+}
+
+impl <'a> Drop for RangeLockGuard<'a> {
+    fn drop(&mut self) {
+        let mut held = self.locks.held.lock().unwrap();
+        let id = self.id;
+        held.ranges.retain(|&(i, _, _)| i != id);
+        self.locks.released.notify_all();
+    }
+}
+
+/// Empty ranges overlap nothing
+fn overlaps(start_a: &[u8], end_a: &[u8], start_b: &[u8], end_b: &[u8]) -> bool {
+    start_a < end_a && start_b < end_b && start_a < end_b && start_b < end_a
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn test_overlap() {
+        let locks = RangeLocks::new();
+        let _ab = locks.lock(b"a", b"c");
+        assert!(locks.try_lock(b"b", b"d").is_none());
+        // Ranges are half open, so these only touch
+        let cd = locks.try_lock(b"c", b"d");
+        assert!(cd.is_some());
+        assert!(locks.try_lock(b"", b"a").is_some());
+        assert_eq!(2, locks.num_held());
+        drop(cd);
+        assert!(locks.try_lock(b"b", b"d").is_none());
+        assert!(locks.try_lock(b"c", b"e").is_some());
+    }
+
+    #[test]
+    fn test_lock_waits_for_release() {
+        let locks = Arc::new(RangeLocks::new());
+        let step = Arc::new(AtomicUsize::new(0));
+        let guard = locks.lock(b"k0", b"k5");
+        let t = {
+            let locks = locks.clone();
+            let step = step.clone();
+            thread::spawn(move || {
+                let _g = locks.lock(b"k3", b"k9");
+                step.load(Ordering::SeqCst)
+            })
+        };
+        thread::sleep(Duration::from_millis(20));
+        step.store(1, Ordering::SeqCst);
+        drop(guard);
+        // The other thread only got in after we let go
+        assert_eq!(1, t.join().unwrap());
+    }
+}
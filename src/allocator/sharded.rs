@@ -0,0 +1,121 @@
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::Ordering::Relaxed;
+
+/// Counters for the read path, which every reader bumps. One atomic would
+/// have every core fighting over its cache line, so each value is split
+/// into SHARDS copies, each on its own line. A thread always adds to the
+/// same shard, picked when it first counts anything, so counting is a
+/// single uncontended RMW unless more threads than shards read at once.
+/// Reading a value sums the shards, which is only as exact as a sum of
+/// Relaxed loads taken while others add: good for stats, not for limits.
+
+pub const SHARDS: usize = 16;
+const CACHE_LINE: usize = 64;
+
+lazy_static! {
+    static ref NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+}
+
+thread_local! {
+    static SHARD: usize = NEXT_SHARD.fetch_add(1, Relaxed) % SHARDS;
+}
+
+/// The shard the current thread counts into
+pub fn shard() -> usize {
+    SHARD.with(|s| *s)
+}
+
+/// A value followed by enough padding that the next one can't share its
+/// last cache line
+struct Padded<T> {
+    value: T,
+    _pad: [u8; CACHE_LINE],
+}
+
+/// One T per shard
+pub struct Sharded<T> {
+    shards: Vec<Padded<T>>,
+}
+
+impl<T> Sharded<T> {
+    pub fn new<F>(make: F) -> Sharded<T> where F: Fn() -> T {
+        Sharded {
+            shards: (0..SHARDS).map(|_| Padded { value: make(), _pad: [0; CACHE_LINE] }).collect(),
+        }
+    }
+
+    /// The current thread's shard
+    pub fn local(&self) -> &T {
+        &self.shards[shard()].value
+    }
+
+    pub fn iter<'a>(&'a self) -> Box<Iterator<Item=&'a T> + 'a> {
+        Box::new(self.shards.iter().map(|p| &p.value))
+    }
+}
+
+/// A sharded AtomicUsize, with the subset of its interface that counting
+/// needs, so a stats field can be switched over without its readers
+/// noticing
+pub struct ShardedCounter {
+    shards: Sharded<AtomicUsize>,
+}
+
+impl ShardedCounter {
+    pub fn new(val: usize) -> ShardedCounter {
+        let counter = ShardedCounter { shards: Sharded::new(|| AtomicUsize::new(0)) };
+        counter.shards.local().store(val, Relaxed);
+        counter
+    }
+
+    /// Returns what the current thread's shard held, not the total
+    pub fn fetch_add(&self, val: usize, order: Ordering) -> usize {
+        self.shards.local().fetch_add(val, order)
+    }
+
+    pub fn load(&self, order: Ordering) -> usize {
+        self.shards.iter().fold(0, |sum, shard| sum.wrapping_add(shard.load(order)))
+    }
+}
+
+impl Default for ShardedCounter {
+    fn default() -> ShardedCounter {
+        ShardedCounter::new(0)
+    }
+}
+
+impl fmt::Debug for ShardedCounter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.load(Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_counts_from_many_threads() {
+        let counter = Arc::new(ShardedCounter::new(5));
+        let threads: Vec<_> = (0..2 * SHARDS).map(|_| {
+            let counter = counter.clone();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    counter.fetch_add(1, Relaxed);
+                }
+                shard()
+            })
+        }).collect();
+        let mut used: Vec<usize> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        assert_eq!(5 + 2 * SHARDS * 1000, counter.load(Relaxed));
+        // Threads spread over the shards, other tests' threads take
+        // shards too so which ones isn't known
+        used.sort();
+        used.dedup();
+        assert!(used.len() > 1);
+        assert_eq!(format!("{}", 5 + 2 * SHARDS * 1000), format!("{:?}", counter));
+    }
+}
@@ -0,0 +1,123 @@
+use codec::*;
+use super::pool::PAGE_SIZE;
+use {FormatMismatch, LodestoneError};
+
+/// What a pool image was written by, so a build that can't read it says
+/// why instead of misreading it. Pool::new writes it in the last bytes of
+/// the metadata page, where it stays whatever the metadata's layout, and
+/// Pool::open reads it before anything else. Everything is little endian
+/// except the endianness marker, written natively: a build of the other
+/// endianness reads it reversed. An image without the magic, from before
+/// superblocks or with the page clobbered, is refused.
+///
+/// Layout:
+///   magic: u64
+///   endianness marker: u32, native
+///   format version: u32
+///   page size: u32
+///   node capacity: u32, the B of the tree in the pool, 0 if none yet
+
+pub const SUPERBLOCK_SIZE: usize = 24;
+/// The layout the rest of the image is in. Bumped by changes older
+/// builds can't read.
+pub const FORMAT_VERSION: u32 = 1;
+const SUPERBLOCK_MAGIC: u64 = 0x4b4c_4253_5250_5553;
+const ENDIANNESS_MARKER: u32 = 0x0102_0304;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Superblock {
+    pub format_version: u32,
+    pub page_size: u32,
+    pub node_capacity: u32,
+}
+
+impl Superblock {
+    /// What this build writes
+    pub fn current(node_capacity: usize) -> Superblock {
+        Superblock {
+            format_version: FORMAT_VERSION,
+            page_size: PAGE_SIZE as u32,
+            node_capacity: node_capacity as u32,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; SUPERBLOCK_SIZE] {
+        let mut bytes = [0u8; SUPERBLOCK_SIZE];
+        write_u64_le(&mut bytes, 0, SUPERBLOCK_MAGIC);
+        bytes[8..12].copy_from_slice(&ENDIANNESS_MARKER.to_ne_bytes());
+        write_u32_le(&mut bytes, 12, self.format_version);
+        write_u32_le(&mut bytes, 16, self.page_size);
+        write_u32_le(&mut bytes, 20, self.node_capacity);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Superblock, LodestoneError> {
+        if bytes.len() != SUPERBLOCK_SIZE {
+            return Err(LodestoneError::Corruption("Superblock has the wrong length"));
+        }
+        if read_u64_le(bytes, 0) != SUPERBLOCK_MAGIC {
+            return Err(LodestoneError::InvalidReference("Pool image has no superblock"));
+        }
+        let (ours, theirs) = (read_u32_le(&ENDIANNESS_MARKER.to_ne_bytes(), 0), read_u32_le(bytes, 8));
+        if theirs != ours {
+            return Err(mismatch("endianness marker", ours, theirs));
+        }
+        Ok(Superblock {
+            format_version: read_u32_le(bytes, 12),
+            page_size: read_u32_le(bytes, 16),
+            node_capacity: read_u32_le(bytes, 20),
+        })
+    }
+
+    /// Whether this build can read the image the superblock came from
+    pub fn check(&self) -> Result<(), LodestoneError> {
+        if self.format_version != FORMAT_VERSION {
+            return Err(mismatch("format version", FORMAT_VERSION, self.format_version));
+        }
+        if self.page_size != PAGE_SIZE as u32 {
+            return Err(mismatch("page size", PAGE_SIZE as u32, self.page_size));
+        }
+        Ok(())
+    }
+}
+
+fn mismatch(field: &'static str, expected: u32, found: u32) -> LodestoneError {
+    LodestoneError::Format(FormatMismatch {
+        field: field,
+        expected: expected as u64,
+        found: found as u64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_superblock_round_trip() {
+        let superblock = Superblock::current(100);
+        let mut bytes = superblock.encode();
+        assert_eq!(superblock, Superblock::decode(&bytes).unwrap());
+        assert!(superblock.check().is_ok());
+        assert!(Superblock::decode(&[0; SUPERBLOCK_SIZE]).is_err());
+        assert!(Superblock::decode(&[1; SUPERBLOCK_SIZE]).is_err());
+        assert!(Superblock::decode(&bytes[1..]).is_err());
+
+        // Written by a build of the other endianness
+        bytes[8..12].reverse();
+        match Superblock::decode(&bytes) {
+            Err(LodestoneError::Format(m)) => {
+                assert_eq!("endianness marker", m.field);
+                assert_eq!(m.expected, (m.found as u32).swap_bytes() as u64);
+            },
+            other => panic!("Expected a format mismatch, got {:?}", other),
+        }
+
+        let newer = Superblock { format_version: FORMAT_VERSION + 1, ..superblock };
+        match newer.check() {
+            Err(LodestoneError::Format(m)) => assert_eq!("format version", m.field),
+            other => panic!("Expected a format mismatch, got {:?}", other),
+        }
+        assert!(Superblock { page_size: 8192, ..superblock }.check().is_err());
+    }
+}
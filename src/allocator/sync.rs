@@ -0,0 +1,378 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::Ordering::{Acquire, AcqRel, Relaxed};
+#[cfg(not(feature = "thread-safe"))]
+use std::cell::{RefCell, RefMut};
+#[cfg(feature = "thread-safe")]
+use std::sync::{Mutex, MutexGuard};
+
+/// All of the atomic operations the allocator relies on go through
+/// this trait, so that the concurrency tests can substitute loom's
+/// modeled atomics for the std ones.
+///
+/// Orderings, and why they're enough:
+///  * retain is Relaxed. A new reference is only ever made from an
+///    existing one, which already keeps the block alive.
+///  * retain_if_live is Acquire on success. It takes a count from a weak
+///    handle, which doesn't keep the block alive, so it must see the
+///    writes of whoever last made the block.
+///  * release is AcqRel. Release so our writes to the block happen before
+///    whoever frees it, Acquire so the one freeing sees everyone's writes.
+///  * next_tag is Relaxed, only uniqueness matters and any RMW gives that.
+///  * claim and switch_root publish a block, AcqRel on success so the
+///    winner's writes are visible to whoever reads the tag or root after.
+///  * ref_count and roll are hints and Relaxed.
+pub trait Counter {
+    fn load(&self, order: Ordering) -> usize;
+    fn store(&self, val: usize, order: Ordering);
+    fn fetch_add(&self, val: usize, order: Ordering) -> usize;
+    fn fetch_sub(&self, val: usize, order: Ordering) -> usize;
+    fn compare_exchange(&self, current: usize, new: usize, success: Ordering, failure: Ordering)
+        -> Result<usize, usize>;
+}
+
+impl Counter for AtomicUsize {
+    #[inline]
+    fn load(&self, order: Ordering) -> usize {
+        AtomicUsize::load(self, order)
+    }
+
+    #[inline]
+    fn store(&self, val: usize, order: Ordering) {
+        AtomicUsize::store(self, val, order)
+    }
+
+    #[inline]
+    fn fetch_add(&self, val: usize, order: Ordering) -> usize {
+        AtomicUsize::fetch_add(self, val, order)
+    }
+
+    #[inline]
+    fn fetch_sub(&self, val: usize, order: Ordering) -> usize {
+        AtomicUsize::fetch_sub(self, val, order)
+    }
+
+    #[inline]
+    fn compare_exchange(&self, current: usize, new: usize, success: Ordering, failure: Ordering)
+        -> Result<usize, usize> {
+        AtomicUsize::compare_exchange(self, current, new, success, failure)
+    }
+}
+
+#[cfg(feature = "loom")]
+impl Counter for ::loom::sync::atomic::AtomicUsize {
+    fn load(&self, order: Ordering) -> usize {
+        ::loom::sync::atomic::AtomicUsize::load(self, order)
+    }
+
+    fn store(&self, val: usize, order: Ordering) {
+        ::loom::sync::atomic::AtomicUsize::store(self, val, order)
+    }
+
+    fn fetch_add(&self, val: usize, order: Ordering) -> usize {
+        ::loom::sync::atomic::AtomicUsize::fetch_add(self, val, order)
+    }
+
+    fn fetch_sub(&self, val: usize, order: Ordering) -> usize {
+        ::loom::sync::atomic::AtomicUsize::fetch_sub(self, val, order)
+    }
+
+    fn compare_exchange(&self, current: usize, new: usize, success: Ordering, failure: Ordering)
+        -> Result<usize, usize> {
+        ::loom::sync::atomic::AtomicUsize::compare_exchange(self, current, new, success, failure)
+    }
+}
+
+/// How a pool keeps the ref counts of its blocks. Atomic is always safe.
+/// Plain is for embedders that never touch a pool's buffer from more
+/// than one thread at a time: counts are read and written back like a
+/// Cell, without the locked instructions an atomic RMW costs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RefCounting {
+    Atomic,
+    Plain,
+}
+
+impl RefCounting {
+    #[inline]
+    pub fn retain<C: Counter>(&self, strong: &C) {
+        match *self {
+            RefCounting::Atomic => retain(strong),
+            RefCounting::Plain => strong.store(strong.load(Relaxed) + 1, Relaxed),
+        }
+    }
+
+    /// Same as retain_if_live
+    #[inline]
+    pub fn retain_if_live<C: Counter>(&self, strong: &C) -> bool {
+        match *self {
+            RefCounting::Atomic => retain_if_live(strong),
+            RefCounting::Plain => match strong.load(Relaxed) {
+                0 | POISONED_REF_COUNT => false,
+                count => {
+                    strong.store(count + 1, Relaxed);
+                    true
+                },
+            },
+        }
+    }
+
+    /// Same as release
+    #[inline]
+    pub fn release<C: Counter>(&self, strong: &C) -> usize {
+        match *self {
+            RefCounting::Atomic => release(strong),
+            RefCounting::Plain => {
+                let remaining = strong.load(Relaxed) - 1;
+                strong.store(remaining, Relaxed);
+                remaining
+            },
+        }
+    }
+}
+
+/// Counts above this are taken to be a ref counting bug, like std's Arc
+pub const MAX_REF_COUNT: usize = ::std::isize::MAX as usize;
+/// The count of a block poisoned by RefCountPolicy::Poison
+pub const POISONED_REF_COUNT: usize = !0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RefCountError {
+    /// A block was released more often than it was retained. Holds the
+    /// offset of the block's ArcByteSliceInner.
+    Underflow(usize),
+    /// A block's count went past MAX_REF_COUNT, most likely by leaking
+    /// retains in a loop
+    Overflow(usize),
+}
+
+/// What to do about a ref count going wrong. Either way the error is
+/// counted and reported to the pool's handler first.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RefCountPolicy {
+    /// Pin the count at 0 or MAX_REF_COUNT and carry on. The block is
+    /// never freed twice, but may leak.
+    Saturate,
+    /// Make the block unreachable: it's never freed and resolving a
+    /// handle to it fails from then on
+    Poison,
+    /// Stop the process before anything else goes wrong
+    Abort,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RefCountStats {
+    pub underflows: usize,
+    pub overflows: usize,
+    pub poisoned: usize,
+}
+
+/// Take a strong reference
+#[inline]
+pub fn retain<C: Counter>(strong: &C) {
+    strong.fetch_add(1, Relaxed);
+}
+
+/// Take a strong reference unless the count already hit 0, which means
+/// the block is being freed, or the block was poisoned. Returns whether
+/// a reference was taken.
+pub fn retain_if_live<C: Counter>(strong: &C) -> bool {
+    let mut current = strong.load(Relaxed);
+    loop {
+        if current == 0 || current == POISONED_REF_COUNT {
+            return false;
+        }
+        match strong.compare_exchange(current, current + 1, Acquire, Relaxed) {
+            Ok(_) => return true,
+            Err(actual) => current = actual,
+        }
+    }
+}
+
+/// Give up a strong reference, returning the number of references
+/// that remain. When this hits 0 the caller owns the memory.
+#[inline]
+pub fn release<C: Counter>(strong: &C) -> usize {
+    strong.fetch_sub(1, AcqRel) - 1
+}
+
+/// Read the current strong count. Only useful as a hint.
+#[inline]
+pub fn ref_count<C: Counter>(strong: &C) -> usize {
+    strong.load(Relaxed)
+}
+
+/// Hand out the next unique id tag
+#[inline]
+pub fn next_tag<C: Counter>(next_id_tag: &C) -> usize {
+    next_id_tag.fetch_add(1, Relaxed)
+}
+
+/// Claim a free (tag 0) block by stamping it with a fresh id tag.
+/// Returns None if somebody else got there first.
+pub fn claim<T: Counter, N: Counter>(tag: &T, next_id_tag: &N) -> Option<usize> {
+    let new_tag = next_tag(next_id_tag);
+    match tag.compare_exchange(0, new_tag, AcqRel, Acquire) {
+        Ok(_) => Some(new_tag),
+        Err(_) => None,
+    }
+}
+
+/// Swing the root from `expected` to `new`. Fails if another writer
+/// already moved it.
+pub fn switch_root<C: Counter>(root: &C, expected: usize, new: usize) -> bool {
+    root.compare_exchange(expected, new, AcqRel, Acquire).is_ok()
+}
+
+/// Advance a xorshift generator kept in an atomic and return the new
+/// value. Racing threads may see the same roll, which is fine for the
+/// sampling this is used for. The state must not be 0.
+pub fn roll<C: Counter>(state: &C) -> usize {
+    let mut x = state.load(Relaxed);
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    state.store(x, Relaxed);
+    x
+}
+
+/// A usable xorshift state for any seed
+pub fn xorshift_seed(seed: usize) -> usize {
+    if seed == 0 { 0x2545F491 } else { seed }
+}
+
+/// Interior mutability for a pool's own bookkeeping: a RefCell, or a
+/// Mutex in thread safe builds (feature "thread-safe"), where pools are
+/// Sync and any number of threads may be allocating at once. Either way
+/// taking it twice on one thread is a bug, a RefCell panics and a Mutex
+/// deadlocks.
+pub struct Guarded<T> {
+    #[cfg(not(feature = "thread-safe"))]
+    inner: RefCell<T>,
+    #[cfg(feature = "thread-safe")]
+    inner: Mutex<T>,
+}
+
+#[cfg(not(feature = "thread-safe"))]
+pub type GuardedMut<'a, T> = RefMut<'a, T>;
+#[cfg(feature = "thread-safe")]
+pub type GuardedMut<'a, T> = MutexGuard<'a, T>;
+
+#[cfg(not(feature = "thread-safe"))]
+impl<T> Guarded<T> {
+    pub fn new(value: T) -> Guarded<T> {
+        Guarded { inner: RefCell::new(value) }
+    }
+
+    pub fn lock<'a>(&'a self) -> GuardedMut<'a, T> {
+        self.inner.borrow_mut()
+    }
+}
+
+#[cfg(feature = "thread-safe")]
+impl<T> Guarded<T> {
+    pub fn new(value: T) -> Guarded<T> {
+        Guarded { inner: Mutex::new(value) }
+    }
+
+    pub fn lock<'a>(&'a self) -> GuardedMut<'a, T> {
+        self.inner.lock().unwrap()
+    }
+}
+
+#[cfg(all(test, feature = "loom"))]
+mod tests {
+    use loom;
+    use loom::sync::Arc;
+    use loom::sync::atomic::AtomicUsize;
+    use loom::thread;
+    use std::sync::atomic::Ordering::SeqCst;
+    use super::*;
+
+    #[test]
+    fn test_retain_release_frees_once() {
+        loom::model(|| {
+            let strong = Arc::new(AtomicUsize::new(1));
+            let other = strong.clone();
+            retain(&*strong);
+
+            let t = thread::spawn(move || release(&*other) == 0);
+            let mine = release(&*strong) == 0;
+            let theirs = t.join().unwrap();
+
+            // Exactly one side gets to free the memory
+            assert!(mine ^ theirs);
+            assert_eq!(0, strong.load(SeqCst));
+        });
+    }
+
+    #[test]
+    fn test_upgrade_races_last_release() {
+        loom::model(|| {
+            let strong = Arc::new(AtomicUsize::new(1));
+            let other = strong.clone();
+
+            let t = thread::spawn(move || retain_if_live(&*other));
+            let freed = release(&*strong) == 0;
+            let upgraded = t.join().unwrap();
+
+            // Either the upgrade came first and keeps the block, or the
+            // block is freed and the upgrade fails
+            assert!(freed ^ upgraded);
+            assert_eq!(if upgraded { 1 } else { 0 }, strong.load(SeqCst));
+        });
+    }
+
+    #[test]
+    fn test_concurrent_retains_are_not_lost() {
+        loom::model(|| {
+            let strong = Arc::new(AtomicUsize::new(1));
+            let threads: Vec<_> = (0..2).map(|_| {
+                let s = strong.clone();
+                thread::spawn(move || retain(&*s))
+            }).collect();
+            for t in threads {
+                t.join().unwrap();
+            }
+            assert_eq!(3, ref_count(&*strong));
+        });
+    }
+
+    #[test]
+    fn test_root_switch_single_winner() {
+        loom::model(|| {
+            let root = Arc::new(AtomicUsize::new(10));
+            let other = root.clone();
+
+            let t = thread::spawn(move || switch_root(&*other, 10, 20));
+            let mine = switch_root(&*root, 10, 30);
+            let theirs = t.join().unwrap();
+
+            assert!(mine ^ theirs);
+            let current = root.load(SeqCst);
+            if mine {
+                assert_eq!(30, current);
+            } else {
+                assert_eq!(20, current);
+            }
+        });
+    }
+
+    #[test]
+    fn test_free_block_claimed_once() {
+        loom::model(|| {
+            let tag = Arc::new(AtomicUsize::new(0));
+            let next_id = Arc::new(AtomicUsize::new(1));
+            let (t2, n2) = (tag.clone(), next_id.clone());
+
+            let t = thread::spawn(move || claim(&*t2, &*n2));
+            let mine = claim(&*tag, &*next_id);
+            let theirs = t.join().unwrap();
+
+            assert!(mine.is_some() ^ theirs.is_some());
+            let winner = mine.or(theirs).unwrap();
+            assert_eq!(winner, tag.load(SeqCst));
+            // Both claimants consumed a unique tag
+            assert_eq!(3, next_id.load(SeqCst));
+        });
+    }
+}
@@ -0,0 +1,195 @@
+use super::arc::{ArcByteSlice, Reference};
+use super::pool::Pool;
+use super::progress::{CompactionPhase, ProgressHandle};
+use LodestoneError;
+
+/// An active pool taking every new write, in front of an archive pool
+/// holding immutable historical blocks (e.g. on cheaper storage or in a
+/// compressed archive file). Nothing is allocated in the archive except
+/// by migrating cold blocks into it.
+///
+/// References made here say which tier they point into in the low bit of
+/// the index. Blocks are word aligned so the bit is otherwise always 0,
+/// and a plain pool refuses a tiered archive reference rather than
+/// reading the wrong block.
+
+const ARCHIVE_TIER_BIT: usize = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tier {
+    Active,
+    Archive,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MigrationReport {
+    pub migrated: usize,
+    pub bytes: usize,
+    /// Already in the archive, or not cold
+    pub skipped: usize,
+}
+
+pub struct TieredPools<'buf> {
+    active: Pool<'buf>,
+    archive: Pool<'buf>,
+}
+
+impl<'buf> TieredPools<'buf> {
+    pub fn new(active: Pool<'buf>, archive: Pool<'buf>) -> TieredPools<'buf> {
+        TieredPools {
+            active: active,
+            archive: archive,
+        }
+    }
+
+    pub fn active(&self) -> &Pool<'buf> {
+        &self.active
+    }
+
+    pub fn archive(&self) -> &Pool<'buf> {
+        &self.archive
+    }
+
+    pub fn tier_of(reference: &Reference) -> Tier {
+        if reference.arc_inner_index() & ARCHIVE_TIER_BIT == 0 {
+            Tier::Active
+        } else {
+            Tier::Archive
+        }
+    }
+
+    pub fn malloc<'a>(&'a self, data: &[u8]) -> Result<ArcByteSlice<'a>, LodestoneError> {
+        self.active.malloc(data)
+    }
+
+    /// Like Pool::make_reference, for an arc from either tier
+    pub fn make_reference(&self, arc: &ArcByteSlice) -> Result<Reference, LodestoneError> {
+        if arc._belongs_to(&self.active) {
+            Ok(self.active.make_reference(arc))
+        } else if arc._belongs_to(&self.archive) {
+            let reference = self.archive.make_reference(arc);
+            Ok(reference._with_arc_inner_index(reference.arc_inner_index() | ARCHIVE_TIER_BIT))
+        } else {
+            Err(LodestoneError::InvalidReference("Arc belongs to neither tier"))
+        }
+    }
+
+    /// Follow a reference into whichever tier it points at
+    pub fn resolve<'a>(&'a self, reference: &Reference) -> Result<ArcByteSlice<'a>, LodestoneError> {
+        let (pool, untagged) = self.untag(reference);
+        pool.resolve(&untagged)
+    }
+
+    /// Give up a reference's hold on its block
+    pub fn release(&self, reference: &Reference) -> Result<(), LodestoneError> {
+        let (pool, untagged) = self.untag(reference);
+        let mut persisted = try!(pool.take_reference(&untagged));
+        try!(persisted.release(pool));
+        Ok(())
+    }
+
+    /// Move the block behind an active reference into the archive and
+    /// hand back a reference to it there. The old reference is released,
+    /// so whatever stored it must store the new one instead.
+    pub fn migrate(&self, reference: &Reference) -> Result<Reference, LodestoneError> {
+        if TieredPools::tier_of(reference) == Tier::Archive {
+            return Ok(*reference);
+        }
+        let moved = {
+            let arc = try!(self.active.resolve(reference));
+            try!(self.active.copy_block(&arc, &self.archive))
+        };
+        let archived = try!(self.make_reference(&moved));
+        try!(self.release(reference));
+        Ok(archived)
+    }
+
+    /// The compaction job: migrate every reference is_cold picks out.
+    /// references is updated in place, for the caller to write back.
+    pub fn migrate_cold<F>(&self, references: &mut [Reference], is_cold: F)
+        -> Result<MigrationReport, LodestoneError>
+        where F: FnMut(&Reference, &[u8]) -> bool {
+        self.migrate_cold_with_progress(references, is_cold, &ProgressHandle::new())
+    }
+
+    /// migrate_cold, reporting to progress as it goes. Cancelled, it
+    /// stops between references, and the ones it didn't get to are left
+    /// as they were, still pointing into the active pool.
+    pub fn migrate_cold_with_progress<F>(&self, references: &mut [Reference], mut is_cold: F,
+                                         progress: &ProgressHandle) -> Result<MigrationReport, LodestoneError>
+        where F: FnMut(&Reference, &[u8]) -> bool {
+        let mut report = MigrationReport::default();
+        progress.set_phase(CompactionPhase::Moving);
+        for reference in references.iter_mut() {
+            if progress.should_stop() {
+                return Ok(report);
+            }
+            let size = {
+                let arc = try!(self.resolve(reference));
+                if TieredPools::tier_of(reference) == Tier::Archive || !is_cold(reference, &arc[..]) {
+                    report.skipped += 1;
+                    continue;
+                }
+                arc.len()
+            };
+            *reference = try!(self.migrate(reference));
+            report.migrated += 1;
+            report.bytes += size;
+            progress.update(report.migrated, report.bytes);
+        }
+        progress.set_phase(CompactionPhase::Done);
+        Ok(report)
+    }
+
+    fn untag(&self, reference: &Reference) -> (&Pool<'buf>, Reference) {
+        match TieredPools::tier_of(reference) {
+            Tier::Active => (&self.active, *reference),
+            Tier::Archive => (&self.archive,
+                reference._with_arc_inner_index(reference.arc_inner_index() & !ARCHIVE_TIER_BIT)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use allocator::Pool;
+
+    #[test]
+    fn test_tiers() {
+        let mut active_buf = vec![0u8; 0x4000];
+        let mut archive_buf = vec![0u8; 0x4000];
+        let tiers = TieredPools::new(Pool::new(&mut active_buf[..]), Pool::new(&mut archive_buf[..]));
+
+        let mut references = Vec::new();
+        for i in 0..4u8 {
+            let arc = tiers.malloc(&[i; 10]).unwrap();
+            references.push(tiers.make_reference(&arc).unwrap());
+        }
+        assert!(references.iter().all(|r| TieredPools::tier_of(r) == Tier::Active));
+        let active_blocks = tiers.active().lifetime_stats().live_blocks;
+
+        // Even blocks are cold
+        let report = tiers.migrate_cold(&mut references, |_, bytes| bytes[0] % 2 == 0).unwrap();
+        assert_eq!(MigrationReport { migrated: 2, bytes: 20, skipped: 2 }, report);
+        assert_eq!(active_blocks - 2, tiers.active().lifetime_stats().live_blocks);
+        let tiers_now: Vec<Tier> = references.iter().map(|r| TieredPools::tier_of(r)).collect();
+        assert_eq!(vec![Tier::Archive, Tier::Active, Tier::Archive, Tier::Active], tiers_now);
+
+        // Reads don't care which tier a block is in
+        for (i, reference) in references.iter().enumerate() {
+            assert_eq!(&[i as u8; 10], &tiers.resolve(reference).unwrap()[..]);
+        }
+        // A plain pool won't follow a tiered archive reference
+        assert!(tiers.archive().resolve(&references[0]).is_err());
+        // Migrating again is a no-op
+        assert_eq!(references[0], tiers.migrate(&references[0]).unwrap());
+
+        tiers.release(&references[0]).unwrap();
+        assert!(tiers.resolve(&references[0]).is_err());
+
+        let mut other_buf = vec![0u8; 0x4000];
+        let other = Pool::new(&mut other_buf[..]);
+        assert!(tiers.make_reference(&other.malloc(&[1]).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,392 @@
+use std::{cmp, fmt, slice};
+
+use codec::*;
+use super::progress::{CompactionPhase, ProgressHandle};
+use LodestoneError;
+
+/// Circular, append-only log for values that are too big to copy on
+/// every write (WiscKey-style value separation). The tree stores a
+/// ValuePointer in place of the value, so copying a path only copies
+/// 16 bytes. Space is reclaimed from the tail by gc, which rewrites
+/// values that are still live at the head.
+///
+/// Layout: head, tail and used words, followed by records of
+/// [len: u64][value, padded to 8 bytes]. A record that doesn't fit
+/// before the end of the buffer starts over at the front, leaving a
+/// WRAP marker behind if there is room for one.
+
+const HEAD: usize = 0;
+const TAIL: usize = 8;
+const USED: usize = 16;
+const DATA_START: usize = 24;
+const WRAP: usize = !0;
+
+pub const VALUE_POINTER_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ValuePointer {
+    pub offset: usize,
+    pub len: usize,
+}
+
+impl ValuePointer {
+    /// Little endian offset followed by length
+    pub fn to_bytes(&self) -> [u8; VALUE_POINTER_SIZE] {
+        let mut bytes = [0u8; VALUE_POINTER_SIZE];
+        write_word_le(&mut bytes, 0, self.offset);
+        write_word_le(&mut bytes, WORD, self.len);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<ValuePointer, LodestoneError> {
+        if bytes.len() != VALUE_POINTER_SIZE {
+            return Err(LodestoneError::InvalidReference("Encoded value pointer has the wrong length"));
+        }
+        Ok(ValuePointer {
+            offset: read_word_le(bytes, 0),
+            len: read_word_le(bytes, WORD),
+        })
+    }
+}
+
+pub struct ValueLog {
+    buffer: *mut u8,
+    buffer_size: usize,
+}
+
+impl fmt::Debug for ValueLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ValueLog")
+            .field("buffer_size", &self.buffer_size)
+            .field("head", &self.read_word(HEAD))
+            .field("tail", &self.read_word(TAIL))
+            .field("used", &self.used())
+            .finish()
+    }
+}
+
+/// Public interface
+impl ValueLog {
+    /// Start an empty log in the given buffer
+    pub fn new(buf: &mut [u8]) -> ValueLog {
+        assert!(buf.len() > DATA_START, "Buffer too small for a value log");
+        let log = ValueLog {
+            buffer: buf.as_mut_ptr(),
+            buffer_size: buf.len(),
+        };
+        log.write_word(HEAD, DATA_START);
+        log.write_word(TAIL, DATA_START);
+        log.write_word(USED, 0);
+        log
+    }
+
+    /// Reattach to a log previously created in this buffer
+    pub fn open(buf: &mut [u8]) -> Result<ValueLog, LodestoneError> {
+        if buf.len() <= DATA_START {
+            return Err(LodestoneError::StructureCorrupt("Buffer too small for a value log"));
+        }
+        let log = ValueLog {
+            buffer: buf.as_mut_ptr(),
+            buffer_size: buf.len(),
+        };
+        let (head, tail, used) = (log.read_word(HEAD), log.read_word(TAIL), log.read_word(USED));
+        let in_bounds = |i: usize| i >= DATA_START && i <= log.buffer_size && i % WORD == 0;
+        if !in_bounds(head) || !in_bounds(tail) || used > log.capacity() {
+            return Err(LodestoneError::StructureCorrupt("Value log header is out of bounds"));
+        }
+        Ok(log)
+    }
+
+    /// Bytes available for records
+    pub fn capacity(&self) -> usize {
+        self.buffer_size - DATA_START
+    }
+
+    /// Bytes held by records (live or not) and wrap gaps
+    pub fn used(&self) -> usize {
+        self.read_word(USED)
+    }
+
+    pub fn append(&mut self, value: &[u8]) -> Result<ValuePointer, LodestoneError> {
+        let record_size = WORD + round_up(value.len());
+        let (head, tail, used) = (self.read_word(HEAD), self.read_word(TAIL), self.used());
+        let (at, limit, wraps) = if used == 0 {
+            // Nothing to preserve, so start again from the front
+            (DATA_START, self.buffer_size, false)
+        } else if head <= tail {
+            // Already wrapped, free space is everything up to the tail
+            (head, tail, false)
+        } else if head + record_size <= self.buffer_size {
+            (head, self.buffer_size, false)
+        } else {
+            (DATA_START, tail, true)
+        };
+        if at + record_size > limit {
+            return Err(LodestoneError::OutOfMemory("Value log is full"));
+        }
+        let mut added = record_size;
+        if used == 0 {
+            self.write_word(TAIL, DATA_START);
+        }
+        if wraps {
+            if head + WORD <= self.buffer_size {
+                self.write_word(head, WRAP);
+            }
+            // The gap at the end counts as used until the tail passes it
+            added += self.buffer_size - head;
+        }
+        self.write_word(at, value.len());
+        self.bytes_mut(at + WORD, value.len()).copy_from_slice(value);
+        self.write_word(HEAD, at + record_size);
+        self.write_word(USED, used + added);
+        Ok(ValuePointer {
+            offset: at,
+            len: value.len(),
+        })
+    }
+
+    pub fn get(&self, pointer: &ValuePointer) -> Result<&[u8], LodestoneError> {
+        try!(self.check_live(pointer.offset));
+        if pointer.len > self.buffer_size - pointer.offset - WORD
+            || self.read_word(pointer.offset) != pointer.len {
+            return Err(LodestoneError::InvalidReference("Value pointer doesn't match the record it points to"));
+        }
+        Ok(self.bytes(pointer.offset + WORD, pointer.len))
+    }
+
+    /// len bytes of the value starting at offset, without touching the rest
+    /// of it. The range is cut short at the end of the value.
+    pub fn get_range(&self, pointer: &ValuePointer, offset: usize, len: usize) -> Result<&[u8], LodestoneError> {
+        let value = try!(self.get(pointer));
+        if offset > value.len() {
+            return Err(LodestoneError::UserError("Range starts past the end of the value"));
+        }
+        let end = offset + cmp::min(len, value.len() - offset);
+        Ok(&value[offset..end])
+    }
+
+    /// Reclaim at least max_bytes from the tail of the log (or everything,
+    /// if there's less than that). Records for which is_live returns true
+    /// are rewritten at the head and reported through relocated, so the
+    /// caller can repoint whatever referred to them. Returns the net number
+    /// of bytes freed.
+    pub fn gc<F, G>(&mut self, max_bytes: usize, is_live: F, relocated: G) -> Result<usize, LodestoneError>
+        where F: Fn(&ValuePointer) -> bool, G: FnMut(ValuePointer, ValuePointer) {
+        self.gc_with_progress(max_bytes, is_live, relocated, &ProgressHandle::new())
+    }
+
+    /// gc, reporting to progress as it goes. Cancelled, it stops between
+    /// records, each either rewritten and reported or left where it was.
+    pub fn gc_with_progress<F, G>(&mut self, max_bytes: usize, is_live: F, mut relocated: G,
+                                  progress: &ProgressHandle) -> Result<usize, LodestoneError>
+        where F: Fn(&ValuePointer) -> bool, G: FnMut(ValuePointer, ValuePointer) {
+        let start_used = self.used();
+        let mut examined = 0;
+        let mut moved = 0;
+        progress.set_phase(CompactionPhase::Moving);
+        while examined < max_bytes && self.used() > 0 {
+            if progress.should_stop() {
+                return Ok(start_used.saturating_sub(self.used()));
+            }
+            let tail = self.read_word(TAIL);
+            if tail + WORD > self.buffer_size || self.read_word(tail) == WRAP {
+                let gap = self.buffer_size - tail;
+                self.write_word(TAIL, DATA_START);
+                self.write_word(USED, self.used() - gap);
+                examined += gap;
+                continue;
+            }
+            let old = ValuePointer {
+                offset: tail,
+                len: self.read_word(tail),
+            };
+            let record_size = WORD + round_up(old.len);
+            if is_live(&old) {
+                // Copy before moving the tail, so a full log loses nothing
+                let value = self.bytes(tail + WORD, old.len).to_vec();
+                let new = try!(self.append(&value));
+                relocated(old, new);
+                moved += 1;
+            }
+            self.write_word(TAIL, tail + record_size);
+            self.write_word(USED, self.used() - record_size);
+            examined += record_size;
+            progress.update(moved, start_used.saturating_sub(self.used()));
+        }
+        progress.set_phase(CompactionPhase::Done);
+        Ok(start_used.saturating_sub(self.used()))
+    }
+}
+
+/// Private interface
+impl ValueLog {
+    fn check_live(&self, offset: usize) -> Result<(), LodestoneError> {
+        let (head, tail) = (self.read_word(HEAD), self.read_word(TAIL));
+        let live = if self.used() == 0 {
+            false
+        } else if tail < head {
+            offset >= tail && offset < head
+        } else {
+            offset >= tail || (offset >= DATA_START && offset < head)
+        };
+        if !live || offset % WORD != 0 || offset + WORD > self.buffer_size {
+            return Err(LodestoneError::InvalidReference("Value pointer is outside the live log"));
+        }
+        Ok(())
+    }
+
+    fn bytes(&self, at: usize, len: usize) -> &[u8] {
+        debug_assert!(at + len <= self.buffer_size);
+        unsafe { slice::from_raw_parts(self.buffer.offset(at as isize), len) }
+    }
+
+    fn bytes_mut(&mut self, at: usize, len: usize) -> &mut [u8] {
+        debug_assert!(at + len <= self.buffer_size);
+        unsafe { slice::from_raw_parts_mut(self.buffer.offset(at as isize), len) }
+    }
+
+    fn read_word(&self, at: usize) -> usize {
+        read_word_le(self.bytes(at, WORD), 0)
+    }
+
+    fn write_word(&self, at: usize, value: usize) {
+        debug_assert!(at + WORD <= self.buffer_size);
+        let bytes = unsafe { slice::from_raw_parts_mut(self.buffer.offset(at as isize), WORD) };
+        write_word_le(bytes, 0, value);
+    }
+}
+
+fn round_up(len: usize) -> usize {
+    (len + WORD - 1) / WORD * WORD
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use allocator::CompactionProgress;
+    use LodestoneError;
+
+    #[test]
+    fn test_append_and_get() {
+        let mut buf = [0u8; 256];
+        let mut log = ValueLog::new(&mut buf);
+        let a = log.append(b"hello").unwrap();
+        let b = log.append(&[7; 20]).unwrap();
+        assert_eq!(b"hello", log.get(&a).unwrap());
+        assert_eq!(&[7; 20][..], log.get(&b).unwrap());
+        assert_eq!(8 + 8 + 8 + 24, log.used());
+
+        assert_eq!(a, ValuePointer::from_bytes(&a.to_bytes()).unwrap());
+        assert!(ValuePointer::from_bytes(&[1, 2, 3]).is_err());
+
+        // Pointers that don't line up with a live record are rejected
+        let bogus = [
+            ValuePointer { offset: a.offset, len: 4 },
+            ValuePointer { offset: a.offset + 1, len: 5 },
+            ValuePointer { offset: 200, len: 5 },
+            ValuePointer { offset: b.offset, len: !0 - 4 },
+        ];
+        for p in bogus.iter() {
+            match log.get(p) {
+                Err(LodestoneError::InvalidReference(_)) => (),
+                other => panic!("Expected {:?} to be rejected, got {:?}", p, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_get_range() {
+        let mut buf = [0u8; 256];
+        let mut log = ValueLog::new(&mut buf);
+        let p = log.append(b"0123456789").unwrap();
+        assert_eq!(b"234", log.get_range(&p, 2, 3).unwrap());
+        assert_eq!(b"89", log.get_range(&p, 8, 100).unwrap());
+        assert_eq!(b"", log.get_range(&p, 10, 1).unwrap());
+        assert!(log.get_range(&p, 11, 1).is_err());
+        assert!(log.get_range(&ValuePointer { offset: p.offset, len: 3 }, 0, 1).is_err());
+    }
+
+    #[test]
+    fn test_full_log_and_reopen() {
+        let mut buf = [0u8; 24 + 64];
+        {
+            let mut log = ValueLog::new(&mut buf);
+            log.append(&[1; 24]).unwrap();
+            log.append(&[2; 24]).unwrap();
+            match log.append(&[3; 8]) {
+                Err(LodestoneError::OutOfMemory(_)) => (),
+                other => panic!("Expected the log to be full, got {:?}", other),
+            }
+        }
+        let log = ValueLog::open(&mut buf).unwrap();
+        assert_eq!(64, log.used());
+        assert_eq!(&[2; 24][..], log.get(&ValuePointer { offset: 56, len: 24 }).unwrap());
+    }
+
+    #[test]
+    fn test_gc_relocates_live_values_across_wrap() {
+        let mut buf = [0u8; 24 + 128];
+        let mut log = ValueLog::new(&mut buf);
+        // key -> pointer, standing in for the tree
+        let mut live = HashMap::new();
+        for i in 0..4u8 {
+            live.insert(i, log.append(&[i; 24]).unwrap());
+        }
+        assert!(log.append(&[9; 24]).is_err());
+        // Overwriting keys 0 and 1 leaves their old records as garbage
+        live.remove(&0);
+        live.remove(&1);
+
+        {
+            let pointers: Vec<ValuePointer> = live.values().cloned().collect();
+            let mut moves = Vec::new();
+            let freed = log.gc(64, |p| pointers.contains(p), |old, new| moves.push((old, new))).unwrap();
+            assert_eq!(64, freed);
+            assert!(moves.is_empty());
+        }
+        // New writes wrap around to the front
+        let p = log.append(&[4; 24]).unwrap();
+        assert_eq!(24, p.offset);
+        live.insert(4, p);
+
+        // Key 2 is still live, so gc has to move it out of the way
+        let pointers: Vec<ValuePointer> = live.values().cloned().collect();
+        let mut moves = Vec::new();
+        log.gc(32, |p| pointers.contains(p), |old, new| moves.push((old, new))).unwrap();
+        assert_eq!(1, moves.len());
+        assert_eq!(live[&2], moves[0].0);
+        live.insert(2, moves[0].1);
+        for (k, p) in live.iter() {
+            assert_eq!(&[*k; 24][..], log.get(p).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_gc_cancelled() {
+        let mut buf = [0u8; 24 + 256];
+        let mut log = ValueLog::new(&mut buf);
+        let mut live = HashMap::new();
+        for i in 0..4u8 {
+            live.insert(i, log.append(&[i; 24]).unwrap());
+        }
+        let pointers: Vec<ValuePointer> = live.values().cloned().collect();
+        let progress = ProgressHandle::new();
+        let mut moves = Vec::new();
+        // Stop as soon as the first record is rewritten
+        log.gc_with_progress(128, |p| pointers.contains(p), |old, new| {
+            moves.push((old, new));
+            progress.cancel();
+        }, &progress).unwrap();
+        assert_eq!(1, moves.len());
+        assert_eq!(CompactionProgress { blocks_moved: 1, bytes_reclaimed: 0, phase: CompactionPhase::Cancelled },
+            progress.progress());
+        // The moved record is readable at its new place, the rest where they were
+        let (old, new) = moves[0];
+        for (k, p) in live.iter() {
+            let p = if *p == old { new } else { *p };
+            assert_eq!(&[*k; 24][..], log.get(&p).unwrap());
+        }
+        assert_eq!(128, log.used());
+    }
+}
@@ -0,0 +1,254 @@
+use std::cmp;
+
+/// Above this many entries a sorted array takes more room than a bitmap
+pub const ARRAY_MAX: usize = 4096;
+/// 2^16 bits worth of u64 words
+pub const BITMAP_WORDS: usize = 1024;
+
+pub const KIND_ARRAY: u16 = 0;
+pub const KIND_BITS: u16 = 1;
+
+/// A container holds the low 16 bits of every value that shares
+/// the same high 16 bits. Sparse containers are sorted arrays,
+/// dense containers are plain bitmaps.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Container {
+    Array(Vec<u16>),
+    Bits(Vec<u64>),
+}
+
+use self::Container::*;
+
+/// Public interface
+impl Container {
+    pub fn new() -> Container {
+        Array(Vec::new())
+    }
+
+    pub fn len(&self) -> usize {
+        match *self {
+            Array(ref a) => a.len(),
+            Bits(ref b) => b.iter().map(|w| w.count_ones() as usize).sum(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, low: u16) -> bool {
+        match *self {
+            Array(ref a) => a.binary_search(&low).is_ok(),
+            Bits(ref b) => b[low as usize / 64] & (1 << (low as usize % 64)) != 0,
+        }
+    }
+
+    /// Returns true if the value was not already present
+    pub fn insert(&mut self, low: u16) -> bool {
+        let added = match *self {
+            Array(ref mut a) => match a.binary_search(&low) {
+                Ok(_) => false,
+                Err(i) => {
+                    a.insert(i, low);
+                    true
+                },
+            },
+            Bits(ref mut b) => {
+                let mask = 1 << (low as usize % 64);
+                let word = &mut b[low as usize / 64];
+                let added = *word & mask == 0;
+                *word |= mask;
+                added
+            },
+        };
+        self.normalize();
+        added
+    }
+
+    /// Returns true if the value was present
+    pub fn remove(&mut self, low: u16) -> bool {
+        let removed = match *self {
+            Array(ref mut a) => match a.binary_search(&low) {
+                Ok(i) => {
+                    a.remove(i);
+                    true
+                },
+                Err(_) => false,
+            },
+            Bits(ref mut b) => {
+                let mask = 1 << (low as usize % 64);
+                let word = &mut b[low as usize / 64];
+                let removed = *word & mask != 0;
+                *word &= !mask;
+                removed
+            },
+        };
+        self.normalize();
+        removed
+    }
+
+    /// Number of values less than or equal to low
+    pub fn rank(&self, low: u16) -> usize {
+        match *self {
+            Array(ref a) => match a.binary_search(&low) {
+                Ok(i) => i + 1,
+                Err(i) => i,
+            },
+            Bits(ref b) => {
+                let word = low as usize / 64;
+                let bit = low as usize % 64;
+                let full: usize = b[..word].iter().map(|w| w.count_ones() as usize).sum();
+                let mask = if bit == 63 { !0 } else { (1u64 << (bit + 1)) - 1 };
+                full + (b[word] & mask).count_ones() as usize
+            },
+        }
+    }
+
+    /// The nth (0 based) smallest value in the container
+    pub fn select(&self, n: usize) -> Option<u16> {
+        match *self {
+            Array(ref a) => a.get(n).cloned(),
+            Bits(ref b) => {
+                let mut remaining = n;
+                for (i, w) in b.iter().enumerate() {
+                    let ones = w.count_ones() as usize;
+                    if remaining < ones {
+                        let mut word = *w;
+                        for _ in 0..remaining {
+                            word &= word - 1; // Drop the lowest set bit
+                        }
+                        return Some((i * 64 + word.trailing_zeros() as usize) as u16);
+                    }
+                    remaining -= ones;
+                }
+                None
+            },
+        }
+    }
+
+    pub fn union(&self, other: &Container) -> Container {
+        let mut result = match (self, other) {
+            (&Array(ref a), &Array(ref b)) => {
+                let mut out = Vec::with_capacity(a.len() + b.len());
+                let (mut i, mut j) = (0, 0);
+                while i < a.len() && j < b.len() {
+                    match a[i].cmp(&b[j]) {
+                        cmp::Ordering::Less => { out.push(a[i]); i += 1; },
+                        cmp::Ordering::Greater => { out.push(b[j]); j += 1; },
+                        cmp::Ordering::Equal => { out.push(a[i]); i += 1; j += 1; },
+                    }
+                }
+                out.extend_from_slice(&a[i..]);
+                out.extend_from_slice(&b[j..]);
+                Array(out)
+            },
+            _ => {
+                let mut bits = self.to_bits();
+                for (w, o) in bits.iter_mut().zip(other.to_bits()) {
+                    *w |= o;
+                }
+                Bits(bits)
+            },
+        };
+        result.normalize();
+        result
+    }
+
+    pub fn intersect(&self, other: &Container) -> Container {
+        let mut result = match (self, other) {
+            (&Array(ref a), _) => Array(a.iter().cloned().filter(|v| other.contains(*v)).collect()),
+            (_, &Array(ref b)) => Array(b.iter().cloned().filter(|v| self.contains(*v)).collect()),
+            (&Bits(ref a), &Bits(ref b)) => Bits(a.iter().zip(b.iter()).map(|(x, y)| x & y).collect()),
+        };
+        result.normalize();
+        result
+    }
+
+    /// All values in ascending order
+    pub fn values(&self) -> Vec<u16> {
+        match *self {
+            Array(ref a) => a.clone(),
+            Bits(ref b) => {
+                let mut out = Vec::new();
+                for (i, w) in b.iter().enumerate() {
+                    let mut word = *w;
+                    while word != 0 {
+                        out.push((i * 64 + word.trailing_zeros() as usize) as u16);
+                        word &= word - 1;
+                    }
+                }
+                out
+            },
+        }
+    }
+}
+
+/// Serialization
+impl Container {
+    pub fn kind(&self) -> u16 {
+        match *self {
+            Array(_) => KIND_ARRAY,
+            Bits(_) => KIND_BITS,
+        }
+    }
+
+    /// Number of payload bytes for a container of the given kind and cardinality
+    pub fn encoded_size(kind: u16, cardinality: usize) -> usize {
+        if kind == KIND_ARRAY { cardinality * 2 } else { BITMAP_WORDS * 8 }
+    }
+
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match *self {
+            Array(ref a) => for v in a {
+                out.push(*v as u8);
+                out.push((*v >> 8) as u8);
+            },
+            Bits(ref b) => for w in b {
+                for shift in 0..8 {
+                    out.push((*w >> (shift * 8)) as u8);
+                }
+            },
+        }
+    }
+
+    pub fn decode(kind: u16, cardinality: usize, bytes: &[u8]) -> Container {
+        if kind == KIND_ARRAY {
+            Array((0..cardinality)
+                .map(|i| bytes[2*i] as u16 | (bytes[2*i + 1] as u16) << 8)
+                .collect())
+        } else {
+            Bits((0..BITMAP_WORDS)
+                .map(|i| (0..8).fold(0u64, |w, shift| w | (bytes[8*i + shift] as u64) << (shift * 8)))
+                .collect())
+        }
+    }
+}
+
+/// Private interface
+impl Container {
+    fn to_bits(&self) -> Vec<u64> {
+        match *self {
+            Array(ref a) => {
+                let mut bits = vec![0u64; BITMAP_WORDS];
+                for v in a {
+                    bits[*v as usize / 64] |= 1 << (*v as usize % 64);
+                }
+                bits
+            },
+            Bits(ref b) => b.clone(),
+        }
+    }
+
+    /// Switch to whichever representation is smaller
+    fn normalize(&mut self) {
+        let len = self.len();
+        let replacement = match *self {
+            Array(_) if len > ARRAY_MAX => Some(Bits(self.to_bits())),
+            Bits(_) if len <= ARRAY_MAX => Some(Array(self.values())),
+            _ => None,
+        };
+        if let Some(c) = replacement {
+            *self = c;
+        }
+    }
+}
@@ -0,0 +1,361 @@
+/// Compressed (roaring style) bitmaps of u32 values stored in pool blocks.
+/// Like tree nodes, bitmaps are Copy-on-Write: every modification
+/// produces a new block and leaves the old one readable, so a bitmap
+/// referenced from a tree value shares the tree's durability story.
+///
+/// Block layout (all integers little endian):
+///   num_containers: u32
+///   num_containers * [key: u16, kind: u16, cardinality: u32]
+///   container payloads, in key order
+use self::container::*;
+use allocator::*;
+use LodestoneError;
+
+pub mod container;
+
+const HEADER_SIZE: usize = 4;
+const DESCRIPTOR_SIZE: usize = 8;
+
+pub struct Bitmap<'p> {
+    arc: ArcByteSlice<'p>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Descriptor {
+    key: u16,
+    kind: u16,
+    cardinality: usize,
+    offset: usize,
+}
+
+/// Public API
+impl<'p> Bitmap<'p> {
+    pub fn new(pool: &'p Pool) -> Result<Bitmap<'p>, LodestoneError> {
+        Bitmap::from_containers(&[], pool)
+    }
+
+    pub fn from_values<I: IntoIterator<Item=u32>>(values: I, pool: &'p Pool) -> Result<Bitmap<'p>, LodestoneError> {
+        let mut containers: Vec<(u16, Container)> = Vec::new();
+        for v in values {
+            let (key, low) = split(v);
+            let i = match containers.binary_search_by(|&(k, _)| k.cmp(&key)) {
+                Ok(i) => i,
+                Err(i) => {
+                    containers.insert(i, (key, Container::new()));
+                    i
+                },
+            };
+            containers[i].1.insert(low);
+        }
+        Bitmap::from_containers(&containers, pool)
+    }
+
+    /// Reopen a bitmap that was persisted, e.g. inside a tree value
+    pub fn open(handle: &Handle, pool: &'p Pool) -> Result<Bitmap<'p>, LodestoneError> {
+        let arc = try!(handle.get(pool));
+        if !is_valid(&*arc) {
+            return Err(LodestoneError::InvalidReference("Block is not a bitmap"));
+        }
+        Ok(Bitmap { arc: arc })
+    }
+
+    pub fn persist(&self) -> Handle {
+        Handle::new(&self.arc)
+    }
+
+    pub fn len(&self) -> usize {
+        self.descriptors().iter().map(|d| d.cardinality).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, value: u32) -> bool {
+        let (key, low) = split(value);
+        match self.container_for(key) {
+            Some(c) => c.contains(low),
+            None => false,
+        }
+    }
+
+    /// Number of values in the bitmap less than or equal to value
+    pub fn rank(&self, value: u32) -> usize {
+        let (key, low) = split(value);
+        let mut rank = 0;
+        for d in self.descriptors() {
+            if d.key < key {
+                rank += d.cardinality;
+            } else {
+                if d.key == key {
+                    rank += self.decode(&d).rank(low);
+                }
+                break;
+            }
+        }
+        rank
+    }
+
+    /// The nth (0 based) smallest value in the bitmap
+    pub fn select(&self, n: usize) -> Option<u32> {
+        let mut remaining = n;
+        for d in self.descriptors() {
+            if remaining < d.cardinality {
+                return self.decode(&d).select(remaining).map(|low| join(d.key, low));
+            }
+            remaining -= d.cardinality;
+        }
+        None
+    }
+
+    /// All values in ascending order
+    pub fn values(&self) -> Vec<u32> {
+        let mut out = Vec::with_capacity(self.len());
+        for (key, c) in self.containers() {
+            out.extend(c.values().into_iter().map(|low| join(key, low)));
+        }
+        out
+    }
+
+    /// Returns a new bitmap with value set
+    pub fn set(&self, value: u32, pool: &'p Pool) -> Result<Bitmap<'p>, LodestoneError> {
+        let (key, low) = split(value);
+        let mut containers = self.containers();
+        match containers.binary_search_by(|&(k, _)| k.cmp(&key)) {
+            Ok(i) => { containers[i].1.insert(low); },
+            Err(i) => {
+                let mut c = Container::new();
+                c.insert(low);
+                containers.insert(i, (key, c));
+            },
+        }
+        Bitmap::from_containers(&containers, pool)
+    }
+
+    /// Returns a new bitmap with value cleared
+    pub fn clear(&self, value: u32, pool: &'p Pool) -> Result<Bitmap<'p>, LodestoneError> {
+        let (key, low) = split(value);
+        let mut containers = self.containers();
+        if let Ok(i) = containers.binary_search_by(|&(k, _)| k.cmp(&key)) {
+            containers[i].1.remove(low);
+            if containers[i].1.is_empty() {
+                containers.remove(i);
+            }
+        }
+        Bitmap::from_containers(&containers, pool)
+    }
+
+    pub fn union(&self, other: &Bitmap, pool: &'p Pool) -> Result<Bitmap<'p>, LodestoneError> {
+        let mut containers = self.containers();
+        for (key, c) in other.containers() {
+            match containers.binary_search_by(|&(k, _)| k.cmp(&key)) {
+                Ok(i) => containers[i].1 = containers[i].1.union(&c),
+                Err(i) => containers.insert(i, (key, c)),
+            }
+        }
+        Bitmap::from_containers(&containers, pool)
+    }
+
+    pub fn intersect(&self, other: &Bitmap, pool: &'p Pool) -> Result<Bitmap<'p>, LodestoneError> {
+        let theirs = other.containers();
+        let containers: Vec<(u16, Container)> = self.containers().into_iter()
+            .filter_map(|(key, c)| {
+                theirs.binary_search_by(|&(k, _)| k.cmp(&key)).ok()
+                    .map(|i| (key, c.intersect(&theirs[i].1)))
+            })
+            .filter(|&(_, ref c)| !c.is_empty())
+            .collect();
+        Bitmap::from_containers(&containers, pool)
+    }
+}
+
+/// Internal Functions
+impl<'p> Bitmap<'p> {
+    fn from_containers(containers: &[(u16, Container)], pool: &'p Pool) -> Result<Bitmap<'p>, LodestoneError> {
+        let mut bytes = Vec::new();
+        write_u32(&mut bytes, containers.len() as u32);
+        for &(key, ref c) in containers {
+            write_u16(&mut bytes, key);
+            write_u16(&mut bytes, c.kind());
+            write_u32(&mut bytes, c.len() as u32);
+        }
+        for &(_, ref c) in containers {
+            c.encode(&mut bytes);
+        }
+        Ok(Bitmap { arc: try!(pool.malloc(&bytes[..])) })
+    }
+
+    fn descriptors(&self) -> Vec<Descriptor> {
+        descriptors(&*self.arc)
+    }
+
+    fn decode(&self, d: &Descriptor) -> Container {
+        Container::decode(d.kind, d.cardinality, &self.arc[d.offset..])
+    }
+
+    fn container_for(&self, key: u16) -> Option<Container> {
+        let ds = self.descriptors();
+        ds.binary_search_by(|d| d.key.cmp(&key)).ok().map(|i| self.decode(&ds[i]))
+    }
+
+    fn containers(&self) -> Vec<(u16, Container)> {
+        self.descriptors().iter().map(|d| (d.key, self.decode(d))).collect()
+    }
+}
+
+fn split(value: u32) -> (u16, u16) {
+    ((value >> 16) as u16, value as u16)
+}
+
+fn join(key: u16, low: u16) -> u32 {
+    (key as u32) << 16 | low as u32
+}
+
+fn descriptors(bytes: &[u8]) -> Vec<Descriptor> {
+    let n = read_u32(bytes, 0) as usize;
+    let mut offset = HEADER_SIZE + n * DESCRIPTOR_SIZE;
+    (0..n).map(|i| {
+        let at = HEADER_SIZE + i * DESCRIPTOR_SIZE;
+        let d = Descriptor {
+            key: read_u16(bytes, at),
+            kind: read_u16(bytes, at + 2),
+            cardinality: read_u32(bytes, at + 4) as usize,
+            offset: offset,
+        };
+        offset += Container::encoded_size(d.kind, d.cardinality);
+        d
+    })
+    .collect()
+}
+
+/// Check that the block is laid out the way a bitmap would be
+fn is_valid(bytes: &[u8]) -> bool {
+    if bytes.len() < HEADER_SIZE {
+        return false;
+    }
+    let n = read_u32(bytes, 0) as usize;
+    if bytes.len() < HEADER_SIZE + n * DESCRIPTOR_SIZE {
+        return false;
+    }
+    let ds = descriptors(bytes);
+    let keys_ordered = ds.windows(2).all(|w| w[0].key < w[1].key);
+    let kinds_known = ds.iter().all(|d| d.kind == KIND_ARRAY || d.kind == KIND_BITS);
+    let end = ds.last()
+        .map(|d| d.offset + Container::encoded_size(d.kind, d.cardinality))
+        .unwrap_or(HEADER_SIZE);
+    keys_ordered && kinds_known && end == bytes.len()
+}
+
+fn read_u16(bytes: &[u8], at: usize) -> u16 {
+    bytes[at] as u16 | (bytes[at + 1] as u16) << 8
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    read_u16(bytes, at) as u32 | (read_u16(bytes, at + 2) as u32) << 16
+}
+
+fn write_u16(out: &mut Vec<u8>, v: u16) {
+    out.push(v as u8);
+    out.push((v >> 8) as u8);
+}
+
+fn write_u32(out: &mut Vec<u8>, v: u32) {
+    write_u16(out, v as u16);
+    write_u16(out, (v >> 16) as u16);
+}
+
+#[cfg(test)]
+mod tests {
+    use allocator::*;
+    use super::*;
+
+    #[test]
+    fn test_set_clear_contains() {
+        let mut buf = [0u8; 0x4000];
+        let pool = Pool::new(&mut buf);
+
+        let empty = Bitmap::new(&pool).unwrap();
+        assert!(empty.is_empty());
+        let b = empty.set(7, &pool).unwrap();
+        let b = b.set(1 << 20, &pool).unwrap();
+        let b = b.set(3, &pool).unwrap();
+
+        assert!(b.contains(3));
+        assert!(b.contains(7));
+        assert!(b.contains(1 << 20));
+        assert!(!b.contains(4));
+        assert_eq!(vec![3, 7, 1 << 20], b.values());
+        // The old version is untouched
+        assert!(empty.is_empty());
+
+        let b = b.clear(7, &pool).unwrap();
+        assert!(!b.contains(7));
+        assert_eq!(2, b.len());
+    }
+
+    #[test]
+    fn test_rank_select() {
+        let mut buf = [0u8; 0x4000];
+        let pool = Pool::new(&mut buf);
+
+        let b = Bitmap::from_values(vec![10, 20, 30, 70000], &pool).unwrap();
+        assert_eq!(0, b.rank(9));
+        assert_eq!(1, b.rank(10));
+        assert_eq!(3, b.rank(69999));
+        assert_eq!(4, b.rank(!0));
+
+        assert_eq!(Some(10), b.select(0));
+        assert_eq!(Some(30), b.select(2));
+        assert_eq!(Some(70000), b.select(3));
+        assert_eq!(None, b.select(4));
+    }
+
+    #[test]
+    fn test_dense_container() {
+        let mut buf = vec![0u8; 0x10000];
+        let pool = Pool::new(&mut buf);
+
+        let b = Bitmap::from_values((0..10000).map(|i| i * 2), &pool).unwrap();
+        assert_eq!(10000, b.len());
+        assert!(b.contains(19998));
+        assert!(!b.contains(19999));
+        assert_eq!(5000, b.rank(9999));
+        assert_eq!(Some(19998), b.select(9999));
+
+        // Clearing enough values flips back to a sparse container
+        let mut sparse = b;
+        for i in 0..6000 {
+            sparse = sparse.clear(i * 2, &pool).unwrap();
+        }
+        assert_eq!(4000, sparse.len());
+        assert_eq!(Some(12000), sparse.select(0));
+    }
+
+    #[test]
+    fn test_union_intersect() {
+        let mut buf = [0u8; 0x4000];
+        let pool = Pool::new(&mut buf);
+
+        let a = Bitmap::from_values(vec![1, 2, 3, 100000], &pool).unwrap();
+        let b = Bitmap::from_values(vec![3, 4, 200000], &pool).unwrap();
+
+        assert_eq!(vec![1, 2, 3, 4, 100000, 200000], a.union(&b, &pool).unwrap().values());
+        assert_eq!(vec![3], a.intersect(&b, &pool).unwrap().values());
+    }
+
+    #[test]
+    fn test_persist_and_open() {
+        let mut buf = [0u8; 0x4000];
+        let pool = Pool::new(&mut buf);
+
+        let persisted = {
+            let b = Bitmap::from_values(vec![5, 500, 50000], &pool).unwrap();
+            b.persist()
+        };
+        let reopened = Bitmap::open(&persisted, &pool).unwrap();
+        assert_eq!(vec![5, 500, 50000], reopened.values());
+
+        let not_a_bitmap = Handle::new(&pool.malloc(&[0xff; 3]).unwrap());
+        assert!(Bitmap::open(&not_a_bitmap, &pool).is_err());
+    }
+}
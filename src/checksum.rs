@@ -0,0 +1,30 @@
+/// CRC32 (IEEE 802.3 polynomial, as used by zlib)
+const POLYNOMIAL: u32 = 0xEDB88320;
+
+lazy_static! {
+    static ref TABLE: Vec<u32> = (0..256u32).map(|i| {
+        (0..8).fold(i, |c, _| if c & 1 == 1 { POLYNOMIAL ^ (c >> 1) } else { c >> 1 })
+    }).collect();
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+/// Continue a running checksum over more data, so that
+/// crc32_update(crc32(a), b) == crc32(a ++ b)
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    !data.iter().fold(!crc, |c, b| TABLE[((c ^ *b as u32) & 0xFF) as usize] ^ (c >> 8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(0, crc32(b""));
+        assert_eq!(0xCBF43926, crc32(b"123456789"));
+        assert_eq!(crc32(b"hello world"), crc32_update(crc32(b"hello "), b"world"));
+    }
+}
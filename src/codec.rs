@@ -0,0 +1,54 @@
+/// The on-disk integer encoding, in one place: integers are written little
+/// endian at fixed offsets, so the format doesn't depend on the platform
+/// or on how Rust lays out a struct. Words are 64 bits, see static_checks.
+/// Skip list headers and the catalog, reference, value pointer and lineage
+/// records go through it; ArcByteSliceInner and Node are still used in
+/// place in the buffer, so an image only opens on a build of the same
+/// endianness, which the superblock's marker checks.
+
+pub const WORD: usize = 8;
+
+pub fn read_u32_le(bytes: &[u8], at: usize) -> u32 {
+    (0..4).fold(0u32, |v, i| v | (bytes[at + i] as u32) << (i * 8))
+}
+
+pub fn write_u32_le(bytes: &mut [u8], at: usize, v: u32) {
+    for i in 0..4 {
+        bytes[at + i] = (v >> (i * 8)) as u8;
+    }
+}
+
+pub fn read_u64_le(bytes: &[u8], at: usize) -> u64 {
+    (0..8).fold(0u64, |v, i| v | (bytes[at + i] as u64) << (i * 8))
+}
+
+pub fn write_u64_le(bytes: &mut [u8], at: usize, v: u64) {
+    for i in 0..8 {
+        bytes[at + i] = (v >> (i * 8)) as u8;
+    }
+}
+
+/// Offsets, sizes and tags are stored as words
+pub fn read_word_le(bytes: &[u8], at: usize) -> usize {
+    read_u64_le(bytes, at) as usize
+}
+
+pub fn write_word_le(bytes: &mut [u8], at: usize, v: usize) {
+    write_u64_le(bytes, at, v as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_little_endian_at_offsets() {
+        let mut bytes = [0u8; 14];
+        write_u32_le(&mut bytes, 1, 0x0403_0201);
+        write_word_le(&mut bytes, 5, 0x0c0b_0a09_0807_0605);
+        assert_eq!([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 0], bytes);
+        assert_eq!(0x0403_0201, read_u32_le(&bytes, 1));
+        assert_eq!(0x0c0b_0a09_0807_0605, read_word_le(&bytes, 5));
+        assert_eq!(0x0c0b_0a09_0807_0605, read_u64_le(&bytes, 5));
+    }
+}
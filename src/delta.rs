@@ -0,0 +1,218 @@
+/// Delta values: a large value plus a short list of small edits, so that
+/// appending a few bytes or flipping a flag doesn't copy the whole value.
+/// The base stays in its own block and the delta block holds a Reference
+/// to it; adding an edit copies only the (small) delta block. Reads
+/// materialize the value by replaying the edits, and once the chain gets
+/// long, collapse writes the result out as a new plain value.
+///
+/// Block layout (all integers little endian):
+///   base: Reference (REFERENCE_SIZE bytes)
+///   edits, back to back, each [kind: u8, offset: u32, len: u32, bytes]
+///
+/// Delta blocks own a strong count on their base, so trees storing them
+/// must register `references` as a reference extractor.
+use allocator::*;
+use LodestoneError;
+
+const EDIT_HEADER_SIZE: usize = 9;
+const KIND_APPEND: u8 = 0;
+const KIND_OVERWRITE: u8 = 1;
+const KIND_TRUNCATE: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Edit {
+    Append(Vec<u8>),
+    /// Replace bytes starting at the offset, which must lie inside the value
+    Overwrite(usize, Vec<u8>),
+    Truncate(usize),
+}
+
+pub struct DeltaValue<'p> {
+    arc: ArcByteSlice<'p>,
+}
+
+/// Public API
+impl<'p> DeltaValue<'p> {
+    /// Start a chain with no edits on top of base
+    pub fn new(base: &ArcByteSlice, pool: &'p Pool) -> Result<DeltaValue<'p>, LodestoneError> {
+        let bytes = pool.make_reference(base).to_bytes();
+        Ok(DeltaValue { arc: try!(pool.malloc(&bytes[..])) })
+    }
+
+    /// Reopen a delta value that was persisted, e.g. inside a tree value
+    pub fn open(handle: &Handle, pool: &'p Pool) -> Result<DeltaValue<'p>, LodestoneError> {
+        let arc = try!(handle.get(pool));
+        if decode_edits(&*arc).is_none() {
+            return Err(LodestoneError::InvalidReference("Block is not a delta value"));
+        }
+        Ok(DeltaValue { arc: arc })
+    }
+
+    pub fn persist(&self) -> Handle {
+        Handle::new(&self.arc)
+    }
+
+    pub fn num_edits(&self) -> usize {
+        self.edits().len()
+    }
+
+    /// Returns a new delta value with the edit added to the end of the chain
+    pub fn push(&self, edit: &Edit, pool: &'p Pool) -> Result<DeltaValue<'p>, LodestoneError> {
+        let base = try!(pool.resolve(&self.base()));
+        let mut bytes = pool.make_reference(&base).to_bytes().to_vec();
+        bytes.extend_from_slice(&self.arc[REFERENCE_SIZE..]);
+        encode_edit(edit, &mut bytes);
+        Ok(DeltaValue { arc: try!(pool.malloc(&bytes[..])) })
+    }
+
+    /// The base with every edit applied
+    pub fn materialize(&self, pool: &'p Pool) -> Result<Vec<u8>, LodestoneError> {
+        let base = try!(pool.resolve(&self.base()));
+        let mut value = base.to_vec();
+        for edit in self.edits() {
+            match edit {
+                Edit::Append(bytes) => value.extend_from_slice(&bytes[..]),
+                Edit::Overwrite(offset, bytes) => {
+                    if offset + bytes.len() > value.len() {
+                        return Err(LodestoneError::UserError("Overwrite runs past the end of the value"));
+                    }
+                    value[offset..offset + bytes.len()].copy_from_slice(&bytes[..]);
+                },
+                Edit::Truncate(len) => value.truncate(len),
+            }
+        }
+        Ok(value)
+    }
+
+    /// Write the materialized value out as a plain value
+    pub fn collapse(&self, pool: &'p Pool) -> Result<ArcByteSlice<'p>, LodestoneError> {
+        let value = try!(self.materialize(pool));
+        pool.malloc(&value[..])
+    }
+}
+
+/// Internal Functions
+impl<'p> DeltaValue<'p> {
+    fn base(&self) -> Reference {
+        // Checked when the block was opened or created
+        Reference::from_bytes(&self.arc[..REFERENCE_SIZE]).unwrap()
+    }
+
+    fn edits(&self) -> Vec<Edit> {
+        decode_edits(&*self.arc).unwrap_or_else(Vec::new)
+    }
+}
+
+/// The references held by a delta value block, for use as a tree's
+/// reference extractor
+pub fn references(bytes: &[u8]) -> Vec<Reference> {
+    match decode_edits(bytes) {
+        Some(_) => Reference::from_bytes(&bytes[..REFERENCE_SIZE]).into_iter().collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn encode_edit(edit: &Edit, out: &mut Vec<u8>) {
+    let (kind, offset, bytes): (u8, usize, &[u8]) = match *edit {
+        Edit::Append(ref bytes) => (KIND_APPEND, 0, &bytes[..]),
+        Edit::Overwrite(offset, ref bytes) => (KIND_OVERWRITE, offset, &bytes[..]),
+        Edit::Truncate(len) => (KIND_TRUNCATE, len, &[]),
+    };
+    out.push(kind);
+    write_u32(out, offset as u32);
+    write_u32(out, bytes.len() as u32);
+    out.extend_from_slice(bytes);
+}
+
+/// None if the block isn't laid out like a delta value
+fn decode_edits(bytes: &[u8]) -> Option<Vec<Edit>> {
+    if bytes.len() < REFERENCE_SIZE {
+        return None;
+    }
+    let mut edits = Vec::new();
+    let mut at = REFERENCE_SIZE;
+    while at < bytes.len() {
+        if at + EDIT_HEADER_SIZE > bytes.len() {
+            return None;
+        }
+        let offset = read_u32(bytes, at + 1) as usize;
+        let len = read_u32(bytes, at + 5) as usize;
+        let data_at = at + EDIT_HEADER_SIZE;
+        if len > bytes.len() - data_at {
+            return None;
+        }
+        let data = bytes[data_at..data_at + len].to_vec();
+        edits.push(match bytes[at] {
+            KIND_APPEND => Edit::Append(data),
+            KIND_OVERWRITE => Edit::Overwrite(offset, data),
+            KIND_TRUNCATE => Edit::Truncate(offset),
+            _ => return None,
+        });
+        at = data_at + len;
+    }
+    Some(edits)
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    (0..4).fold(0u32, |v, i| v | (bytes[at + i] as u32) << (i * 8))
+}
+
+fn write_u32(out: &mut Vec<u8>, v: u32) {
+    for i in 0..4 {
+        out.push((v >> (i * 8)) as u8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use allocator::*;
+    use super::*;
+
+    #[test]
+    fn test_edits_materialize() {
+        let mut buf = vec![0u8; 0x4000];
+        let pool = Pool::new(&mut buf);
+        let base = pool.malloc(b"hello world").unwrap();
+
+        let d = DeltaValue::new(&base, &pool).unwrap();
+        assert_eq!(b"hello world".to_vec(), d.materialize(&pool).unwrap());
+        let d = d.push(&Edit::Append(b"!!".to_vec()), &pool).unwrap();
+        let d = d.push(&Edit::Overwrite(0, b"J".to_vec()), &pool).unwrap();
+        let d2 = d.push(&Edit::Truncate(5), &pool).unwrap();
+
+        assert_eq!(b"Jello world!!".to_vec(), d.materialize(&pool).unwrap());
+        assert_eq!(b"Jello".to_vec(), d2.materialize(&pool).unwrap());
+        assert_eq!(3, d2.num_edits());
+        // The base is never touched
+        assert_eq!(b"hello world", &base[..]);
+
+        let collapsed = d2.collapse(&pool).unwrap();
+        assert_eq!(b"Jello", &collapsed[..]);
+
+        let bad = d2.push(&Edit::Overwrite(4, b"xx".to_vec()), &pool).unwrap();
+        assert!(bad.materialize(&pool).is_err());
+    }
+
+    #[test]
+    fn test_persist_and_references() {
+        let mut buf = vec![0u8; 0x4000];
+        let pool = Pool::new(&mut buf);
+        let base = pool.malloc(b"base").unwrap();
+
+        let persisted = {
+            let d = DeltaValue::new(&base, &pool).unwrap();
+            d.push(&Edit::Append(b"+1".to_vec()), &pool).unwrap().persist()
+        };
+        let d = DeltaValue::open(&persisted, &pool).unwrap();
+        assert_eq!(b"base+1".to_vec(), d.materialize(&pool).unwrap());
+
+        let block = persisted.get(&pool).unwrap();
+        let refs = references(&*block);
+        assert_eq!(1, refs.len());
+        assert_eq!(b"base", &pool.resolve(&refs[0]).unwrap()[..]);
+
+        let not_a_delta = pool.malloc(&[1, 2, 3]).unwrap();
+        assert!(references(&*not_a_delta).is_empty());
+        assert!(DeltaValue::open(&Handle::new(&not_a_delta), &pool).is_err());
+    }
+}
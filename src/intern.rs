@@ -0,0 +1,210 @@
+/// Interning table mapping byte strings to stable ids and back, stored
+/// in a pool block. Useful for packing repetitive key components (tenant
+/// names, field names) into a few bytes of a tree key. Ids are handed out
+/// in insertion order and never change. Like bitmaps, tables are
+/// Copy-on-Write: interning a new string produces a new block.
+///
+/// Block layout (all integers little endian):
+///   count: u32
+///   count * [offset: u32, len: u32]   string locations, by id
+///   count * [hash: u32, id: u32]      sorted by hash, then id
+///   string data
+use allocator::*;
+use checksum::crc32;
+use codec::read_u32_le;
+use LodestoneError;
+
+const HEADER_SIZE: usize = 4;
+const SLOT_SIZE: usize = 8;
+
+pub struct Interner<'p> {
+    arc: ArcByteSlice<'p>,
+}
+
+/// Public API
+impl<'p> Interner<'p> {
+    pub fn new(pool: &'p Pool) -> Result<Interner<'p>, LodestoneError> {
+        Interner::from_strings(&[], pool)
+    }
+
+    /// Reopen a table that was persisted, e.g. inside a tree value
+    pub fn open(handle: &Handle, pool: &'p Pool) -> Result<Interner<'p>, LodestoneError> {
+        let arc = try!(handle.get(pool));
+        if !is_valid(&*arc) {
+            return Err(LodestoneError::InvalidReference("Block is not an interning table"));
+        }
+        Ok(Interner { arc: arc })
+    }
+
+    pub fn persist(&self) -> Handle {
+        Handle::new(&self.arc)
+    }
+
+    pub fn len(&self) -> usize {
+        read_u32_le(&*self.arc, 0) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn id_of(&self, s: &[u8]) -> Option<u64> {
+        let hash = crc32(s);
+        let n = self.len();
+        let (mut lo, mut hi) = (0, n);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if self.hash_slot(mid).0 < hash {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        // Collisions sit next to each other
+        (lo..n).map(|i| self.hash_slot(i))
+            .take_while(|&(h, _)| h == hash)
+            .find(|&(_, id)| self.resolve(id as u64) == Some(s))
+            .map(|(_, id)| id as u64)
+    }
+
+    pub fn resolve(&self, id: u64) -> Option<&[u8]> {
+        if id >= self.len() as u64 {
+            return None;
+        }
+        let at = HEADER_SIZE + id as usize * SLOT_SIZE;
+        let offset = read_u32_le(&*self.arc, at) as usize;
+        let len = read_u32_le(&*self.arc, at + 4) as usize;
+        Some(&self.arc[offset..offset + len])
+    }
+
+    /// Returns the id for s, along with the table that contains it.
+    /// If s was already interned that's this table, otherwise it's a new one.
+    pub fn intern(&self, s: &[u8], pool: &'p Pool) -> Result<(Interner<'p>, u64), LodestoneError> {
+        if let Some(id) = self.id_of(s) {
+            return Ok((Interner { arc: self.arc.clone() }, id));
+        }
+        let mut strings: Vec<&[u8]> = (0..self.len() as u64)
+            .map(|id| self.resolve(id).unwrap())
+            .collect();
+        let id = strings.len() as u64;
+        strings.push(s);
+        Ok((try!(Interner::from_strings(&strings, pool)), id))
+    }
+}
+
+/// Internal Functions
+impl<'p> Interner<'p> {
+    fn from_strings(strings: &[&[u8]], pool: &'p Pool) -> Result<Interner<'p>, LodestoneError> {
+        let n = strings.len();
+        let mut bytes = Vec::new();
+        write_u32(&mut bytes, n as u32);
+        let mut offset = HEADER_SIZE + 2 * n * SLOT_SIZE;
+        for s in strings {
+            write_u32(&mut bytes, offset as u32);
+            write_u32(&mut bytes, s.len() as u32);
+            offset += s.len();
+        }
+        let mut hashes: Vec<(u32, u32)> = strings.iter().enumerate()
+            .map(|(id, s)| (crc32(s), id as u32))
+            .collect();
+        hashes.sort();
+        for &(hash, id) in hashes.iter() {
+            write_u32(&mut bytes, hash);
+            write_u32(&mut bytes, id);
+        }
+        for s in strings {
+            bytes.extend_from_slice(s);
+        }
+        Ok(Interner { arc: try!(pool.malloc(&bytes[..])) })
+    }
+
+    fn hash_slot(&self, i: usize) -> (u32, u32) {
+        let at = HEADER_SIZE + (self.len() + i) * SLOT_SIZE;
+        (read_u32_le(&*self.arc, at), read_u32_le(&*self.arc, at + 4))
+    }
+}
+
+/// Check that the block is laid out the way a table would be
+fn is_valid(bytes: &[u8]) -> bool {
+    if bytes.len() < HEADER_SIZE {
+        return false;
+    }
+    let n = read_u32_le(bytes, 0) as usize;
+    let data_start = HEADER_SIZE + 2 * n * SLOT_SIZE;
+    if bytes.len() < data_start {
+        return false;
+    }
+    // Strings are packed back to back, in id order
+    let mut expected = data_start;
+    for id in 0..n {
+        let at = HEADER_SIZE + id * SLOT_SIZE;
+        if read_u32_le(bytes, at) as usize != expected {
+            return false;
+        }
+        expected += read_u32_le(bytes, at + 4) as usize;
+    }
+    let hashes: Vec<(u32, u32)> = (0..n).map(|i| {
+        let at = HEADER_SIZE + (n + i) * SLOT_SIZE;
+        (read_u32_le(bytes, at), read_u32_le(bytes, at + 4))
+    }).collect();
+    let hashes_ordered = hashes.windows(2).all(|w| w[0] < w[1]);
+    let ids_known = hashes.iter().all(|&(_, id)| (id as usize) < n);
+    hashes_ordered && ids_known && expected == bytes.len()
+}
+
+fn write_u32(out: &mut Vec<u8>, v: u32) {
+    for i in 0..4 {
+        out.push((v >> (i * 8)) as u8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use allocator::*;
+    use super::*;
+
+    #[test]
+    fn test_intern_and_resolve() {
+        let mut buf = vec![0u8; 0x4000];
+        let pool = Pool::new(&mut buf);
+
+        let empty = Interner::new(&pool).unwrap();
+        assert!(empty.is_empty());
+        assert_eq!(None, empty.id_of(b"tenant-a"));
+
+        let (t, a) = empty.intern(b"tenant-a", &pool).unwrap();
+        let (t, b) = t.intern(b"tenant-b", &pool).unwrap();
+        let (t, again) = t.intern(b"tenant-a", &pool).unwrap();
+        let (t, nothing) = t.intern(b"", &pool).unwrap();
+        assert_eq!((0, 1, 0, 2), (a, b, again, nothing));
+        assert_eq!(3, t.len());
+
+        assert_eq!(Some(&b"tenant-b"[..]), t.resolve(b));
+        assert_eq!(Some(&b""[..]), t.resolve(nothing));
+        assert_eq!(None, t.resolve(3));
+        assert_eq!(Some(1), t.id_of(b"tenant-b"));
+        assert_eq!(None, t.id_of(b"tenant-c"));
+        // The old version is untouched
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn test_persist_and_open() {
+        let mut buf = vec![0u8; 0x4000];
+        let pool = Pool::new(&mut buf);
+
+        let persisted = {
+            let mut t = Interner::new(&pool).unwrap();
+            for name in ["id", "name", "email", "created_at"].iter() {
+                t = t.intern(name.as_bytes(), &pool).unwrap().0;
+            }
+            t.persist()
+        };
+        let reopened = Interner::open(&persisted, &pool).unwrap();
+        assert_eq!(Some(2), reopened.id_of(b"email"));
+        assert_eq!(Some(&b"created_at"[..]), reopened.resolve(3));
+
+        let not_a_table = Handle::new(&pool.malloc(&[1, 0, 0, 0, 9]).unwrap());
+        assert!(Interner::open(&not_a_table, &pool).is_err());
+    }
+}
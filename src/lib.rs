@@ -1,9 +1,43 @@
 #[macro_use] extern crate error_type;
 #[macro_use] extern crate lazy_static;
+#[cfg(feature = "loom")] extern crate loom;
+#[cfg(all(target_os = "linux", feature = "hole-punching"))] extern crate libc;
 
 pub mod allocator;
+pub mod bitmap;
+pub mod delta;
+pub mod intern;
+#[cfg(feature = "pod")] pub mod pod;
 
+mod checksum;
+mod codec;
 mod slicebtree;
+mod static_checks;
+
+pub use slicebtree::{BTree, Stats};
+pub use slicebtree::options::*;
+pub use slicebtree::snapshot::Snapshot;
+pub use slicebtree::iter::Iter;
+pub use slicebtree::frozen::FrozenTree;
+pub use slicebtree::consistency::{CommitToken, CommitWatch};
+pub use slicebtree::blocking::{in_blocking_section, BlockingOp, BlockingScope};
+pub use slicebtree::access::HotRange;
+pub use slicebtree::node::TreeSnapshot;
+
+/// Background upkeep, see BTree::maintenance
+pub mod maintenance {
+    pub use slicebtree::maintenance::{spawn, Background, Budget, Maintenance, MaintenanceBudget, Progress, TaskStats};
+}
+
+/// Key normalizers for BTree::set_key_normalizer
+pub mod normalize {
+    pub use slicebtree::normalize::{ascii_case_insensitive, KeyNormalizer};
+}
+
+/// The sorted files BTree::bulk_load and BTree::ingest_sorted_file read
+pub mod ingest {
+    pub use slicebtree::ingest::{write_record, SortedRecords};
+}
 use std::borrow::Cow;
 
 #[derive(Debug)]
@@ -11,4 +45,46 @@ pub enum LodestoneError {
     OutOfMemory(&'static str),
     InvalidReference(&'static str),
     UserError(&'static str),
+    StructureCorrupt(&'static str),
+    Corruption(&'static str),
+    ReadFailed(ReadDiagnostics),
+    /// A commit panicked part way through, the tree has to be reopened
+    Poisoned(&'static str),
+    /// The storage backend failed at a request
+    Storage(&'static str),
+    /// User keys can't go in the reserved system namespace
+    ReservedKey(&'static str),
+    /// A value can't be viewed as the requested type
+    Layout(LayoutMismatch),
+    /// The pool image was written by a build this one can't read
+    Format(FormatMismatch),
+}
+
+/// What a read went through before giving up
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadDiagnostics {
+    pub attempts: usize,
+    /// Whether the later attempts verified checksums
+    pub escalated: bool,
+    pub last_error: &'static str,
+}
+
+/// Why a value didn't fit the type it was read as
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayoutMismatch {
+    pub expected_size: usize,
+    pub actual_size: usize,
+    pub required_align: usize,
+    /// How far the value's address is past the last aligned one
+    pub misaligned_by: usize,
+}
+
+/// What about a pool image this build can't read, see Superblock
+#[derive(Debug, Clone, PartialEq)]
+pub struct FormatMismatch {
+    pub field: &'static str,
+    /// This build's value
+    pub expected: u64,
+    /// The image's
+    pub found: u64,
 }
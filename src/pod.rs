@@ -0,0 +1,104 @@
+/// Typed views of fixed layout values, so user code can read a value as
+/// a struct without unsafe of its own. Size and alignment are checked on
+/// every view and a mismatch is an error, never a panic.
+use std::mem;
+use std::slice;
+
+use allocator::ArcByteSlice;
+use {LodestoneError, LayoutMismatch};
+
+/// Types that are valid for any bytes of the right size: integers,
+/// floats and arrays of them, and #[repr(C)] structs with no padding
+/// made only of those. Implementing it for anything else is undefined
+/// behavior, which is why it's unsafe.
+pub unsafe trait FromBytes: Copy {}
+
+macro_rules! from_bytes {
+    ($($t:ty),*) => { $(unsafe impl FromBytes for $t {})* };
+}
+
+from_bytes!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize, f32, f64);
+
+macro_rules! from_bytes_arrays {
+    ($($n:expr),*) => { $(unsafe impl<T: FromBytes> FromBytes for [T; $n] {})* };
+}
+
+from_bytes_arrays!(1, 2, 3, 4, 5, 6, 7, 8, 16, 32, 64);
+
+/// View bytes as exactly one T
+pub fn as_pod<T: FromBytes>(bytes: &[u8]) -> Result<&T, LodestoneError> {
+    try!(check::<T>(bytes, mem::size_of::<T>()));
+    Ok(unsafe { &*(bytes.as_ptr() as *const T) })
+}
+
+/// View bytes as a run of Ts, e.g. a fixed width column.
+/// A trailing partial T is an error, not silently dropped.
+pub fn as_pod_slice<T: FromBytes>(bytes: &[u8]) -> Result<&[T], LodestoneError> {
+    let size = mem::size_of::<T>();
+    let whole = bytes.len() - bytes.len() % size;
+    try!(check::<T>(bytes, whole));
+    Ok(unsafe { slice::from_raw_parts(bytes.as_ptr() as *const T, whole / size) })
+}
+
+fn check<T>(bytes: &[u8], expected_size: usize) -> Result<(), LodestoneError> {
+    let align = mem::align_of::<T>();
+    let misaligned_by = bytes.as_ptr() as usize % align;
+    if bytes.len() != expected_size || misaligned_by != 0 {
+        return Err(LodestoneError::Layout(LayoutMismatch {
+            expected_size: expected_size,
+            actual_size: bytes.len(),
+            required_align: align,
+            misaligned_by: misaligned_by,
+        }));
+    }
+    Ok(())
+}
+
+impl<'p> ArcByteSlice<'p> {
+    /// The value as a T. Blocks are word aligned, so any T
+    /// aligned to a word or less only fails on size.
+    pub fn as_pod<'a, T: FromBytes>(&'a self) -> Result<&'a T, LodestoneError> {
+        as_pod(&self[..])
+    }
+
+    pub fn as_pod_slice<'a, T: FromBytes>(&'a self) -> Result<&'a [T], LodestoneError> {
+        as_pod_slice(&self[..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use allocator::Pool;
+    use LodestoneError;
+
+    #[repr(C)]
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Point {
+        x: u32,
+        y: u32,
+    }
+
+    unsafe impl FromBytes for Point {}
+
+    #[test]
+    fn test_pod_views() {
+        let mut buf = vec![0u8; 0x4000];
+        let p = Pool::new(&mut buf[..]);
+        let arc = p.malloc(&[1, 0, 0, 0, 2, 0, 0, 0]).unwrap();
+        assert_eq!(Point { x: 1, y: 2 }, *arc.as_pod::<Point>().unwrap());
+        assert_eq!(&[1, 2], arc.as_pod_slice::<u32>().unwrap());
+        assert_eq!(&[1, 0, 0, 0, 2, 0, 0, 0], arc.as_pod::<[u8; 8]>().unwrap());
+
+        match arc.as_pod::<u32>() {
+            Err(LodestoneError::Layout(m)) => assert_eq!((4, 8), (m.expected_size, m.actual_size)),
+            other => panic!("Expected a layout error, got {:?}", other),
+        }
+        match as_pod::<u32>(&arc[1..5]) {
+            Err(LodestoneError::Layout(m)) => assert_eq!((4, 1), (m.required_align, m.misaligned_by)),
+            other => panic!("Expected a layout error, got {:?}", other),
+        }
+        assert!(as_pod_slice::<u32>(&arc[..7]).is_err());
+        assert_eq!(&[1], as_pod_slice::<u32>(&arc[..4]).unwrap());
+    }
+}
@@ -0,0 +1,173 @@
+/// Read counters per leaf key range, for hot/cold analysis (cache sizing,
+/// tier placement). Reads record the range of the leaf they landed in;
+/// counts are halved every decay_every records so old traffic fades out.
+/// The counters encode to bytes, so they can be kept in the pool and
+/// carried across restarts.
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use LodestoneError;
+
+pub const DEFAULT_DECAY_EVERY: usize = 10000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HotRange {
+    pub start: Vec<u8>,
+    pub end: Vec<u8>,
+    pub score: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessStats {
+    /// Leaf min key -> (max key, decayed count)
+    ranges: BTreeMap<Vec<u8>, (Vec<u8>, u64)>,
+    decay_every: usize,
+    since_decay: usize,
+}
+
+impl AccessStats {
+    pub fn new(decay_every: usize) -> AccessStats {
+        assert!(decay_every > 0, "Access counts must decay");
+        AccessStats {
+            ranges: BTreeMap::new(),
+            decay_every: decay_every,
+            since_decay: 0,
+        }
+    }
+
+    /// A read landed in the leaf holding [start, end]. Leaves that split
+    /// or merged since start new counters under their new bounds.
+    pub fn record(&mut self, start: &[u8], end: &[u8]) {
+        {
+            let entry = self.ranges.entry(start.to_vec()).or_insert((end.to_vec(), 0));
+            if entry.0 != end {
+                *entry = (end.to_vec(), 0);
+            }
+            entry.1 += 1;
+        }
+        self.since_decay += 1;
+        if self.since_decay >= self.decay_every {
+            self.decay();
+        }
+    }
+
+    /// Halve every count, forgetting ranges nobody reads any more
+    pub fn decay(&mut self) {
+        self.since_decay = 0;
+        for counter in self.ranges.values_mut() {
+            counter.1 /= 2;
+        }
+        let cold: Vec<Vec<u8>> = self.ranges.iter()
+            .filter(|&(_, c)| c.1 == 0)
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in cold {
+            self.ranges.remove(&key);
+        }
+    }
+
+    /// The most read ranges first, ties in key order
+    pub fn hot_ranges(&self, top_n: usize) -> Vec<HotRange> {
+        let mut ranges: Vec<HotRange> = self.ranges.iter()
+            .map(|(start, &(ref end, score))| HotRange { start: start.clone(), end: end.clone(), score: score })
+            .collect();
+        ranges.sort_by(|a, b| match b.score.cmp(&a.score) {
+            Ordering::Equal => a.start.cmp(&b.start),
+            other => other,
+        });
+        ranges.truncate(top_n);
+        ranges
+    }
+
+    /// Little endian decay_every, since_decay and range count, then per
+    /// range the length prefixed start and end keys and the count
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for n in [self.decay_every as u64, self.since_decay as u64, self.ranges.len() as u64].iter() {
+            put_u64(&mut bytes, *n);
+        }
+        for (start, &(ref end, count)) in self.ranges.iter() {
+            for key in [start, end].iter() {
+                put_u64(&mut bytes, key.len() as u64);
+                bytes.extend_from_slice(key);
+            }
+            put_u64(&mut bytes, count);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<AccessStats, LodestoneError> {
+        let mut at = 0;
+        let decay_every = try!(get_u64(bytes, &mut at)) as usize;
+        let since_decay = try!(get_u64(bytes, &mut at)) as usize;
+        let count = try!(get_u64(bytes, &mut at));
+        if decay_every == 0 {
+            return Err(LodestoneError::Corruption("Access stats never decay"));
+        }
+        let mut stats = AccessStats::new(decay_every);
+        stats.since_decay = since_decay;
+        for _ in 0..count {
+            let start = try!(get_slice(bytes, &mut at)).to_vec();
+            let end = try!(get_slice(bytes, &mut at)).to_vec();
+            let count = try!(get_u64(bytes, &mut at));
+            stats.ranges.insert(start, (end, count));
+        }
+        Ok(stats)
+    }
+}
+
+fn put_u64(bytes: &mut Vec<u8>, n: u64) {
+    bytes.extend((0..8).map(|i| (n >> (i * 8)) as u8));
+}
+
+fn get_u64(bytes: &[u8], at: &mut usize) -> Result<u64, LodestoneError> {
+    if bytes.len() < *at + 8 {
+        return Err(LodestoneError::Corruption("Access stats are truncated"));
+    }
+    let n = bytes[*at..*at + 8].iter().rev().fold(0u64, |n, &b| n << 8 | b as u64);
+    *at += 8;
+    Ok(n)
+}
+
+fn get_slice<'a>(bytes: &'a [u8], at: &mut usize) -> Result<&'a [u8], LodestoneError> {
+    let len = try!(get_u64(bytes, at)) as usize;
+    if bytes.len() - *at < len {
+        return Err(LodestoneError::Corruption("Access stats are truncated"));
+    }
+    *at += len;
+    Ok(&bytes[*at - len..*at])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hot_ranges() {
+        let mut stats = AccessStats::new(10);
+        for _ in 0..6 {
+            stats.record(b"m", b"t");
+        }
+        stats.record(b"a", b"f");
+        stats.record(b"a", b"f");
+        stats.record(b"g", b"l");
+        assert_eq!(vec![HotRange { start: b"m".to_vec(), end: b"t".to_vec(), score: 6 },
+                        HotRange { start: b"a".to_vec(), end: b"f".to_vec(), score: 2 }],
+                   stats.hot_ranges(2));
+
+        // The tenth record decays everything, dropping g..l
+        stats.record(b"a", b"f");
+        let scores: Vec<u64> = stats.hot_ranges(10).iter().map(|r| r.score).collect();
+        assert_eq!(vec![3, 1], scores);
+
+        // A split leaf starts over
+        stats.record(b"m", b"p");
+        assert_eq!(1, stats.hot_ranges(10).iter().find(|r| r.start == b"m").unwrap().score);
+
+        let decoded = AccessStats::from_bytes(&stats.to_bytes()).unwrap();
+        assert_eq!(stats, decoded);
+        let bytes = stats.to_bytes();
+        assert!(AccessStats::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(AccessStats::from_bytes(&[0; 24]).is_err());
+    }
+}
@@ -0,0 +1,121 @@
+/// Marking the parts of tree operations that can block for a long time
+/// (flush, compaction, large scans, commits), for embedding the tree in
+/// an async service. Code running on an executor can ask whether it is
+/// inside such a section, and a callback can be set to hear about any
+/// section that ran longer than a threshold, so a starved executor can
+/// be traced back to the operation that starved it.
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlockingOp {
+    Commit,
+    Flush,
+    Compaction,
+    Scan,
+}
+
+thread_local! {
+    static DEPTH: Cell<usize> = Cell::new(0);
+}
+
+/// Whether the current thread is inside a blocking section
+pub fn in_blocking_section() -> bool {
+    DEPTH.with(|d| d.get() > 0)
+}
+
+pub struct BlockingMonitor {
+    threshold: Option<Duration>,
+    on_slow: Option<Box<Fn(BlockingOp, Duration) + Send>>,
+}
+
+impl BlockingMonitor {
+    pub fn new() -> BlockingMonitor {
+        BlockingMonitor {
+            threshold: None,
+            on_slow: None,
+        }
+    }
+
+    /// on_slow is called, on the blocking thread, with any section
+    /// that took longer than threshold
+    pub fn on_slow<F>(&mut self, threshold: Duration, on_slow: F)
+        where F: Fn(BlockingOp, Duration) + Send + 'static {
+        self.threshold = Some(threshold);
+        self.on_slow = Some(Box::new(on_slow));
+    }
+
+    pub fn enter<'a>(&'a self, op: BlockingOp) -> BlockingScope<'a> {
+        DEPTH.with(|d| d.set(d.get() + 1));
+        BlockingScope {
+            monitor: self,
+            op: op,
+            start: Instant::now(),
+        }
+    }
+}
+
+/// Marks the current thread as blocking until dropped
+pub struct BlockingScope<'a> {
+    monitor: &'a BlockingMonitor,
+    op: BlockingOp,
+    start: Instant,
+}
+
+impl<'a> BlockingScope<'a> {
+    pub fn op(&self) -> BlockingOp {
+        self.op
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+impl<'a> Drop for BlockingScope<'a> {
+    fn drop(&mut self) {
+        DEPTH.with(|d| d.set(d.get() - 1));
+        let elapsed = self.start.elapsed();
+        if let (Some(threshold), Some(ref on_slow)) = (self.monitor.threshold, self.monitor.on_slow.as_ref()) {
+            if elapsed > threshold {
+                on_slow(self.op, elapsed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_blocking_scope() {
+        let slow = Arc::new(Mutex::new(Vec::new()));
+        let mut monitor = BlockingMonitor::new();
+        let seen = slow.clone();
+        monitor.on_slow(Duration::from_millis(5), move |op, _| seen.lock().unwrap().push(op));
+
+        assert!(!in_blocking_section());
+        {
+            let _flush = monitor.enter(BlockingOp::Flush);
+            assert!(in_blocking_section());
+            {
+                let _scan = monitor.enter(BlockingOp::Scan);
+                thread::sleep(Duration::from_millis(10));
+            }
+            // Still inside the outer section
+            assert!(in_blocking_section());
+        }
+        assert!(!in_blocking_section());
+        // Only counts the thread that entered it
+        let _commit = monitor.enter(BlockingOp::Commit);
+        assert!(!thread::spawn(in_blocking_section).join().unwrap());
+        drop(_commit);
+
+        // The flush contained the slow scan, so it was slow too
+        assert_eq!(vec![BlockingOp::Scan, BlockingOp::Flush], *slow.lock().unwrap());
+    }
+}
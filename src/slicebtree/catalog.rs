@@ -0,0 +1,376 @@
+/// The named trees of a pool, listed in one block pinned as "catalog".
+/// Opening the catalog reads that block and nothing else, so open costs
+/// the same however many trees there are. Each tree's root is verified
+/// the first time it's asked for, or all at once with preload_all.
+///
+/// The block is never changed in place. Pool::catalog_txn writes a whole
+/// new block and swaps the pin over to it, so any number of creates,
+/// drops and renames land together or not at all.
+///
+/// Layout: magic, format, the catalog's version (one more every
+/// transaction) and the entry count, then per entry the length prefixed
+/// name and the root's Reference, then a crc32 of all of it. Catalogs
+/// from before the header are entries only, read as version 0.
+use std::mem;
+use std::sync::Mutex;
+
+use super::node::verify_quick;
+use allocator::{ArcByteSlice, Pool, Reference, REFERENCE_SIZE};
+use checksum::crc32;
+use codec::*;
+use LodestoneError;
+
+pub const CATALOG_PIN: &'static str = "catalog";
+/// How much of a tree is verified on first access
+const VERIFY_LEVELS: usize = 2;
+const VERIFY_SAMPLES: usize = 4;
+
+const CATALOG_MAGIC: u32 = 0x7461_636c;
+const CATALOG_FORMAT: u32 = 1;
+const MAGIC_AT: usize = 0;
+const FORMAT_AT: usize = 4;
+const VERSION_AT: usize = 8;
+const COUNT_AT: usize = 8 + WORD;
+const HEADER_SIZE: usize = 8 + 2 * WORD;
+const CHECKSUM_SIZE: usize = 4;
+
+lazy_static! {
+    /// Transactions read the catalog, then swap it, so they go one at a time
+    static ref CATALOG_TXNS: Mutex<()> = Mutex::new(());
+}
+
+pub struct Catalog<'a> {
+    pool: &'a Pool<'a>,
+    version: usize,
+    trees: Vec<(String, Reference)>,
+    verified: Mutex<Vec<bool>>,
+}
+
+impl<'a> Catalog<'a> {
+    /// An empty catalog if none was written yet
+    pub fn open(pool: &'a Pool) -> Result<Catalog<'a>, LodestoneError> {
+        let (version, trees) = match pool.pinned(CATALOG_PIN) {
+            Some(reference) => try!(decode(&try!(pool.resolve(&reference)))),
+            None => (0, Vec::new()),
+        };
+        let count = trees.len();
+        Ok(Catalog {
+            pool: pool,
+            version: version,
+            trees: trees,
+            verified: Mutex::new(vec![false; count]),
+        })
+    }
+
+    /// Replace the catalog with these trees. The catalog holds their
+    /// roots, and gives up its hold on the roots it listed before.
+    pub fn write<'p>(pool: &'p Pool, trees: &[(&str, &ArcByteSlice<'p>)]) -> Result<(), LodestoneError> {
+        pool.catalog_txn(|cat| {
+            cat.trees.clear();
+            for &(name, root) in trees.iter() {
+                try!(cat.create(name, root));
+            }
+            Ok(())
+        })
+    }
+
+    /// How many transactions the catalog has been through
+    pub fn version(&self) -> usize {
+        self.version
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.trees.iter().map(|&(ref name, _)| &name[..]).collect()
+    }
+
+    /// The root of the named tree, verified on first access
+    pub fn tree(&self, name: &str) -> Result<Option<ArcByteSlice<'a>>, LodestoneError> {
+        match self.trees.iter().position(|&(ref n, _)| n == name) {
+            Some(i) => self.load(i).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Every tree's root, unverified, e.g. to find what the catalog
+    /// keeps alive
+    pub fn roots(&self) -> Vec<Reference> {
+        self.trees.iter().map(|&(_, reference)| reference).collect()
+    }
+
+    pub fn is_verified(&self, name: &str) -> bool {
+        let verified = self.verified.lock().unwrap();
+        self.trees.iter().position(|&(ref n, _)| n == name).map_or(false, |i| verified[i])
+    }
+
+    /// Verify every tree now rather than on first access.
+    /// Returns how many trees needed it.
+    pub fn preload_all(&self) -> Result<usize, LodestoneError> {
+        let pending: Vec<usize> = {
+            let verified = self.verified.lock().unwrap();
+            (0..self.trees.len()).filter(|&i| !verified[i]).collect()
+        };
+        for &i in pending.iter() {
+            try!(self.load(i));
+        }
+        Ok(pending.len())
+    }
+
+    fn load(&self, i: usize) -> Result<ArcByteSlice<'a>, LodestoneError> {
+        let root = try!(self.pool.resolve(&self.trees[i].1));
+        if !self.verified.lock().unwrap()[i] {
+            try!(verify_quick(&try!(self.pool.take_reference(&self.trees[i].1)),
+                self.pool, VERIFY_LEVELS, VERIFY_SAMPLES, i));
+            self.verified.lock().unwrap()[i] = true;
+        }
+        Ok(root)
+    }
+}
+
+/// The catalog's entries while a Pool::catalog_txn runs. Nothing is
+/// written until the transaction's closure returns Ok.
+pub struct CatalogTxn<'p> {
+    trees: Vec<(String, ArcByteSlice<'p>)>,
+}
+
+impl<'p> CatalogTxn<'p> {
+    pub fn names(&self) -> Vec<&str> {
+        self.trees.iter().map(|&(ref name, _)| &name[..]).collect()
+    }
+
+    /// The named tree's root, as of this transaction
+    pub fn tree(&self, name: &str) -> Option<&ArcByteSlice<'p>> {
+        self.position(name).map(|i| &self.trees[i].1)
+    }
+
+    pub fn create(&mut self, name: &str, root: &ArcByteSlice<'p>) -> Result<(), LodestoneError> {
+        if name.is_empty() {
+            return Err(LodestoneError::UserError("Tree names can't be empty"));
+        }
+        if self.position(name).is_some() {
+            return Err(LodestoneError::UserError("A tree of that name is already in the catalog"));
+        }
+        self.trees.push((name.to_string(), root.clone()));
+        Ok(())
+    }
+
+    /// Take the tree out of the catalog, handing back its root
+    pub fn drop_tree(&mut self, name: &str) -> Result<ArcByteSlice<'p>, LodestoneError> {
+        match self.position(name) {
+            Some(i) => Ok(self.trees.remove(i).1),
+            None => Err(LodestoneError::UserError("No tree of that name is in the catalog")),
+        }
+    }
+
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), LodestoneError> {
+        let root = try!(self.drop_tree(from));
+        self.create(to, &root)
+    }
+
+    /// Point name at root, creating it if needed. Returns the root it replaced.
+    pub fn replace(&mut self, name: &str, root: &ArcByteSlice<'p>) -> Result<Option<ArcByteSlice<'p>>, LodestoneError> {
+        match self.position(name) {
+            Some(i) => Ok(Some(mem::replace(&mut self.trees[i].1, root.clone()))),
+            None => self.create(name, root).map(|_| None),
+        }
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.trees.iter().position(|&(ref n, _)| n == name)
+    }
+}
+
+impl<'buf> Pool<'buf> {
+    /// Change the catalog in one atomic step, e.g. swap a staging tree
+    /// into place by dropping it and replacing the live one with its root.
+    /// If f fails the catalog is left as it was. Otherwise its entries
+    /// are written to a new block and the catalog pin swapped over to it,
+    /// so a crash leaves one catalog or the other, and the roots only the
+    /// old catalog listed are released. Not reentrant.
+    pub fn catalog_txn<'p, T, F>(&'p self, f: F) -> Result<T, LodestoneError>
+        where F: FnOnce(&mut CatalogTxn<'p>) -> Result<T, LodestoneError>
+    {
+        // A panic in f wrote nothing, so the catalog is still sound
+        let _serial = CATALOG_TXNS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let (version, listed) = match self.pinned(CATALOG_PIN) {
+            Some(reference) => try!(decode(&try!(self.resolve(&reference)))),
+            None => (0, Vec::new()),
+        };
+        let mut txn = CatalogTxn { trees: Vec::with_capacity(listed.len()) };
+        for &(ref name, ref reference) in listed.iter() {
+            txn.trees.push((name.clone(), try!(self.resolve(reference))));
+        }
+        let result = try!(f(&mut txn));
+
+        let block = try!(self.malloc(&encode(version + 1, &txn.trees, self)));
+        if self.pinned(CATALOG_PIN).is_some() {
+            try!(self.repin(CATALOG_PIN, &block));
+        } else {
+            try!(self.pin_root(CATALOG_PIN, &block));
+        }
+        for (_, reference) in listed {
+            let mut root = try!(self.take_reference(&reference));
+            try!(root.release(self));
+        }
+        Ok(result)
+    }
+}
+
+/// Encode the entries, taking a count on each root
+fn encode(version: usize, trees: &[(String, ArcByteSlice)], pool: &Pool) -> Vec<u8> {
+    let mut bytes = vec![0; HEADER_SIZE];
+    write_u32_le(&mut bytes, MAGIC_AT, CATALOG_MAGIC);
+    write_u32_le(&mut bytes, FORMAT_AT, CATALOG_FORMAT);
+    write_word_le(&mut bytes, VERSION_AT, version);
+    write_word_le(&mut bytes, COUNT_AT, trees.len());
+    for &(ref name, ref root) in trees.iter() {
+        let at = bytes.len();
+        bytes.extend_from_slice(&[0; 4]);
+        write_u32_le(&mut bytes, at, name.len() as u32);
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.extend_from_slice(&pool.make_reference(root).to_bytes());
+    }
+    let at = bytes.len();
+    let checksum = crc32(&bytes);
+    bytes.extend_from_slice(&[0; CHECKSUM_SIZE]);
+    write_u32_le(&mut bytes, at, checksum);
+    bytes
+}
+
+/// The catalog's version and entries
+fn decode(bytes: &[u8]) -> Result<(usize, Vec<(String, Reference)>), LodestoneError> {
+    if bytes.len() < 4 || read_u32_le(bytes, MAGIC_AT) != CATALOG_MAGIC {
+        return decode_entries(bytes).map(|trees| (0, trees));
+    }
+    if bytes.len() < HEADER_SIZE + CHECKSUM_SIZE {
+        return Err(LodestoneError::Corruption("Catalog is truncated"));
+    }
+    let end = bytes.len() - CHECKSUM_SIZE;
+    if crc32(&bytes[..end]) != read_u32_le(bytes, end) {
+        return Err(LodestoneError::Corruption("Catalog checksum doesn't match"));
+    }
+    if read_u32_le(bytes, FORMAT_AT) != CATALOG_FORMAT {
+        return Err(LodestoneError::Corruption("Catalog has an unknown format"));
+    }
+    let trees = try!(decode_entries(&bytes[HEADER_SIZE..end]));
+    if trees.len() != read_word_le(bytes, COUNT_AT) {
+        return Err(LodestoneError::Corruption("Catalog entry count doesn't match"));
+    }
+    Ok((read_word_le(bytes, VERSION_AT), trees))
+}
+
+fn decode_entries(bytes: &[u8]) -> Result<Vec<(String, Reference)>, LodestoneError> {
+    let mut trees = Vec::new();
+    let mut at = 0;
+    while at < bytes.len() {
+        if bytes.len() - at < 4 {
+            return Err(LodestoneError::Corruption("Catalog is truncated"));
+        }
+        let len = read_u32_le(bytes, at) as usize;
+        at += 4;
+        if bytes.len() - at < len + REFERENCE_SIZE {
+            return Err(LodestoneError::Corruption("Catalog is truncated"));
+        }
+        let name = match String::from_utf8(bytes[at..at + len].to_vec()) {
+            Ok(name) => name,
+            Err(_) => return Err(LodestoneError::Corruption("Catalog names must be utf8")),
+        };
+        at += len;
+        trees.push((name, try!(Reference::from_bytes(&bytes[at..at + REFERENCE_SIZE]))));
+        at += REFERENCE_SIZE;
+    }
+    Ok(trees)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use allocator::Pool;
+    use slicebtree::node::{bulk_build, Node};
+    use std::mem;
+
+    #[test]
+    fn test_lazy_catalog() {
+        let mut buf = vec![0u8; 0x40000];
+        let pool = Pool::new(&mut buf);
+        assert!(Catalog::open(&pool).unwrap().names().is_empty());
+
+        let build = |n: usize| bulk_build((0..n).map(|i| Ok((vec![i as u8], vec![]))), 1, 50, &pool).unwrap().unwrap();
+        let (users, orders, logs) = (build(10), build(120), build(3));
+        Catalog::write(&pool, &[("users", &users), ("orders", &orders)]).unwrap();
+
+        let catalog = Catalog::open(&pool).unwrap();
+        assert_eq!(vec!["users", "orders"], catalog.names());
+        assert!(!catalog.is_verified("users"));
+        assert_eq!(users.get_ref_count(), catalog.tree("users").unwrap().unwrap().get_ref_count() - 1);
+        assert!(catalog.is_verified("users"));
+        assert!(!catalog.is_verified("orders"));
+        assert!(catalog.tree("nope").unwrap().is_none());
+        // Only orders was left
+        assert_eq!(1, catalog.preload_all().unwrap());
+        assert_eq!(0, catalog.preload_all().unwrap());
+
+        // Rewriting drops the old catalog's hold on users
+        let held = users.get_ref_count();
+        Catalog::write(&pool, &[("orders", &orders), ("logs", &logs)]).unwrap();
+        assert_eq!(held - 1, users.get_ref_count());
+        assert_eq!(vec!["orders", "logs"], Catalog::open(&pool).unwrap().names());
+
+        // A broken root only fails the tree it belongs to
+        let junk = pool.malloc(&vec![0xff; mem::size_of::<Node>()]).unwrap();
+        Catalog::write(&pool, &[("junk", &junk), ("logs", &logs)]).unwrap();
+        let catalog = Catalog::open(&pool).unwrap();
+        assert!(catalog.tree("junk").is_err());
+        assert!(catalog.tree("logs").unwrap().is_some());
+        assert!(catalog.preload_all().is_err());
+    }
+
+    #[test]
+    fn test_catalog_txn() {
+        let mut buf = vec![0u8; 0x40000];
+        let pool = Pool::new(&mut buf);
+        let build = |n: usize| bulk_build((0..n).map(|i| Ok((vec![i as u8], vec![]))), 1, 50, &pool).unwrap().unwrap();
+        let (live, staging, other) = (build(10), build(20), build(3));
+        pool.catalog_txn(|cat| {
+            try!(cat.create("live", &live));
+            cat.create("staging", &staging)
+        }).unwrap();
+        assert_eq!(1, Catalog::open(&pool).unwrap().version());
+
+        // A failed transaction leaves everything as it was
+        let held = live.get_ref_count();
+        let failed: Result<(), LodestoneError> = pool.catalog_txn(|cat| {
+            try!(cat.drop_tree("live"));
+            try!(cat.create("other", &other));
+            cat.rename("nope", "live")
+        });
+        assert!(failed.is_err());
+        let catalog = Catalog::open(&pool).unwrap();
+        assert_eq!(vec!["live", "staging"], catalog.names());
+        assert_eq!(1, catalog.version());
+        assert_eq!(held, live.get_ref_count());
+
+        // Swap staging into place and add another tree, all at once
+        let replaced = pool.catalog_txn(|cat| {
+            let staged = try!(cat.drop_tree("staging"));
+            try!(cat.create("other", &other));
+            cat.replace("live", &staged)
+        }).unwrap().unwrap();
+        assert_eq!(held, replaced.get_ref_count());
+        drop(replaced);
+        assert_eq!(held - 1, live.get_ref_count());
+        let catalog = Catalog::open(&pool).unwrap();
+        assert_eq!(vec!["live", "other"], catalog.names());
+        assert_eq!(2, catalog.version());
+        assert_eq!(staging.get_ref_count(), catalog.tree("live").unwrap().unwrap().get_ref_count() - 1);
+        assert!(catalog.tree("staging").unwrap().is_none());
+
+        // Damage anywhere in the block is caught
+        let bytes = encode(3, &[("a".to_string(), other.clone())], &pool);
+        assert_eq!(3, decode(&bytes).unwrap().0);
+        for at in vec![0, FORMAT_AT, VERSION_AT, HEADER_SIZE + 2, bytes.len() - 1] {
+            let mut damaged = bytes.clone();
+            damaged[at] ^= 1;
+            assert!(decode(&damaged).is_err());
+        }
+    }
+}
@@ -0,0 +1,116 @@
+/// Read-your-writes across handles. Every commit is named by a
+/// CommitToken, its transaction id and the generation of the root it
+/// made. A writer hands the token of its commit to whoever reads next,
+/// and the reader checks (at_least) or waits (wait_for) until what it
+/// reads from has caught up to it, without going through the writer.
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommitToken {
+    pub tx_id: usize,
+    /// Tells apart commits with the same tx_id in different histories,
+    /// e.g. after reopening an older image
+    pub generation: usize,
+}
+
+impl CommitToken {
+    /// Whether a tree whose last commit is self has seen token's commit
+    pub fn covers(&self, token: &CommitToken) -> bool {
+        self.tx_id > token.tx_id || *self == *token
+    }
+}
+
+struct Shared {
+    latest: Mutex<CommitToken>,
+    committed: Condvar,
+}
+
+/// The last commit of a tree, for other threads to check and wait on.
+/// Clones watch the same tree.
+#[derive(Clone)]
+pub struct CommitWatch {
+    shared: Arc<Shared>,
+}
+
+impl CommitWatch {
+    pub fn new(latest: CommitToken) -> CommitWatch {
+        CommitWatch {
+            shared: Arc::new(Shared {
+                latest: Mutex::new(latest),
+                committed: Condvar::new(),
+            }),
+        }
+    }
+
+    pub fn latest(&self) -> CommitToken {
+        *self.shared.latest.lock().unwrap()
+    }
+
+    pub fn at_least(&self, token: &CommitToken) -> bool {
+        self.latest().covers(token)
+    }
+
+    /// Block until token's commit has been made
+    pub fn wait_for(&self, token: &CommitToken) {
+        let mut latest = self.shared.latest.lock().unwrap();
+        while !latest.covers(token) {
+            latest = self.shared.committed.wait(latest).unwrap();
+        }
+    }
+
+    /// wait_for, giving up after timeout. Returns whether the commit was made.
+    pub fn wait_for_timeout(&self, token: &CommitToken, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut latest = self.shared.latest.lock().unwrap();
+        while !latest.covers(token) {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            latest = self.shared.committed.wait_timeout(latest, deadline - now).unwrap().0;
+        }
+        true
+    }
+
+    /// For the tree, after every commit
+    pub fn publish(&self, token: CommitToken) {
+        *self.shared.latest.lock().unwrap() = token;
+        self.shared.committed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    fn token(tx_id: usize, generation: usize) -> CommitToken {
+        CommitToken { tx_id: tx_id, generation: generation }
+    }
+
+    #[test]
+    fn test_wait_for_commit() {
+        let watch = CommitWatch::new(token(0, 0));
+        assert!(watch.at_least(&token(0, 0)));
+        assert!(!watch.at_least(&token(2, 7)));
+        assert!(!watch.wait_for_timeout(&token(2, 7), Duration::from_millis(1)));
+
+        let reader = {
+            let watch = watch.clone();
+            thread::spawn(move || {
+                watch.wait_for(&token(2, 7));
+                watch.latest()
+            })
+        };
+        watch.publish(token(1, 5));
+        watch.publish(token(2, 7));
+        assert_eq!(token(2, 7), reader.join().unwrap());
+
+        // Later commits cover earlier ones, but the same tx_id from
+        // another history doesn't
+        assert!(watch.at_least(&token(1, 3)));
+        assert!(!watch.at_least(&token(2, 8)));
+    }
+}
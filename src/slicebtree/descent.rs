@@ -0,0 +1,97 @@
+use std::mem;
+use allocator::*;
+
+use super::*;
+use super::node::Node;
+use LodestoneError;
+
+/// Tracks a single root-to-leaf descent so that corrupted child
+/// pointers can't send us around in circles or arbitrarily deep.
+pub struct Descent {
+    max_depth: usize,
+    visited: Vec<usize>,
+}
+
+impl Descent {
+    pub fn new(max_depth: usize) -> Descent {
+        Descent {
+            max_depth: max_depth,
+            visited: Vec::with_capacity(max_depth),
+        }
+    }
+
+    /// A descent limited to the deepest tree that could fit in the pool
+    pub fn for_pool(pool: &Pool) -> Descent {
+        Descent::new(max_depth_for(pool.size()))
+    }
+
+    /// Record that we're about to step into the given node
+    pub fn enter(&mut self, node: &PersistedArcByteSlice) -> Result<(), LodestoneError> {
+        self.enter_reference(&Reference::from_persisted(node))
+    }
+
+    /// enter, for a node known by its reference, e.g. from the node cache
+    pub fn enter_reference(&mut self, node: &Reference) -> Result<(), LodestoneError> {
+        if self.visited.len() >= self.max_depth {
+            return Err(LodestoneError::StructureCorrupt("Tree is deeper than the maximum allowed depth"));
+        }
+        if self.visited.contains(&node.arc_inner_index()) {
+            return Err(LodestoneError::StructureCorrupt("Node visited twice in a single descent"));
+        }
+        self.visited.push(node.arc_inner_index());
+        Ok(())
+    }
+}
+
+/// The deepest a valid tree can get in a pool of the given size.
+/// Every node below the root is at least half full, so the number
+/// of nodes that fit in the pool bounds the height.
+pub fn max_depth_for(pool_size: usize) -> usize {
+    let max_nodes = pool_size / mem::size_of::<Node>();
+    let min_fanout = B/2;
+    // The root only needs 2 children
+    let mut reach = 2;
+    let mut depth = 2;
+    while reach < max_nodes {
+        reach = reach.saturating_mul(min_fanout);
+        depth += 1;
+    }
+    depth
+}
+
+#[cfg(test)]
+mod tests {
+    use allocator::*;
+    use super::*;
+    use LodestoneError;
+
+    #[test]
+    fn test_max_depth_for() {
+        assert_eq!(2, max_depth_for(0));
+        assert_eq!(3, max_depth_for(0x10000));
+        // A terabyte of nodes is still only a handful of levels
+        assert!(max_depth_for(1 << 40) < 10);
+    }
+
+    #[test]
+    fn test_descent_limits() {
+        let mut buf = [0u8; 0x4000];
+        let pool = Pool::new(&mut buf);
+        let a = pool.malloc(&[1]).unwrap().clone_to_persisted();
+        let b = pool.malloc(&[2]).unwrap().clone_to_persisted();
+        let c = pool.malloc(&[3]).unwrap().clone_to_persisted();
+
+        let mut d = Descent::new(2);
+        assert!(d.enter(&a).is_ok());
+        match d.enter(&a) {
+            Err(LodestoneError::StructureCorrupt(_)) => (),
+            other => panic!("Expected cycle to be detected, got {:?}", other),
+        }
+        assert!(d.enter(&b).is_ok());
+        assert_eq!(2, d.visited.len());
+        match d.enter(&c) {
+            Err(LodestoneError::StructureCorrupt(_)) => (),
+            other => panic!("Expected depth limit, got {:?}", other),
+        }
+    }
+}
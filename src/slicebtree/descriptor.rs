@@ -0,0 +1,196 @@
+/// A tree's own description, kept in a block of its pool pinned as "tree":
+/// its recent roots, its transaction counter and the options it was
+/// created with. With it the pool describes itself, and a tree is rebuilt
+/// around the pool alone (BTree::from_pool) rather than from whatever the
+/// opener remembers. Options that are code (key normalizers, reference
+/// extractors) can't be stored, and have to be set again on every open.
+use std::cmp;
+
+use super::{B, N};
+use super::options::*;
+use allocator::{ArcByteSlice, Pool};
+use {FormatMismatch, LodestoneError};
+
+pub const DESCRIPTOR_PIN: &'static str = "tree";
+const DESCRIPTOR_MAGIC: u64 = 0x4c4f_4445_5452_4545;
+const DESCRIPTOR_VERSION: u32 = 1;
+
+const FLAG_ENTRY_CHECKSUMS: u32 = 1;
+/// Set when message_buffer was written, older descriptors leave it unset
+const FLAG_MESSAGE_BUFFER: u32 = 2;
+const FLAG_RELOCATABLE: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct RootSlot {
+    /// 0 while the tree is empty
+    pub index: usize,
+    pub generation: usize,
+    pub tx_id: usize,
+    /// How many entries the tree held as of this root
+    pub entries: usize,
+}
+
+/// Fixed layout, read in place from its block
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct TreeDescriptor {
+    magic: u64,
+    version: u32,
+    flags: u32,
+    /// Which of roots is the current one. The others are older commits,
+    /// and may have been freed since, their generations tell.
+    head: usize,
+    roots: [RootSlot; N],
+    integrity_sample_one_in: usize,
+    access_sample_one_in: usize,
+    /// Option<bool> and Option<Durability> as 0 for None, 1 + the value
+    verify_checksums: u8,
+    fill_cache: u8,
+    durability: u8,
+    /// The B the tree's nodes were last written with, 0 if it wasn't
+    /// recorded
+    node_capacity: u8,
+    /// TreeOptions::message_buffer, if FLAG_MESSAGE_BUFFER is set
+    message_buffer: u8,
+}
+
+impl TreeDescriptor {
+    pub fn new(options: &TreeOptions) -> TreeDescriptor {
+        TreeDescriptor {
+            magic: DESCRIPTOR_MAGIC,
+            version: DESCRIPTOR_VERSION,
+            flags: FLAG_MESSAGE_BUFFER
+                | if options.entry_checksums { FLAG_ENTRY_CHECKSUMS } else { 0 }
+                | if options.relocatable { FLAG_RELOCATABLE } else { 0 },
+            head: 0,
+            roots: [RootSlot { index: 0, generation: 0, tx_id: 0, entries: 0 }; N],
+            integrity_sample_one_in: options.integrity_sample_one_in,
+            access_sample_one_in: options.access_sample_one_in,
+            verify_checksums: encode_bool(options.verify_checksums),
+            fill_cache: encode_bool(options.fill_cache),
+            durability: match options.durability {
+                None => 0,
+                Some(Durability::Buffered) => 1,
+                Some(Durability::Synced) => 2,
+            },
+            node_capacity: B as u8,
+            message_buffer: cmp::min(options.message_buffer, 255) as u8,
+        }
+    }
+
+    /// The descriptor pinned in pool, if the tree in it was ever described
+    pub fn load(pool: &Pool) -> Result<Option<TreeDescriptor>, LodestoneError> {
+        let reference = match pool.pinned(DESCRIPTOR_PIN) {
+            Some(reference) => reference,
+            None => return Ok(None),
+        };
+        let block = try!(pool.resolve(&reference));
+        let descriptor = *try!(TreeDescriptor::check(&block));
+        Ok(Some(descriptor))
+    }
+
+    /// Replace the pinned descriptor with this one
+    pub fn store(&self, pool: &Pool) -> Result<(), LodestoneError> {
+        let block = try!(pool.make_new::<TreeDescriptor>());
+        *block.deref_as_mut::<TreeDescriptor>() = *self;
+        pool.mark_written(&block);
+        if pool.pinned(DESCRIPTOR_PIN).is_some() {
+            try!(pool.unpin(DESCRIPTOR_PIN));
+        }
+        pool.pin_root(DESCRIPTOR_PIN, &block)
+    }
+
+    /// Make root the current one in the pinned descriptor, in place.
+    /// Returns false if the pool has no descriptor to update.
+    pub fn record_root(pool: &Pool, root: RootSlot) -> Result<bool, LodestoneError> {
+        TreeDescriptor::update(pool, |descriptor| {
+            descriptor.head = (descriptor.head + 1) % N;
+            descriptor.roots[descriptor.head] = root;
+        })
+    }
+
+    /// Correct the current root's entry count, in place
+    pub fn record_entries(pool: &Pool, entries: usize) -> Result<bool, LodestoneError> {
+        TreeDescriptor::update(pool, |descriptor| descriptor.roots[descriptor.head % N].entries = entries)
+    }
+
+    /// Record the B every node is now written with
+    pub fn record_capacity(pool: &Pool, capacity: usize) -> Result<bool, LodestoneError> {
+        TreeDescriptor::update(pool, |descriptor| descriptor.node_capacity = capacity as u8)
+    }
+
+    /// The B the tree's nodes were written with, None if it wasn't recorded
+    pub fn node_capacity(&self) -> Option<usize> {
+        match self.node_capacity {
+            0 => None,
+            capacity => Some(capacity as usize),
+        }
+    }
+
+    pub fn root(&self) -> RootSlot {
+        self.roots[self.head % N]
+    }
+
+    /// The roots of the last N commits, newest first
+    pub fn recent_roots(&self) -> Vec<RootSlot> {
+        (0..N).map(|i| self.roots[(self.head + N - i) % N]).filter(|r| r.index != 0).collect()
+    }
+
+    pub fn options(&self) -> TreeOptions {
+        TreeOptions {
+            entry_checksums: self.flags & FLAG_ENTRY_CHECKSUMS != 0,
+            verify_checksums: decode_bool(self.verify_checksums),
+            fill_cache: decode_bool(self.fill_cache),
+            durability: match self.durability {
+                1 => Some(Durability::Buffered),
+                2 => Some(Durability::Synced),
+                _ => None,
+            },
+            integrity_sample_one_in: self.integrity_sample_one_in,
+            access_sample_one_in: self.access_sample_one_in,
+            message_buffer: if self.flags & FLAG_MESSAGE_BUFFER != 0 { self.message_buffer as usize } else { 0 },
+            relocatable: self.flags & FLAG_RELOCATABLE != 0,
+        }
+    }
+
+    fn update<F: FnOnce(&mut TreeDescriptor)>(pool: &Pool, change: F) -> Result<bool, LodestoneError> {
+        let block = match pool.pinned(DESCRIPTOR_PIN) {
+            Some(reference) => try!(pool.resolve(&reference)),
+            None => return Ok(false),
+        };
+        try!(TreeDescriptor::check(&block));
+        change(block.deref_as_mut::<TreeDescriptor>());
+        pool.mark_written(&block);
+        Ok(true)
+    }
+
+    fn check<'a>(block: &'a ArcByteSlice) -> Result<&'a TreeDescriptor, LodestoneError> {
+        if block.len() != ::std::mem::size_of::<TreeDescriptor>() {
+            return Err(LodestoneError::Corruption("Tree descriptor is the wrong size"));
+        }
+        let descriptor = block.deref_as::<TreeDescriptor>();
+        if descriptor.magic != DESCRIPTOR_MAGIC {
+            return Err(LodestoneError::Corruption("Tree descriptor has a bad magic number"));
+        }
+        if descriptor.version != DESCRIPTOR_VERSION {
+            return Err(LodestoneError::Format(FormatMismatch {
+                field: "tree descriptor version",
+                expected: DESCRIPTOR_VERSION as u64,
+                found: descriptor.version as u64,
+            }));
+        }
+        Ok(descriptor)
+    }
+}
+
+fn encode_bool(value: Option<bool>) -> u8 {
+    value.map_or(0, |v| 1 + v as u8)
+}
+
+fn decode_bool(value: u8) -> Option<bool> {
+    match value {
+        0 => None,
+        v => Some(v == 2),
+    }
+}
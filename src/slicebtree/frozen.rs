@@ -0,0 +1,105 @@
+/// A read-only copy of a tree's index on the heap, for phases that only
+/// read, like serving an index that's done being built. Internal nodes are
+/// decoded once, with direct links to their children instead of references
+/// to resolve, and each leaf's keys sit in a sorted array of their own, so
+/// a lookup is a few binary searches without touching the pool. A frozen
+/// tree is a snapshot: writes after freezing aren't seen, and the values
+/// it holds stay alive until it's dropped. Nothing persisted changes.
+use std::mem;
+
+use allocator::*;
+use super::BTree;
+use super::node::Node;
+use LodestoneError;
+
+pub struct FrozenTree<'a> {
+    tree: &'a BTree<'a>,
+    root: Option<FrozenNode<'a>>,
+    len: usize,
+}
+
+enum FrozenNode<'a> {
+    /// Child i holds the keys <= keys[i], the last child the rest
+    Internal { keys: Vec<Vec<u8>>, children: Vec<FrozenNode<'a>> },
+    Leaf { keys: Vec<Vec<u8>>, values: Vec<ArcByteSlice<'a>> },
+}
+
+impl<'a> FrozenTree<'a> {
+    /// Freeze the tree under root, None for an empty tree
+    pub(crate) fn new(tree: &'a BTree<'a>, root: Option<PersistedArcByteSlice>, pool: &'a Pool)
+        -> Result<FrozenTree<'a>, LodestoneError> {
+        let mut len = 0;
+        let root = match root {
+            Some(root) => Some(try!(freeze(&root, pool, &mut len))),
+            None => None,
+        };
+        Ok(FrozenTree {
+            tree: tree,
+            root: root,
+            len: len,
+        })
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        let key = self.tree.normalize_key(key);
+        let mut node = match self.root {
+            Some(ref root) => root,
+            None => return None,
+        };
+        loop {
+            match *node {
+                FrozenNode::Internal { ref keys, ref children } => {
+                    let i = match keys.binary_search_by(|k| (&k[..]).cmp(&key)) {
+                        Ok(i) => i,
+                        Err(i) => i,
+                    };
+                    node = &children[i];
+                },
+                FrozenNode::Leaf { ref keys, ref values } => {
+                    return keys.binary_search_by(|k| (&k[..]).cmp(&key)).ok().map(|i| &values[i][..]);
+                },
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Rough heap footprint of the index, not counting the values
+    pub fn heap_size(&self) -> usize {
+        self.root.as_ref().map_or(0, |root| root.heap_size())
+    }
+}
+
+impl<'a> FrozenNode<'a> {
+    fn heap_size(&self) -> usize {
+        match *self {
+            FrozenNode::Internal { ref keys, ref children } =>
+                keys.iter().map(|k| k.len()).sum::<usize>()
+                    + children.iter().map(|c| mem::size_of::<FrozenNode>() + c.heap_size()).sum::<usize>(),
+            FrozenNode::Leaf { ref keys, ref values } =>
+                keys.iter().map(|k| k.len()).sum::<usize>() + values.len() * mem::size_of::<ArcByteSlice>(),
+        }
+    }
+}
+
+fn freeze<'a>(persist: &PersistedArcByteSlice, pool: &'a Pool, len: &mut usize) -> Result<FrozenNode<'a>, LodestoneError> {
+    let arc = try!(persist.clone_to_arc_byte_slice(pool));
+    let node = arc.deref_as::<Node>();
+    if node.is_leaf() {
+        let (keys, values) = try!(node.leaf_node_decode(pool));
+        *len += keys.len();
+        return Ok(FrozenNode::Leaf { keys: keys, values: values });
+    }
+    let decoded = try!(node.internal_node_decode(pool));
+    let mut children = Vec::with_capacity(decoded.children.len());
+    for child in decoded.children.iter() {
+        children.push(try!(freeze(&try!(pool.take_reference(child)), pool, len)));
+    }
+    Ok(FrozenNode::Internal { keys: decoded.keys, children: children })
+}
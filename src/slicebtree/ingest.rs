@@ -0,0 +1,176 @@
+/// Bulk importing pre-sorted data, like ingesting an SST: the pairs are
+/// built into a subtree bottom up with node::bulk_build rather than
+/// inserted one at a time. A sorted file is a sequence of records, each a
+/// little endian u32 key length, the key, a u32 value length and the value,
+/// in ascending key order.
+use std::io::{self, Read, Write};
+use std::thread;
+
+use allocator::*;
+use super::node::{build_levels, bulk_build, copy_leaves};
+use LodestoneError;
+
+/// Reads the records of a sorted file in order
+pub struct SortedRecords<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> SortedRecords<R> {
+    pub fn new(reader: R) -> SortedRecords<R> {
+        SortedRecords { reader: reader }
+    }
+
+    /// None at a clean end of the file, between records
+    fn read_len(&mut self) -> Result<Option<usize>, LodestoneError> {
+        let mut bytes = [0u8; 4];
+        let mut read = 0;
+        while read < 4 {
+            match self.reader.read(&mut bytes[read..]) {
+                Ok(0) if read == 0 => return Ok(None),
+                Ok(0) => return Err(LodestoneError::UserError("Sorted file ends inside a record")),
+                Ok(n) => read += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(_) => return Err(LodestoneError::Storage("Couldn't read the sorted file")),
+            }
+        }
+        Ok(Some(bytes.iter().rev().fold(0, |n, &b| n << 8 | b as usize)))
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>, LodestoneError> {
+        let mut bytes = vec![0u8; len];
+        match self.reader.read_exact(&mut bytes) {
+            Ok(()) => Ok(bytes),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof =>
+                Err(LodestoneError::UserError("Sorted file ends inside a record")),
+            Err(_) => Err(LodestoneError::Storage("Couldn't read the sorted file")),
+        }
+    }
+
+    fn read_record(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>, LodestoneError> {
+        let key_len = match try!(self.read_len()) {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        let key = try!(self.read_bytes(key_len));
+        let value_len = try!(try!(self.read_len())
+            .ok_or(LodestoneError::UserError("Sorted file ends inside a record")));
+        let value = try!(self.read_bytes(value_len));
+        Ok(Some((key, value)))
+    }
+}
+
+impl<R: Read> Iterator for SortedRecords<R> {
+    type Item = Result<(Vec<u8>, Vec<u8>), LodestoneError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.read_record() {
+            Ok(Some(pair)) => Some(Ok(pair)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Write one record of a sorted file
+pub fn write_record<W: Write>(writer: &mut W, key: &[u8], value: &[u8]) -> io::Result<()> {
+    for bytes in [key, value].iter() {
+        let len = bytes.len() as u32;
+        try!(writer.write_all(&[len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8]));
+        try!(writer.write_all(bytes));
+    }
+    Ok(())
+}
+
+/// Build a sorted file into a subtree of its own, ready to be linked into
+/// a tree. None if the file was empty.
+pub fn build_subtree<'p, R: Read>(reader: R, tx_id: usize, fill: usize, pool: &'p Pool)
+    -> Result<Option<ArcByteSlice<'p>>, LodestoneError> {
+    bulk_build(SortedRecords::new(reader), tx_id, fill, pool)
+}
+
+/// Build pre-partitioned sorted streams on a thread each, every one into
+/// a private pool of partition_size bytes, then stitch them together in
+/// pool under a new root. Partitions must be in key order and mustn't
+/// overlap. Pools can't be shared between threads, so the stitch (copying
+/// the leaves in and building the levels above them) runs on the caller's
+/// thread; reading, checking and building the partitions is what runs in
+/// parallel. None if every partition was empty.
+pub fn build_partitioned<'p, I>(partitions: Vec<I>, tx_id: usize, fill: usize, partition_size: usize, pool: &'p Pool)
+    -> Result<Option<ArcByteSlice<'p>>, LodestoneError>
+    where I: IntoIterator<Item=Result<(Vec<u8>, Vec<u8>), LodestoneError>> + Send + 'static {
+    let builders: Vec<_> = partitions.into_iter().map(|partition| thread::spawn(move || {
+        let private = Pool::with_backend(Box::new(HeapBackend::new(partition_size)));
+        let root = try!(bulk_build(partition, tx_id, fill, &private)).map(|root| root.clone_to_persisted());
+        Ok((private, root))
+    })).collect();
+    // Join every builder before giving up on any of them
+    let built: Vec<Result<_, LodestoneError>> = builders.into_iter()
+        .map(|builder| builder.join().unwrap_or(Err(LodestoneError::UserError("A partition build panicked"))))
+        .collect();
+    let mut leaves = Vec::new();
+    for result in built {
+        let (private, root) = try!(result);
+        if let Some(root) = root {
+            try!(copy_leaves(&root, &private, pool, tx_id, &mut leaves));
+        }
+    }
+    build_levels(leaves, tx_id, fill, pool)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use allocator::*;
+    use slicebtree::node::{count_entries, find_value, snapshot};
+    use LodestoneError;
+
+    #[test]
+    fn test_build_subtree_from_file() {
+        let mut file = Vec::new();
+        for i in 0..120u32 {
+            write_record(&mut file, format!("{:04}", i).as_bytes(), &vec![7; i as usize]).unwrap();
+        }
+        let records: Vec<_> = SortedRecords::new(&file[..]).collect::<Result<_, _>>().unwrap();
+        assert_eq!(120, records.len());
+        assert_eq!((b"0003".to_vec(), vec![7; 3]), records[3]);
+
+        let mut buf = vec![0u8; 0x100000];
+        let pool = Pool::new(&mut buf);
+        let root = build_subtree(&file[..], 1, 60, &pool).unwrap().unwrap();
+        let picture = snapshot(&root.clone_to_persisted(), &pool).unwrap();
+        assert_eq!(2, picture.children.len());
+        assert_eq!(b"0059".to_vec(), picture.keys[0]);
+        assert!(build_subtree(&[][..], 1, 60, &pool).unwrap().is_none());
+
+        // Cut off part way through the last record
+        assert!(build_subtree(&file[..file.len() - 3], 1, 60, &pool).is_err());
+    }
+
+    #[test]
+    fn test_build_partitioned() {
+        let partition = |from: usize, to: usize| -> Vec<Result<(Vec<u8>, Vec<u8>), LodestoneError>> {
+            (from..to).map(|i| Ok((format!("{:05}", i).into_bytes(), vec![i as u8]))).collect()
+        };
+        let mut buf = vec![0u8; 0x400000];
+        let pool = Pool::new(&mut buf);
+        let partitions = vec![partition(0, 1000), partition(1000, 1001), vec![], partition(1001, 2500)];
+        let root = build_partitioned(partitions, 1, 50, 0x200000, &pool).unwrap().unwrap();
+
+        // 51 leaves (the one entry partition is a leaf of its own), so
+        // two evenly filled nodes above them
+        let picture = snapshot(&root.clone_to_persisted(), &pool).unwrap();
+        assert_eq!(2, picture.children.len());
+        let persisted = root.clone_to_persisted();
+        assert_eq!(2500, count_entries(&persisted, &pool).unwrap());
+        assert_eq!(Some(vec![77u8]), find_value(&root, &pool, b"00077").unwrap().map(|v| v.to_vec()));
+        assert_eq!(Some(vec![(2222 % 256) as u8]), find_value(&root, &pool, b"02222").unwrap().map(|v| v.to_vec()));
+
+        let overlapping = vec![partition(0, 100), partition(50, 150)];
+        assert!(build_partitioned(overlapping, 1, 50, 0x100000, &pool).is_err());
+        let empty: Vec<Vec<Result<(Vec<u8>, Vec<u8>), LodestoneError>>> = vec![vec![], vec![]];
+        assert!(build_partitioned(empty, 1, 50, 0x10000, &pool).unwrap().is_none());
+        // Errors in a partition come back to the caller
+        let broken = vec![partition(0, 10), vec![Err(LodestoneError::UserError("bad record"))]];
+        assert!(build_partitioned(broken, 1, 50, 0x10000, &pool).is_err());
+    }
+}
@@ -0,0 +1,91 @@
+/// Iterating over every entry of a tree in key order. Like a snapshot,
+/// the iterator holds a count on the root it started from, so writers
+/// carry on around it and it keeps reading the version it started with,
+/// however long it lives. An iterator can be limited to the keys with a
+/// given prefix, in which case it starts by seeking to the first of them
+/// and stops at the first key past them, without visiting the rest.
+use super::BTree;
+use super::node::{release_node_traced, Cursor};
+use allocator::{ArcByteSlice, PersistedArcByteSlice, Pool};
+use LodestoneError;
+
+pub struct Iter<'a> {
+    tree: &'a BTree<'a>,
+    pool: &'a Pool<'a>,
+    /// None for an empty tree
+    root: Option<PersistedArcByteSlice>,
+    cursor: Cursor<'a>,
+    /// Empty to visit every entry
+    prefix: Vec<u8>,
+    error: Option<LodestoneError>,
+}
+
+impl<'a> Iter<'a> {
+    /// An iterator over the entries of tree under root whose keys start
+    /// with prefix. root is the tree's own reference (the iterator takes
+    /// a count of its own), or the error getting it.
+    pub(crate) fn of_tree(tree: &'a BTree<'a>, pool: &'a Pool<'a>, root: Result<Option<PersistedArcByteSlice>, LodestoneError>,
+        prefix: &[u8]) -> Iter<'a> {
+        let mut iter = Iter {
+            tree: tree,
+            pool: pool,
+            root: None,
+            cursor: Cursor::empty(),
+            prefix: prefix.to_vec(),
+            error: None,
+        };
+        let started = root.and_then(|root| match root {
+            Some(root) => {
+                iter.root = Some(try!(root.clone(pool)));
+                Cursor::seek(&root, pool, prefix)
+            },
+            None => Ok(Cursor::empty()),
+        });
+        match started {
+            Ok(cursor) => iter.cursor = cursor,
+            Err(e) => iter.error = Some(e),
+        }
+        iter
+    }
+
+    /// What ended the iteration early, if anything did. A corrupt tree
+    /// or a poisoned one ends it at the first entry that can't be read.
+    pub fn error(&self) -> Option<&LodestoneError> {
+        self.error.as_ref()
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (ArcByteSlice<'a>, ArcByteSlice<'a>);
+
+    fn next(&mut self) -> Option<(ArcByteSlice<'a>, ArcByteSlice<'a>)> {
+        if self.error.is_some() {
+            return None;
+        }
+        match self.cursor.next(self.pool) {
+            Ok(Some((ref key, _))) if !key.starts_with(&self.prefix) => {
+                // Past the prefix, and so past every key that has it
+                self.cursor = Cursor::empty();
+                None
+            },
+            Ok(entry) => entry,
+            Err(e) => {
+                self.error = Some(e);
+                None
+            },
+        }
+    }
+}
+
+impl<'a> Drop for Iter<'a> {
+    fn drop(&mut self) {
+        // The cursor's Arcs go first, so that if the tree has moved on
+        // the root's count is the last one and its version is reclaimed
+        self.cursor = Cursor::empty();
+        if let Some(mut root) = self.root.take() {
+            let tree = self.tree;
+            // Drop has nowhere to report a corrupt version to, see Snapshot
+            let _ = release_node_traced(&mut root, self.pool, &|value: &[u8]| tree.extract_references(value));
+        }
+    }
+}
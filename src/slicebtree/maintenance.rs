@@ -0,0 +1,216 @@
+/// One place to run maintenance work (GC, compaction, integrity sampling,
+/// ...) instead of ad-hoc calls. Tasks are registered by name and given a
+/// slice of a per-tick budget of time and IO in turn, round robin, so one
+/// busy task can't starve the rest. Each tick hands the tasks a context,
+/// e.g. the BTree they look after (see BTree::maintenance), and is driven
+/// by the caller, or for tasks that need none by a background thread
+/// started with spawn.
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::SeqCst;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use LodestoneError;
+
+/// How much a single tick may spend across all tasks
+#[derive(Debug, Clone)]
+pub struct MaintenanceBudget {
+    pub time: Duration,
+    pub io_bytes: usize,
+}
+
+/// What is left of the tick's budget, handed to each task
+#[derive(Debug, Clone)]
+pub struct Budget {
+    pub deadline: Instant,
+    pub io_bytes: usize,
+}
+
+/// What a task reports back after running
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Progress {
+    pub io_bytes: usize,
+    /// Whether the task stopped short for lack of budget
+    pub more: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TaskStats {
+    pub runs: usize,
+    pub errors: usize,
+    pub io_bytes: usize,
+    pub time: Duration,
+    /// The last run stopped short
+    pub pending: bool,
+}
+
+struct Task<C> {
+    name: &'static str,
+    run: Box<FnMut(&C, &Budget) -> Result<Progress, LodestoneError> + Send>,
+    stats: TaskStats,
+}
+
+pub struct Maintenance<C = ()> {
+    budget: MaintenanceBudget,
+    tasks: Vec<Task<C>>,
+    /// Where the next tick starts, so every task gets a turn
+    next: usize,
+}
+
+impl<C> Maintenance<C> {
+    pub fn new(budget: MaintenanceBudget) -> Maintenance<C> {
+        Maintenance {
+            budget: budget,
+            tasks: Vec::new(),
+            next: 0,
+        }
+    }
+
+    /// Tasks should do what they can within the budget they're given
+    /// and report whether anything is left over
+    pub fn register<F>(&mut self, name: &'static str, run: F)
+        where F: FnMut(&C, &Budget) -> Result<Progress, LodestoneError> + Send + 'static {
+        self.tasks.push(Task {
+            name: name,
+            run: Box::new(run),
+            stats: TaskStats::default(),
+        });
+    }
+
+    pub fn stats(&self, name: &str) -> Option<&TaskStats> {
+        self.tasks.iter().find(|t| t.name == name).map(|t| &t.stats)
+    }
+
+    /// Run tasks until the budget runs out or each had a turn.
+    /// Returns how many tasks ran. Errors are counted, not returned,
+    /// so one failing task doesn't stop the others.
+    pub fn tick(&mut self, context: &C) -> usize {
+        let start = Instant::now();
+        let mut budget = Budget {
+            deadline: start + self.budget.time,
+            io_bytes: self.budget.io_bytes,
+        };
+        let count = self.tasks.len();
+        let mut ran = 0;
+        while ran < count && budget.io_bytes > 0 && Instant::now() < budget.deadline {
+            let task = &mut self.tasks[(self.next + ran) % count];
+            let task_start = Instant::now();
+            let result = (task.run)(context, &budget);
+            task.stats.runs += 1;
+            task.stats.time += task_start.elapsed();
+            match result {
+                Ok(progress) => {
+                    task.stats.io_bytes += progress.io_bytes;
+                    task.stats.pending = progress.more;
+                    budget.io_bytes = budget.io_bytes.saturating_sub(progress.io_bytes);
+                },
+                Err(_) => task.stats.errors += 1,
+            }
+            ran += 1;
+        }
+        if count > 0 {
+            self.next = (self.next + ran) % count;
+        }
+        ran
+    }
+}
+
+/// Ticks a Maintenance from a background thread until stopped
+pub struct Background {
+    maintenance: Arc<Mutex<Maintenance>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Tick every interval on a background thread
+pub fn spawn(maintenance: Maintenance, interval: Duration) -> Background {
+    let maintenance = Arc::new(Mutex::new(maintenance));
+    let stop = Arc::new(AtomicBool::new(false));
+    let (m, s) = (maintenance.clone(), stop.clone());
+    let thread = thread::spawn(move || {
+        while !s.load(SeqCst) {
+            m.lock().unwrap().tick(&());
+            thread::sleep(interval);
+        }
+    });
+    Background {
+        maintenance: maintenance,
+        stop: stop,
+        thread: Some(thread),
+    }
+}
+
+impl Background {
+    /// Look at the stats without stopping the thread
+    pub fn with<T, F: FnOnce(&Maintenance) -> T>(&self, f: F) -> T {
+        f(&*self.maintenance.lock().unwrap())
+    }
+
+    /// Stop ticking and hand the Maintenance back
+    pub fn stop(mut self) -> Maintenance {
+        self.join();
+        let empty = Maintenance::new(MaintenanceBudget { time: Duration::from_millis(0), io_bytes: 0 });
+        mem::replace(&mut *self.maintenance.lock().unwrap(), empty)
+    }
+
+    fn join(&mut self) {
+        self.stop.store(true, SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+    }
+}
+
+impl Drop for Background {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use LodestoneError;
+
+    fn budget(io_bytes: usize) -> MaintenanceBudget {
+        MaintenanceBudget { time: Duration::from_secs(10), io_bytes: io_bytes }
+    }
+
+    #[test]
+    fn test_tick_shares_budget() {
+        let mut m = Maintenance::new(budget(100));
+        // Always wants more than it can have
+        m.register("gc", |_, b| Ok(Progress { io_bytes: b.io_bytes, more: true }));
+        m.register("compaction", |_, _| Ok(Progress { io_bytes: 10, more: false }));
+        m.register("sampling", |_, _| Err(LodestoneError::Corruption("bad node")));
+
+        // gc eats the whole budget, so the others wait for the next tick,
+        // which starts with them and leaves gc the rest
+        assert_eq!(1, m.tick(&()));
+        assert_eq!(3, m.tick(&()));
+        assert_eq!(2, m.stats("gc").unwrap().runs);
+        assert_eq!(1, m.stats("compaction").unwrap().runs);
+        assert_eq!(1, m.stats("sampling").unwrap().errors);
+        assert!(m.stats("gc").unwrap().pending);
+        assert_eq!(100 + 90, m.stats("gc").unwrap().io_bytes);
+        assert!(m.stats("nope").is_none());
+
+        // An empty scheduler has nothing to do
+        assert_eq!(0, Maintenance::new(budget(100)).tick(&()));
+    }
+
+    #[test]
+    fn test_background() {
+        let mut m = Maintenance::new(budget(100));
+        m.register("task", |_, _| Ok(Progress { io_bytes: 1, more: false }));
+        let background = spawn(m, Duration::from_millis(1));
+        while background.with(|m| m.stats("task").unwrap().runs) < 3 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let m = background.stop();
+        assert!(m.stats("task").unwrap().runs >= 3);
+    }
+}
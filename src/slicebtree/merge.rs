@@ -0,0 +1,44 @@
+/// How to settle a key that exists in both trees when merging one into
+/// the other. resolve has the shape expected by Node::leaf_node_update,
+/// so a merge is a series of updates with the incoming values.
+use LodestoneError;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictPolicy {
+    /// The incoming value replaces the existing one
+    LastWriteWins,
+    /// The existing value is left alone
+    KeepExisting,
+    /// Differing values abort the merge
+    Error,
+}
+
+pub fn resolve(policy: ConflictPolicy, current: Option<&[u8]>, incoming: &[u8]) -> Result<Option<Vec<u8>>, LodestoneError> {
+    match current {
+        None => Ok(Some(incoming.to_vec())),
+        // Nothing to do, and no need to copy the value
+        Some(existing) if existing == incoming => Ok(None),
+        Some(_) => match policy {
+            ConflictPolicy::LastWriteWins => Ok(Some(incoming.to_vec())),
+            ConflictPolicy::KeepExisting => Ok(None),
+            ConflictPolicy::Error => Err(LodestoneError::UserError("Conflicting values for key during merge")),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::ConflictPolicy::*;
+
+    #[test]
+    fn test_resolve() {
+        for policy in [LastWriteWins, KeepExisting, Error].iter() {
+            assert_eq!(Some(b"new".to_vec()), resolve(*policy, None, b"new").unwrap());
+            assert_eq!(None, resolve(*policy, Some(b"same"), b"same").unwrap());
+        }
+        assert_eq!(Some(b"new".to_vec()), resolve(LastWriteWins, Some(b"old"), b"new").unwrap());
+        assert_eq!(None, resolve(KeepExisting, Some(b"old"), b"new").unwrap());
+        assert!(resolve(Error, Some(b"old"), b"new").is_err());
+    }
+}
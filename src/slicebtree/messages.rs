@@ -0,0 +1,149 @@
+/// Message buffers for the write-optimized mode (TreeOptions::message_buffer).
+/// An internal node can hold a buffer of writes bound for the leaves
+/// beneath it, one message per key, sorted by key. A message puts a value
+/// or removes the key, and is newer than anything below the node holding
+/// it. Buffers are flushed a child's worth at a time once they fill, so
+/// many writes share the copy of each node on the way down.
+///
+/// A buffer is a block of its own: the message count, then per message
+/// the length prefixed key, 1 for a put (followed by a Reference to the
+/// value block) or 0 for a remove. The buffer holds a count on every
+/// value it refers to.
+use allocator::{ArcByteSlice, Pool, Reference, REFERENCE_SIZE};
+use codec::*;
+use LodestoneError;
+
+const REMOVE: u8 = 0;
+const PUT: u8 = 1;
+
+pub struct Message<'p> {
+    pub key: Vec<u8>,
+    /// None removes the key
+    pub value: Option<ArcByteSlice<'p>>,
+}
+
+/// Encode messages as a buffer, taking a count on each value
+pub fn encode(messages: &[Message], pool: &Pool) -> Result<Vec<u8>, LodestoneError> {
+    let mut bytes = vec![0; WORD];
+    write_word_le(&mut bytes, 0, messages.len());
+    for message in messages.iter() {
+        let at = bytes.len();
+        bytes.resize(at + WORD, 0);
+        write_word_le(&mut bytes, at, message.key.len());
+        bytes.extend_from_slice(&message.key);
+        match message.value {
+            Some(ref value) => {
+                bytes.push(PUT);
+                let held = try!(pool.persist(value));
+                bytes.extend_from_slice(&Reference::from_persisted(&held).to_bytes());
+            },
+            None => bytes.push(REMOVE),
+        }
+    }
+    Ok(bytes)
+}
+
+/// The keys and value references in a buffer, without resolving them
+pub fn read(bytes: &[u8]) -> Result<Vec<(&[u8], Option<Reference>)>, LodestoneError> {
+    let count = try!(get_word(bytes, 0));
+    let mut at = WORD;
+    let mut found = Vec::new();
+    for _ in 0..count {
+        let len = try!(get_word(bytes, at));
+        at += WORD;
+        if bytes.len() < at + len + 1 {
+            return Err(LodestoneError::Corruption("Message buffer is truncated"));
+        }
+        let key = &bytes[at..at + len];
+        at += len + 1;
+        let value = match bytes[at - 1] {
+            REMOVE => None,
+            PUT if bytes.len() >= at + REFERENCE_SIZE => {
+                at += REFERENCE_SIZE;
+                Some(try!(Reference::from_bytes(&bytes[at - REFERENCE_SIZE..at])))
+            },
+            _ => return Err(LodestoneError::Corruption("Message buffer holds a bad message")),
+        };
+        found.push((key, value));
+    }
+    Ok(found)
+}
+
+pub fn decode<'p>(bytes: &[u8], pool: &'p Pool) -> Result<Vec<Message<'p>>, LodestoneError> {
+    let mut messages = Vec::new();
+    for (key, value) in try!(read(bytes)) {
+        messages.push(Message {
+            key: key.to_vec(),
+            value: match value {
+                Some(reference) => Some(try!(pool.resolve(&reference))),
+                None => None,
+            },
+        });
+    }
+    Ok(messages)
+}
+
+/// The message for key, if the buffer has one: Some(None) for a remove
+pub fn find<'p>(bytes: &[u8], key: &[u8], pool: &'p Pool) -> Result<Option<Option<ArcByteSlice<'p>>>, LodestoneError> {
+    for (k, value) in try!(read(bytes)) {
+        if k == key {
+            return match value {
+                Some(reference) => pool.resolve(&reference).map(|v| Some(Some(v))),
+                None => Ok(Some(None)),
+            };
+        }
+    }
+    Ok(None)
+}
+
+/// Both sorted lists of messages as one, newer winning over older
+pub fn merge<'p>(older: Vec<Message<'p>>, newer: Vec<Message<'p>>) -> Vec<Message<'p>> {
+    let mut merged = Vec::with_capacity(older.len() + newer.len());
+    let mut older = older.into_iter().peekable();
+    for message in newer {
+        while older.peek().map_or(false, |m| m.key < message.key) {
+            merged.push(older.next().unwrap());
+        }
+        if older.peek().map_or(false, |m| m.key == message.key) {
+            older.next();
+        }
+        merged.push(message);
+    }
+    merged.extend(older);
+    merged
+}
+
+fn get_word(bytes: &[u8], at: usize) -> Result<usize, LodestoneError> {
+    if bytes.len() < at + WORD {
+        return Err(LodestoneError::Corruption("Message buffer is truncated"));
+    }
+    Ok(read_word_le(bytes, at))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use allocator::Pool;
+
+    #[test]
+    fn test_encode_merge_find() {
+        let mut buf = vec![0u8; 0x4000];
+        let pool = Pool::new(&mut buf);
+        let put = |key: &[u8], value: &[u8]| Message { key: key.to_vec(), value: Some(pool.malloc(value).unwrap()) };
+        let remove = |key: &[u8]| Message { key: key.to_vec(), value: None };
+
+        let merged = merge(vec![put(b"a", b"1"), put(b"c", b"3"), put(b"e", b"5")],
+            vec![remove(b"c"), put(b"d", b"4"), put(b"e", b"five")]);
+        let keys: Vec<&[u8]> = merged.iter().map(|m| &m.key[..]).collect();
+        assert_eq!(vec![&b"a"[..], b"c", b"d", b"e"], keys);
+
+        let bytes = encode(&merged, &pool).unwrap();
+        drop(merged);
+        // The buffer's counts keep the values alive
+        assert_eq!(&b"five"[..], &find(&bytes, b"e", &pool).unwrap().unwrap().unwrap()[..]);
+        assert!(find(&bytes, b"c", &pool).unwrap().unwrap().is_none());
+        assert!(find(&bytes, b"b", &pool).unwrap().is_none());
+        assert_eq!(4, decode(&bytes, &pool).unwrap().len());
+        assert!(read(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
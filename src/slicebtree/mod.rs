@@ -2,45 +2,1356 @@
 /// Supports MVCC up to 2 revisions
 /// Lives entirely within the slice that is given to it.
 /// Keys and Values are byte slices.
+use self::access::{AccessStats, HotRange, DEFAULT_DECAY_EVERY};
+use self::blocking::*;
+use self::consistency::{CommitToken, CommitWatch};
+use self::descriptor::{RootSlot, TreeDescriptor};
+use self::maintenance::{Budget, Maintenance, MaintenanceBudget, Progress};
+use self::node_cache::{CacheStats, NodeCache, DEFAULT_NODE_CACHE_BYTES};
 use self::node::*;
-use std::sync::atomic::AtomicUsize;
+use self::normalize::KeyNormalizer;
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::atomic::Ordering::{Relaxed, SeqCst};
+use std::time::{Duration, Instant};
+use std::vec;
 use allocator::*;
+use allocator::sync::*;
+use {FormatMismatch, LodestoneError};
 
 pub mod node;
+pub mod descent;
+pub mod numeric;
+pub mod merge;
+pub mod retry;
+pub mod node_cache;
+pub mod options;
+pub mod maintenance;
+pub mod replication;
+pub mod prefixes;
+pub mod normalize;
+pub mod ingest;
+pub mod scan;
+pub mod blocking;
+pub mod access;
+pub mod system;
+pub mod catalog;
+pub mod snapshot;
+pub mod descriptor;
+pub mod frozen;
+pub mod consistency;
+pub mod iter;
+pub mod messages;
+pub mod startup;
+
+pub use self::options::*;
 
 pub const N: usize = 2;
 pub const B: usize = 100;
 pub const NOT_FOUND: usize = B+1;
+/// How much of a tree open verifies, see node::verify_quick
+const OPEN_VERIFY_LEVELS: usize = 2;
+const OPEN_VERIFY_SAMPLES: usize = 4;
+/// Subtree digests kept before the cache is cleared, so digests of
+/// freed nodes don't pile up
+const DIGEST_CACHE_ENTRIES: usize = 1 << 16;
+/// The system key the high water mark of applied changes is kept under,
+/// see replication
+const REPLICATION_HIGH_WATER: &'static [u8] = b"replication high water";
 
 /// Maps arbitrary [u8] to [u8].
 /// One value per key
-pub struct BTree {
-    page_pool: Pool,
+///
+/// ```
+/// use lodestone::BTree;
+///
+/// let mut buf = vec![0u8; 0x10000];
+/// let tree = BTree::new(&mut buf);
+/// tree.insert(b"hello", b"world").unwrap();
+/// assert_eq!(&b"world"[..], &tree.get(b"hello").unwrap().unwrap()[..]);
+/// assert!(tree.remove(b"hello").unwrap());
+/// assert!(tree.get(b"hello").unwrap().is_none());
+/// ```
+pub struct BTree<'buf> {
+    page_pool: Pool<'buf>,
+    /// The root node's index, 0 while the tree is empty
     current_root: AtomicUsize,
+    /// And its id tag, so a stale root never resolves
+    root_generation: AtomicUsize,
     tx_id: AtomicUsize,
+    /// Kept up to date by every commit, along with the root
+    entry_count: AtomicUsize,
+    pool_defaults: PoolDefaults,
+    options: TreeOptions,
+    stats: Stats,
+    reference_extractors: Vec<Box<Fn(&[u8]) -> Vec<Reference> + Send>>,
+    sample_state: AtomicUsize,
+    integrity_failure_handlers: Vec<Box<Fn(&LodestoneError) + Send>>,
+    poisoned: AtomicBool,
+    key_normalizer: Option<KeyNormalizer>,
+    blocking: BlockingMonitor,
+    access_stats: Mutex<AccessStats>,
+    /// Decoded internal nodes, for reads that go through them
+    node_cache: Mutex<NodeCache>,
+    /// Kept between digest_range calls, so only what commits copied is
+    /// digested again
+    digests: Mutex<DigestCache>,
+    commits: CommitWatch,
     // roots: Vec<EntryLocation>,
 }
 
+/// Counters describing the work the tree has done
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// Entries checked on the read path, sharded so that counting
+    /// doesn't slow down parallel reads
+    pub checksums_verified: ShardedCounter,
+    pub checksums_failed: ShardedCounter,
+    /// Nodes picked by integrity sampling, and how many of them failed
+    pub samples_verified: AtomicUsize,
+    pub samples_failed: AtomicUsize,
+}
+
 /// Public API
-impl BTree {
-    pub fn new(buf: &mut [u8]) -> BTree {
+impl<'buf> BTree<'buf> {
+    pub fn new(buf: &'buf mut [u8]) -> BTree<'buf> {
+        BTree::with_options(buf, TreeOptions::default())
+    }
+
+    pub fn with_options(buf: &'buf mut [u8], options: TreeOptions) -> BTree<'buf> {
+        BTree::with_config(buf, PoolDefaults::default(), options)
+    }
+
+    pub fn with_config(buf: &'buf mut [u8], pool_defaults: PoolDefaults, options: TreeOptions) -> BTree<'buf> {
         let page_pool = Pool::new(buf);
+        page_pool.record_node_capacity(B);
+        BTree::around(page_pool, pool_defaults, options)
+    }
+
+    /// Rebuild the tree described in page_pool (see describe). Normalizers,
+    /// extractors and handlers aren't stored, and need setting again.
+    /// A tree written with another B fails with a node capacity mismatch,
+    /// unless pool_defaults.migrate_capacity opts in to rewriting it.
+    pub fn from_pool(page_pool: Pool<'buf>, pool_defaults: PoolDefaults) -> Result<BTree<'buf>, LodestoneError> {
+        let descriptor = match try!(TreeDescriptor::load(&page_pool)) {
+            Some(descriptor) => descriptor,
+            None => return Err(LodestoneError::UserError("The pool doesn't describe a tree")),
+        };
+        let tree = BTree::around(page_pool, pool_defaults, descriptor.options());
+        let mut root = descriptor.root();
+        // The journal is checksummed and written first, so it wins if a
+        // crash came before the descriptor caught up with it
+        if let Some(journaled) = tree.page_pool.journaled_root() {
+            if journaled.tx_id > root.tx_id {
+                root = RootSlot {
+                    index: journaled.index,
+                    generation: journaled.generation,
+                    tx_id: journaled.tx_id,
+                    entries: journaled.entries,
+                };
+                try!(TreeDescriptor::record_root(&tree.page_pool, root));
+            }
+        }
+        tree.current_root.store(root.index, SeqCst);
+        tree.root_generation.store(root.generation, SeqCst);
+        tree.tx_id.store(root.tx_id, SeqCst);
+        tree.entry_count.store(root.entries, SeqCst);
+        tree.commits.publish(tree.commit_token());
+        try!(tree.migrate_capacity(&descriptor));
+        Ok(tree)
+    }
 
+    /// Keep a description of the tree in its own pool, updated on every
+    /// commit from now on, so the pool alone is enough for from_pool
+    pub fn describe(&self) -> Result<(), LodestoneError> {
+        try!(TreeDescriptor::new(&self.options).store(&self.page_pool));
+        try!(TreeDescriptor::record_root(&self.page_pool, self.root_slot()));
+        Ok(())
+    }
+
+    /// Give up the tree, keeping its pool. The tree's hold on its root
+    /// stays in the pool for the next from_pool.
+    pub fn into_pool(self) -> Pool<'buf> {
+        self.page_pool
+    }
+
+    fn around(mut page_pool: Pool<'buf>, pool_defaults: PoolDefaults, options: TreeOptions) -> BTree<'buf> {
+        page_pool.set_logical_references(options.relocatable);
         BTree {
             page_pool: page_pool,
             tx_id: AtomicUsize::new(0),
             current_root: AtomicUsize::new(0),
+            root_generation: AtomicUsize::new(0),
+            entry_count: AtomicUsize::new(0),
+            pool_defaults: pool_defaults,
+            options: options,
+            stats: Stats::default(),
+            reference_extractors: Vec::new(),
+            sample_state: AtomicUsize::new(xorshift_seed(0)),
+            integrity_failure_handlers: Vec::new(),
+            poisoned: AtomicBool::new(false),
+            key_normalizer: None,
+            blocking: BlockingMonitor::new(),
+            access_stats: Mutex::new(AccessStats::new(DEFAULT_DECAY_EVERY)),
+            node_cache: Mutex::new(NodeCache::new(DEFAULT_NODE_CACHE_BYTES)),
+            digests: Mutex::new(DigestCache::new()),
+            commits: CommitWatch::new(CommitToken { tx_id: 0, generation: 0 }),
+        }
+    }
+
+    pub fn get<'a>(&'a self, key: &[u8]) -> Result<Option<ArcByteSlice<'a>>, LodestoneError> {
+        self.get_with(key, &ReadOptions::default())
+    }
+
+    pub fn get_with<'a>(&'a self, key: &[u8], options: &ReadOptions) -> Result<Option<ArcByteSlice<'a>>, LodestoneError> {
+        try!(self.check_poisoned());
+        let key = self.normalize_key(key);
+        self.get_normalized(&key, options)
+    }
+
+    /// How many entries the tree holds, without counting them. The
+    /// tree's own system keys aren't counted.
+    pub fn len(&self) -> usize {
+        self.entry_count.load(SeqCst)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Insert key, or replace its value if it's already there
+    pub fn insert(&self, key: &[u8], value: &[u8]) -> Result<(), LodestoneError> {
+        self.insert_value(key, value, None)
+    }
+
+    /// insert, storing value in block, a block of the tree's pool that
+    /// already holds it, if given. Buffered writes copy value regardless.
+    fn insert_value<'t>(&'t self, key: &[u8], value: &[u8], block: Option<&ArcByteSlice<'t>>) -> Result<(), LodestoneError> {
+        try!(self.check_poisoned());
+        let key = self.normalize_key(key);
+        try!(system::check_user_key(&key));
+        let checksummed = self.options.entry_checksums;
+        let added = try!(self.get_normalized(&key, &ReadOptions::default())).is_none() as usize;
+        let entries = self.len() + added;
+        if self.options.message_buffer > 0 {
+            return self.write_message(&key, Some(value), entries);
+        }
+        self.commit_root(entries, Some(&key), |pool, root, tx_id| {
+            let root = match root {
+                Some(root) => root,
+                None => try!(Node::new_leaf(tx_id, checksummed, pool)),
+            };
+            let result = match block {
+                Some(block) => try!(root.deref_as::<Node>().insert_block(tx_id, &key, block, pool)),
+                None => try!(root.deref_as::<Node>().insert(tx_id, &key, value, pool)),
+            };
+            match result {
+                InsertionResult::HadRoom(new_root) => Ok(new_root),
+                InsertionResult::NoRoom(split) => Node::new_root(tx_id, split, pool),
+            }
+        })
+    }
+
+    /// Returns whether the key was there to remove
+    pub fn remove(&self, key: &[u8]) -> Result<bool, LodestoneError> {
+        try!(self.check_poisoned());
+        let key = self.normalize_key(key);
+        try!(system::check_user_key(&key));
+        if try!(self.get_normalized(&key, &ReadOptions::default())).is_none() {
+            return Ok(false);
+        }
+        let entries = self.len().saturating_sub(1);
+        if self.options.message_buffer > 0 {
+            try!(self.write_message(&key, None, entries));
+            return Ok(true);
+        }
+        try!(self.commit_root(entries, Some(&key), |pool, root, tx_id| {
+            let root = match root {
+                Some(root) => root,
+                None => return Err(LodestoneError::StructureCorrupt("Tree lost its root during remove")),
+            };
+            let result = try!(root.deref_as::<Node>().remove(tx_id, &key, pool));
+            match result {
+                Some(new_root) => Ok(new_root),
+                None => Err(LodestoneError::StructureCorrupt("Key vanished during remove")),
+            }
+        }));
+        Ok(true)
+    }
+
+    /// Remove the entries in [start, end) that pred(key, value) picks, at
+    /// most max_entries of them and out of a single leaf, in one commit.
+    /// Returns how many were removed, and the key to start the next call
+    /// at, None once the range is done, so a clean up can work through a
+    /// big range a little at a time.
+    pub fn delete_where<F>(&self, start: &[u8], end: Option<&[u8]>, pred: F, max_entries: usize)
+        -> Result<(usize, Option<Vec<u8>>), LodestoneError>
+        where F: Fn(&[u8], &[u8]) -> bool {
+        try!(self.check_poisoned());
+        try!(self.flush_messages());
+        let start = self.normalize_key(start);
+        let end = end.map(|end| self.normalize_key(end).into_owned());
+        let in_range = |key: &[u8]| end.as_ref().map_or(true, |end| key < &end[..]);
+        if !in_range(&start) {
+            return Ok((0, None));
+        }
+        let root = match try!(self.root()) {
+            Some(root) => try!(root.clone_to_arc_byte_slice(&self.page_pool)),
+            None => return Ok((0, None)),
+        };
+        let tx_id = self.tx_id.load(SeqCst) + 1;
+        let removal = try!(root.deref_as::<Node>().remove_where(tx_id, &start,
+            &|key: &[u8], value: &[u8]| in_range(key) && pred(key, value), max_entries, &self.page_pool));
+        let resume_from = removal.resume_from.and_then(|key| if in_range(&key) { Some(key) } else { None });
+        if let Some(new_root) = removal.node {
+            try!(self.commit_root(self.len() - removal.removed, Some(&start), |_, _, _| Ok(new_root)));
+        }
+        Ok((removal.removed, resume_from))
+    }
+
+    /// Store n under key if it's greater than the 8 byte big-endian
+    /// integer there, or key has no value. Returns whether it was stored.
+    pub fn put_if_greater(&self, key: &[u8], n: u64) -> Result<bool, LodestoneError> {
+        self.update(key, |current| numeric::if_greater(current, n)).map(|stored| stored.is_some())
+    }
+
+    /// Store n under key if it's less than the 8 byte big-endian integer
+    /// there, or key has no value. Returns whether it was stored.
+    pub fn put_if_less(&self, key: &[u8], n: u64) -> Result<bool, LodestoneError> {
+        self.update(key, |current| numeric::if_less(current, n)).map(|stored| stored.is_some())
+    }
+
+    /// Add delta to the 8 byte big-endian counter under key, which starts
+    /// at 0, and return the new count. Going below 0 or overflowing is an
+    /// error, and leaves the counter alone.
+    pub fn add(&self, key: &[u8], delta: i64) -> Result<u64, LodestoneError> {
+        match try!(self.update(key, |current| numeric::add(current, delta))) {
+            Some(value) => numeric::decode_u64(&value),
+            None => Err(LodestoneError::StructureCorrupt("Counter update stored nothing")),
+        }
+    }
+
+    /// Copy every entry of other into this tree, settling keys both hold
+    /// by policy. Each entry is its own commit, so a ConflictPolicy::Error
+    /// merge that fails keeps what it merged before the conflict. Returns
+    /// how many entries were stored.
+    pub fn merge_from(&self, other: &BTree, policy: merge::ConflictPolicy) -> Result<usize, LodestoneError> {
+        self.merge_from_batched(other, policy, 1, |_| ())
+    }
+
+    /// merge_from, committing batch_size entries of other at a time and
+    /// calling progress with how many have been merged after each batch.
+    /// A failed merge keeps the batches committed before the failure.
+    pub fn merge_from_batched<F>(&self, other: &BTree, policy: merge::ConflictPolicy, batch_size: usize, mut progress: F)
+        -> Result<usize, LodestoneError> where F: FnMut(usize) {
+        try!(self.check_poisoned());
+        let (mut merged, mut stored) = (0, 0);
+        let mut entries = other.iter().peekable();
+        while entries.peek().is_some() {
+            let mut batch: BTreeMap<Vec<u8>, Vec<u8>> = BTreeMap::new();
+            let mut added = 0;
+            for (key, value) in entries.by_ref().take(cmp::max(batch_size, 1)) {
+                let key = self.normalize_key(&key).into_owned();
+                try!(system::check_user_key(&key));
+                // Another of other's keys may normalize to the same one
+                let resolved = match batch.get(&key) {
+                    Some(pending) => try!(merge::resolve(policy, Some(&pending[..]), &value)),
+                    None => {
+                        let current = try!(self.get_normalized(&key, &ReadOptions::default()));
+                        added += current.is_none() as usize;
+                        try!(merge::resolve(policy, current.as_ref().map(|v| &**v), &value))
+                    },
+                };
+                if let Some(resolved) = resolved {
+                    batch.insert(key, resolved);
+                    stored += 1;
+                }
+                merged += 1;
+            }
+            if !batch.is_empty() {
+                let changes = batch.iter().map(|(key, value)| (key.clone(), Some(&value[..]))).collect();
+                try!(self.write_messages(changes, self.len() + added));
+            }
+            progress(merged);
+        }
+        Ok(stored)
+    }
+
+    /// Copy key's entry into other, replacing any value other has for
+    /// it. The value's block is copied straight from this tree's pool into
+    /// other's (see Pool::copy_block) and stored there as it is. Returns
+    /// whether there was an entry to copy.
+    pub fn copy_entry_to(&self, key: &[u8], other: &BTree) -> Result<bool, LodestoneError> {
+        match try!(self.get(key)) {
+            Some(value) => {
+                let copy = try!(self.page_pool.copy_block(&value, &other.page_pool));
+                try!(other.insert_value(key, &copy, Some(&copy)));
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
+    /// Apply a stream of changes shipped from another tree, see
+    /// replication. Each batch is one commit, which also stores the high
+    /// water mark, so the stream can be replayed from any earlier point
+    /// after a crash and only what's new gets applied.
+    pub fn apply_changes<I>(&self, stream: I, batch_size: usize) -> Result<replication::AppliedSummary, LodestoneError>
+        where I: IntoIterator<Item=replication::ChangeRecord> {
+        try!(self.check_poisoned());
+        let high_water = try!(self.replication_high_water());
+        replication::apply_changes(high_water, stream, batch_size, |batch, high_water| {
+            let mut changes = BTreeMap::new();
+            for record in batch {
+                let key = self.normalize_key(&record.key).into_owned();
+                try!(system::check_user_key(&key));
+                // Later changes to a key replace earlier ones
+                changes.insert(key, match record.op {
+                    replication::ChangeOp::Put(ref value) => Some(&value[..]),
+                    replication::ChangeOp::Delete => None,
+                });
+            }
+            let mark = numeric::encode_u64(high_water as u64);
+            changes.insert(system::system_key(REPLICATION_HIGH_WATER), Some(&mark[..]));
+            let (mut added, mut removed) = (0, 0);
+            // System keys aren't entries as far as len goes
+            for (key, value) in changes.iter().filter(|&(key, _)| !system::is_system_key(key)) {
+                match (try!(self.get_normalized(key, &ReadOptions::default())).is_some(), value.is_some()) {
+                    (false, true) => added += 1,
+                    (true, false) => removed += 1,
+                    _ => (),
+                }
+            }
+            self.write_messages(changes, self.len() + added - removed)
+        })
+    }
+
+    /// Fill an empty tree from a sorted file (see ingest), building it
+    /// bottom up with fill entries per node, in one commit, instead of
+    /// inserting the records one by one. Returns how many entries were
+    /// loaded. Keys are stored as they are, so a tree with a key
+    /// normalizer can't be bulk loaded.
+    pub fn bulk_load<R: Read>(&self, reader: R, fill: usize) -> Result<usize, LodestoneError> {
+        try!(self.check_bulk_load());
+        let tx_id = self.tx_id.load(SeqCst) + 1;
+        let root = try!(ingest::build_subtree(reader, tx_id, fill, &self.page_pool));
+        self.commit_bulk_load(root)
+    }
+
+    /// Merge a sorted file (see ingest) into the tree. The records are
+    /// built into a subtree bottom up, fill entries per node, and its
+    /// leaves are linked in among the tree's wherever their key ranges
+    /// don't overlap a leaf of the tree; only the records of the leaves
+    /// that do are inserted one by one. The levels above the leaves are
+    /// built again, in one commit. Returns how many records there were.
+    /// Keys are stored as they are, as with bulk_load.
+    pub fn ingest_sorted_file<R: Read>(&self, reader: R, fill: usize) -> Result<usize, LodestoneError> {
+        try!(self.check_poisoned());
+        try!(self.check_stored_keys());
+        try!(self.flush_messages());
+        let tx_id = self.tx_id.load(SeqCst) + 1;
+        let pool = &self.page_pool;
+        let (subtree, records) = match try!(ingest::build_subtree(reader, tx_id, fill, pool)) {
+            Some(subtree) => try!(self.check_bulk_built(subtree)),
+            None => return Ok(0),
+        };
+        let root = match try!(self.root()) {
+            Some(root) if !self.is_empty() => root,
+            _ => {
+                try!(self.commit_root(records, None, |_, _, _| Ok(subtree)));
+                return Ok(records);
+            },
+        };
+        let mut subtree = {
+            let persisted = subtree.clone_to_persisted();
+            drop(subtree);
+            persisted
+        };
+        let built = node::splice_leaves(&root, &subtree, pool).and_then(|splice| {
+            let merged = try!(node::build_levels(splice.leaves, tx_id, fill, pool));
+            Ok((merged, splice.linked, splice.overlapping))
+        });
+        // Whatever of the subtree wasn't linked in goes with it
+        try!(release_node(&mut subtree, pool));
+        let (merged, linked, overlapping) = try!(built);
+        let merged = try!(merged.ok_or(LodestoneError::StructureCorrupt("A non-empty tree has no leaves")));
+        try!(self.commit_root(self.len() + linked, None, |_, _, _| Ok(merged)));
+        for (key, value) in overlapping {
+            try!(self.insert(&key, &value));
+        }
+        Ok(records)
+    }
+
+    /// bulk_load out of partitions in key order, each built on a thread
+    /// of its own, see ingest::build_partitioned
+    pub fn bulk_load_partitioned<I>(&self, partitions: Vec<I>, fill: usize, partition_size: usize)
+        -> Result<usize, LodestoneError>
+        where I: IntoIterator<Item=Result<(Vec<u8>, Vec<u8>), LodestoneError>> + Send + 'static {
+        try!(self.check_bulk_load());
+        let tx_id = self.tx_id.load(SeqCst) + 1;
+        let root = try!(ingest::build_partitioned(partitions, tx_id, fill, partition_size, &self.page_pool));
+        self.commit_bulk_load(root)
+    }
+
+    /// The stamp of the last transaction apply_changes committed, 0
+    /// before the first
+    pub fn replication_high_water(&self) -> Result<usize, LodestoneError> {
+        let key = system::system_key(REPLICATION_HIGH_WATER);
+        match try!(self.get_normalized(&key, &ReadOptions::default())) {
+            Some(mark) => numeric::decode_u64(&mark).map(|n| n as usize),
+            None => Ok(0),
+        }
+    }
+
+    /// Store whatever rule makes of key's current value, see numeric and
+    /// merge, in a single descent. Returns the stored value, None if the rule left
+    /// the entry alone.
+    fn update<F>(&self, key: &[u8], rule: F) -> Result<Option<Vec<u8>>, LodestoneError>
+        where F: Fn(Option<&[u8]>) -> Result<Option<Vec<u8>>, LodestoneError> {
+        try!(self.check_poisoned());
+        let key = self.normalize_key(key);
+        try!(system::check_user_key(&key));
+        let current = try!(self.get_normalized(&key, &ReadOptions::default()));
+        let value = match try!(rule(current.as_ref().map(|v| &**v))) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let entries = self.len() + current.is_none() as usize;
+        if self.options.message_buffer > 0 {
+            // The leaf may not have the current value yet, only a buffer
+            // on the way down to it
+            try!(self.write_message(&key, Some(&value), entries));
+            return Ok(Some(value));
+        }
+        let checksummed = self.options.entry_checksums;
+        try!(self.commit_root(entries, Some(&key), |pool, root, tx_id| {
+            let root = match root {
+                Some(root) => root,
+                None => try!(Node::new_leaf(tx_id, checksummed, pool)),
+            };
+            match try!(root.deref_as::<Node>().update(tx_id, &key, &rule, pool)) {
+                Some(InsertionResult::HadRoom(new_root)) => Ok(new_root),
+                Some(InsertionResult::NoRoom(split)) => Node::new_root(tx_id, split, pool),
+                None => Err(LodestoneError::StructureCorrupt("Another commit changed the value")),
+            }
+        }));
+        Ok(Some(value))
+    }
+
+    /// A consistent read-only view of the tree as of now. Writers carry
+    /// on around it, and what it sees stays put until it drops.
+    pub fn snapshot<'a>(&'a self) -> Result<snapshot::Snapshot<'a>, LodestoneError> {
+        try!(self.check_poisoned());
+        try!(self.flush_messages());
+        snapshot::Snapshot::of_tree(self, &self.page_pool, try!(self.root()), self.tx_id.load(SeqCst))
+    }
+
+    /// What key held as of each commit the tree still has a root for,
+    /// newest first, by tx_id: the current root, and the recent roots the
+    /// descriptor remembers (see describe) for as long as something else,
+    /// e.g. a snapshot, keeps them from being freed. Freed roots are
+    /// skipped, so the history has gaps wherever nothing held on.
+    pub fn versions<'a>(&'a self, key: &[u8]) -> Result<vec::IntoIter<(usize, Option<ArcByteSlice<'a>>)>, LodestoneError> {
+        try!(self.check_poisoned());
+        let key = self.normalize_key(key);
+        let pool = &self.page_pool;
+        let tx_id = self.tx_id.load(SeqCst);
+        let mut versions = Vec::new();
+        let current = match try!(self.root()) {
+            Some(root) => try!(node::find_value(&try!(root.clone_to_arc_byte_slice(pool)), pool, &key)),
+            None => None,
+        };
+        versions.push((tx_id, current));
+        if let Some(descriptor) = try!(TreeDescriptor::load(pool)) {
+            for slot in descriptor.recent_roots().into_iter().filter(|slot| slot.tx_id < tx_id) {
+                let reference = Reference::new(slot.index, slot.generation);
+                if let Ok(root) = pool.take_reference(&reference) {
+                    let root = try!(root.clone_to_arc_byte_slice(pool));
+                    versions.push((slot.tx_id, try!(node::find_value(&root, pool, &key))));
+                }
+            }
+        }
+        Ok(versions.into_iter())
+    }
+
+    /// Every entry in key order, as of now, see iter
+    pub fn iter<'a>(&'a self) -> iter::Iter<'a> {
+        let root = self.check_poisoned().and_then(|_| self.flush_messages()).and_then(|_| self.root());
+        iter::Iter::of_tree(self, &self.page_pool, root, b"")
+    }
+
+    /// The entries whose keys start with prefix, in key order, as of now.
+    /// Only the keys with the prefix are visited, see iter.
+    pub fn scan_prefix<'a>(&'a self, prefix: &[u8]) -> iter::Iter<'a> {
+        let root = self.check_poisoned().and_then(|_| self.flush_messages()).and_then(|_| self.root());
+        iter::Iter::of_tree(self, &self.page_pool, root, &self.normalize_key(prefix))
+    }
+
+    /// The entries of [start, end), at most max_entries of them and
+    /// stopping early once max_duration is up, and a Continuation to carry
+    /// on from, None once the range is done, see scan. Carrying on reads
+    /// the version the first call read, so it fails once the tree no
+    /// longer has that version, unless a Snapshot of it is held open.
+    pub fn scan_with_limit(&self, start: &[u8], end: &[u8], from: Option<&scan::Continuation>,
+        max_entries: usize, max_duration: Duration)
+        -> Result<(Vec<scan::Entry>, Option<scan::Continuation>), LodestoneError> {
+        try!(self.check_poisoned());
+        let tx_id = match from {
+            Some(from) => from.snapshot,
+            None => {
+                try!(self.flush_messages());
+                self.tx_id.load(SeqCst)
+            },
+        };
+        let root = match try!(self.root_as_of(tx_id)) {
+            Some(root) => root,
+            None => return Ok((Vec::new(), None)),
+        };
+        let pool = &self.page_pool;
+        scan::scan_with_limit(&self.normalize_key(start), &self.normalize_key(end), from, tx_id,
+            max_entries, max_duration, |key| {
+            Ok(try!(node::seek(&root, pool, key)).map(|(key, value)| (key.to_vec(), value.to_vec())))
+        })
+    }
+
+    /// The distinct next components of the keys under the prefix under,
+    /// when keys are paths split by delimiter, as of now. One seek per
+    /// component, see prefixes.
+    pub fn distinct_prefixes(&self, delimiter: u8, under: &[u8]) -> Result<Vec<Vec<u8>>, LodestoneError> {
+        try!(self.check_poisoned());
+        try!(self.flush_messages());
+        let root = match try!(self.root()) {
+            Some(root) => root,
+            None => return Ok(Vec::new()),
+        };
+        let pool = &self.page_pool;
+        prefixes::distinct_prefixes(delimiter, &self.normalize_key(under), |key| {
+            Ok(try!(node::seek(&root, pool, key)).map(|(key, _)| key.to_vec()))
+        })
+    }
+
+    /// How many entries have start <= key < end, as of now. Subtrees the
+    /// node fences put outside the range aren't descended into.
+    pub fn count_range(&self, start: &[u8], end: &[u8]) -> Result<usize, LodestoneError> {
+        try!(self.check_poisoned());
+        try!(self.flush_messages());
+        match try!(self.root()) {
+            Some(root) => node::count_range(&root, &self.page_pool, &self.normalize_key(start), &self.normalize_key(end)),
+            None => Ok(0),
+        }
+    }
+
+    /// A digest of the entries with start <= key < end (no end means to
+    /// the last key), as of now, that any tree holding the same entries
+    /// agrees on, see node::digest_range. Replicas compare digests to
+    /// find the ranges they differ in.
+    pub fn digest_range(&self, start: &[u8], end: Option<&[u8]>) -> Result<u64, LodestoneError> {
+        try!(self.check_poisoned());
+        try!(self.flush_messages());
+        let root = match try!(self.root()) {
+            Some(root) => root,
+            None => return Ok(0),
+        };
+        let end = end.map(|end| self.normalize_key(end).into_owned());
+        let mut digests = self.digests.lock().unwrap();
+        if digests.len() > DIGEST_CACHE_ENTRIES {
+            digests.clear();
+        }
+        node::digest_range(&root, &self.page_pool, &self.normalize_key(start), end.as_ref().map(|end| &end[..]), &mut digests)
+    }
+
+    /// A picture of the tree's nodes, keys and values as of now that
+    /// doesn't depend on where the blocks are, for golden tests of the
+    /// tree's shape. Writes still buffered in internal nodes aren't in
+    /// it. None for a tree that has never been written to.
+    pub fn structure_snapshot(&self) -> Result<Option<node::TreeSnapshot>, LodestoneError> {
+        try!(self.check_poisoned());
+        match try!(self.root()) {
+            Some(root) => node::snapshot(&root, &self.page_pool).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// The named trees kept in the tree's pool alongside it, see catalog
+    pub fn catalog<'a>(&'a self) -> Result<catalog::Catalog<'a>, LodestoneError> {
+        catalog::Catalog::open(&self.page_pool)
+    }
+
+    /// A read-optimized copy of the tree's index as of now, see frozen
+    pub fn freeze<'a>(&'a self) -> Result<frozen::FrozenTree<'a>, LodestoneError> {
+        try!(self.check_poisoned());
+        try!(self.flush_messages());
+        frozen::FrozenTree::new(self, try!(self.root()), &self.page_pool)
+    }
+
+    /// Push every buffered write down to the leaves, in one commit, see
+    /// TreeOptions::message_buffer. Scans do this first on their own.
+    pub fn flush_messages(&self) -> Result<(), LodestoneError> {
+        if self.options.message_buffer == 0 {
+            return Ok(());
+        }
+        let buffered = match try!(self.root()) {
+            Some(root) => try!(try!(root.clone_to_arc_byte_slice(&self.page_pool)).deref_as::<Node>().has_messages(&self.page_pool)),
+            None => false,
+        };
+        if !buffered {
+            return Ok(());
+        }
+        self.commit_root(self.len(), None, |pool, root, tx_id| {
+            let root = match root {
+                Some(root) => root,
+                None => return Err(LodestoneError::StructureCorrupt("Tree lost its root during a flush")),
+            };
+            let root = root.deref_as::<Node>();
+            let pieces = try!(root.apply_messages(tx_id, Vec::new(), 0, pool));
+            root.root_over(pieces, tx_id, pool)
+        })
+    }
+
+    /// Names the last commit, for read-your-writes elsewhere: hand it to
+    /// a reader holding a commit_watch after writing
+    pub fn commit_token(&self) -> CommitToken {
+        CommitToken {
+            tx_id: self.tx_id.load(SeqCst),
+            generation: self.root_generation.load(SeqCst),
+        }
+    }
+
+    /// Whether the tree has seen token's commit
+    pub fn at_least(&self, token: &CommitToken) -> bool {
+        self.commit_token().covers(token)
+    }
+
+    /// Follows the tree's commits from other threads, see consistency
+    pub fn commit_watch(&self) -> CommitWatch {
+        self.commits.clone()
+    }
+
+    /// Blocks in the tree's pool that no root reaches, with their bytes,
+    /// so an application can salvage values a crash left behind (by
+    /// whatever identifiers they carry) before sweep_orphans frees them.
+    /// The pool can't tell keys from values, so orphaned keys are listed
+    /// too, and blocks the size of a node are taken for nodes and left
+    /// out. Versions only held in memory, by snapshots or ArcByteSlices,
+    /// count as unreachable.
+    pub fn orphaned_values(&self) -> Result<Vec<Unreachable>, LodestoneError> {
+        let roots = try!(self.live_references());
+        let extract = |value: &[u8]| self.extract_references(value);
+        Ok(self.page_pool.unreachable_blocks(&roots, extract).into_iter()
+            .filter(|orphan| node::node_capacity(orphan.bytes.len()).is_none())
+            .collect())
+    }
+
+    /// Free every block no root reaches, see orphaned_values
+    pub fn sweep_orphans(&self) -> Result<SweepReport, LodestoneError> {
+        let roots = try!(self.live_references());
+        let extract = |value: &[u8]| self.extract_references(value);
+        Ok(self.page_pool.sweep_unreachable(&roots, extract))
+    }
+
+    /// The tree's own upkeep, to be ticked with the tree: pushing
+    /// buffered writes down, sweeping orphaned values and verifying a
+    /// sample of the current root. Register more tasks on it as needed.
+    pub fn maintenance(budget: MaintenanceBudget) -> Maintenance<BTree<'buf>> {
+        let mut maintenance = Maintenance::new(budget);
+        maintenance.register("flush messages", maintain_flush_messages);
+        maintenance.register("sweep orphans", maintain_sweep_orphans);
+        maintenance.register("integrity sampling", maintain_integrity_sampling);
+        maintenance
+    }
+
+    /// Count the entries and compare with len. A mismatch is an error,
+    /// see repair_counts.
+    pub fn verify_counts(&self) -> Result<(), LodestoneError> {
+        if try!(self.count_entries()) != self.len() {
+            return Err(LodestoneError::StructureCorrupt("The entry count doesn't match the tree"));
+        }
+        Ok(())
+    }
+
+    /// Count the entries and make len agree, persisting the fixed count
+    /// if the tree is described in its pool. Returns how far off it was.
+    pub fn repair_counts(&self) -> Result<isize, LodestoneError> {
+        let counted = try!(self.count_entries());
+        let drift = self.len() as isize - counted as isize;
+        if drift != 0 {
+            self.entry_count.store(counted, SeqCst);
+            try!(TreeDescriptor::record_entries(&self.page_pool, counted));
+        }
+        Ok(drift)
+    }
+
+    /// Values that embed References to other blocks need an extractor
+    /// to find them, so that the referenced blocks are released along
+    /// with the value.
+    pub fn register_reference_extractor<F>(&mut self, extract: F)
+        where F: Fn(&[u8]) -> Vec<Reference> + Send + 'static {
+        self.reference_extractors.push(Box::new(extract));
+    }
+
+    /// Keys are passed through normalize on the way in and on lookup, e.g.
+    /// normalize::ascii_case_insensitive. Changing it once keys are stored
+    /// makes the stored ones unreachable by their new form.
+    pub fn set_key_normalizer<F>(&mut self, normalize: F)
+        where F: Fn(&[u8]) -> Cow<[u8]> + Send + 'static {
+        self.key_normalizer = Some(Box::new(normalize));
+    }
+
+    /// The key as the tree stores it
+    pub fn normalize_key<'a>(&self, key: &'a [u8]) -> Cow<'a, [u8]> {
+        match self.key_normalizer {
+            Some(ref normalize) => normalize(key),
+            None => Cow::Borrowed(key),
         }
     }
 
-    pub fn open() {
+    /// Mark a section of the caller's own that may block, e.g. a large
+    /// scan, so it shows up in in_blocking_section and is
+    /// reported if slow like the tree's own commits
+    pub fn blocking_scope<'a>(&'a self, op: BlockingOp) -> BlockingScope<'a> {
+        self.blocking.enter(op)
+    }
+
+    /// Called with any blocking section that takes longer than threshold
+    pub fn on_slow_blocking<F>(&mut self, threshold: Duration, on_slow: F)
+        where F: Fn(BlockingOp, Duration) + Send + 'static {
+        self.blocking.on_slow(threshold, on_slow);
+    }
+
+    /// Writes must check their keys with this first. The check is on the
+    /// normalized key, since a normalizer could map a user key into the
+    /// reserved namespace.
+    pub fn check_user_key(&self, key: &[u8]) -> Result<(), LodestoneError> {
+        system::check_user_key(&self.normalize_key(key))
+    }
+
+    /// Called with the error whenever integrity sampling finds a bad node
+    pub fn on_integrity_failure<F>(&mut self, handler: F)
+        where F: Fn(&LodestoneError) + Send + 'static {
+        self.integrity_failure_handlers.push(Box::new(handler));
+    }
+
+    /// Set when a commit panicked. Every operation fails with
+    /// Poisoned until the tree is reopened.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(SeqCst)
+    }
+
+    /// The most read leaf key ranges, when access_sample_one_in is set
+    pub fn hot_ranges(&self, top_n: usize) -> Vec<HotRange> {
+        self.access_stats.lock().unwrap().hot_ranges(top_n)
+    }
+
+    /// Store the access statistics in the pool, to carry them across
+    /// a restart with load_access_stats
+    pub fn persist_access_stats<'a>(&'a self) -> Result<ArcByteSlice<'a>, LodestoneError> {
+        let bytes = self.access_stats.lock().unwrap().to_bytes();
+        self.page_pool.malloc(&bytes)
+    }
+
+    pub fn load_access_stats(&self, persisted: &ArcByteSlice) -> Result<(), LodestoneError> {
+        let stats = try!(AccessStats::from_bytes(&persisted[..]));
+        *self.access_stats.lock().unwrap() = stats;
+        Ok(())
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// How point reads fared with the cache of decoded internal nodes
+    pub fn node_cache_stats(&self) -> CacheStats {
+        self.node_cache.lock().unwrap().stats().clone()
+    }
+
+    /// Resolve per-call read options against the tree and pool defaults
+    pub fn read_settings(&self, options: &ReadOptions) -> ReadSettings {
+        options.resolve(&self.options, &self.pool_defaults)
+    }
+
+    /// Resolve per-call write options against the tree and pool defaults
+    pub fn write_settings(&self, options: &WriteOptions) -> WriteSettings {
+        options.resolve(&self.options, &self.pool_defaults)
+    }
+
+    /// Recover the tree an earlier BTree left in buf, as of its last
+    /// commit. The tree must have been described (see describe). The pool
+    /// image is checked and the top of the tree verified; a buffer that
+    /// doesn't hold a described tree is refused with InvalidReference.
+    pub fn open(buf: &'buf mut [u8], pool_defaults: PoolDefaults) -> Result<BTree<'buf>, LodestoneError> {
+        BTree::open_verified(buf, pool_defaults).map(|(tree, _)| tree)
+    }
+
+    /// open, trusting the tree without verifying any of it
+    pub fn open_fast(buf: &'buf mut [u8], pool_defaults: PoolDefaults)
+        -> Result<(BTree<'buf>, startup::OpenReport), LodestoneError> {
+        BTree::open_in_mode(buf, pool_defaults, startup::OpenMode::Fast)
+    }
 
+    /// open, with a report of what was verified
+    pub fn open_verified(buf: &'buf mut [u8], pool_defaults: PoolDefaults)
+        -> Result<(BTree<'buf>, startup::OpenReport), LodestoneError> {
+        BTree::open_in_mode(buf, pool_defaults, startup::OpenMode::Verified)
+    }
+
+    /// open after an unclean shutdown: every node is verified, the entry
+    /// count is recounted (see repair_counts) and blocks no root reaches
+    /// are freed (see sweep_orphans). Takes time in proportion to the
+    /// whole pool. A tree that fails verification can't be repaired, and
+    /// is refused with the error.
+    pub fn open_repair(buf: &'buf mut [u8], pool_defaults: PoolDefaults)
+        -> Result<(BTree<'buf>, startup::OpenReport), LodestoneError> {
+        BTree::open_in_mode(buf, pool_defaults, startup::OpenMode::Repair)
     }
 }
 
 /// Internal Functions
-impl BTree {
+impl<'buf> BTree<'buf> {
+    fn open_in_mode(buf: &'buf mut [u8], pool_defaults: PoolDefaults, mode: startup::OpenMode)
+        -> Result<(BTree<'buf>, startup::OpenReport), LodestoneError> {
+        let started = Instant::now();
+        let page_pool = try!(Pool::open(buf));
+        if try!(TreeDescriptor::load(&page_pool)).is_none() {
+            return Err(LodestoneError::InvalidReference("The pool doesn't describe a tree"));
+        }
+        let tree = try!(BTree::from_pool(page_pool, pool_defaults));
+        let tx_id = tree.tx_id.load(SeqCst);
+        let (levels, samples) = match mode {
+            startup::OpenMode::Fast => (0, 0),
+            startup::OpenMode::Verified => (OPEN_VERIFY_LEVELS, OPEN_VERIFY_SAMPLES),
+            startup::OpenMode::Repair => (usize::max_value(), 0),
+        };
+        let verified = match try!(tree.root()) {
+            Some(ref root) if levels > 0 => Some(try!(node::verify_quick(root, &tree.page_pool, levels, samples, tx_id))),
+            _ => None,
+        };
+        let catalog_trees = match mode {
+            startup::OpenMode::Fast => 0,
+            _ => try!(try!(tree.catalog()).preload_all()),
+        };
+        let (count_drift, swept) = match mode {
+            startup::OpenMode::Repair => (try!(tree.repair_counts()), Some(try!(tree.sweep_orphans()))),
+            _ => (0, None),
+        };
+        let report = startup::OpenReport {
+            mode: mode,
+            tx_id: tree.tx_id.load(SeqCst),
+            entries: tree.len(),
+            verified: verified,
+            catalog_trees: catalog_trees,
+            count_drift: count_drift,
+            swept: swept,
+            elapsed: started.elapsed(),
+        };
+        Ok((tree, report))
+    }
+
+    fn extract_references(&self, value: &[u8]) -> Vec<Reference> {
+        self.reference_extractors.iter().flat_map(|extract| extract(value)).collect()
+    }
+
+    fn check_stored_keys(&self) -> Result<(), LodestoneError> {
+        if self.key_normalizer.is_some() {
+            return Err(LodestoneError::UserError("Bulk loads store keys as they are, which a key normalizer would change"));
+        }
+        Ok(())
+    }
+
+    fn check_bulk_load(&self) -> Result<(), LodestoneError> {
+        try!(self.check_poisoned());
+        try!(self.check_stored_keys());
+        if !self.is_empty() {
+            return Err(LodestoneError::UserError("Bulk loads only go into an empty tree"));
+        }
+        Ok(())
+    }
+
+    /// Commit a bulk built root in place of the empty tree, see check_bulk_built
+    fn commit_bulk_load(&self, root: Option<ArcByteSlice>) -> Result<usize, LodestoneError> {
+        let (root, entries) = match root {
+            Some(root) => try!(self.check_bulk_built(root)),
+            None => return Ok(0),
+        };
+        try!(self.commit_root(entries, None, |_, _, _| Ok(root)));
+        Ok(entries)
+    }
+
+    /// Count the entries of a bulk built subtree, unless it holds
+    /// reserved keys, in which case it's released instead
+    fn check_bulk_built<'t>(&'t self, root: ArcByteSlice<'t>) -> Result<(ArcByteSlice<'t>, usize), LodestoneError> {
+        let pool = &self.page_pool;
+        let mut persisted = root.clone_to_persisted();
+        let counted = node::seek(&persisted, pool, system::SYSTEM_PREFIX)
+            .and_then(|found| match found {
+                Some((ref key, _)) if system::is_system_key(key) => system::check_user_key(key),
+                _ => Ok(()),
+            })
+            .and_then(|_| node::count_entries(&persisted, pool));
+        let entries = match counted {
+            Ok(entries) => entries,
+            Err(e) => {
+                drop(root);
+                try!(release_node(&mut persisted, pool));
+                return Err(e);
+            },
+        };
+        try!(persisted.release(pool));
+        Ok((root, entries))
+    }
+
+    /// Insert (Some value) or remove key through the message buffers
+    fn write_message(&self, key: &[u8], value: Option<&[u8]>, entries: usize) -> Result<(), LodestoneError> {
+        let mut changes = BTreeMap::new();
+        changes.insert(key.to_vec(), value);
+        self.write_messages(changes, entries)
+    }
+
+    /// Insert (Some value) or remove each of the normalized keys, all in
+    /// one commit. Without message buffers the changes go straight down
+    /// to the leaves.
+    fn write_messages(&self, changes: BTreeMap<Vec<u8>, Option<&[u8]>>, entries: usize) -> Result<(), LodestoneError> {
+        let checksummed = self.options.entry_checksums;
+        let capacity = cmp::min(self.options.message_buffer, 255);
+        self.commit_root(entries, None, |pool, root, tx_id| {
+            let root = match root {
+                Some(root) => root,
+                None => try!(Node::new_leaf(tx_id, checksummed, pool)),
+            };
+            let mut messages = Vec::with_capacity(changes.len());
+            for (key, value) in changes {
+                messages.push(messages::Message {
+                    key: key,
+                    value: match value {
+                        Some(value) => Some(try!(pool.malloc(value))),
+                        None => None,
+                    },
+                });
+            }
+            let pieces = try!(root.deref_as::<Node>().apply_messages(tx_id, messages, capacity, pool));
+            root.deref_as::<Node>().root_over(pieces, tx_id, pool)
+        })
+    }
+
+    fn get_normalized<'a>(&'a self, key: &[u8], options: &ReadOptions) -> Result<Option<ArcByteSlice<'a>>, LodestoneError> {
+        let settings = self.read_settings(options);
+        let pool = &self.page_pool;
+        let mut reference = match try!(self.root()) {
+            Some(root) => Reference::from_persisted(&root),
+            None => return Ok(None),
+        };
+        let mut descent = descent::Descent::for_pool(pool);
+        loop {
+            // Internal nodes without a buffer are read out of the cache
+            // (filling it, unless the read says not to) instead of
+            // taking a reference on every key compared against
+            let cached = self.node_cache.lock().unwrap().get(&reference);
+            reference = match cached {
+                Some(decoded) => try!(child_for(&decoded, key)),
+                None => {
+                    let arc = try!(pool.resolve(&reference));
+                    let node = arc.deref_as::<Node>();
+                    self.sample_integrity(node);
+                    if node.is_leaf() {
+                        self.record_access(node);
+                        return if settings.verify_checksums {
+                            node.leaf_node_checked_value_for_key(key, pool, &self.stats)
+                        } else {
+                            node.leaf_node_value_for_key(key, pool)
+                        };
+                    }
+                    if let Some(buffered) = try!(node.buffered_value(key, pool)) {
+                        return Ok(buffered);
+                    }
+                    if settings.fill_cache && !node.buffered() {
+                        let decoded = try!(node.internal_node_decode(pool));
+                        try!(child_for(&self.node_cache.lock().unwrap().insert(&reference, decoded), key))
+                    } else {
+                        try!(node.internal_node_child_reference(key, pool))
+                    }
+                },
+            };
+            try!(descent.enter_reference(&reference));
+        }
+    }
+
+    /// The tree's own reference to its root, None while it's empty
+    fn root(&self) -> Result<Option<PersistedArcByteSlice>, LodestoneError> {
+        match self.current_root.load(SeqCst) {
+            0 => Ok(None),
+            index => {
+                let reference = Reference::new(index, self.root_generation.load(SeqCst));
+                self.page_pool.take_reference(&reference).map(Some)
+            },
+        }
+    }
+
+    /// The root as of commit tx_id, if it's the current one or a recent
+    /// one that hasn't been freed yet
+    fn root_as_of(&self, tx_id: usize) -> Result<Option<PersistedArcByteSlice>, LodestoneError> {
+        if tx_id == self.tx_id.load(SeqCst) {
+            return self.root();
+        }
+        if let Some(descriptor) = try!(TreeDescriptor::load(&self.page_pool)) {
+            for slot in descriptor.recent_roots().into_iter().filter(|slot| slot.tx_id == tx_id) {
+                let reference = Reference::new(slot.index, slot.generation);
+                if let Ok(root) = self.page_pool.take_reference(&reference) {
+                    return Ok(Some(root));
+                }
+            }
+        }
+        Err(LodestoneError::UserError("The tree no longer has the version the scan was reading"))
+    }
+
+    /// Every block of the current root, of the recent roots the
+    /// descriptor remembers that haven't been freed yet, and of the
+    /// trees in the pool's catalog
+    fn live_references(&self) -> Result<Vec<Reference>, LodestoneError> {
+        let mut roots = Vec::new();
+        if let Some(root) = try!(self.root()) {
+            roots.extend(try!(node::tree_references(&root, &self.page_pool)));
+        }
+        for reference in try!(self.catalog()).roots() {
+            let root = try!(self.page_pool.take_reference(&reference));
+            roots.extend(try!(node::tree_references(&root, &self.page_pool)));
+        }
+        if let Some(descriptor) = try!(TreeDescriptor::load(&self.page_pool)) {
+            for slot in descriptor.recent_roots() {
+                let reference = Reference::new(slot.index, slot.generation);
+                if let Ok(root) = self.page_pool.take_reference(&reference) {
+                    roots.extend(try!(node::tree_references(&root, &self.page_pool)));
+                }
+            }
+        }
+        Ok(roots)
+    }
+
+    fn root_slot(&self) -> RootSlot {
+        RootSlot {
+            index: self.current_root.load(SeqCst),
+            generation: self.root_generation.load(SeqCst),
+            tx_id: self.tx_id.load(SeqCst),
+            entries: self.len(),
+        }
+    }
+
+    /// How many user entries there are, leaving out system keys as len does
+    fn count_entries(&self) -> Result<usize, LodestoneError> {
+        try!(self.flush_messages());
+        match try!(self.root()) {
+            Some(root) => {
+                let system = try!(node::count_range(&root, &self.page_pool, system::SYSTEM_PREFIX, system::SYSTEM_END));
+                Ok(try!(node::count_entries(&root, &self.page_pool)) - system)
+            },
+            None => Ok(0),
+        }
+    }
+
+    /// Commit the root that build makes out of the current one (None for
+    /// an empty tree), stamped with tx_id, and holding entries entries.
+    /// The new root is journaled once build has written it, and only then
+    /// published and recorded in the descriptor, so nobody sees a root a
+    /// crash could lose. If the commit fails after the journal took the
+    /// new root, the old one is journaled again on top of it. The tree
+    /// holds a reference to its root: once the new one is published the
+    /// old one's is released, which frees whatever the new version no
+    /// longer shares; a commit that fails releases the new one instead.
+    /// A write names the key it touched, and the underfull leaves along
+    /// its path are merged before the commit, see
+    /// node::compact_leaves_on_path, so deletes don't leave them behind.
+    fn commit_root<'t, F>(&'t self, entries: usize, touched: Option<&[u8]>, build: F) -> Result<(), LodestoneError>
+        where F: FnOnce(&'t Pool<'buf>, Option<ArcByteSlice<'t>>, usize) -> Result<ArcByteSlice<'t>, LodestoneError> {
+        // Another commit can swap the root between reading its index and
+        // its generation, which reading them again gets past
+        let old_root = try!(retry::with_retries(&retry::RetryPolicy::default(), |attempt| {
+            let root = try!(self.root());
+            if let Some(ref root) = root {
+                if attempt.verify_checksums {
+                    try!(try!(root.clone_to_arc_byte_slice(&self.page_pool)).deref_as::<Node>().verify(&self.page_pool));
+                }
+            }
+            Ok(root)
+        }));
+        let old_slot = self.root_slot();
+        let tx_id = self.tx_id.load(SeqCst) + 1;
+        let sync = self.write_settings(&WriteOptions::default()).durability == Durability::Synced;
+        let mut built = None;
+        let committed = self.commit_with(|pool| {
+            let old = match old_root {
+                Some(ref root) => Some(try!(root.clone_to_arc_byte_slice(pool))),
+                None => None,
+            };
+            let mut new_root = try!(build(pool, old, tx_id));
+            if let Some(key) = touched {
+                new_root = try!(node::compact_leaves_on_path(new_root, tx_id, key, pool));
+            }
+            let new_root = new_root.clone_to_persisted();
+            let journaled = JournaledRoot {
+                index: new_root._arc_inner_index(),
+                generation: new_root.get_id_tag(),
+                tx_id: tx_id,
+                entries: entries,
+            };
+            built = Some((new_root, journaled));
+            try!(pool.journal_root(journaled, sync));
+            Ok(journaled.index)
+        });
+        let extract = |value: &[u8]| self.extract_references(value);
+        if let Err(e) = committed {
+            if let Some((mut new_root, _)) = built {
+                if self.page_pool.journaled_root().map_or(false, |r| r.tx_id == tx_id) {
+                    try!(self.page_pool.journal_root(JournaledRoot {
+                        index: old_slot.index,
+                        generation: old_slot.generation,
+                        tx_id: old_slot.tx_id,
+                        entries: old_slot.entries,
+                    }, sync));
+                }
+                try!(release_node_traced(&mut new_root, &self.page_pool, &extract));
+            }
+            return Err(e);
+        }
+        let generation = built.map_or(0, |(_, journaled)| journaled.generation);
+        self.root_generation.store(generation, SeqCst);
+        self.entry_count.store(entries, SeqCst);
+        self.commits.publish(self.commit_token());
+        let recorded = TreeDescriptor::record_root(&self.page_pool, self.root_slot());
+        let released = match old_root {
+            Some(mut old) => release_node_traced(&mut old, &self.page_pool, &extract),
+            None => Ok(()),
+        };
+        recorded.and(released)
+    }
+
+    /// Rewrite the tree in this build's node layout if it was written
+    /// with another B, when the pool defaults opt in. The whole rewrite is
+    /// one commit, so a failure leaves the tree as it was.
+    fn migrate_capacity(&self, descriptor: &TreeDescriptor) -> Result<(), LodestoneError> {
+        let written = match descriptor.node_capacity() {
+            Some(capacity) => Some(capacity),
+            // Not recorded, so go by the root's block
+            None => match try!(self.root()) {
+                Some(root) => node::node_capacity(try!(root.clone_to_arc_byte_slice(&self.page_pool)).len()),
+                None => None,
+            },
+        };
+        match written {
+            None => {},
+            Some(capacity) if capacity == B => {},
+            Some(capacity) if !self.pool_defaults.migrate_capacity => {
+                return Err(LodestoneError::Format(FormatMismatch {
+                    field: "node capacity",
+                    expected: B as u64,
+                    found: capacity as u64,
+                }));
+            },
+            Some(_) => try!(self.rewrite_capacity()),
+        }
+        if descriptor.node_capacity() != Some(B) {
+            try!(TreeDescriptor::record_capacity(&self.page_pool, B));
+        }
+        self.page_pool.record_node_capacity(B);
+        Ok(())
+    }
+
+    fn rewrite_capacity(&self) -> Result<(), LodestoneError> {
+        if let Some(root) = try!(self.root()) {
+            let root = try!(root.clone_to_arc_byte_slice(&self.page_pool));
+            let tx_id = self.tx_id.load(SeqCst) + 1;
+            if let Some(migration) = try!(node::migrate_capacity(&root, &self.page_pool, tx_id)) {
+                let new_root = migration.root.clone();
+                match self.commit_root(self.len(), None, |_, _, _| Ok(new_root)) {
+                    Ok(()) => try!(migration.finish(&self.page_pool)),
+                    Err(e) => {
+                        try!(migration.abandon(&self.page_pool));
+                        return Err(e);
+                    },
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_poisoned(&self) -> Result<(), LodestoneError> {
+        if self.is_poisoned() {
+            return Err(LodestoneError::Poisoned("A commit panicked, reopen the tree"));
+        }
+        Ok(())
+    }
+
+    /// Commit boundary. build makes the new tree and returns its root,
+    /// which only becomes current once build returns successfully. If
+    /// build (or a user callback inside it) panics, the old root stays
+    /// current and the tree is poisoned, since whatever build allocated
+    /// may be half linked. The root is swung with switch_root from the
+    /// one current when build started, so a commit that raced another
+    /// fails rather than dropping the other's root.
+    fn commit_with<'t, F>(&'t self, build: F) -> Result<usize, LodestoneError>
+        where F: FnOnce(&'t Pool<'buf>) -> Result<usize, LodestoneError> {
+        try!(self.check_poisoned());
+        let _blocking = self.blocking.enter(BlockingOp::Commit);
+        let pool = &self.page_pool;
+        let expected = self.current_root.load(SeqCst);
+        match panic::catch_unwind(AssertUnwindSafe(|| build(pool))) {
+            Ok(Ok(root)) => {
+                if !switch_root(&self.current_root, expected, root) {
+                    return Err(LodestoneError::StructureCorrupt("Another commit moved the root"));
+                }
+                self.tx_id.fetch_add(1, SeqCst);
+                self.page_pool.record_commit(root);
+                Ok(root)
+            },
+            Ok(Err(e)) => Err(e),
+            Err(_) => {
+                self.poisoned.store(true, SeqCst);
+                Err(LodestoneError::Poisoned("A commit panicked, reopen the tree"))
+            },
+        }
+    }
+
+    /// Reads hand every node they touch to this, and a small random
+    /// fraction of them are fully verified. A failure doesn't fail the
+    /// read, it's counted and reported to the failure handlers.
+    fn sample_integrity(&self, node: &Node) {
+        let one_in = self.options.integrity_sample_one_in;
+        if one_in == 0 || roll(&self.sample_state) % one_in != 0 {
+            return;
+        }
+        match node.verify(&self.page_pool) {
+            Ok(()) => { self.stats.samples_verified.fetch_add(1, Relaxed); },
+            Err(e) => {
+                self.stats.samples_failed.fetch_add(1, Relaxed);
+                for handler in self.integrity_failure_handlers.iter() {
+                    handler(&e);
+                }
+            },
+        }
+    }
+
+    /// Reads hand the leaf they land in to this, and a sample of
+    /// them is counted towards hot_ranges
+    fn record_access(&self, leaf: &Node) {
+        let one_in = self.options.access_sample_one_in;
+        if one_in == 0 || roll(&self.sample_state) % one_in != 0 {
+            return;
+        }
+        if let Ok(Some((first, last))) = leaf.leaf_node_key_range(&self.page_pool) {
+            self.access_stats.lock().unwrap().record(&first, &last);
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a BTree<'a> {
+    type Item = (ArcByteSlice<'a>, ArcByteSlice<'a>);
+    type IntoIter = iter::Iter<'a>;
 
+    fn into_iter(self) -> iter::Iter<'a> {
+        self.iter()
+    }
 }
 
 // pub struct Context {
@@ -48,3 +1359,1438 @@ impl BTree {
 //     pool: &Pool,
 // }
 //
+
+/// The child of a cached internal node that key belongs under
+fn child_for(decoded: &node_cache::DecodedNode, key: &[u8]) -> Result<Reference, LodestoneError> {
+    match decoded.child_for(key) {
+        Some(child) => Ok(*child),
+        None => Err(LodestoneError::StructureCorrupt("Internal node has no child for the key")),
+    }
+}
+
+fn maintain_flush_messages(tree: &BTree, _: &Budget) -> Result<Progress, LodestoneError> {
+    try!(tree.flush_messages());
+    Ok(Progress::default())
+}
+
+fn maintain_sweep_orphans(tree: &BTree, _: &Budget) -> Result<Progress, LodestoneError> {
+    let swept = try!(tree.sweep_orphans());
+    Ok(Progress { io_bytes: swept.freed_bytes, more: false })
+}
+
+/// node::verify_quick over the current root, as on open, reporting a bad
+/// node to the integrity failure handlers like reads do
+fn maintain_integrity_sampling(tree: &BTree, _: &Budget) -> Result<Progress, LodestoneError> {
+    let root = match try!(tree.root()) {
+        Some(root) => root,
+        None => return Ok(Progress::default()),
+    };
+    let seed = roll(&tree.sample_state);
+    match node::verify_quick(&root, &tree.page_pool, OPEN_VERIFY_LEVELS, OPEN_VERIFY_SAMPLES, seed) {
+        Ok(_) => {
+            tree.stats.samples_verified.fetch_add(1, Relaxed);
+            Ok(Progress::default())
+        },
+        Err(e) => {
+            tree.stats.samples_failed.fetch_add(1, Relaxed);
+            for handler in tree.integrity_failure_handlers.iter() {
+                handler(&e);
+            }
+            Err(e)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem;
+    use std::sync::{mpsc, Arc};
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::thread;
+    use LodestoneError;
+
+    #[test]
+    fn test_key_normalizer() {
+        let mut buf = vec![0u8; 0x2000];
+        let mut tree = BTree::new(&mut buf);
+        assert_eq!(&b"Key"[..], &*tree.normalize_key(b"Key"));
+        tree.set_key_normalizer(normalize::ascii_case_insensitive);
+        assert_eq!(&b"key"[..], &*tree.normalize_key(b"Key"));
+        assert_eq!(tree.normalize_key(b"KEY"), tree.normalize_key(b"key"));
+    }
+
+    #[test]
+    fn test_reserved_keys() {
+        let mut buf = vec![0u8; 0x2000];
+        let mut tree = BTree::new(&mut buf);
+        assert!(tree.check_user_key(b"\xffSYS").is_ok());
+        match tree.check_user_key(&system::system_key(b"stats")) {
+            Err(LodestoneError::ReservedKey(_)) => (),
+            other => panic!("Expected ReservedKey, got {:?}", other),
+        }
+        // Only reserved once normalized
+        tree.set_key_normalizer(normalize::ascii_case_insensitive);
+        assert!(tree.check_user_key(b"\xffSYS").is_err());
+    }
+
+    #[test]
+    fn test_insert_get_remove() {
+        let mut buf = vec![0u8; 0x100000];
+        let tree = BTree::new(&mut buf);
+        assert_eq!(None, tree.get(b"missing").unwrap().map(|v| v.to_vec()));
+        // Enough keys, in a scrambled order, for the root to split
+        // more than once
+        let keys: Vec<Vec<u8>> = (0..1000).map(|i| format!("key {:04}", (i * 389) % 1000).into_bytes()).collect();
+        for key in &keys {
+            tree.insert(key, &key[4..]).unwrap();
+        }
+        for key in &keys {
+            assert_eq!(Some(key[4..].to_vec()), tree.get(key).unwrap().map(|v| v.to_vec()));
+        }
+        assert!(tree.get(b"key 1000").unwrap().is_none());
+
+        for key in keys.iter().filter(|k| k[7] % 2 == 0) {
+            assert!(tree.remove(key).unwrap());
+            assert!(!tree.remove(key).unwrap());
+        }
+        for key in &keys {
+            let expected = if key[7] % 2 == 0 { None } else { Some(key[4..].to_vec()) };
+            assert_eq!(expected, tree.get(key).unwrap().map(|v| v.to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_relocatable_tree() {
+        for &message_buffer in [0, 8].iter() {
+            let mut buf = vec![0u8; 0x100000];
+            {
+                let tree = BTree::with_options(&mut buf, TreeOptions {
+                    relocatable: true,
+                    message_buffer: message_buffer,
+                    ..TreeOptions::default()
+                });
+                tree.describe().unwrap();
+                let empty = tree.page_pool.lifetime_stats().live_blocks;
+                let keys: Vec<Vec<u8>> = (0..500).map(|i| format!("key {:03}", (i * 389) % 500).into_bytes()).collect();
+                for key in &keys {
+                    tree.insert(key, &key[4..]).unwrap();
+                }
+                for key in keys.iter().filter(|k| k[6] % 2 == 0) {
+                    assert!(tree.remove(key).unwrap());
+                }
+                tree.flush_messages().unwrap();
+                // Everything below the root is named through the block table
+                let references = node::tree_references(&tree.root().unwrap().unwrap(), &tree.page_pool).unwrap();
+                assert!(!references[0].is_logical() && references[1..].iter().all(|r| r.is_logical()));
+                for key in keys.iter().filter(|k| k[6] % 2 != 0) {
+                    assert!(tree.remove(key).unwrap());
+                }
+                tree.flush_messages().unwrap();
+                // The table let go of every node, key and value the tree
+                // no longer reaches
+                let reached = node::tree_references(&tree.root().unwrap().unwrap(), &tree.page_pool).unwrap().len();
+                assert_eq!(empty + reached + 1, tree.page_pool.lifetime_stats().live_blocks);
+                for key in keys.iter().take(100) {
+                    tree.insert(key, &key[4..]).unwrap();
+                }
+            }
+            let tree = BTree::open(&mut buf, PoolDefaults::default()).unwrap();
+            assert!(tree.options.relocatable && tree.page_pool.logical_references());
+            assert_eq!(100, tree.iter().count());
+            tree.insert(b"key 999", b"999").unwrap();
+            assert_eq!(b"999", &tree.get(b"key 999").unwrap().unwrap()[..]);
+        }
+    }
+
+    #[test]
+    fn test_compact_fragmented_tree() {
+        let mut buf = vec![0u8; 0x100000];
+        {
+            let tree = BTree::with_options(&mut buf, TreeOptions {
+                relocatable: true,
+                ..TreeOptions::default()
+            });
+            tree.describe().unwrap();
+            for i in 0..400 {
+                tree.insert(format!("key {:03}", i).as_bytes(), &[i as u8; 200]).unwrap();
+            }
+            // Leave every tenth entry, scattered over the pool
+            for i in (0..400).filter(|i| i % 10 != 0) {
+                assert!(tree.remove(format!("key {:03}", i).as_bytes()).unwrap());
+            }
+            let report = tree.page_pool.compact().unwrap();
+            assert!(report.moved_blocks > 0);
+            assert!(report.largest_free_after > report.largest_free_before);
+            assert_eq!(report.largest_free_after, tree.page_pool.largest_free_block());
+            assert_eq!(40, tree.iter().count());
+            for i in (0..400).filter(|i| i % 10 == 0) {
+                assert_eq!(&[i as u8; 200][..], &tree.get(format!("key {:03}", i).as_bytes()).unwrap().unwrap()[..]);
+            }
+            tree.insert(b"key 400", b"after").unwrap();
+        }
+        let tree = BTree::open(&mut buf, PoolDefaults::default()).unwrap();
+        assert_eq!(41, tree.iter().count());
+        assert_eq!(&[100u8; 200][..], &tree.get(b"key 100").unwrap().unwrap()[..]);
+        assert_eq!(&b"after"[..], &tree.get(b"key 400").unwrap().unwrap()[..]);
+    }
+
+    #[test]
+    fn test_tree_described_in_its_pool() {
+        let mut buf = vec![0u8; 0x40000];
+        let tree = BTree::with_options(&mut buf, TreeOptions {
+            entry_checksums: true,
+            durability: Some(Durability::Synced),
+            ..TreeOptions::default()
+        });
+        tree.insert(b"before", b"1").unwrap();
+        tree.describe().unwrap();
+        for i in 0..200 {
+            tree.insert(format!("key {:03}", i).as_bytes(), b"value").unwrap();
+        }
+        let pool = tree.into_pool();
+        let descriptor = descriptor::TreeDescriptor::load(&pool).unwrap().unwrap();
+        assert_eq!(2, descriptor.recent_roots().len());
+
+        let tree = BTree::from_pool(pool, PoolDefaults::default()).unwrap();
+        assert!(tree.options.entry_checksums);
+        assert_eq!(Some(Durability::Synced), tree.options.durability);
+        assert_eq!(201, tree.tx_id.load(SeqCst));
+        assert_eq!(&b"1"[..], &tree.get(b"before").unwrap().unwrap()[..]);
+        assert_eq!(&b"value"[..], &tree.get(b"key 199").unwrap().unwrap()[..]);
+        tree.insert(b"after", b"2").unwrap();
+
+        let tree = BTree::from_pool(tree.into_pool(), PoolDefaults::default()).unwrap();
+        assert_eq!(&b"2"[..], &tree.get(b"after").unwrap().unwrap()[..]);
+
+        let mut other_buf = vec![0u8; 0x2000];
+        let undescribed = BTree::new(&mut other_buf).into_pool();
+        assert!(BTree::from_pool(undescribed, PoolDefaults::default()).is_err());
+    }
+
+    #[test]
+    fn test_open() {
+        let mut buf = vec![0u8; 0x40000];
+        {
+            let tree = BTree::with_options(&mut buf, TreeOptions {
+                entry_checksums: true,
+                ..TreeOptions::default()
+            });
+            tree.describe().unwrap();
+            for i in 0..500 {
+                tree.insert(format!("key {:03}", i).as_bytes(), b"value").unwrap();
+            }
+        }
+        {
+            let tree = BTree::open(&mut buf, PoolDefaults::default()).unwrap();
+            assert_eq!(500, tree.len());
+            assert_eq!(500, tree.tx_id.load(SeqCst));
+            assert!(tree.options.entry_checksums);
+            assert_eq!(&b"value"[..], &tree.get(b"key 250").unwrap().unwrap()[..]);
+            tree.remove(b"key 250").unwrap();
+        }
+        let tree = BTree::open(&mut buf, PoolDefaults::default()).unwrap();
+        assert!(tree.get(b"key 250").unwrap().is_none());
+        tree.verify_counts().unwrap();
+
+        let mut undescribed = vec![0u8; 0x2000];
+        BTree::new(&mut undescribed);
+        let mut junk = vec![0x5au8; 0x2000];
+        for buf in [&mut undescribed, &mut junk].iter_mut() {
+            match BTree::open(buf, PoolDefaults::default()) {
+                Err(LodestoneError::InvalidReference(_)) => (),
+                Err(e) => panic!("Expected InvalidReference, got {:?}", e),
+                Ok(_) => panic!("Expected InvalidReference"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_open_modes() {
+        let mut buf = vec![0u8; 0x80000];
+        {
+            let tree = BTree::new(&mut buf);
+            tree.describe().unwrap();
+            for i in 0..300 {
+                tree.insert(format!("key {:03}", i).as_bytes(), b"value").unwrap();
+            }
+        }
+        {
+            let (tree, report) = BTree::open_fast(&mut buf, PoolDefaults::default()).unwrap();
+            assert_eq!(startup::OpenMode::Fast, report.mode);
+            assert_eq!((300, 300), (report.tx_id, report.entries));
+            assert!(report.verified.is_none() && report.swept.is_none());
+            assert_eq!(&b"value"[..], &tree.get(b"key 150").unwrap().unwrap()[..]);
+        }
+        {
+            let (tree, report) = BTree::open_verified(&mut buf, PoolDefaults::default()).unwrap();
+            assert!(report.verified.unwrap().nodes_verified > 0);
+            // An unclean shutdown: a block nothing reaches and a stale count
+            mem::forget(tree.page_pool.malloc(b"lost").unwrap());
+            descriptor::TreeDescriptor::record_entries(&tree.page_pool, 7).unwrap();
+        }
+        let (tree, report) = BTree::open_repair(&mut buf, PoolDefaults::default()).unwrap();
+        assert!(report.verified.unwrap().complete);
+        assert_eq!(7 - 300, report.count_drift);
+        assert_eq!(1, report.swept.unwrap().freed_blocks);
+        assert_eq!(300, tree.len());
+        tree.verify_counts().unwrap();
+    }
+
+    #[test]
+    fn test_open_verifies_catalog() {
+        let mut buf = vec![0u8; 0x80000];
+        {
+            let tree = BTree::new(&mut buf);
+            tree.describe().unwrap();
+            tree.insert(b"key", b"value").unwrap();
+            let pool = &tree.page_pool;
+            let other = node::bulk_build((0..120u8).map(|i| Ok((vec![i], vec![]))), 1, 50, pool).unwrap().unwrap();
+            catalog::Catalog::write(pool, &[("other", &other)]).unwrap();
+        }
+        let (_, report) = BTree::open_fast(&mut buf, PoolDefaults::default()).unwrap();
+        assert_eq!(0, report.catalog_trees);
+        let (tree, report) = BTree::open_verified(&mut buf, PoolDefaults::default()).unwrap();
+        assert_eq!(1, report.catalog_trees);
+        assert_eq!(vec!["other"], tree.catalog().unwrap().names());
+        drop(tree);
+        // Repair keeps what the catalog reaches
+        let (tree, report) = BTree::open_repair(&mut buf, PoolDefaults::default()).unwrap();
+        assert_eq!(1, report.catalog_trees);
+        let catalog = tree.catalog().unwrap();
+        let other = catalog.tree("other").unwrap().unwrap();
+        assert_eq!(120, node::count_entries(&other.clone_to_persisted(), &tree.page_pool).unwrap());
+        drop(other);
+        drop(catalog);
+
+        // A broken tree in the catalog fails a verified open
+        let junk = tree.page_pool.malloc(&vec![0xff; mem::size_of::<Node>()]).unwrap();
+        catalog::Catalog::write(&tree.page_pool, &[("junk", &junk)]).unwrap();
+        drop(junk);
+        drop(tree);
+        assert!(BTree::open_verified(&mut buf, PoolDefaults::default()).is_err());
+        assert!(BTree::open_fast(&mut buf, PoolDefaults::default()).is_ok());
+    }
+
+    #[test]
+    fn test_open_refuses_other_formats() {
+        let mut buf = vec![0u8; 0x10000];
+        {
+            let tree = BTree::new(&mut buf);
+            tree.describe().unwrap();
+            tree.insert(b"key", b"value").unwrap();
+            assert_eq!(Superblock::current(B), tree.page_pool.superblock().unwrap());
+        }
+        let format_version = buf.len() - SUPERBLOCK_SIZE + 12;
+        buf[format_version] += 1;
+        match BTree::open(&mut buf, PoolDefaults::default()) {
+            Err(LodestoneError::Format(m)) => {
+                assert_eq!("format version", m.field);
+                assert_eq!((FORMAT_VERSION as u64, FORMAT_VERSION as u64 + 1), (m.expected, m.found));
+            },
+            other => panic!("Expected a format mismatch, got {:?}", other.map(|t| t.len())),
+        }
+
+        // A clobbered superblock isn't taken for an image from before them
+        buf[format_version] -= 1;
+        assert_eq!(1, BTree::open(&mut buf, PoolDefaults::default()).unwrap().len());
+        for b in buf[format_version - 12..].iter_mut() {
+            *b = 0x5a;
+        }
+        assert!(BTree::open(&mut buf, PoolDefaults::default()).is_err());
+    }
+
+    #[test]
+    fn test_commit_retries_reading_the_root() {
+        let mut buf = vec![0u8; 0x10000];
+        let tree = BTree::new(&mut buf);
+        tree.insert(b"key", b"value").unwrap();
+        // A root generation that never matches, as if commits kept
+        // swapping the root under this one
+        let generation = tree.root_generation.load(SeqCst);
+        tree.root_generation.store(generation + 1, SeqCst);
+        match tree.commit_root(2, None, |_, _, _| panic!("Built on a root that couldn't be read")) {
+            Err(LodestoneError::ReadFailed(d)) => assert_eq!((3, true), (d.attempts, d.escalated)),
+            other => panic!("Expected ReadFailed, got {:?}", other),
+        }
+        tree.root_generation.store(generation, SeqCst);
+        tree.insert(b"other", b"value").unwrap();
+        assert_eq!(2, tree.len());
+    }
+
+    #[test]
+    fn test_failed_journal_leaves_the_commit_unseen() {
+        struct Flaky(HeapBackend, Arc<AtomicBool>);
+        impl StorageBackend for Flaky {
+            fn as_mut_ptr(&mut self) -> *mut u8 { self.0.as_mut_ptr() }
+            fn len(&self) -> usize { self.0.len() }
+            fn flush(&self, _: usize, _: usize) -> Result<(), LodestoneError> {
+                if self.1.load(SeqCst) { Err(LodestoneError::Storage("flaky")) } else { Ok(()) }
+            }
+        }
+        let failing = Arc::new(AtomicBool::new(false));
+        let pool = Pool::with_backend(Box::new(Flaky(HeapBackend::new(0x40000), failing.clone())));
+        let options = TreeOptions { durability: Some(Durability::Synced), ..TreeOptions::default() };
+        TreeDescriptor::new(&options).store(&pool).unwrap();
+        TreeDescriptor::record_root(&pool, RootSlot { index: 0, generation: 0, tx_id: 0, entries: 0 }).unwrap();
+        let tree = BTree::from_pool(pool, PoolDefaults::default()).unwrap();
+        tree.insert(b"a", b"1").unwrap();
+        let allocated = tree.page_pool.usage().allocated;
+
+        failing.store(true, SeqCst);
+        assert!(tree.insert(b"b", b"2").is_err());
+        assert!(tree.get(b"b").unwrap().is_none());
+        assert_eq!((1, 1), (tree.len(), tree.tx_id.load(SeqCst)));
+        assert_eq!(Some(1), tree.page_pool.journaled_root().map(|r| r.tx_id));
+        // The failed commit's nodes and value were released
+        assert_eq!(allocated, tree.page_pool.usage().allocated);
+
+        failing.store(false, SeqCst);
+        tree.insert(b"b", b"2").unwrap();
+        assert_eq!(&b"2"[..], &tree.get(b"b").unwrap().unwrap()[..]);
+        assert_eq!(Some(2), tree.page_pool.journaled_root().map(|r| r.tx_id));
+    }
+
+    #[test]
+    fn test_nodes_sealed_under_block_checksums() {
+        for &message_buffer in &[0, 8] {
+            let mut buf = vec![0u8; 0x80000];
+            let root = {
+                let mut pool = {
+                    let tree = BTree::with_options(&mut buf, TreeOptions {
+                        message_buffer: message_buffer,
+                        ..TreeOptions::default()
+                    });
+                    tree.describe().unwrap();
+                    tree.into_pool()
+                };
+                pool.set_block_checksums(true);
+                let tree = BTree::from_pool(pool, PoolDefaults::default()).unwrap();
+                for i in 0..3 * B {
+                    tree.insert(format!("key {:03}", i).as_bytes(), b"value").unwrap();
+                }
+                for i in 0..B {
+                    assert!(tree.remove(format!("key {:03}", 2 * i).as_bytes()).unwrap());
+                }
+                tree.flush_messages().unwrap();
+                assert_eq!(&b"value"[..], &tree.get(b"key 001").unwrap().unwrap()[..]);
+                assert_eq!(2 * B, tree.len());
+                tree.root().unwrap().unwrap()._arc_inner_index() + *ARC_INNER_SIZE
+            };
+            {
+                let tree = BTree::open(&mut buf, PoolDefaults::default()).unwrap();
+                assert_eq!(&b"value"[..], &tree.get(b"key 001").unwrap().unwrap()[..]);
+            }
+
+            // Flip a byte of the root node
+            buf[root + 8] ^= 1;
+            let caught = BTree::open(&mut buf, PoolDefaults::default()).and_then(|tree| tree.get(b"key 001").map(|_| ()));
+            match caught {
+                Err(LodestoneError::Corruption(_)) => (),
+                other => panic!("Expected the flipped byte to be caught, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_journaled_commits() {
+        let mut buf = vec![0u8; 0x40000];
+        {
+            let tree = BTree::new(&mut buf);
+            tree.describe().unwrap();
+            tree.insert(b"a", b"1").unwrap();
+            let stale = descriptor::TreeDescriptor::load(&tree.page_pool).unwrap().unwrap().root();
+            tree.insert(b"b", b"2").unwrap();
+            assert_eq!(Some(2), tree.page_pool.journaled_root().map(|r| r.tx_id));
+            // Crash after the journal flip, before the descriptor caught up
+            descriptor::TreeDescriptor::record_root(&tree.page_pool, stale).unwrap();
+        }
+        let tree = BTree::open(&mut buf, PoolDefaults::default()).unwrap();
+        assert_eq!(2, tree.tx_id.load(SeqCst));
+        assert_eq!(2, tree.len());
+        assert_eq!(&b"2"[..], &tree.get(b"b").unwrap().unwrap()[..]);
+        assert_eq!(2, descriptor::TreeDescriptor::load(&tree.page_pool).unwrap().unwrap().root().tx_id);
+        tree.verify_counts().unwrap();
+    }
+
+    #[test]
+    fn test_commit_tokens() {
+        let mut buf = vec![0u8; 0x10000];
+        let written = {
+            let tree = BTree::new(&mut buf);
+            tree.describe().unwrap();
+            let watch = tree.commit_watch();
+            let (send, receive) = mpsc::channel::<CommitToken>();
+            // Reads elsewhere, told what to wait for by the writer
+            let reader = thread::spawn(move || {
+                receive.iter().map(|token| {
+                    watch.wait_for(&token);
+                    watch.latest().tx_id
+                }).collect::<Vec<_>>()
+            });
+            for i in 0..3 {
+                tree.insert(format!("key {}", i).as_bytes(), b"value").unwrap();
+                assert!(tree.at_least(&tree.commit_token()));
+                send.send(tree.commit_token()).unwrap();
+            }
+            drop(send);
+            let seen = reader.join().unwrap();
+            assert_eq!(3, seen.len());
+            assert!(seen.iter().zip(1..).all(|(&seen, tx_id)| seen >= tx_id));
+            tree.commit_token()
+        };
+        let tree = BTree::open(&mut buf, PoolDefaults::default()).unwrap();
+        assert_eq!(written, tree.commit_token());
+        assert!(tree.commit_watch().at_least(&written));
+        tree.remove(b"key 0").unwrap();
+        assert!(tree.at_least(&written));
+        assert!(!tree.at_least(&CommitToken { tx_id: written.tx_id + 1, generation: 0 }));
+    }
+
+    #[test]
+    fn test_versions() {
+        let mut buf = vec![0u8; 0x40000];
+        let tree = BTree::new(&mut buf);
+        tree.describe().unwrap();
+        let versions = |key: &[u8]| -> Vec<(usize, Option<Vec<u8>>)> {
+            tree.versions(key).unwrap().map(|(tx_id, value)| (tx_id, value.map(|v| v.to_vec()))).collect()
+        };
+        tree.insert(b"other", b"x").unwrap();
+        tree.insert(b"key", b"one").unwrap();
+        assert_eq!(vec![(2, Some(b"one".to_vec()))], versions(b"key"));
+
+        let held = tree.snapshot().unwrap();
+        tree.insert(b"key", b"two").unwrap();
+        tree.remove(b"key").unwrap();
+        // Commit 3 was freed, and 2 is held but no longer remembered
+        assert_eq!(vec![(4, None)], versions(b"key"));
+        assert_eq!(2, held.tx_id());
+        tree.insert(b"key", b"three").unwrap();
+        let held_too = tree.snapshot().unwrap();
+        tree.insert(b"key", b"four").unwrap();
+        assert_eq!(vec![(6, Some(b"four".to_vec())), (5, Some(b"three".to_vec()))], versions(b"key"));
+        assert_eq!(vec![(6, Some(b"x".to_vec())), (5, Some(b"x".to_vec()))], versions(b"other"));
+
+        drop(held_too);
+        assert_eq!(vec![(6, Some(b"four".to_vec()))], versions(b"key"));
+        drop(held);
+        tree.verify_counts().unwrap();
+    }
+
+    #[test]
+    fn test_freeze() {
+        let mut buf = vec![0u8; 0x100000];
+        let mut tree = BTree::new(&mut buf);
+        tree.set_key_normalizer(normalize::ascii_case_insensitive);
+        assert!(tree.freeze().unwrap().get(b"key").is_none());
+        let keys: Vec<Vec<u8>> = (0..1000).map(|i| format!("key {:04}", (i * 389) % 1000).into_bytes()).collect();
+        for key in &keys {
+            tree.insert(key, &key[4..]).unwrap();
+        }
+        let frozen = tree.freeze().unwrap();
+        assert_eq!(1000, frozen.len());
+        for key in &keys {
+            assert_eq!(Some(&key[4..]), frozen.get(key));
+        }
+        assert_eq!(Some(&b"0123"[..]), frozen.get(b"KEY 0123"));
+        assert!(frozen.get(b"key 1000").is_none());
+        assert!(frozen.get(b"").is_none());
+        assert!(frozen.heap_size() > 1000 * 8);
+
+        // A snapshot, writes after freezing aren't seen
+        tree.remove(b"key 0123").unwrap();
+        tree.insert(b"key 0124", b"changed").unwrap();
+        assert_eq!(Some(&b"0123"[..]), frozen.get(b"key 0123"));
+        assert_eq!(Some(&b"0124"[..]), frozen.get(b"key 0124"));
+        assert!(tree.get(b"key 0123").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_snapshot() {
+        fn fill(tree: &BTree) {
+            for i in 0..200 {
+                tree.insert(format!("key {:03}", i).as_bytes(), b"old").unwrap();
+            }
+        }
+        fn change(tree: &BTree) {
+            for i in 0..100 {
+                tree.insert(format!("key {:03}", i).as_bytes(), b"new").unwrap();
+                tree.remove(format!("key {:03}", i + 100).as_bytes()).unwrap();
+            }
+            tree.insert(b"key 500", b"new").unwrap();
+        }
+        let mut buf = vec![0u8; 0x100000];
+        let tree = BTree::new(&mut buf);
+        assert!(tree.snapshot().unwrap().range(b"", None).unwrap().is_empty());
+        fill(&tree);
+        {
+            let snapshot = tree.snapshot().unwrap();
+            change(&tree);
+            assert_eq!(&b"old"[..], snapshot.get_ref(b"key 050").unwrap().unwrap());
+            assert_eq!(&b"old"[..], snapshot.get_ref(b"key 150").unwrap().unwrap());
+            assert!(snapshot.get(b"key 500").unwrap().is_none());
+            assert_eq!(&b"new"[..], &tree.get(b"key 050").unwrap().unwrap()[..]);
+            assert!(tree.get(b"key 150").unwrap().is_none());
+
+            let range = snapshot.range(b"key 095", Some(b"key 105")).unwrap();
+            let keys: Vec<Vec<u8>> = range.iter().map(|&(ref k, _)| k.to_vec()).collect();
+            let expected: Vec<Vec<u8>> = (95..105).map(|i| format!("key {:03}", i).into_bytes()).collect();
+            assert_eq!(expected, keys);
+            assert!(range.iter().all(|&(_, ref v)| &v[..] == b"old"));
+            assert_eq!(200, snapshot.range(b"", None).unwrap().len());
+            assert_eq!(101, tree.snapshot().unwrap().range(b"key 000", None).unwrap().len());
+        }
+
+        // Once the snapshot drops, its version is reclaimed as if it had
+        // never been taken
+        let mut control_buf = vec![0u8; 0x100000];
+        let control = BTree::new(&mut control_buf);
+        fill(&control);
+        change(&control);
+        assert_eq!(control.page_pool.lifetime_stats().live_blocks, tree.page_pool.lifetime_stats().live_blocks);
+    }
+
+    #[test]
+    fn test_iter() {
+        fn fill(tree: &BTree) {
+            for i in (0..300).rev() {
+                tree.insert(format!("key {:03}", i).as_bytes(), format!("{}", i).as_bytes()).unwrap();
+            }
+        }
+        fn change(tree: &BTree) {
+            for i in 0..150 {
+                tree.remove(format!("key {:03}", i).as_bytes()).unwrap();
+            }
+        }
+        let mut buf = vec![0u8; 0x100000];
+        let tree = BTree::new(&mut buf);
+        assert_eq!(0, tree.iter().count());
+        fill(&tree);
+        {
+            // Writers carry on around the iterator
+            let iter = tree.iter();
+            change(&tree);
+            let entries: Vec<(Vec<u8>, Vec<u8>)> = iter.map(|(k, v)| (k.to_vec(), v.to_vec())).collect();
+            let expected: Vec<(Vec<u8>, Vec<u8>)> = (0..300)
+                .map(|i| (format!("key {:03}", i).into_bytes(), format!("{}", i).into_bytes()))
+                .collect();
+            assert_eq!(expected, entries);
+        }
+        let mut seen = 0;
+        for (key, _) in &tree {
+            assert_eq!(format!("key {:03}", 150 + seen).as_bytes(), &key[..]);
+            seen += 1;
+        }
+        assert_eq!(150, seen);
+
+        // The version the iterator read is reclaimed once it drops
+        let mut control_buf = vec![0u8; 0x100000];
+        let control = BTree::new(&mut control_buf);
+        fill(&control);
+        change(&control);
+        assert_eq!(control.page_pool.lifetime_stats().live_blocks, tree.page_pool.lifetime_stats().live_blocks);
+    }
+
+    #[test]
+    fn test_scan_prefix() {
+        let mut buf = vec![0u8; 0x100000];
+        let tree = BTree::new(&mut buf);
+        assert_eq!(0, tree.scan_prefix(b"user/").count());
+        for i in 0..200 {
+            tree.insert(format!("user/{:03}", i).as_bytes(), b"u").unwrap();
+            tree.insert(format!("group/{:03}", i).as_bytes(), b"g").unwrap();
+        }
+        tree.insert(b"user", b"not under user/").unwrap();
+        tree.insert(b"users", b"not under user/").unwrap();
+
+        let keys: Vec<Vec<u8>> = tree.scan_prefix(b"user/1").map(|(k, _)| k.to_vec()).collect();
+        let expected: Vec<Vec<u8>> = (100..200).map(|i| format!("user/{:03}", i).into_bytes()).collect();
+        assert_eq!(expected, keys);
+        assert!(tree.scan_prefix(b"user/").all(|(k, v)| k.starts_with(b"user/") && &v[..] == b"u"));
+        assert_eq!(200, tree.scan_prefix(b"group/").count());
+        assert_eq!(402, tree.scan_prefix(b"").count());
+        assert_eq!(0, tree.scan_prefix(b"zzz").count());
+        assert_eq!(vec![b"user/050".to_vec()], tree.scan_prefix(b"user/050").map(|(k, _)| k.to_vec()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_node_cache() {
+        for &message_buffer in &[0, 32] {
+            let mut buf = vec![0u8; 0x800000];
+            let tree = BTree::with_options(&mut buf, TreeOptions { message_buffer: message_buffer, ..Default::default() });
+            for i in 0..1000 {
+                tree.insert(format!("key {:04}", i).as_bytes(), format!("{}", i).as_bytes()).unwrap();
+            }
+            tree.flush_messages().unwrap();
+            // Reads that skip the cache leave it empty
+            let skip = ReadOptions { fill_cache: Some(false), ..Default::default() };
+            assert_eq!(&b"10"[..], &tree.get_with(b"key 0010", &skip).unwrap().unwrap()[..]);
+            assert_eq!(0, tree.node_cache_stats().hits);
+            // Repeated reads go through the decoded root
+            for i in 0..1000 {
+                assert_eq!(format!("{}", i).as_bytes(), &tree.get(format!("key {:04}", i).as_bytes()).unwrap().unwrap()[..]);
+            }
+            if message_buffer == 0 {
+                assert!(tree.node_cache_stats().hits > 900);
+            }
+            // A new root after writes is decoded afresh, not served stale
+            for i in 0..1000 {
+                tree.insert(format!("key {:04}", i).as_bytes(), b"again").unwrap();
+            }
+            tree.remove(b"key 0500").unwrap();
+            for i in 0..1000 {
+                let value = tree.get(format!("key {:04}", i).as_bytes()).unwrap();
+                if i == 500 {
+                    assert!(value.is_none());
+                } else {
+                    assert_eq!(&b"again"[..], &value.unwrap()[..]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_distinct_prefixes() {
+        for &message_buffer in &[0, 32] {
+            let mut buf = vec![0u8; 0x800000];
+            let tree = BTree::with_options(&mut buf, TreeOptions { message_buffer: message_buffer, ..Default::default() });
+            assert!(tree.distinct_prefixes(b'/', b"").unwrap().is_empty());
+            for dir in 0..20 {
+                for file in 0..50 {
+                    tree.insert(format!("home/{:02}/{:02}", dir, file).as_bytes(), b"").unwrap();
+                }
+            }
+            tree.insert(b"home", b"").unwrap();
+            tree.insert(b"home/readme", b"").unwrap();
+            tree.insert(b"var/log", b"").unwrap();
+            assert_eq!(vec![b"home".to_vec(), b"home/".to_vec(), b"var/".to_vec()], tree.distinct_prefixes(b'/', b"").unwrap());
+            let under_home = tree.distinct_prefixes(b'/', b"home/").unwrap();
+            assert_eq!(21, under_home.len());
+            assert_eq!(&b"home/00/"[..], &under_home[0][..]);
+            assert_eq!(&b"home/readme"[..], &under_home[20][..]);
+            assert_eq!(50, tree.distinct_prefixes(b'/', b"home/07/").unwrap().len());
+            assert!(tree.distinct_prefixes(b'/', b"tmp/").unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_scan_with_limit() {
+        let minute = Duration::from_secs(60);
+        let mut buf = vec![0u8; 0x800000];
+        let tree = BTree::with_options(&mut buf, TreeOptions { message_buffer: 32, ..Default::default() });
+        // Recent versions are remembered in the tree's description
+        tree.describe().unwrap();
+        assert_eq!((vec![], None), tree.scan_with_limit(b"", b"z", None, 10, minute).unwrap());
+        for i in 0..500 {
+            tree.insert(format!("key {:03}", i).as_bytes(), format!("{}", i).as_bytes()).unwrap();
+        }
+        // Pages of 100 over [key 100, key 350), the continuation passed
+        // along as a token
+        let mut pages = Vec::new();
+        let mut token: Option<Vec<u8>> = None;
+        loop {
+            let from = token.map(|t| scan::Continuation::from_token(&t).unwrap());
+            let (entries, next) = tree.scan_with_limit(b"key 100", b"key 350", from.as_ref(), 100, minute).unwrap();
+            pages.push(entries.len());
+            assert_eq!(format!("{}", 100 + (pages.len() - 1) * 100).as_bytes(), &entries[0].value[..]);
+            match next {
+                Some(c) => token = Some(c.to_token()),
+                None => break,
+            }
+        }
+        assert_eq!(vec![100, 100, 50], pages);
+
+        // Carrying on reads the version the scan started on, while a
+        // snapshot keeps it around
+        let (_, next) = tree.scan_with_limit(b"key 100", b"key 350", None, 100, minute).unwrap();
+        {
+            let _snapshot = tree.snapshot().unwrap();
+            assert!(tree.remove(b"key 250").unwrap());
+            let (entries, _) = tree.scan_with_limit(b"key 100", b"key 350", next.as_ref(), 100, minute).unwrap();
+            assert_eq!(&b"key 250"[..], &entries[50].key[..]);
+        }
+        // and fails once the tree lets go of it
+        for i in 0..3 {
+            tree.insert(format!("key {:03}", i).as_bytes(), b"again").unwrap();
+        }
+        assert!(tree.scan_with_limit(b"key 100", b"key 350", next.as_ref(), 100, minute).is_err());
+        assert_eq!(99, tree.scan_with_limit(b"key 200", b"key 300", None, 1000, minute).unwrap().0.len());
+    }
+
+    #[test]
+    fn test_count_range() {
+        let mut buf = vec![0u8; 0x800000];
+        let tree = BTree::new(&mut buf);
+        assert_eq!(0, tree.count_range(b"", b"z").unwrap());
+        for i in 0..1000 {
+            tree.insert(format!("key {:04}", i).as_bytes(), b"value").unwrap();
+        }
+        for i in 200..400 {
+            tree.remove(format!("key {:04}", i).as_bytes()).unwrap();
+        }
+        assert_eq!(800, tree.count_range(b"", b"z").unwrap());
+        assert_eq!(100, tree.count_range(b"key 0100", b"key 0300").unwrap());
+        assert_eq!(0, tree.count_range(b"key 0200", b"key 0400").unwrap());
+        assert_eq!(1, tree.count_range(b"key 0999", b"key 1").unwrap());
+        assert_eq!(0, tree.count_range(b"key 1", b"z").unwrap());
+        // Seeks skip the children the removes emptied
+        let minute = Duration::from_secs(60);
+        let (entries, _) = tree.scan_with_limit(b"key 0199a", b"z", None, 1, minute).unwrap();
+        assert_eq!(b"key 0400".to_vec(), entries[0].key);
+    }
+
+    #[test]
+    fn test_digest_range() {
+        let mut buf = vec![0u8; 0x800000];
+        let tree = BTree::new(&mut buf);
+        assert_eq!(0, tree.digest_range(b"", None).unwrap());
+        for i in 0..1000 {
+            tree.insert(format!("key {:04}", i).as_bytes(), b"value").unwrap();
+        }
+        // Another split of the same entries into nodes, with writes
+        // still buffered on the way down
+        let mut replica_buf = vec![0u8; 0x800000];
+        let replica = BTree::with_options(&mut replica_buf, TreeOptions { message_buffer: 32, ..Default::default() });
+        for i in (0..1000).rev() {
+            replica.insert(format!("key {:04}", i).as_bytes(), b"value").unwrap();
+        }
+        let digest = |tree: &BTree, start: &[u8], end: Option<&[u8]>| tree.digest_range(start, end).unwrap();
+        assert_eq!(digest(&tree, b"", None), digest(&replica, b"", None));
+        assert_eq!(digest(&tree, b"key 0100", Some(b"key 0700")), digest(&replica, b"key 0100", Some(b"key 0700")));
+        // Ranges add up
+        assert_eq!(digest(&tree, b"", None),
+            digest(&tree, b"", Some(b"key 0500")).wrapping_add(digest(&tree, b"key 0500", None)));
+
+        // A change shows in the ranges covering it and no others, and
+        // only what the commit copied is digested again
+        let cached = tree.digests.lock().unwrap().len();
+        replica.insert(b"key 0600", b"changed").unwrap();
+        tree.insert(b"key 0600", b"changed").unwrap();
+        assert_eq!(digest(&tree, b"", None), digest(&replica, b"", None));
+        // The new root and leaf
+        assert_eq!(cached + 2, tree.digests.lock().unwrap().len());
+        replica.insert(b"key 0601", b"diverged").unwrap();
+        assert!(digest(&tree, b"key 0500", Some(b"key 0700")) != digest(&replica, b"key 0500", Some(b"key 0700")));
+        assert_eq!(digest(&tree, b"", Some(b"key 0601")), digest(&replica, b"", Some(b"key 0601")));
+        assert_eq!(digest(&tree, b"key 0602", None), digest(&replica, b"key 0602", None));
+    }
+
+    #[test]
+    fn test_matches_btreemap() {
+        use std::collections::BTreeMap;
+        let minute = Duration::from_secs(60);
+        for &message_buffer in &[0, 32] {
+            let mut buf = vec![0u8; 0x800000];
+            let tree = BTree::with_options(&mut buf, TreeOptions { message_buffer: message_buffer, ..Default::default() });
+            let mut map = BTreeMap::new();
+            let mut state = 1usize;
+            for step in 0..3000 {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                let key = format!("key {:03}", (state >> 33) % 400).into_bytes();
+                if (state >> 20) % 3 == 0 {
+                    assert_eq!(map.remove(&key).is_some(), tree.remove(&key).unwrap());
+                } else {
+                    let value = format!("{}", step).into_bytes();
+                    tree.insert(&key, &value).unwrap();
+                    map.insert(key.clone(), value);
+                }
+                let expected = map.get(&key).cloned();
+                assert_eq!(expected, tree.get(&key).unwrap().map(|v| v.to_vec()));
+                assert_eq!(map.len(), tree.len());
+                if step % 500 == 0 {
+                    let start = b"key 100".to_vec();
+                    let end = b"key 300".to_vec();
+                    let range: Vec<(Vec<u8>, Vec<u8>)> = map.range(start.clone()..end.clone())
+                        .map(|(k, v)| (k.clone(), v.clone())).collect();
+                    let (entries, next) = tree.scan_with_limit(&start, &end, None, usize::max_value(), minute).unwrap();
+                    assert!(next.is_none());
+                    assert_eq!(range, entries.into_iter().map(|e| (e.key, e.value)).collect::<Vec<_>>());
+                }
+            }
+            let all: Vec<(Vec<u8>, Vec<u8>)> = map.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+            assert_eq!(all, tree.iter().map(|(k, v)| (k.to_vec(), v.to_vec())).collect::<Vec<_>>());
+            tree.verify_counts().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_message_buffers() {
+        // A scattered insert order, and every third key removed again
+        fn write(tree: &BTree) {
+            for i in 0..2000 {
+                let k = i * 7919 % 2000;
+                tree.insert(format!("key {:04}", k).as_bytes(), format!("{}", k).as_bytes()).unwrap();
+            }
+            for k in (0..2000).filter(|k| k % 3 == 0) {
+                assert!(tree.remove(format!("key {:04}", k).as_bytes()).unwrap());
+            }
+        }
+        let mut buf = vec![0u8; 0x800000];
+        let tree = BTree::with_options(&mut buf, TreeOptions { message_buffer: 32, ..Default::default() });
+        tree.describe().unwrap();
+        write(&tree);
+        let mut plain_buf = vec![0u8; 0x800000];
+        let plain = BTree::new(&mut plain_buf);
+        write(&plain);
+        // Fewer nodes copied for the same writes
+        let nodes_copied = |tree: &BTree| tree.page_pool.lifetime_stats().typed_allocations;
+        assert!(nodes_copied(&tree) * 3 < nodes_copied(&plain) * 2);
+
+        // Reads see through the buffers, overwrites included
+        assert_eq!(1333, tree.len());
+        assert!(tree.get(b"key 0300").unwrap().is_none());
+        tree.insert(b"key 0301", b"again").unwrap();
+        assert_eq!(&b"again"[..], &tree.get(b"key 0301").unwrap().unwrap()[..]);
+        assert_eq!(&b"1999"[..], &tree.get(b"key 1999").unwrap().unwrap()[..]);
+        assert!(!tree.remove(b"key 0300").unwrap());
+
+        // Scans drain the buffers first
+        let keys: Vec<Vec<u8>> = tree.iter().map(|(k, _)| k.to_vec()).collect();
+        let expected: Vec<Vec<u8>> = (0..2000).filter(|k| k % 3 != 0).map(|k| format!("key {:04}", k).into_bytes()).collect();
+        assert_eq!(expected, keys);
+        tree.verify_counts().unwrap();
+        assert!(tree.orphaned_values().unwrap().is_empty());
+
+        // The mode is part of the tree's description
+        let tree = BTree::from_pool(tree.into_pool(), PoolDefaults::default()).unwrap();
+        assert_eq!(32, tree.options.message_buffer);
+        assert_eq!(&b"again"[..], &tree.get(b"key 0301").unwrap().unwrap()[..]);
+    }
+
+    #[test]
+    fn test_message_buffers_rebalance() {
+        fn assert_filled(picture: &node::TreeSnapshot, root: bool) {
+            assert!(root || picture.fill >= 50, "underfull node {:?}", picture.keys.first());
+            for child in &picture.children {
+                assert_filled(child, false);
+            }
+        }
+        let mut buf = vec![0u8; 0x800000];
+        let tree = BTree::with_options(&mut buf, TreeOptions { message_buffer: 8, ..Default::default() });
+        let keys: Vec<Vec<u8>> = (0..2000).map(|i| format!("key {:04}", i * 7919 % 2000).into_bytes()).collect();
+        for key in &keys {
+            tree.insert(key, &key[4..]).unwrap();
+        }
+        // Emptied leaves go away and the rest are joined up again, as
+        // removes do without buffers
+        for key in keys.iter().filter(|k| k[7] != b'0') {
+            assert!(tree.remove(key).unwrap());
+        }
+        tree.flush_messages().unwrap();
+        assert_filled(&node::snapshot(&tree.root().unwrap().unwrap(), &tree.page_pool).unwrap(), true);
+        assert_eq!(200, tree.iter().count());
+        tree.verify_counts().unwrap();
+
+        for key in keys.iter().filter(|k| k[7] == b'0') {
+            assert!(tree.remove(key).unwrap());
+        }
+        tree.flush_messages().unwrap();
+        let root = tree.root().unwrap().unwrap();
+        assert_eq!(1, node::tree_references(&root, &tree.page_pool).unwrap().len());
+        assert!(node::snapshot(&root, &tree.page_pool).unwrap().leaf);
+        assert!(tree.orphaned_values().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_structure_snapshot() {
+        let mut buf = vec![0u8; 0x100000];
+        let tree = BTree::new(&mut buf);
+        assert_eq!(None, tree.structure_snapshot().unwrap());
+        tree.insert(b"b", b"2").unwrap();
+        tree.insert(b"a", b"1").unwrap();
+        let picture = tree.structure_snapshot().unwrap().unwrap();
+        assert!(picture.leaf);
+        assert_eq!(vec![b"a".to_vec(), b"b".to_vec()], picture.keys);
+        assert_eq!(vec![b"1".to_vec(), b"2".to_vec()], picture.values);
+        // The same entries written in another order make the same picture
+        let mut other_buf = vec![0u8; 0x100000];
+        let other = BTree::new(&mut other_buf);
+        other.insert(b"a", b"1").unwrap();
+        other.insert(b"b", b"2").unwrap();
+        assert_eq!(picture, other.structure_snapshot().unwrap().unwrap());
+    }
+
+    #[test]
+    fn test_commit_compacts_leaves() {
+        let mut buf = vec![0u8; 0x200000];
+        let tree = BTree::new(&mut buf);
+        tree.insert(b"key 000", b"0").unwrap();
+        // Leaves of 20, as a bulk load with a low fill leaves them
+        {
+            let pool = &tree.page_pool;
+            let pairs = (0..300).map(|i| Ok((format!("key {:03}", i).into_bytes(), format!("{}", i).into_bytes())));
+            let sparse = node::bulk_build(pairs, 2, 20, pool).unwrap().unwrap();
+            tree.commit_root(300, None, |_, _, _| Ok(sparse)).unwrap();
+        }
+        let leaves = |tree: &BTree| node::snapshot(&tree.root().unwrap().unwrap(), &tree.page_pool).unwrap().children.len();
+        assert_eq!(15, leaves(&tree));
+
+        // Writing anywhere under the root merges its leaves back up
+        tree.insert(b"key 150", b"again").unwrap();
+        assert_eq!(4, leaves(&tree));
+        assert_eq!(300, tree.iter().count());
+        assert_eq!(&b"299"[..], &tree.get(b"key 299").unwrap().unwrap()[..]);
+        assert_eq!(&b"again"[..], &tree.get(b"key 150").unwrap().unwrap()[..]);
+        // Nothing the merges replaced is left behind (separators share
+        // their leaf's key block)
+        let mut reached: Vec<usize> = node::tree_references(&tree.root().unwrap().unwrap(), &tree.page_pool).unwrap()
+            .iter().map(|r| r.arc_inner_index()).collect();
+        reached.sort();
+        reached.dedup();
+        assert_eq!(reached.len(), tree.page_pool.lifetime_stats().live_blocks);
+    }
+
+    #[test]
+    fn test_merge_from() {
+        use super::merge::ConflictPolicy::*;
+        let mut shard_buf = vec![0u8; 0x100000];
+        let shard = BTree::new(&mut shard_buf);
+        for i in 100..300 {
+            shard.insert(format!("key {:03}", i).as_bytes(), b"shard").unwrap();
+        }
+        shard.insert(b"key 000", b"same").unwrap();
+        for &(policy, expected) in &[(LastWriteWins, &b"shard"[..]), (KeepExisting, &b"mine"[..])] {
+            let mut buf = vec![0u8; 0x100000];
+            let tree = BTree::new(&mut buf);
+            for i in 0..200 {
+                tree.insert(format!("key {:03}", i).as_bytes(), b"mine").unwrap();
+            }
+            tree.insert(b"key 000", b"same").unwrap();
+            let stored = tree.merge_from(&shard, policy).unwrap();
+            assert_eq!(if policy == LastWriteWins { 200 } else { 100 }, stored);
+            assert_eq!(300, tree.len());
+            assert_eq!(300, tree.iter().count());
+            assert_eq!(expected, &tree.get(b"key 150").unwrap().unwrap()[..]);
+            assert_eq!(&b"shard"[..], &tree.get(b"key 250").unwrap().unwrap()[..]);
+            assert_eq!(&b"mine"[..], &tree.get(b"key 050").unwrap().unwrap()[..]);
+        }
+
+        // A conflict stops the merge, keeping what came before it
+        let mut buf = vec![0u8; 0x100000];
+        let tree = BTree::new(&mut buf);
+        tree.insert(b"key 200", b"mine").unwrap();
+        assert!(tree.merge_from(&shard, Error).is_err());
+        assert_eq!(&b"mine"[..], &tree.get(b"key 200").unwrap().unwrap()[..]);
+        assert_eq!(&b"shard"[..], &tree.get(b"key 199").unwrap().unwrap()[..]);
+        assert!(tree.get(b"key 201").unwrap().is_none());
+
+        // Batched, the merge commits whole batches and reports after each
+        let mut buf = vec![0u8; 0x100000];
+        let tree = BTree::new(&mut buf);
+        tree.insert(b"key 250", b"mine").unwrap();
+        let tx_id = tree.tx_id.load(SeqCst);
+        let mut reported = Vec::new();
+        assert!(tree.merge_from_batched(&shard, Error, 60, |merged| reported.push(merged)).is_err());
+        assert_eq!(vec![60, 120], reported);
+        assert_eq!(tx_id + 2, tree.tx_id.load(SeqCst));
+        assert_eq!(121, tree.len());
+        assert!(tree.get(b"key 218").unwrap().is_some());
+        assert!(tree.get(b"key 219").unwrap().is_none());
+
+        let mut buf = vec![0u8; 0x100000];
+        let tree = BTree::new(&mut buf);
+        tree.insert(b"key 000", b"mine").unwrap();
+        let mut reported = Vec::new();
+        let stored = tree.merge_from_batched(&shard, KeepExisting, 64, |merged| reported.push(merged)).unwrap();
+        assert_eq!(200, stored);
+        assert_eq!(vec![64, 128, 192, 201], reported);
+        assert_eq!(201, tree.len());
+        assert_eq!(&b"mine"[..], &tree.get(b"key 000").unwrap().unwrap()[..]);
+        tree.verify_counts().unwrap();
+    }
+
+    #[test]
+    fn test_copy_entry_to() {
+        let mut buf = vec![0u8; 0x10000];
+        let tree = BTree::new(&mut buf);
+        let mut other_buf = vec![0u8; 0x10000];
+        let other = BTree::with_options(&mut other_buf, TreeOptions { message_buffer: 32, ..Default::default() });
+        tree.insert(b"key", b"value").unwrap();
+        other.insert(b"key", b"old").unwrap();
+        assert!(tree.copy_entry_to(b"key", &other).unwrap());
+        assert!(!tree.copy_entry_to(b"missing", &other).unwrap());
+        assert_eq!(&b"value"[..], &other.get(b"key").unwrap().unwrap()[..]);
+        assert_eq!(1, other.len());
+        assert_eq!(&b"value"[..], &tree.get(b"key").unwrap().unwrap()[..]);
+
+        // Stored as the copied block, which goes with the entry
+        let mut plain_buf = vec![0u8; 0x10000];
+        let plain = BTree::new(&mut plain_buf);
+        assert!(tree.copy_entry_to(b"key", &plain).unwrap());
+        assert!(tree.copy_entry_to(b"key", &plain).unwrap());
+        assert_eq!(&b"value"[..], &plain.get(b"key").unwrap().unwrap()[..]);
+        assert_eq!(1, plain.len());
+        assert!(plain.orphaned_values().unwrap().is_empty());
+        assert!(plain.remove(b"key").unwrap());
+        assert_eq!(1, plain.page_pool.lifetime_stats().live_blocks);
+    }
+
+    #[test]
+    fn test_apply_changes() {
+        use super::replication::{AppliedSummary, ChangeOp, ChangeRecord};
+        fn put(tx_stamp: usize, key: &str, value: &str) -> ChangeRecord {
+            ChangeRecord { tx_stamp: tx_stamp, key: key.as_bytes().to_vec(), op: ChangeOp::Put(value.as_bytes().to_vec()) }
+        }
+        let mut stream: Vec<ChangeRecord> = (0..300).map(|i| put(i / 3 + 1, &format!("key {:03}", i), "first")).collect();
+        stream.push(put(101, "key 000", "second"));
+        stream.push(ChangeRecord { tx_stamp: 101, key: b"key 001".to_vec(), op: ChangeOp::Delete });
+        for &message_buffer in &[0, 32] {
+            let mut buf = vec![0u8; 0x800000];
+            let tree = BTree::with_options(&mut buf, TreeOptions { message_buffer: message_buffer, ..Default::default() });
+            assert_eq!(0, tree.replication_high_water().unwrap());
+            let summary = tree.apply_changes(stream[..150].to_vec(), 20).unwrap();
+            assert_eq!(AppliedSummary { applied: 150, skipped: 0, batches: 8, high_water: 50 }, summary);
+            assert_eq!(50, tree.replication_high_water().unwrap());
+
+            // Replaying the whole stream only applies what's new
+            let summary = tree.apply_changes(stream.clone(), 20).unwrap();
+            assert_eq!(AppliedSummary { applied: 152, skipped: 150, batches: 8, high_water: 101 }, summary);
+            assert_eq!(&b"second"[..], &tree.get(b"key 000").unwrap().unwrap()[..]);
+            assert!(tree.get(b"key 001").unwrap().is_none());
+            assert_eq!(&b"first"[..], &tree.get(b"key 299").unwrap().unwrap()[..]);
+            // The high water mark is kept in the tree, but isn't an entry
+            assert_eq!(299, tree.len());
+            assert_eq!(1, tree.count_range(system::SYSTEM_PREFIX, system::SYSTEM_END).unwrap());
+            tree.verify_counts().unwrap();
+            assert!(tree.orphaned_values().unwrap().is_empty());
+
+            let reserved = vec![ChangeRecord { tx_stamp: 102, key: system::system_key(b"stats"), op: ChangeOp::Delete }];
+            match tree.apply_changes(reserved, 1) {
+                Err(LodestoneError::ReservedKey(_)) => (),
+                other => panic!("Expected ReservedKey, got {:?}", other),
+            }
+            assert_eq!(101, tree.replication_high_water().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_maintenance() {
+        let mut buf = vec![0u8; 0x800000];
+        let tree = BTree::with_options(&mut buf, TreeOptions { message_buffer: 32, ..Default::default() });
+        for i in 0..500 {
+            tree.insert(format!("key {:03}", i).as_bytes(), b"value").unwrap();
+        }
+        let orphan = tree.page_pool.malloc(b"orphan").unwrap();
+        mem::forget(orphan);
+        assert!(!tree.orphaned_values().unwrap().is_empty());
+
+        let mut maintenance = BTree::maintenance(MaintenanceBudget { time: Duration::from_secs(10), io_bytes: 1 << 20 });
+        assert_eq!(3, maintenance.tick(&tree));
+        for name in &["flush messages", "sweep orphans", "integrity sampling"] {
+            let stats = maintenance.stats(name).unwrap();
+            assert_eq!((1, 0), (stats.runs, stats.errors), "{}", name);
+        }
+        assert!(maintenance.stats("sweep orphans").unwrap().io_bytes > 0);
+        assert!(tree.orphaned_values().unwrap().is_empty());
+        assert_eq!(1, tree.stats.samples_verified.load(SeqCst));
+        // Nothing left buffered
+        let root = tree.root().unwrap().unwrap();
+        assert!(!root.clone_to_arc_byte_slice(&tree.page_pool).unwrap().deref_as::<Node>().has_messages(&tree.page_pool).unwrap());
+        assert_eq!(&b"value"[..], &tree.get(b"key 250").unwrap().unwrap()[..]);
+        tree.verify_counts().unwrap();
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        let mut file = Vec::new();
+        for i in 0..1000 {
+            ingest::write_record(&mut file, format!("key {:04}", i).as_bytes(), format!("{}", i).as_bytes()).unwrap();
+        }
+        let mut buf = vec![0u8; 0x800000];
+        let tree = BTree::new(&mut buf);
+        tree.insert(b"key 0000", b"taken").unwrap();
+        assert!(tree.bulk_load(&file[..], 80).is_err());
+        assert!(tree.remove(b"key 0000").unwrap());
+        assert_eq!(1000, tree.bulk_load(&file[..], 80).unwrap());
+        assert_eq!(1000, tree.len());
+        assert_eq!(&b"517"[..], &tree.get(b"key 0517").unwrap().unwrap()[..]);
+        // An ordinary tree from here on
+        tree.insert(b"key 0517", b"again").unwrap();
+        assert!(tree.remove(b"key 0999").unwrap());
+        assert_eq!(999, tree.iter().count());
+        tree.verify_counts().unwrap();
+        assert!(tree.orphaned_values().unwrap().is_empty());
+
+        let partition = |from: usize, to: usize| -> Vec<Result<(Vec<u8>, Vec<u8>), LodestoneError>> {
+            (from..to).map(|i| Ok((format!("key {:04}", i).into_bytes(), vec![i as u8]))).collect()
+        };
+        let mut buf = vec![0u8; 0x800000];
+        let tree = BTree::new(&mut buf);
+        assert_eq!(2500, tree.bulk_load_partitioned(vec![partition(0, 1200), partition(1200, 2500)], 50, 0x200000).unwrap());
+        assert_eq!(&[77u8][..], &tree.get(b"key 0077").unwrap().unwrap()[..]);
+        tree.verify_counts().unwrap();
+
+        // Reserved keys leave the tree as it was
+        let mut buf = vec![0u8; 0x800000];
+        let tree = BTree::new(&mut buf);
+        let reserved = vec![Ok((b"key".to_vec(), vec![])), Ok((system::system_key(b"stats"), vec![]))];
+        match tree.bulk_load_partitioned(vec![reserved], 50, 0x10000) {
+            Err(LodestoneError::ReservedKey(_)) => (),
+            other => panic!("Expected ReservedKey, got {:?}", other),
+        }
+        assert!(tree.is_empty());
+        assert!(tree.orphaned_values().unwrap().is_empty());
+        assert!(tree.page_pool.iter_blocks().all(|b| b.is_free));
+    }
+
+    #[test]
+    fn test_ingest_sorted_file() {
+        let mut buf = vec![0u8; 0x800000];
+        let tree = BTree::new(&mut buf);
+        for i in (0..300).chain(700..1000) {
+            tree.insert(format!("key {:04}", i).as_bytes(), b"old").unwrap();
+        }
+        let mut file = Vec::new();
+        for i in 250..700 {
+            ingest::write_record(&mut file, format!("key {:04}", i).as_bytes(), b"new").unwrap();
+        }
+        let allocated = tree.page_pool.lifetime_stats().typed_allocations;
+        assert_eq!(450, tree.ingest_sorted_file(&file[..], 50).unwrap());
+        // Only the leaf overlapping the tree's keys went in key by key
+        assert!(tree.page_pool.lifetime_stats().typed_allocations - allocated < 450);
+        assert_eq!(1000, tree.len());
+        assert_eq!(1000, tree.iter().count());
+        assert_eq!(&b"old"[..], &tree.get(b"key 0249").unwrap().unwrap()[..]);
+        assert_eq!(&b"new"[..], &tree.get(b"key 0250").unwrap().unwrap()[..]);
+        assert_eq!(&b"new"[..], &tree.get(b"key 0500").unwrap().unwrap()[..]);
+        assert_eq!(&b"old"[..], &tree.get(b"key 0700").unwrap().unwrap()[..]);
+        tree.verify_counts().unwrap();
+        assert!(tree.orphaned_values().unwrap().is_empty());
+        // An ordinary tree from here on
+        tree.insert(b"key 0500", b"again").unwrap();
+        assert!(tree.remove(b"key 0501").unwrap());
+        assert_eq!(999, tree.iter().count());
+
+        // Into an empty tree it's a bulk load
+        let mut buf = vec![0u8; 0x800000];
+        let tree = BTree::new(&mut buf);
+        assert_eq!(450, tree.ingest_sorted_file(&file[..], 50).unwrap());
+        assert_eq!(450, tree.len());
+        assert_eq!(0, tree.ingest_sorted_file(&b""[..], 50).unwrap());
+    }
+
+    #[test]
+    fn test_delete_where() {
+        for &message_buffer in &[0, 8] {
+            let mut buf = vec![0u8; 0x200000];
+            let tree = BTree::with_options(&mut buf, TreeOptions { message_buffer: message_buffer, ..Default::default() });
+            for i in 0..1000 {
+                let expired = if i % 3 == 0 { "expired" } else { "live" };
+                tree.insert(format!("key {:04}", i).as_bytes(), expired.as_bytes()).unwrap();
+            }
+            let expired = |_: &[u8], value: &[u8]| value == b"expired";
+
+            // Calls stop short, a leaf or max_entries in, and say where to
+            // go on
+            let (mut total, mut cursor) = (0, Some(b"key 0100".to_vec()));
+            let mut calls = 0;
+            while let Some(from) = cursor {
+                let (removed, next) = tree.delete_where(&from, Some(b"key 0900"), &expired, 5).unwrap();
+                assert!(removed <= 5);
+                assert!(next.as_ref().map_or(true, |next| next > &from));
+                total += removed;
+                cursor = next;
+                calls += 1;
+                assert_eq!(1000 - total, tree.len());
+            }
+            assert_eq!(266, total);
+            assert!(calls >= 266 / 5);
+            assert_eq!(1000 - 266, tree.len());
+            assert_eq!(1000 - 266, tree.iter().count());
+            assert!(tree.get(b"key 0099").unwrap().is_some());
+            assert!(tree.get(b"key 0102").unwrap().is_none());
+            assert!(tree.get(b"key 0101").unwrap().is_some());
+            assert!(tree.get(b"key 0900").unwrap().is_some());
+            tree.verify_counts().unwrap();
+
+            // Unbounded at the end, nothing left to do after the last leaf
+            let (_, resume) = tree.delete_where(b"key 0950", None, &expired, 1000).unwrap();
+            assert!(resume.is_none());
+            assert!(tree.get(b"key 0999").unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_numeric_updates() {
+        for &message_buffer in &[0, 8] {
+            let mut buf = vec![0u8; 0x200000];
+            let tree = BTree::with_options(&mut buf, TreeOptions { message_buffer: message_buffer, ..Default::default() });
+            for i in 0..300 {
+                tree.insert(format!("key {:03}", i).as_bytes(), b"filler").unwrap();
+            }
+            assert!(tree.put_if_greater(b"high", 5).unwrap());
+            assert!(!tree.put_if_greater(b"high", 5).unwrap());
+            assert!(tree.put_if_greater(b"high", 9).unwrap());
+            assert!(tree.put_if_less(b"low", 5).unwrap());
+            assert!(!tree.put_if_less(b"low", 7).unwrap());
+            assert!(tree.put_if_less(b"low", 2).unwrap());
+            assert_eq!(numeric::encode_u64(9), tree.get(b"high").unwrap().unwrap().to_vec());
+            assert_eq!(numeric::encode_u64(2), tree.get(b"low").unwrap().unwrap().to_vec());
+
+            for _ in 0..10 {
+                tree.add(b"count", 3).unwrap();
+            }
+            assert_eq!(25, tree.add(b"count", -5).unwrap());
+            // Going below 0 leaves the counter alone
+            assert!(tree.add(b"count", -26).is_err());
+            assert_eq!(numeric::encode_u64(25), tree.get(b"count").unwrap().unwrap().to_vec());
+            assert!(tree.add(b"key 000", 1).is_err());
+            assert_eq!(303, tree.len());
+            assert_eq!(303, tree.iter().count());
+        }
+    }
+
+    #[test]
+    fn test_orphaned_values() {
+        let mut buf = vec![0u8; 0x40000];
+        let tree = BTree::new(&mut buf);
+        tree.describe().unwrap();
+        for i in 0..200 {
+            tree.insert(format!("order {:03}", i).as_bytes(), b"shipped").unwrap();
+        }
+        assert!(tree.orphaned_values().unwrap().is_empty());
+
+        // A crash after a value (and a node) were written, before any
+        // leaf referred to them
+        let orphan = tree.page_pool.malloc(b"order 200:shipped").unwrap();
+        mem::forget(orphan.clone_to_persisted());
+        drop(orphan);
+        mem::forget(tree.page_pool.make_new::<Node>().unwrap().clone_to_persisted());
+
+        let orphans = tree.orphaned_values().unwrap();
+        assert_eq!(1, orphans.len());
+        for orphan in orphans {
+            let text = String::from_utf8(orphan.bytes).unwrap();
+            let mut fields = text.split(':');
+            tree.insert(fields.next().unwrap().as_bytes(), fields.next().unwrap().as_bytes()).unwrap();
+        }
+        assert_eq!(&b"shipped"[..], &tree.get(b"order 200").unwrap().unwrap()[..]);
+
+        let report = tree.sweep_orphans().unwrap();
+        assert_eq!(2, report.freed_blocks);
+        assert!(tree.orphaned_values().unwrap().is_empty());
+        assert_eq!(201, tree.count_entries().unwrap());
+    }
+
+    #[test]
+    fn test_entry_counts() {
+        let mut buf = vec![0u8; 0x40000];
+        let tree = BTree::new(&mut buf);
+        tree.describe().unwrap();
+        assert!(tree.is_empty());
+        for i in 0..300 {
+            tree.insert(format!("key {:03}", i).as_bytes(), b"value").unwrap();
+        }
+        // Overwrites and misses don't count
+        tree.insert(b"key 000", b"new value").unwrap();
+        assert!(!tree.remove(b"missing").unwrap());
+        assert!(tree.remove(b"key 100").unwrap());
+        assert_eq!(299, tree.len());
+        tree.verify_counts().unwrap();
+        assert_eq!(0, tree.repair_counts().unwrap());
+
+        // Drift is caught, and repaired for good
+        tree.entry_count.store(310, SeqCst);
+        assert!(tree.verify_counts().is_err());
+        assert_eq!(11, tree.repair_counts().unwrap());
+        tree.verify_counts().unwrap();
+        let tree = BTree::from_pool(tree.into_pool(), PoolDefaults::default()).unwrap();
+        assert_eq!(299, tree.len());
+    }
+
+    #[test]
+    fn test_overwrite_releases_old_versions() {
+        let mut buf = vec![0u8; 0x10000];
+        let tree = BTree::new(&mut buf);
+        tree.insert(b"key", b"first").unwrap();
+        tree.insert(b"key", b"second").unwrap();
+        let live = tree.page_pool.lifetime_stats().live_blocks;
+        for i in 0..1000 {
+            tree.insert(b"key", format!("value {}", i).as_bytes()).unwrap();
+        }
+        assert_eq!(live, tree.page_pool.lifetime_stats().live_blocks);
+        assert_eq!(&b"value 999"[..], &tree.get(b"key").unwrap().unwrap()[..]);
+    }
+
+    #[test]
+    fn test_user_writes_are_checked() {
+        let mut buf = vec![0u8; 0x10000];
+        let mut tree = BTree::new(&mut buf);
+        match tree.insert(&system::system_key(b"stats"), b"value") {
+            Err(LodestoneError::ReservedKey(_)) => (),
+            other => panic!("Expected ReservedKey, got {:?}", other),
+        }
+        tree.set_key_normalizer(normalize::ascii_case_insensitive);
+        tree.insert(b"Key", b"value").unwrap();
+        assert_eq!(&b"value"[..], &tree.get(b"KEY").unwrap().unwrap()[..]);
+        assert!(tree.remove(b"kEy").unwrap());
+        assert!(tree.get(b"key").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_panicking_commit_poisons() {
+        let mut buf = vec![0u8; 0x2000];
+        let tree = BTree::new(&mut buf);
+        assert_eq!(64, tree.commit_with(|_| Ok(64)).unwrap());
+        assert!(tree.commit_with(|_| Err(LodestoneError::UserError("nope"))).is_err());
+        assert!(!tree.is_poisoned());
+
+        match tree.commit_with(|_| panic!("callback blew up")) {
+            Err(LodestoneError::Poisoned(_)) => (),
+            other => panic!("Expected Poisoned, got {:?}", other),
+        }
+        assert!(tree.is_poisoned());
+        // The root from before the failed commit is still current
+        assert_eq!(64, tree.current_root.load(SeqCst));
+        match tree.commit_with(|_| Ok(128)) {
+            Err(LodestoneError::Poisoned(_)) => (),
+            other => panic!("Expected Poisoned, got {:?}", other),
+        }
+        assert_eq!(64, tree.current_root.load(SeqCst));
+    }
+
+    #[test]
+    fn test_access_stats() {
+        let mut buf = vec![0u8; 0x10000];
+        let tree = BTree::with_options(&mut buf, TreeOptions {
+            access_sample_one_in: 1,
+            ..TreeOptions::default()
+        });
+        for key in [b"g", b"h", b"m"].iter() {
+            tree.insert(*key, b"value").unwrap();
+        }
+        tree.get(b"h").unwrap();
+        tree.get(b"nope").unwrap();
+        // Counted against the leaf the reads landed in
+        let hot = tree.hot_ranges(1);
+        assert_eq!((b"g".to_vec(), 2), (hot[0].start.clone(), hot[0].score));
+
+        let persisted = tree.persist_access_stats().unwrap();
+        let mut other_buf = vec![0u8; 0x10000];
+        let other = BTree::new(&mut other_buf);
+        assert!(other.hot_ranges(10).is_empty());
+        // Off by default
+        other.insert(b"a", b"value").unwrap();
+        other.get(b"a").unwrap();
+        assert!(other.hot_ranges(10).is_empty());
+        other.load_access_stats(&persisted).unwrap();
+        assert_eq!(tree.hot_ranges(10), other.hot_ranges(10));
+    }
+
+    #[test]
+    fn test_commits_are_blocking_sections() {
+        let mut buf = vec![0u8; 0x2000];
+        let mut tree = BTree::new(&mut buf);
+        tree.on_slow_blocking(Duration::from_secs(60), |_, _| panic!("Nothing here is slow"));
+        tree.commit_with(|_| {
+            assert!(blocking::in_blocking_section());
+            Ok(64)
+        }).unwrap();
+        {
+            let scope = tree.blocking_scope(BlockingOp::Scan);
+            assert_eq!(BlockingOp::Scan, scope.op());
+            assert!(blocking::in_blocking_section());
+        }
+        assert!(!blocking::in_blocking_section());
+    }
+}
@@ -1,22 +1,17 @@
-use std::{cmp,fmt,str};
+use std::{cmp,fmt,mem,ptr,str};
+use std::collections::HashMap;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
 use allocator::*;
+use allocator::sync::{roll, xorshift_seed};
+use checksum::*;
 
 use super::*;
+use super::descent::*;
+use super::node_cache::DecodedNode;
+use super::messages::{self, Message};
 use LodestoneError;
 
-macro_rules! recover_but_panic_in_debug {
-    ($expr:expr, $default:expr) => ({
-        let t = $expr;
-        debug_assert!(t.is_ok(), format!("Err in debug: {:?}", t.err()));
-        match t {
-            Ok(val) => val,
-            Err(_) => {
-                return $default
-            },
-        }
-    })
-}
-
 /// Internal nodes have keys in order. The corresponding
 /// child to a key index is the node that contains values
 /// less than or equal to the key.
@@ -36,212 +31,808 @@ enum NodeType {
 /// If the NodeType is Root or Internal, the children
 /// are interpreted as Nodes. If the NodeType is Leaf,
 /// the children are interpreted as the values of the mapping.
+/// Checksummed leaves keep a checksum of each key+value pair
+/// alongside the pair, and nodes derived from a checksummed
+/// node are checksummed as well.
+/// Internal nodes keep fences: (possibly truncated) copies of the
+/// smallest and largest key beneath them, so scans can skip
+/// subtrees without descending into them.
+/// The layout is C's, so nodes written with another B read the same up
+/// to their keys (see migrate_capacity).
+#[repr(C)]
 pub struct Node {
-    node_type: NodeType,
+    /// Type, flags, counts and layout version, see the HEADER_ constants
+    header: u32,
+    min_fence: Fence,
+    max_fence: Fence,
     tx_id: usize,
-    num_keys: usize,
     keys: [PersistedArcByteSlice; B],
-    num_children: usize,
     children: [PersistedArcByteSlice; B],
+    checksums: [u32; B],
+}
+
+/// Bumped whenever the node layout changes. Version 2 packed the type,
+/// flags and counts into one header word, version 1 spelled them out.
+pub const NODE_LAYOUT_VERSION: u32 = 2;
+
+// Header bits, low to high: type (2 bits), checksummed, fenced, then 8
+// bits each for num_keys and num_children, buffered, and the layout
+// version on top
+const HEADER_TYPE_SHIFT: u32 = 0;
+const HEADER_TYPE_MASK: u32 = 0b11;
+const HEADER_CHECKSUMMED_SHIFT: u32 = 2;
+const HEADER_FENCED_SHIFT: u32 = 3;
+const HEADER_KEYS_SHIFT: u32 = 4;
+const HEADER_CHILDREN_SHIFT: u32 = 12;
+const HEADER_BUFFERED_SHIFT: u32 = 20;
+pub const HEADER_COUNT_MASK: u32 = 0xFF;
+const HEADER_VERSION_SHIFT: u32 = 24;
+const HEADER_VERSION_MASK: u32 = 0xFF;
+
+pub const FENCE_PREFIX_SIZE: usize = 22;
+
+/// Internal nodes never hold more than B-1 keys, so a buffered one keeps
+/// its message buffer (see messages) in the last key slot
+const BUFFER_SLOT: usize = B - 1;
+
+/// The first FENCE_PREFIX_SIZE bytes of a key
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fence {
+    len: u8,
+    truncated: bool,
+    bytes: [u8; FENCE_PREFIX_SIZE],
+}
+
+impl Fence {
+    fn from_key(key: &[u8]) -> Fence {
+        let len = cmp::min(key.len(), FENCE_PREFIX_SIZE);
+        let mut bytes = [0u8; FENCE_PREFIX_SIZE];
+        bytes[..len].copy_from_slice(&key[..len]);
+        Fence {
+            len: len as u8,
+            truncated: key.len() > FENCE_PREFIX_SIZE,
+            bytes: bytes,
+        }
+    }
+
+    fn prefix(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+
+    /// As a lower fence: every key at or above the fence is >= end
+    fn at_or_above(&self, end: &[u8]) -> bool {
+        self.prefix() >= end
+    }
+
+    /// As an upper fence: every key at or below the fence is < start.
+    /// A truncated fence stands for any key starting with its prefix.
+    fn below(&self, start: &[u8]) -> bool {
+        self.prefix() < start && !(self.truncated && start.starts_with(self.prefix()))
+    }
+}
+
+pub enum InsertionResult<'p> {
+    HadRoom(ArcByteSlice<'p>),
+    NoRoom(Split<'p>),
+}
+
+/// What a bounded removal by predicate did to a leaf
+pub struct Removal<'p> {
+    /// The new node, or None if nothing was removed
+    pub node: Option<ArcByteSlice<'p>>,
+    pub removed: usize,
+    /// Where to pick up: the first key that wasn't examined, if
+    /// max_entries cut us short
+    pub resume_from: Option<Vec<u8>>,
+}
+
+pub struct Split<'p> {
+    bottom_half: ArcByteSlice<'p>,
+    top_half: ArcByteSlice<'p>,
+    mid_key: ArcByteSlice<'p>,
 }
 
-pub enum InsertionResult {
-    HadRoom(ArcByteSlice),
-    NoRoom(Split),
+/// A node after applying messages to it, in as many pieces as it took
+/// to hold the result. Each separator is the largest key under the piece
+/// to its left.
+pub struct Pieces<'p> {
+    pub nodes: Vec<ArcByteSlice<'p>>,
+    pub separators: Vec<ArcByteSlice<'p>>,
 }
 
-pub struct Split {
-    bottom_half: ArcByteSlice,
-    top_half: ArcByteSlice,
-    mid_key: ArcByteSlice,
+/// What became of two neighbouring nodes after Node::rebalance
+pub enum Rebalanced<'p> {
+    /// They fit in one node
+    Merged(ArcByteSlice<'p>),
+    /// Their entries were spread evenly over two, under a new separator
+    Shared(Split<'p>),
 }
 
 /// Public interface
 impl Node {
-    pub fn clone(&self, pool: &Pool) -> Result<ArcByteSlice, LodestoneError> {
+    pub fn clone<'p>(&self, pool: &'p Pool) -> Result<ArcByteSlice<'p>, LodestoneError> {
         let clone = try!(pool.clone(self));
         {
             let node = clone.deref_as_mut::<Node>();
-            for i in 0..node.num_keys {
+            for i in 0..node.num_keys() {
                 let ok = node.keys[i].retain(pool).is_ok();
                 debug_assert!(ok);
             }
-            for i in 0..node.num_children {
+            for i in 0..node.num_children() {
                 let ok = node.children[i].retain(pool).is_ok();
                 debug_assert!(ok);
             }
+            if node.buffered() {
+                let ok = node.keys[BUFFER_SLOT].retain(pool).is_ok();
+                debug_assert!(ok);
+            }
         }
+        pool.mark_written(&clone);
         Ok(clone)
     }
 
-    /// Splits the node in half, immutably, returning a tuple of the
+    /// Check that the node is consistent with itself: counts in range,
+    /// keys in ascending order, every child resolvable, and entry
+    /// checksums matching for checksummed leaves.
+    pub fn verify(&self, pool: &Pool) -> Result<(), LodestoneError> {
+        try!(self.check_counts());
+        let mut previous: Option<ArcByteSlice> = None;
+        for i in 0..self.num_keys() {
+            let key = try!(self.keys[i].clone_to_arc_byte_slice(pool));
+            if previous.map_or(false, |p| *p >= *key) {
+                return Err(LodestoneError::StructureCorrupt("Node keys are out of order"));
+            }
+            if self.node_type() == NodeType::Leaf && self.checksummed() {
+                let value = try!(self.children[i].clone_to_arc_byte_slice(pool));
+                if entry_checksum(&*key, &*value) != self.checksums[i] {
+                    return Err(LodestoneError::Corruption("Entry checksum mismatch"));
+                }
+            }
+            previous = Some(key);
+        }
+        for i in 0..self.num_children() {
+            try!(self.children[i].clone_to_arc_byte_slice(pool));
+        }
+        if self.node_type() != NodeType::Leaf && self.fenced() {
+            let expected = try!(self.child_bounds(pool));
+            if expected != Some((self.min_fence, self.max_fence)) {
+                return Err(LodestoneError::StructureCorrupt("Node fences don't match its children"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether any key in [start, end) could be beneath this node.
+    /// Unfenced internal nodes might contain anything.
+    pub fn may_overlap(&self, start: &[u8], end: &[u8], pool: &Pool) -> bool {
+        match self.bounds(pool) {
+            Ok(Some((min, max))) => !min.at_or_above(end) && !max.below(start),
+            Ok(None) => self.node_type() != NodeType::Leaf,
+            Err(_) => true,
+        }
+    }
+
+    /// Whether any key at or after start could be beneath this node
+    fn may_reach(&self, start: &[u8], pool: &Pool) -> bool {
+        match self.bounds(pool) {
+            Ok(Some((_, max))) => !max.below(start),
+            Ok(None) => self.node_type() != NodeType::Leaf,
+            Err(_) => true,
+        }
+    }
+
+    /// Splits the node in half, immutably, returning the
     /// (
     ///    new_bottom_half,
     ///    new_top_half,
     ///    mid_key,
     /// )
+    /// where mid_key is the largest key in the bottom half, so that it
+    /// can go in the parent as the separator between the halves.
     /// For an internal node, they layout is as follows:
     ///   key1 : key2 : key3 : key4
     ///   /    |      |      |     \
     /// c1     c2     c3     c4    c5
     /// So here, I want to split into
-    /// key1 : key2       key3 : key4
-    ///  /   |            /    |    \
-    /// c1   c2           c3   c4   c5
-    /// So mid_key = 2 = num_keys/2 so we get [0, 1] and [2, 3]
-    /// mid_child = 2 = num_keys/2 so we get [0, 1] and [2, 3, 4]
-    pub fn split<'a>(&'a self, tx_id: usize, pool: &'a Pool)
-        -> Result<Split, LodestoneError> {
-        assert!(self.num_keys > 0 && self.num_children > 0, "Split called on an empty node");
+    ///   key1         key3 : key4
+    ///  /   \         /    |    \
+    /// c1   c2       c3   c4   c5
+    /// with mid_key = key2, which bounds c2 from above.
+    /// A leaf splits its pairs in half and mid_key is the last key kept
+    /// in the bottom half.
+    pub fn split<'p>(&self, tx_id: usize, pool: &'p Pool)
+        -> Result<Split<'p>, LodestoneError> {
+        if self.num_keys() == 0 || self.num_children() == 0 {
+            return Err(LodestoneError::UserError("Split called on an empty node"));
+        }
+        let leaf = self.node_type() == NodeType::Leaf;
+        if !leaf && self.num_keys() < 2 {
+            return Err(LodestoneError::UserError("Split called on an internal node with one key"));
+        }
 
         let new_bottom_half_arc = try!(pool.make_new::<Node>());
         let new_top_half_arc = try!(pool.make_new::<Node>());
-        // Find midpoint
-        let midpoint = self.num_keys/2;
+        // Find midpoint. Leaves keep the pair at the midpoint in the top
+        // half, internal nodes move the key at the midpoint up.
+        let midpoint = self.num_keys()/2;
+        let (mid_key, top_keys) = if leaf { (midpoint-1, midpoint) } else { (midpoint, midpoint+1) };
+        let bottom_children = if leaf { midpoint } else { midpoint+1 };
 
         { // Borrow checker
             let new_bottom_half = new_bottom_half_arc.deref_as_mut::<Node>();
             let new_top_half = new_top_half_arc.deref_as_mut::<Node>();
-            new_bottom_half.init(tx_id, self.node_type.clone());
-            new_top_half.init(tx_id, self.node_type.clone());
+            new_bottom_half.init(tx_id, self.node_type());
+            new_top_half.init(tx_id, self.node_type());
+            new_bottom_half.set_checksummed(self.checksummed());
+            new_top_half.set_checksummed(self.checksummed());
 
             // Copy over values
             for i in 0..midpoint {
                 new_bottom_half.keys[i] = try!(self.keys[i].clone(pool));
             }
-            for i in 0..midpoint {
+            for i in 0..bottom_children {
                 new_bottom_half.children[i] = try!(self.children[i].clone(pool));
             }
-            for i in midpoint..self.num_keys {
-                new_top_half.keys[i-midpoint] = try!(self.keys[i].clone(pool));
+            for i in top_keys..self.num_keys() {
+                new_top_half.keys[i-top_keys] = try!(self.keys[i].clone(pool));
             }
-            for i in midpoint..self.num_children {
-                new_top_half.children[i-midpoint] = try!(self.children[i].clone(pool));
+            for i in bottom_children..self.num_children() {
+                new_top_half.children[i-bottom_children] = try!(self.children[i].clone(pool));
+            }
+            if leaf {
+                new_bottom_half.checksums[..midpoint].copy_from_slice(&self.checksums[..midpoint]);
+                new_top_half.checksums[..self.num_children()-midpoint]
+                    .copy_from_slice(&self.checksums[midpoint..self.num_children()]);
             }
             // Copy over metadata
-            new_bottom_half.num_keys = midpoint;
-            new_bottom_half.num_children = midpoint;
-            new_top_half.num_keys = self.num_keys - midpoint;
-            new_top_half.num_children = self.num_children - midpoint;
+            new_bottom_half.set_num_keys(midpoint);
+            new_bottom_half.set_num_children(bottom_children);
+            new_top_half.set_num_keys(self.num_keys() - top_keys);
+            new_top_half.set_num_children(self.num_children() - bottom_children);
+            try!(new_bottom_half.refresh_fences(pool));
+            try!(new_top_half.refresh_fences(pool));
         }
+        pool.mark_written(&new_bottom_half_arc);
+        pool.mark_written(&new_top_half_arc);
         Ok(Split {
             bottom_half: new_bottom_half_arc,
             top_half: new_top_half_arc,
-            mid_key: try!(self.keys[midpoint].clone_to_arc_byte_slice(pool))
+            mid_key: try!(self.keys[mid_key].clone_to_arc_byte_slice(pool))
         })
     }
 
     /// Joins two underfull nodes, immutably, returning the new merged node
-    pub fn join<'a>(bottom: &'a Node, top: &'a Node, tx_id: usize, pool: &'a Pool)
-        -> Result<ArcByteSlice, LodestoneError> {
-        assert!(bottom.num_keys + top.num_keys < B,
-            "Join called on nodes that have too many keys");
-        assert!(bottom.num_children + top.num_children < B,
-            "Join called on nodes that have too many children");
-        assert_eq!(bottom.node_type, top.node_type);
+    pub fn join<'p>(bottom: &Node, top: &Node, tx_id: usize, pool: &'p Pool)
+        -> Result<ArcByteSlice<'p>, LodestoneError> {
+        if bottom.num_keys() + top.num_keys() >= B {
+            return Err(LodestoneError::UserError("Join called on nodes that have too many keys"));
+        }
+        if bottom.num_children() + top.num_children() >= B {
+            return Err(LodestoneError::UserError("Join called on nodes that have too many children"));
+        }
+        if bottom.node_type() != top.node_type() {
+            return Err(LodestoneError::UserError("Join called on nodes of different types"));
+        }
 
         let new_arc = try!(pool.make_new::<Node>());
         { // Borrow checker
             let new_node = new_arc.deref_as_mut::<Node>();
-            new_node.init(tx_id, bottom.node_type.clone());
+            new_node.init(tx_id, bottom.node_type());
+            new_node.set_checksummed(bottom.checksummed());
 
             // Copy over keys/values
-            for i in 0..bottom.num_keys {
+            for i in 0..bottom.num_keys() {
                 new_node.keys[i] = try!(bottom.keys[i].clone(pool));
             }
-            for i in 0..top.num_keys {
-                new_node.keys[i+bottom.num_keys] = try!(top.keys[i].clone(pool));
+            for i in 0..top.num_keys() {
+                new_node.keys[i+bottom.num_keys()] = try!(top.keys[i].clone(pool));
             }
-            for i in 0..bottom.num_children {
+            for i in 0..bottom.num_children() {
                 new_node.children[i] = try!(bottom.children[i].clone(pool));
             }
-            for i in 0..top.num_children {
-                new_node.children[i+bottom.num_children] = try!(top.children[i].clone(pool));
+            for i in 0..top.num_children() {
+                new_node.children[i+bottom.num_children()] = try!(top.children[i].clone(pool));
             }
+            new_node.checksums[..bottom.num_children()].copy_from_slice(&bottom.checksums[..bottom.num_children()]);
+            new_node.checksums[bottom.num_children()..bottom.num_children()+top.num_children()]
+                .copy_from_slice(&top.checksums[..top.num_children()]);
             // Copy over metadata
-            new_node.num_keys = bottom.num_keys + top.num_keys;
-            new_node.num_children = bottom.num_children + top.num_children;
+            new_node.set_num_keys(bottom.num_keys() + top.num_keys());
+            new_node.set_num_children(bottom.num_children() + top.num_children());
+            try!(new_node.refresh_fences(pool));
         }
+        pool.mark_written(&new_arc);
         Ok(new_arc)
     }
+
+    /// Rebalance two neighbouring nodes, immutably, once either is
+    /// underfull: join them if they fit in one node, or else spread their
+    /// entries evenly over two. separator is the parent's key between
+    /// them, which internal nodes take in when their children come
+    /// together.
+    pub fn rebalance<'p>(bottom: &Node, separator: &PersistedArcByteSlice, top: &Node, tx_id: usize, pool: &'p Pool)
+        -> Result<Rebalanced<'p>, LodestoneError> {
+        if bottom.node_type() != top.node_type() {
+            return Err(LodestoneError::UserError("Rebalance called on nodes of different types"));
+        }
+        let leaf = bottom.node_type() == NodeType::Leaf;
+        let total = bottom.num_children() + top.num_children();
+        if leaf && total < B {
+            return Node::join(bottom, top, tx_id, pool).map(Rebalanced::Merged);
+        }
+
+        let mut keys: Vec<&PersistedArcByteSlice> = bottom.keys[..bottom.num_keys()].iter().collect();
+        if !leaf {
+            keys.push(separator);
+        }
+        keys.extend(top.keys[..top.num_keys()].iter());
+        let children: Vec<&PersistedArcByteSlice> = bottom.children[..bottom.num_children()].iter()
+            .chain(top.children[..top.num_children()].iter())
+            .collect();
+        let mut checksums = Vec::new();
+        if leaf {
+            checksums.extend_from_slice(&bottom.checksums[..bottom.num_children()]);
+            checksums.extend_from_slice(&top.checksums[..top.num_children()]);
+        }
+        if total < B {
+            return bottom.with_entries(tx_id, &keys, &children, &checksums, pool).map(Rebalanced::Merged);
+        }
+
+        // The same cut as split: leaves keep the separator as their last
+        // key in the bottom half, internal nodes move it up
+        let half = total / 2;
+        let bottom_keys = if leaf { half } else { half - 1 };
+        let leaf_checksums = |range: ::std::ops::Range<usize>| if leaf { &checksums[range] } else { &checksums[0..0] };
+        Ok(Rebalanced::Shared(Split {
+            bottom_half: try!(bottom.with_entries(tx_id, &keys[..bottom_keys], &children[..half],
+                leaf_checksums(0..half), pool)),
+            top_half: try!(bottom.with_entries(tx_id, &keys[half..], &children[half..],
+                leaf_checksums(half..total), pool)),
+            mid_key: try!(keys[half - 1].clone_to_arc_byte_slice(pool)),
+        }))
+    }
+}
+
+/// Tree level operations
+impl Node {
+    /// An empty leaf, the root of an empty tree
+    pub fn new_leaf<'p>(tx_id: usize, checksummed: bool, pool: &'p Pool) -> Result<ArcByteSlice<'p>, LodestoneError> {
+        let arc = try!(pool.make_new::<Node>());
+        { // Borrow checker
+            let node = arc.deref_as_mut::<Node>();
+            node.init(tx_id, NodeType::Leaf);
+            node.set_checksummed(checksummed);
+        }
+        pool.mark_written(&arc);
+        Ok(arc)
+    }
+
+    /// A new root one level up, over the halves of the old root
+    pub fn new_root<'p>(tx_id: usize, split: Split, pool: &'p Pool) -> Result<ArcByteSlice<'p>, LodestoneError> {
+        let arc = try!(pool.make_new::<Node>());
+        { // Borrow checker
+            let node = arc.deref_as_mut::<Node>();
+            node.init(tx_id, NodeType::Internal);
+            node.keys[0] = try!(pool.persist(&split.mid_key));
+            node.children[0] = try!(pool.persist(&split.bottom_half));
+            node.children[1] = try!(pool.persist(&split.top_half));
+            node.set_num_keys(1);
+            node.set_num_children(2);
+            try!(node.refresh_fences(pool));
+        }
+        pool.mark_written(&arc);
+        Ok(arc)
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.node_type() == NodeType::Leaf
+    }
+
+    /// Insert or replace key's value under this node, immutably. Returns
+    /// the new version of the node, or its two halves if it split.
+    pub fn insert<'p>(&self, tx_id: usize, key: &[u8], value: &[u8], pool: &'p Pool)
+        -> Result<InsertionResult<'p>, LodestoneError> {
+        match self.node_type() {
+            NodeType::Leaf => self.leaf_node_insert_or_set(tx_id, key, value, pool),
+            NodeType::Internal => self.internal_node_insert(tx_id, key, value, pool),
+            NodeType::Root => Err(LodestoneError::StructureCorrupt("Root nodes aren't used by the tree")),
+        }
+    }
+
+    /// insert, storing value's block as it is instead of copying its
+    /// bytes into a new one. The block must belong to pool.
+    pub fn insert_block<'p>(&self, tx_id: usize, key: &[u8], value: &ArcByteSlice<'p>, pool: &'p Pool)
+        -> Result<InsertionResult<'p>, LodestoneError> {
+        if !value._belongs_to(pool) {
+            return Err(LodestoneError::UserError("The value block belongs to another pool"));
+        }
+        match self.node_type() {
+            NodeType::Leaf => self.leaf_node_put(tx_id, key, value, Some(value), pool),
+            NodeType::Internal => self.internal_node_insert_guarded(tx_id, key, value, Some(value), pool, &mut Descent::for_pool(pool)),
+            NodeType::Root => Err(LodestoneError::StructureCorrupt("Root nodes aren't used by the tree")),
+        }
+    }
+
+    /// Read-modify-write of key's entry under this node, immutably, in one
+    /// descent, see leaf_node_update. Returns None if update left the
+    /// entry alone.
+    pub fn update<'p, F>(&self, tx_id: usize, key: &[u8], update: &F, pool: &'p Pool)
+        -> Result<Option<InsertionResult<'p>>, LodestoneError>
+        where F: Fn(Option<&[u8]>) -> Result<Option<Vec<u8>>, LodestoneError> {
+        match self.node_type() {
+            NodeType::Leaf => self.leaf_node_update(tx_id, key, update, pool),
+            NodeType::Internal => self.internal_node_update(tx_id, key, update, pool, &mut Descent::for_pool(pool)),
+            NodeType::Root => Err(LodestoneError::StructureCorrupt("Root nodes aren't used by the tree")),
+        }
+    }
+
+    /// Remove key from under this node, immutably. Returns the new
+    /// version of the node, or None if key wasn't there.
+    /// Nodes left underfull are rebalanced with a neighbour on the way
+    /// back up, and a root left with a single child gives way to it.
+    pub fn remove<'p>(&self, tx_id: usize, key: &[u8], pool: &'p Pool) -> Result<Option<ArcByteSlice<'p>>, LodestoneError> {
+        match try!(self.remove_guarded(tx_id, key, pool, &mut Descent::for_pool(pool))) {
+            Some(new_root) => collapse_root(new_root, pool).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// leaf_node_remove_where on the leaf `from` falls in, immutably,
+    /// rebalancing on the way back up as remove does. Once the leaf is
+    /// done with, the Removal resumes past it, in the next leaf, or
+    /// nowhere after the last one.
+    pub fn remove_where<'p, F>(&self, tx_id: usize, from: &[u8], pred: &F, max_entries: usize, pool: &'p Pool)
+        -> Result<Removal<'p>, LodestoneError>
+        where F: Fn(&[u8], &[u8]) -> bool {
+        let removal = try!(self.remove_where_guarded(tx_id, from, pred, max_entries, pool, &mut Descent::for_pool(pool)));
+        Ok(Removal {
+            node: match removal.node {
+                Some(new_root) => Some(try!(collapse_root(new_root, pool))),
+                None => None,
+            },
+            ..removal
+        })
+    }
+
+    fn remove_where_guarded<'p, F>(&self, tx_id: usize, from: &[u8], pred: &F, max_entries: usize, pool: &'p Pool,
+        descent: &mut Descent) -> Result<Removal<'p>, LodestoneError>
+        where F: Fn(&[u8], &[u8]) -> bool {
+        match self.node_type() {
+            NodeType::Leaf => self.leaf_node_remove_where(tx_id, from, pred, max_entries, pool),
+            NodeType::Internal => {
+                let (_, i) = try!(self.index_or_insertion_of(from, pool));
+                try!(descent.enter(&self.children[i]));
+                let child_arc = try!(self.children[i].clone_to_arc_byte_slice(pool));
+                let removal = try!(child_arc.deref_as::<Node>().remove_where_guarded(tx_id, from, pred, max_entries, pool, descent));
+                let resume_from = match removal.resume_from {
+                    // Everything up to child i's bound has been looked at
+                    None if i < self.num_keys() => {
+                        let mut past = try!(self.keys[i].clone_to_arc_byte_slice(pool)).to_vec();
+                        past.push(0);
+                        Some(past)
+                    },
+                    resume_from => resume_from,
+                };
+                Ok(Removal {
+                    node: match removal.node {
+                        Some(new_child) => Some(try!(self.internal_node_replace_underfull(tx_id, i, new_child, pool))),
+                        None => None,
+                    },
+                    removed: removal.removed,
+                    resume_from: resume_from,
+                })
+            },
+            NodeType::Root => Err(LodestoneError::StructureCorrupt("Root nodes aren't used by the tree")),
+        }
+    }
+
+    fn remove_guarded<'p>(&self, tx_id: usize, key: &[u8], pool: &'p Pool, descent: &mut Descent)
+        -> Result<Option<ArcByteSlice<'p>>, LodestoneError> {
+        match self.node_type() {
+            NodeType::Leaf => {
+                if !try!(self.leaf_node_contains_key(key, pool)) {
+                    return Ok(None);
+                }
+                self.leaf_node_remove(tx_id, key, pool).map(Some)
+            },
+            NodeType::Internal => {
+                let (_, i) = try!(self.index_or_insertion_of(key, pool));
+                try!(descent.enter(&self.children[i]));
+                let child_arc = try!(self.children[i].clone_to_arc_byte_slice(pool));
+                match try!(child_arc.deref_as::<Node>().remove_guarded(tx_id, key, pool, descent)) {
+                    Some(new_child) => self.internal_node_replace_underfull(tx_id, i, new_child, pool).map(Some),
+                    None => Ok(None),
+                }
+            },
+            NodeType::Root => Err(LodestoneError::StructureCorrupt("Root nodes aren't used by the tree")),
+        }
+    }
+
+    /// The child of an internal node that key belongs under
+    pub fn internal_node_child_for_key<'p>(&self, key: &[u8], pool: &'p Pool, descent: &mut Descent)
+        -> Result<ArcByteSlice<'p>, LodestoneError> {
+        let i = try!(self.internal_node_child_index(key, pool));
+        try!(descent.enter(&self.children[i]));
+        self.children[i].clone_to_arc_byte_slice(pool)
+    }
+
+    /// The reference to the child of an internal node that key belongs
+    /// under, without following it
+    pub fn internal_node_child_reference(&self, key: &[u8], pool: &Pool) -> Result<Reference, LodestoneError> {
+        let i = try!(self.internal_node_child_index(key, pool));
+        Ok(Reference::from_persisted(&self.children[i]))
+    }
+
+    fn internal_node_child_index(&self, key: &[u8], pool: &Pool) -> Result<usize, LodestoneError> {
+        try!(self.expect_type(NodeType::Internal));
+        let (_, i) = try!(self.index_or_insertion_of(key, pool));
+        if i >= self.num_children() {
+            return Err(LodestoneError::StructureCorrupt("Internal node has no child for the key"));
+        }
+        Ok(i)
+    }
+
+    /// The first and last key of a leaf, None if it's empty
+    pub fn leaf_node_key_range<'p>(&self, pool: &'p Pool) -> Result<Option<(ArcByteSlice<'p>, ArcByteSlice<'p>)>, LodestoneError> {
+        try!(self.expect_type(NodeType::Leaf));
+        if self.num_keys() == 0 {
+            return Ok(None);
+        }
+        let first = try!(self.keys[0].clone_to_arc_byte_slice(pool));
+        let last = try!(self.keys[self.num_keys()-1].clone_to_arc_byte_slice(pool));
+        Ok(Some((first, last)))
+    }
 }
 
 /// Private interface
 impl Node {
+    /// A new node of this one's type and checksumming, with its own
+    /// references to the given entries
+    fn with_entries<'p>(&self, tx_id: usize, keys: &[&PersistedArcByteSlice], children: &[&PersistedArcByteSlice],
+        checksums: &[u32], pool: &'p Pool) -> Result<ArcByteSlice<'p>, LodestoneError> {
+        let arc = try!(pool.make_new::<Node>());
+        { // Borrow checker
+            let node = arc.deref_as_mut::<Node>();
+            node.init(tx_id, self.node_type());
+            node.set_checksummed(self.checksummed());
+            for (i, k) in keys.iter().enumerate() {
+                node.keys[i] = try!(PersistedArcByteSlice::clone(k, pool));
+            }
+            for (i, c) in children.iter().enumerate() {
+                node.children[i] = try!(PersistedArcByteSlice::clone(c, pool));
+            }
+            node.checksums[..checksums.len()].copy_from_slice(checksums);
+            node.set_num_keys(keys.len());
+            node.set_num_children(children.len());
+            try!(node.refresh_fences(pool));
+        }
+        pool.mark_written(&arc);
+        Ok(arc)
+    }
+
     /// Perform initial setup, such as fixing the keys/children arrays,
     /// setting the tx_id
     fn init(&mut self, tx: usize, node_type: NodeType) {
-        self.num_keys = 0;
-        self.num_children = 0;
-        self.node_type = node_type;
+        self.header = NODE_LAYOUT_VERSION << HEADER_VERSION_SHIFT;
+        self.set_node_type(node_type);
         self.tx_id = tx;
     }
 
+    fn header_field(&self, shift: u32, mask: u32) -> u32 {
+        (self.header >> shift) & mask
+    }
+
+    fn set_header_field(&mut self, shift: u32, mask: u32, value: u32) {
+        debug_assert!(value <= mask);
+        self.header = (self.header & !(mask << shift)) | ((value & mask) << shift);
+    }
+
+    fn node_type(&self) -> NodeType {
+        match self.header_field(HEADER_TYPE_SHIFT, HEADER_TYPE_MASK) {
+            0 => NodeType::Root,
+            1 => NodeType::Internal,
+            _ => NodeType::Leaf,
+        }
+    }
+
+    fn set_node_type(&mut self, node_type: NodeType) {
+        let bits = match node_type {
+            NodeType::Root => 0,
+            NodeType::Internal => 1,
+            NodeType::Leaf => 2,
+        };
+        self.set_header_field(HEADER_TYPE_SHIFT, HEADER_TYPE_MASK, bits);
+    }
+
+    fn checksummed(&self) -> bool {
+        self.header_field(HEADER_CHECKSUMMED_SHIFT, 1) == 1
+    }
+
+    fn set_checksummed(&mut self, checksummed: bool) {
+        self.set_header_field(HEADER_CHECKSUMMED_SHIFT, 1, checksummed as u32);
+    }
+
+    fn fenced(&self) -> bool {
+        self.header_field(HEADER_FENCED_SHIFT, 1) == 1
+    }
+
+    fn set_fenced(&mut self, fenced: bool) {
+        self.set_header_field(HEADER_FENCED_SHIFT, 1, fenced as u32);
+    }
+
+    /// Whether the node holds a message buffer, see messages
+    pub fn buffered(&self) -> bool {
+        self.header_field(HEADER_BUFFERED_SHIFT, 1) == 1
+    }
+
+    fn set_buffered(&mut self, buffered: bool) {
+        self.set_header_field(HEADER_BUFFERED_SHIFT, 1, buffered as u32);
+    }
+
+    fn num_keys(&self) -> usize {
+        self.header_field(HEADER_KEYS_SHIFT, HEADER_COUNT_MASK) as usize
+    }
+
+    fn set_num_keys(&mut self, n: usize) {
+        self.set_header_field(HEADER_KEYS_SHIFT, HEADER_COUNT_MASK, n as u32);
+    }
+
+    fn num_children(&self) -> usize {
+        self.header_field(HEADER_CHILDREN_SHIFT, HEADER_COUNT_MASK) as usize
+    }
+
+    fn set_num_children(&mut self, n: usize) {
+        self.set_header_field(HEADER_CHILDREN_SHIFT, HEADER_COUNT_MASK, n as u32);
+    }
+
+    /// The layout the node was written with
+    pub fn layout_version(&self) -> u32 {
+        self.header_field(HEADER_VERSION_SHIFT, HEADER_VERSION_MASK)
+    }
+
+    fn check_counts(&self) -> Result<(), LodestoneError> {
+        if self.layout_version() != NODE_LAYOUT_VERSION {
+            return Err(LodestoneError::StructureCorrupt("Node was written with another layout version"));
+        }
+        let leaf_shaped = self.num_keys() == self.num_children();
+        let internal_shaped = self.num_children() == self.num_keys() + 1
+            || (self.num_keys() == 0 && self.num_children() <= 1);
+        let counts_ok = match self.node_type() {
+            NodeType::Leaf => leaf_shaped,
+            NodeType::Internal => internal_shaped,
+            NodeType::Root => leaf_shaped || internal_shaped,
+        };
+        if self.num_keys() > B || self.num_children() > B || !counts_ok {
+            return Err(LodestoneError::StructureCorrupt("Node has inconsistent key and child counts"));
+        }
+        Ok(())
+    }
+
+    /// Smallest and largest key beneath the node, as fences.
+    /// None if that isn't known (an empty or unfenced node).
+    fn bounds(&self, pool: &Pool) -> Result<Option<(Fence, Fence)>, LodestoneError> {
+        match self.node_type() {
+            NodeType::Leaf => {
+                if self.num_keys() == 0 {
+                    return Ok(None);
+                }
+                let min = try!(self.keys[0].clone_to_arc_byte_slice(pool));
+                let max = try!(self.keys[self.num_keys()-1].clone_to_arc_byte_slice(pool));
+                Ok(Some((Fence::from_key(&*min), Fence::from_key(&*max))))
+            },
+            _ if self.fenced() => Ok(Some((self.min_fence, self.max_fence))),
+            _ => Ok(None),
+        }
+    }
+
+    /// What the fences of an internal node should be, going by its children
+    fn child_bounds(&self, pool: &Pool) -> Result<Option<(Fence, Fence)>, LodestoneError> {
+        if self.num_children() == 0 {
+            return Ok(None);
+        }
+        let first = try!(self.children[0].clone_to_arc_byte_slice(pool));
+        let last = try!(self.children[self.num_children()-1].clone_to_arc_byte_slice(pool));
+        let min = try!(first.deref_as::<Node>().bounds(pool)).map(|(min, _)| min);
+        let max = try!(last.deref_as::<Node>().bounds(pool)).map(|(_, max)| max);
+        Ok(min.and_then(|min| max.map(|max| (min, max))))
+    }
+
+    /// Recompute the fences of an internal node after its children changed
+    fn refresh_fences(&mut self, pool: &Pool) -> Result<(), LodestoneError> {
+        if self.node_type() == NodeType::Leaf {
+            return Ok(());
+        }
+        match try!(self.child_bounds(pool)) {
+            Some((min, max)) => {
+                self.set_fenced(true);
+                self.min_fence = min;
+                self.max_fence = max;
+            },
+            None => self.set_fenced(false),
+        }
+        Ok(())
+    }
+
+    /// Operating on the wrong type of node means the tree is corrupt
+    fn expect_type(&self, node_type: NodeType) -> Result<(), LodestoneError> {
+        if self.node_type() == node_type {
+            Ok(())
+        } else {
+            Err(LodestoneError::StructureCorrupt("Operation applied to the wrong type of node"))
+        }
+    }
+
     /// The first return value is true if the given key exists in the node.
     /// The second parameter is the location of the key if it exists, or the
     /// point where the key should be inserted if it does not already exist.
-    pub fn index_or_insertion_of(&self, key: &[u8], pool: &Pool) -> (bool, usize) {
-        if self.num_keys == 0 {
-            return (false, 0)
-        } else {
-            let last_key = recover_but_panic_in_debug!(
-                self.keys[self.num_keys-1].clone_to_arc_byte_slice(pool),
-                (false, BUFFER_END)
-            );
-            if key.cmp(&*last_key) == cmp::Ordering::Greater {
-                return (false, self.num_keys)
-            }
-        }
-        let mut top = self.num_keys-1;
-        let mut bottom = 0;
-        let mut i = top/2;
-        let mut old_i = i;
-        loop {
-            let i_key = recover_but_panic_in_debug!(
-                self.keys[i].clone_to_arc_byte_slice(pool),
-                (false, BUFFER_END)
-            );
+    /// A key that can't be read means the tree is corrupt.
+    pub fn index_or_insertion_of(&self, key: &[u8], pool: &Pool) -> Result<(bool, usize), LodestoneError> {
+        // The first key at or after the given one
+        let (mut bottom, mut top) = (0, self.num_keys());
+        while bottom < top {
+            let i = bottom + (top - bottom)/2;
+            let i_key = match self.keys[i].clone_to_arc_byte_slice(pool) {
+                Ok(i_key) => i_key,
+                Err(_) => return Err(LodestoneError::StructureCorrupt("Node holds a key that can't be read")),
+            };
             match key.cmp(&*i_key) {
-                cmp::Ordering::Equal => break,
-                cmp::Ordering::Less => top = if i > 1 {i-1} else {0},
+                cmp::Ordering::Equal => return Ok((true, i)),
+                cmp::Ordering::Less => top = i,
                 cmp::Ordering::Greater => bottom = i+1,
             }
-            if top < bottom {
-                break;
-            }
-            i = bottom + (top - bottom)/2;
-            if i == old_i {
-                break;
-            } else {
-                old_i = i;
-            }
-        }
-        let i_key = recover_but_panic_in_debug!(
-            self.keys[i].clone_to_arc_byte_slice(pool),
-            (false, BUFFER_END)
-        );
-        if key.cmp(&*i_key) == cmp::Ordering::Equal {
-            (true, i)
-        } else {
-            (false, i)
         }
+        Ok((false, bottom))
     }
 }
 
 /// Internal Node impl
 impl Node {
-    fn internal_node_insert(&self, tx_id: usize, key: &[u8], value: &[u8], pool: &Pool)
-        -> Result<InsertionResult, LodestoneError> {
-        debug_assert!(NodeType::Internal == self.node_type);
-        let (_, i) = self.index_or_insertion_of(key, pool);
+    fn internal_node_insert<'p>(&self, tx_id: usize, key: &[u8], value: &[u8], pool: &'p Pool)
+        -> Result<InsertionResult<'p>, LodestoneError> {
+        self.internal_node_insert_guarded(tx_id, key, value, None, pool, &mut Descent::for_pool(pool))
+    }
+
+    fn internal_node_insert_guarded<'p>(&self, tx_id: usize, key: &[u8], value: &[u8], block: Option<&ArcByteSlice<'p>>,
+        pool: &'p Pool, descent: &mut Descent)
+        -> Result<InsertionResult<'p>, LodestoneError> {
+        try!(self.expect_type(NodeType::Internal));
+        let (_, i) = try!(self.index_or_insertion_of(key, pool));
+        try!(descent.enter(&self.children[i]));
+        let child_arc = try!(self.children[i].clone_to_arc_byte_slice(pool));
+        let child_node = child_arc.deref_as::<Node>();
+        let child_result = match child_node.node_type() {
+            NodeType::Leaf => try!(child_node.leaf_node_put(tx_id, key, value, block, pool)),
+            NodeType::Internal => try!(child_node.internal_node_insert_guarded(tx_id, key, value, block, pool, descent)),
+            NodeType::Root => return Err(LodestoneError::StructureCorrupt("Internal node points to a Root")),
+        };
+        self.internal_node_replace_child(tx_id, i, child_result, pool)
+    }
+
+    /// Read-modify-write of a single entry in one descent. See leaf_node_update.
+    /// Returns None if the update left the tree untouched.
+    fn internal_node_update<'p, F>(&self, tx_id: usize, key: &[u8], update: &F, pool: &'p Pool, descent: &mut Descent)
+        -> Result<Option<InsertionResult<'p>>, LodestoneError>
+        where F: Fn(Option<&[u8]>) -> Result<Option<Vec<u8>>, LodestoneError> {
+        try!(self.expect_type(NodeType::Internal));
+        let (_, i) = try!(self.index_or_insertion_of(key, pool));
+        try!(descent.enter(&self.children[i]));
         let child_arc = try!(self.children[i].clone_to_arc_byte_slice(pool));
         let child_node = child_arc.deref_as::<Node>();
-        let child_result = match child_node.node_type {
-            NodeType::Leaf => try!(child_node.leaf_node_insert_or_set(tx_id, key, value, pool)),
-            NodeType::Internal => try!(child_node.internal_node_insert(tx_id, key, value, pool)),
-            _ => panic!("Internal node points to a Root. Not Okay."),
+        let child_result = match child_node.node_type() {
+            NodeType::Leaf => try!(child_node.leaf_node_update(tx_id, key, update, pool)),
+            NodeType::Internal => try!(child_node.internal_node_update(tx_id, key, update, pool, descent)),
+            NodeType::Root => return Err(LodestoneError::StructureCorrupt("Internal node points to a Root")),
         };
+        match child_result {
+            Some(result) => self.internal_node_replace_child(tx_id, i, result, pool).map(Some),
+            None => Ok(None),
+        }
+    }
 
+    /// Swap in the new version of the child at index i, absorbing
+    /// the child's split if it had one.
+    fn internal_node_replace_child<'p>(&self, tx_id: usize, i: usize, child_result: InsertionResult, pool: &'p Pool)
+        -> Result<InsertionResult<'p>, LodestoneError> {
         match child_result {
             InsertionResult::HadRoom(ref new_child) => {
                 let new_internal = try!(self.internal_node_set(tx_id, i, new_child, pool));
@@ -252,46 +843,212 @@ impl Node {
                 { // Borrow checker
                     let node = node_arc.deref_as_mut::<Node>();
                     node.tx_id = tx_id;
-                    node.num_keys += 1;
-                    insert_into(&mut node.keys, node.num_keys, &split.mid_key, i, pool);
-                    node.children[i] = split.bottom_half.clone_to_persisted();
-                    node.num_children += 1;
-                    insert_into(&mut node.children, node.num_children, &split.top_half, i+1, pool);
+                    let (num_keys, num_children) = (node.num_keys() + 1, node.num_children() + 1);
+                    node.set_num_keys(num_keys);
+                    try!(insert_into(&mut node.keys, num_keys, &split.mid_key, i, pool));
+                    // The clone retained the child that split
+                    try!(node.children[i].release(pool));
+                    node.children[i] = try!(pool.persist(&split.bottom_half));
+                    node.set_num_children(num_children);
+                    try!(insert_into(&mut node.children, num_children, &split.top_half, i+1, pool));
+                    try!(node.refresh_fences(pool));
                 }
-                let node = node_arc.deref_as::<Node>();
-                if node.num_children == B {
-                    let split = try!(node.split(tx_id, pool));
-                    Ok(InsertionResult::NoRoom(split))
-                } else {
-                    Ok(InsertionResult::HadRoom(node_arc.clone()))
+                pool.mark_written(&node_arc);
+                if node_arc.deref_as::<Node>().num_children() < B {
+                    return Ok(InsertionResult::HadRoom(node_arc));
                 }
+                let split = try!(node_arc.deref_as::<Node>().split(tx_id, pool));
+                // The full node was only ever a step on the way to its halves
+                let mut full = node_arc.clone_to_persisted();
+                drop(node_arc);
+                try!(release_node(&mut full, pool));
+                Ok(InsertionResult::NoRoom(split))
             },
         }
     }
 
-    fn internal_node_set(&self, tx_id: usize, index: usize, value: &ArcByteSlice, pool: &Pool) -> Result<ArcByteSlice, LodestoneError> {
-        debug_assert!(NodeType::Internal == self.node_type);
+    fn internal_node_set<'p>(&self, tx_id: usize, index: usize, value: &ArcByteSlice, pool: &'p Pool) -> Result<ArcByteSlice<'p>, LodestoneError> {
+        try!(self.expect_type(NodeType::Internal));
         let node_arc = try!(self.clone(pool));
         { // Borrow checker
             let node = node_arc.deref_as_mut::<Node>();
             node.tx_id = tx_id;
-            node.children[index] = value.clone_to_persisted();
+            // The clone retained the old child
+            try!(node.children[index].release(pool));
+            node.children[index] = try!(pool.persist(&value));
+            try!(node.refresh_fences(pool));
         }
+        pool.mark_written(&node_arc);
         Ok(node_arc)
     }
 
-    fn internal_node_contains_key(&self, key: &[u8], pool: &Pool) -> bool {
-        debug_assert!(NodeType::Internal == self.node_type);
-        let (_, i) = self.index_or_insertion_of(key, pool);
-        let child_arc = recover_but_panic_in_debug!(
-            self.children[i].clone_to_arc_byte_slice(pool),
-            false
-        );
+    /// Merge runs of adjacent underfull leaf children, immutably. Deletes
+    /// can leave leaves nearly empty with nothing ever touching them again,
+    /// so this is meant to be run over the nodes along a write path.
+    /// Returns None if there was nothing worth merging.
+    pub fn internal_node_compact_leaves<'p>(&self, tx_id: usize, pool: &'p Pool)
+        -> Result<Option<ArcByteSlice<'p>>, LodestoneError> {
+        try!(self.expect_type(NodeType::Internal));
+        if self.num_children() < 2 {
+            return Ok(None)
+        }
+        let mut children = vec![try!(self.children[0].clone_to_arc_byte_slice(pool))];
+        let mut keys = Vec::new();
+        let mut merged_any = false;
+        // Whether the last child is a join made here
+        let mut joined_last = false;
+        for i in 1..self.num_children() {
+            let next = try!(self.children[i].clone_to_arc_byte_slice(pool));
+            let joined = {
+                let last_node = children[children.len()-1].deref_as::<Node>();
+                let next_node = next.deref_as::<Node>();
+                if should_merge_leaves(last_node, next_node) {
+                    Some(try!(Node::join(last_node, next_node, tx_id, pool)))
+                } else {
+                    None
+                }
+            };
+            match joined {
+                Some(arc) => {
+                    // The separator between the two leaves goes away
+                    let last = children.len()-1;
+                    let replaced = mem::replace(&mut children[last], arc);
+                    if joined_last {
+                        // Nothing else refers to an earlier join
+                        let mut released = replaced.clone_to_persisted();
+                        drop(replaced);
+                        try!(release_node(&mut released, pool));
+                    }
+                    merged_any = true;
+                    joined_last = true;
+                },
+                None => {
+                    keys.push(try!(self.keys[i-1].clone_to_arc_byte_slice(pool)));
+                    children.push(next);
+                    joined_last = false;
+                },
+            }
+        }
+        if !merged_any {
+            return Ok(None)
+        }
+        self.internal_node_from(tx_id, &keys, &children, pool).map(Some)
+    }
+
+    /// internal_node_compact_leaves on the last internal node along key's
+    /// path, copying the nodes above it. None if nothing was merged, or
+    /// the path goes through a message buffer, which the copies would drop.
+    fn compact_leaves_on_path<'p>(&self, tx_id: usize, key: &[u8], pool: &'p Pool, descent: &mut Descent)
+        -> Result<Option<ArcByteSlice<'p>>, LodestoneError> {
+        if self.node_type() != NodeType::Internal || self.buffered() {
+            return Ok(None);
+        }
+        let (_, i) = try!(self.index_or_insertion_of(key, pool));
+        try!(descent.enter(&self.children[i]));
+        let child_arc = try!(self.children[i].clone_to_arc_byte_slice(pool));
+        let child = child_arc.deref_as::<Node>();
+        if child.is_leaf() {
+            return self.internal_node_compact_leaves(tx_id, pool);
+        }
+        match try!(child.compact_leaves_on_path(tx_id, key, pool, descent)) {
+            Some(new_child) => self.internal_node_set(tx_id, i, &new_child, pool).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// A new internal node like this one over the given keys and children
+    fn internal_node_from<'p>(&self, tx_id: usize, keys: &[ArcByteSlice], children: &[ArcByteSlice], pool: &'p Pool)
+        -> Result<ArcByteSlice<'p>, LodestoneError> {
+        let node_arc = try!(pool.make_new::<Node>());
+        { // Borrow checker
+            let node = node_arc.deref_as_mut::<Node>();
+            node.init(tx_id, self.node_type());
+            node.set_checksummed(self.checksummed());
+            for (i, k) in keys.iter().enumerate() {
+                node.keys[i] = try!(pool.persist(&k));
+            }
+            for (i, c) in children.iter().enumerate() {
+                node.children[i] = try!(pool.persist(&c));
+            }
+            node.set_num_keys(keys.len());
+            node.set_num_children(children.len());
+            try!(node.refresh_fences(pool));
+        }
+        pool.mark_written(&node_arc);
+        Ok(node_arc)
+    }
+
+    /// Swap in the new version of the child at index i after a remove,
+    /// rebalancing it with a neighbour if the remove left it underfull.
+    fn internal_node_replace_underfull<'p>(&self, tx_id: usize, i: usize, new_child: ArcByteSlice, pool: &'p Pool)
+        -> Result<ArcByteSlice<'p>, LodestoneError> {
+        if new_child.deref_as::<Node>().num_children() >= B/2 || self.num_children() < 2 {
+            return self.internal_node_set(tx_id, i, &new_child, pool);
+        }
+        let (bottom, top) = if i > 0 { (i-1, i) } else { (i, i+1) };
+        let sibling = try!(self.children[if bottom == i { top } else { bottom }].clone_to_arc_byte_slice(pool));
+        let rebalanced = {
+            let (child, sibling) = (new_child.deref_as::<Node>(), sibling.deref_as::<Node>());
+            let (bottom_node, top_node) = if bottom == i { (child, sibling) } else { (sibling, child) };
+            try!(Node::rebalance(bottom_node, &self.keys[bottom], top_node, tx_id, pool))
+        };
+        // The rebalanced nodes took their own references to everything
+        // the new child held, and nothing else refers to it
+        let mut released = new_child.clone_to_persisted();
+        drop(new_child);
+        try!(release_node(&mut released, pool));
+
+        let mut keys = Vec::with_capacity(self.num_keys());
+        for k in self.keys.iter().take(self.num_keys()) {
+            keys.push(try!(k.clone_to_arc_byte_slice(pool)));
+        }
+        let mut children = Vec::with_capacity(self.num_children());
+        for c in self.children.iter().take(self.num_children()) {
+            children.push(try!(c.clone_to_arc_byte_slice(pool)));
+        }
+        match rebalanced {
+            Rebalanced::Merged(merged) => {
+                children[bottom] = merged;
+                children.remove(top);
+                keys.remove(bottom);
+            },
+            Rebalanced::Shared(split) => {
+                children[bottom] = split.bottom_half;
+                children[top] = split.top_half;
+                keys[bottom] = split.mid_key;
+            },
+        }
+        self.internal_node_from(tx_id, &keys, &children, pool)
+    }
+
+    /// Copy out the keys and child references, for the node cache
+    pub fn internal_node_decode(&self, pool: &Pool) -> Result<DecodedNode, LodestoneError> {
+        try!(self.expect_type(NodeType::Internal));
+        let mut keys = Vec::with_capacity(self.num_keys());
+        for i in 0..self.num_keys() {
+            keys.push(try!(self.keys[i].clone_to_arc_byte_slice(pool)).to_vec());
+        }
+        Ok(DecodedNode {
+            keys: keys,
+            children: self.children[..self.num_children()].iter().map(Reference::from_persisted).collect(),
+        })
+    }
+
+    fn internal_node_contains_key(&self, key: &[u8], pool: &Pool) -> Result<bool, LodestoneError> {
+        self.internal_node_contains_key_guarded(key, pool, &mut Descent::for_pool(pool))
+    }
+
+    fn internal_node_contains_key_guarded(&self, key: &[u8], pool: &Pool, descent: &mut Descent)
+        -> Result<bool, LodestoneError> {
+        try!(self.expect_type(NodeType::Internal));
+        let (_, i) = try!(self.index_or_insertion_of(key, pool));
+        try!(descent.enter(&self.children[i]));
+        let child_arc = try!(self.children[i].clone_to_arc_byte_slice(pool));
         let child_node = child_arc.deref_as::<Node>();
-        match child_node.node_type {
+        match child_node.node_type() {
             NodeType::Leaf => child_node.leaf_node_contains_key(key, pool),
-            NodeType::Internal => child_node.internal_node_contains_key(key, pool),
-            _ => panic!("Internal node points to a Root. Not Okay."),
+            NodeType::Internal => child_node.internal_node_contains_key_guarded(key, pool, descent),
+            NodeType::Root => Err(LodestoneError::StructureCorrupt("Internal node points to a Root")),
         }
     }
 }
@@ -299,39 +1056,79 @@ impl Node {
 /// Leaf Node impl
 impl Node {
     /// Check to see if the node contains the given key
-    pub fn leaf_node_contains_key(&self, key: &[u8], pool: &Pool) -> bool {
-        debug_assert!(NodeType::Leaf == self.node_type);
-        self.index_or_insertion_of(key, pool).0
+    pub fn leaf_node_contains_key(&self, key: &[u8], pool: &Pool) -> Result<bool, LodestoneError> {
+        try!(self.expect_type(NodeType::Leaf));
+        self.index_or_insertion_of(key, pool).map(|(found, _)| found)
     }
 
     /// Return an arc to the value associated with the given key
     /// or None if the key is not contained within this node
-    pub fn leaf_node_value_for_key(&self, key: &[u8], pool: &Pool) -> Option<ArcByteSlice> {
-        debug_assert!(NodeType::Leaf == self.node_type);
-        let (found, idx) = self.index_or_insertion_of(key, pool);
+    pub fn leaf_node_value_for_key<'p>(&self, key: &[u8], pool: &'p Pool) -> Result<Option<ArcByteSlice<'p>>, LodestoneError> {
+        try!(self.expect_type(NodeType::Leaf));
+        let (found, idx) = try!(self.index_or_insertion_of(key, pool));
         if found {
-            Some(recover_but_panic_in_debug!(
-                self.children[idx].clone_to_arc_byte_slice(pool),
-                None
-            ))
+            self.children[idx].clone_to_arc_byte_slice(pool).map(Some)
         } else {
-            None
+            Ok(None)
+        }
+    }
+
+    /// Copy out the keys and take arcs to the values, for freezing
+    pub fn leaf_node_decode<'p>(&self, pool: &'p Pool) -> Result<(Vec<Vec<u8>>, Vec<ArcByteSlice<'p>>), LodestoneError> {
+        try!(self.expect_type(NodeType::Leaf));
+        let mut keys = Vec::with_capacity(self.num_keys());
+        let mut values = Vec::with_capacity(self.num_keys());
+        for i in 0..self.num_keys() {
+            keys.push(try!(self.keys[i].clone_to_arc_byte_slice(pool)).to_vec());
+            values.push(try!(self.children[i].clone_to_arc_byte_slice(pool)));
+        }
+        Ok((keys, values))
+    }
+
+    /// Like leaf_node_value_for_key, but if the node is checksummed
+    /// the entry is verified before it is returned.
+    pub fn leaf_node_checked_value_for_key<'p>(&self, key: &[u8], pool: &'p Pool, stats: &Stats)
+        -> Result<Option<ArcByteSlice<'p>>, LodestoneError> {
+        try!(self.expect_type(NodeType::Leaf));
+        let (found, idx) = try!(self.index_or_insertion_of(key, pool));
+        if !found {
+            return Ok(None)
         }
+        let value = try!(self.children[idx].clone_to_arc_byte_slice(pool));
+        if self.checksummed() {
+            let stored_key = try!(self.keys[idx].clone_to_arc_byte_slice(pool));
+            if entry_checksum(&*stored_key, &*value) != self.checksums[idx] {
+                stats.checksums_failed.fetch_add(1, Relaxed);
+                return Err(LodestoneError::Corruption("Entry checksum mismatch"));
+            }
+            stats.checksums_verified.fetch_add(1, Relaxed);
+        }
+        Ok(Some(value))
     }
 
     /// Insert in an append only/immutable fashion. Will either return
     /// itself, if there has not been a split, or the two halves of the
     /// split along with the middle key
-    fn leaf_node_insert_or_set(&self, tx_id: usize, key: &[u8], value: &[u8], pool: &Pool) -> Result<InsertionResult, LodestoneError> {
-        debug_assert!(NodeType::Leaf == self.node_type);
-        let (found, _) = self.index_or_insertion_of(key, pool);
+    fn leaf_node_insert_or_set<'p>(&self, tx_id: usize, key: &[u8], value: &[u8], pool: &'p Pool) -> Result<InsertionResult<'p>, LodestoneError> {
+        self.leaf_node_put(tx_id, key, value, None, pool)
+    }
+
+    /// leaf_node_insert_or_set, storing value in block if given, see value_block
+    fn leaf_node_put<'p>(&self, tx_id: usize, key: &[u8], value: &[u8], block: Option<&ArcByteSlice<'p>>, pool: &'p Pool)
+        -> Result<InsertionResult<'p>, LodestoneError> {
+        try!(self.expect_type(NodeType::Leaf));
+        let (found, _) = try!(self.index_or_insertion_of(key, pool));
         if found {
-            let replace_result = try!(self.leaf_node_set(tx_id, key, value, pool));
+            let replace_result = try!(self.leaf_node_set(tx_id, key, value, block, pool));
             Ok(InsertionResult::HadRoom(replace_result))
         } else {
-            let insert_result = try!(self.leaf_node_insert_non_full(tx_id, key, value, pool));
-            if insert_result.deref_as::<Node>().num_children == B {
+            let insert_result = try!(self.leaf_node_insert_new(tx_id, key, value, block, pool));
+            if insert_result.deref_as::<Node>().num_children() == B {
                 let split = try!(insert_result.deref_as::<Node>().split(tx_id, pool));
+                // The full leaf was only ever a step on the way to its halves
+                let mut full = insert_result.clone_to_persisted();
+                drop(insert_result);
+                try!(release_node(&mut full, pool));
                 Ok(InsertionResult::NoRoom(split))
             } else {
                 Ok(InsertionResult::HadRoom(insert_result))
@@ -339,52 +1136,79 @@ impl Node {
         }
     }
 
+    /// Read-modify-write of a single entry. `update` is handed the current
+    /// value, if there is one, and returns the value to store or None to
+    /// leave the node as it is.
+    fn leaf_node_update<'p, F>(&self, tx_id: usize, key: &[u8], update: &F, pool: &'p Pool)
+        -> Result<Option<InsertionResult<'p>>, LodestoneError>
+        where F: Fn(Option<&[u8]>) -> Result<Option<Vec<u8>>, LodestoneError> {
+        try!(self.expect_type(NodeType::Leaf));
+        let current = try!(self.leaf_node_value_for_key(key, pool));
+        match try!(update(current.as_ref().map(|v| &**v))) {
+            Some(value) => self.leaf_node_insert_or_set(tx_id, key, &value[..], pool).map(Some),
+            None => Ok(None),
+        }
+    }
+
     /// Replace the value for the given key with the given value. The key MUST already exist
-    fn leaf_node_set(&self, tx_id: usize, key: &[u8], value: &[u8], pool: &Pool) -> Result<ArcByteSlice, LodestoneError> {
-        debug_assert!(NodeType::Leaf == self.node_type);
-        let val_arc = try!(pool.malloc(value));
+    fn leaf_node_set<'p>(&self, tx_id: usize, key: &[u8], value: &[u8], block: Option<&ArcByteSlice<'p>>, pool: &'p Pool)
+        -> Result<ArcByteSlice<'p>, LodestoneError> {
+        try!(self.expect_type(NodeType::Leaf));
+        let val_arc = try!(value_block(value, block, pool));
         let node_arc = try!(self.clone(pool));
         { // Borrow checker
             let node = node_arc.deref_as_mut::<Node>();
             node.tx_id = tx_id;
-            let (found, index) = node.index_or_insertion_of(key, pool);
+            let (found, index) = try!(node.index_or_insertion_of(key, pool));
             if !found {
                 return Err(LodestoneError::UserError("Key does not exist"));
             }
-            node.children[index] = val_arc.clone_to_persisted();
+            // The clone retained the old value
+            try!(node.children[index].release(pool));
+            node.children[index] = try!(pool.persist(&val_arc));
+            node.checksums[index] = entry_checksum(key, value);
         }
+        pool.mark_written(&node_arc);
         Ok(node_arc)
     }
 
     /// Insert in an append only/immutable fashion
-    fn leaf_node_insert_non_full(&self, tx_id: usize, key: &[u8], value: &[u8], pool: &Pool) -> Result<ArcByteSlice, LodestoneError> {
-        debug_assert!(NodeType::Leaf == self.node_type);
-        let key_arc = try!(pool.malloc(key));
-        let val_arc = try!(pool.malloc(value));
+    fn leaf_node_insert_non_full<'p>(&self, tx_id: usize, key: &[u8], value: &[u8], pool: &'p Pool) -> Result<ArcByteSlice<'p>, LodestoneError> {
+        self.leaf_node_insert_new(tx_id, key, value, None, pool)
+    }
+
+    fn leaf_node_insert_new<'p>(&self, tx_id: usize, key: &[u8], value: &[u8], block: Option<&ArcByteSlice<'p>>, pool: &'p Pool)
+        -> Result<ArcByteSlice<'p>, LodestoneError> {
+        try!(self.expect_type(NodeType::Leaf));
+        let key_arc = try!(pool.malloc(key));
+        let val_arc = try!(value_block(value, block, pool));
         let node_arc = try!(self.clone(pool));
 
         { // Borrow checker
             let node = node_arc.deref_as_mut::<Node>();
             node.tx_id = tx_id;
-            let (found, index) = node.index_or_insertion_of(key, pool);
+            let (found, index) = try!(node.index_or_insertion_of(key, pool));
             if found {
                 return Err(LodestoneError::UserError("Key already exists"));
-            } else if node.num_children == B {
+            } else if node.num_children() == B {
                 return Err(LodestoneError::UserError("Node is already full"));
             }
-            node.num_children += 1;
-            insert_into(&mut node.children, node.num_children, &val_arc, index, pool);
-            node.num_keys += 1;
-            insert_into(&mut node.keys, node.num_keys, &key_arc, index, pool);
+            let (num_keys, num_children) = (node.num_keys() + 1, node.num_children() + 1);
+            node.set_num_children(num_children);
+            try!(insert_into(&mut node.children, num_children, &val_arc, index, pool));
+            node.set_num_keys(num_keys);
+            try!(insert_into(&mut node.keys, num_keys, &key_arc, index, pool));
+            insert_checksum(&mut node.checksums, num_children, entry_checksum(key, value), index);
         }
+        pool.mark_written(&node_arc);
         Ok(node_arc)
     }
 
     /// Remove in an append-only/immutable fashion.
-    /// Precondition: key must exist. Panics if key does not exist
-    fn leaf_node_remove<'a>(&'a self, tx_id: usize, key: &[u8], pool:&'a Pool) -> Result<ArcByteSlice, LodestoneError> {
-        debug_assert!(NodeType::Leaf == self.node_type);
-        let (found, index) = self.index_or_insertion_of(key, pool);
+    /// Precondition: key must exist. Returns an error if it does not
+    fn leaf_node_remove<'p>(&self, tx_id: usize, key: &[u8], pool: &'p Pool) -> Result<ArcByteSlice<'p>, LodestoneError> {
+        try!(self.expect_type(NodeType::Leaf));
+        let (found, index) = try!(self.index_or_insertion_of(key, pool));
         if !found {
             return Err(LodestoneError::UserError("This node does not contain the given key"));
         }
@@ -392,80 +1216,1343 @@ impl Node {
         { // Borrow checker
             let node = arc.deref_as_mut::<Node>();
             // Copy over metadata
-            node.node_type = self.node_type.clone();
-            node.tx_id = tx_id;
-            node.num_keys = self.num_keys-1;
-            node.num_children = self.num_children-1;
+            node.init(tx_id, self.node_type());
+            node.set_checksummed(self.checksummed());
+            node.set_num_keys(self.num_keys()-1);
+            node.set_num_children(self.num_children()-1);
 
             // Copy all data except for the deleted key/val
             let mut off = 0;
-            for i in 0..self.num_keys {
+            for i in 0..self.num_keys() {
                 if i == index {
                     off = 1;
                     continue;
                 }
                 node.keys[i-off] = try!(self.keys[i].clone(pool));
                 node.children[i-off] = try!(self.children[i].clone(pool));
+                node.checksums[i-off] = self.checksums[i];
             }
         }
+        pool.mark_written(&arc);
         Ok(arc)
     }
-}
 
-/// Precondition: The node must have enough space
-/// The memory should already be allocated, this
-/// just inserts the reference in the correct location.
-fn insert_into(array: &mut [PersistedArcByteSlice; B],
-          array_size: usize,
-                 arc: &ArcByteSlice,
-               index: usize,
-                pool: &Pool) {
-    // Shift everything after the index where we're inserting down
-    for i in (index+1..array_size).rev() {
-        array[i] = array[i-1].clone(pool).unwrap();
-        let cleanup = array[i-1].release(pool);
-        debug_assert!(cleanup.is_ok(), format!("{:?}", cleanup.err()));
+    /// Remove up to max_entries entries with keys from `from` onwards for
+    /// which pred(key, value) is true, in an append-only/immutable fashion.
+    pub fn leaf_node_remove_where<'p, F>(&self, tx_id: usize, from: &[u8], pred: &F, max_entries: usize, pool: &'p Pool)
+        -> Result<Removal<'p>, LodestoneError>
+        where F: Fn(&[u8], &[u8]) -> bool {
+        try!(self.expect_type(NodeType::Leaf));
+        let (_, start) = try!(self.index_or_insertion_of(from, pool));
+        let mut doomed = Vec::new();
+        let mut resume_from = None;
+        for i in start..self.num_keys() {
+            if doomed.len() == max_entries {
+                resume_from = Some(try!(self.keys[i].clone_to_arc_byte_slice(pool)).to_vec());
+                break;
+            }
+            let key = try!(self.keys[i].clone_to_arc_byte_slice(pool));
+            let value = try!(self.children[i].clone_to_arc_byte_slice(pool));
+            if pred(&*key, &*value) {
+                doomed.push(i);
+            }
+        }
+        if doomed.is_empty() {
+            return Ok(Removal { node: None, removed: 0, resume_from: resume_from });
+        }
+        let arc = try!(pool.make_new::<Node>());
+        { // Borrow checker
+            let node = arc.deref_as_mut::<Node>();
+            // Copy over metadata
+            node.init(tx_id, self.node_type());
+            node.set_checksummed(self.checksummed());
+            node.set_num_keys(self.num_keys() - doomed.len());
+            node.set_num_children(self.num_children() - doomed.len());
+
+            // Copy all data except for the removed pairs
+            let mut off = 0;
+            for i in 0..self.num_keys() {
+                if off < doomed.len() && doomed[off] == i {
+                    off += 1;
+                    continue;
+                }
+                node.keys[i-off] = try!(self.keys[i].clone(pool));
+                node.children[i-off] = try!(self.children[i].clone(pool));
+                node.checksums[i-off] = self.checksums[i];
+            }
+        }
+        pool.mark_written(&arc);
+        Ok(Removal { node: Some(arc), removed: doomed.len(), resume_from: resume_from })
+    }
+}
+
+/// Buffered node impl, see messages
+impl Node {
+    /// What the node's buffer says about key, if it has a message for
+    /// it: Some(None) for a remove. Always None for unbuffered nodes.
+    pub fn buffered_value<'p>(&self, key: &[u8], pool: &'p Pool) -> Result<Option<Option<ArcByteSlice<'p>>>, LodestoneError> {
+        if !self.buffered() {
+            return Ok(None);
+        }
+        let buffer = try!(self.keys[BUFFER_SLOT].clone_to_arc_byte_slice(pool));
+        messages::find(&buffer, key, pool)
+    }
+
+    /// Whether any node under this one, itself included, buffers messages
+    pub fn has_messages(&self, pool: &Pool) -> Result<bool, LodestoneError> {
+        if self.buffered() {
+            return Ok(true);
+        }
+        if self.node_type() == NodeType::Leaf {
+            return Ok(false);
+        }
+        for i in 0..self.num_children() {
+            let child = try!(self.children[i].clone_to_arc_byte_slice(pool));
+            if try!(child.deref_as::<Node>().has_messages(pool)) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Apply messages (sorted by key, one per key, and newer than anything
+    /// under this node) to the tree under this node, immutably. A leaf
+    /// takes them in, splitting as often as it has to, or leaves no
+    /// pieces if it ends up empty. An internal node adds them to its
+    /// buffer and, while that holds more than capacity, passes the batch
+    /// bound for the child with the most messages down to it, then
+    /// rebalances the children that were left underfull. A capacity of 0
+    /// drains every buffer under the node.
+    pub fn apply_messages<'p>(&self, tx_id: usize, messages: Vec<Message>, capacity: usize, pool: &'p Pool)
+        -> Result<Pieces<'p>, LodestoneError> {
+        match self.node_type() {
+            NodeType::Leaf => self.leaf_node_apply(tx_id, messages, pool),
+            NodeType::Internal => self.internal_node_apply(tx_id, messages, capacity, pool),
+            NodeType::Root => Err(LodestoneError::StructureCorrupt("Root nodes aren't used by the tree")),
+        }
+    }
+
+    /// A root over the pieces this root was left in, see apply_messages
+    pub fn root_over<'p>(&self, pieces: Pieces<'p>, tx_id: usize, pool: &'p Pool) -> Result<ArcByteSlice<'p>, LodestoneError> {
+        let Pieces { nodes, separators } = pieces;
+        if nodes.is_empty() {
+            return Node::new_leaf(tx_id, self.checksummed(), pool);
+        }
+        if nodes.len() == 1 {
+            return collapse_root(nodes.into_iter().next().unwrap(), pool);
+        }
+        // build_levels wants the largest key under every node, but the
+        // last one's is never used as a separator
+        let mut maxes = separators.clone();
+        maxes.push(separators[separators.len() - 1].clone());
+        match try!(build_levels(nodes.into_iter().zip(maxes).collect(), tx_id, B - 1, pool)) {
+            Some(root) => Ok(root),
+            None => Err(LodestoneError::StructureCorrupt("Applying messages left no root")),
+        }
+    }
+
+    fn leaf_node_apply<'p>(&self, tx_id: usize, messages: Vec<Message>, pool: &'p Pool) -> Result<Pieces<'p>, LodestoneError> {
+        try!(self.expect_type(NodeType::Leaf));
+        let mut entries = Vec::with_capacity(self.num_keys() + messages.len());
+        let mut messages = messages.into_iter().peekable();
+        for i in 0..self.num_keys() {
+            let key = try!(self.keys[i].clone_to_arc_byte_slice(pool));
+            while messages.peek().map_or(false, |m| &m.key[..] < &key[..]) {
+                let message = messages.next().unwrap();
+                if let Some(value) = message.value {
+                    entries.push((try!(pool.malloc(&message.key)), value));
+                }
+            }
+            if messages.peek().map_or(false, |m| &m.key[..] == &key[..]) {
+                // The message replaces the entry
+                if let Some(value) = messages.next().unwrap().value {
+                    entries.push((key, value));
+                }
+                continue;
+            }
+            entries.push((key, try!(self.children[i].clone_to_arc_byte_slice(pool))));
+        }
+        for message in messages {
+            if let Some(value) = message.value {
+                entries.push((try!(pool.malloc(&message.key)), value));
+            }
+        }
+
+        // A leaf left empty goes away, leaving no pieces
+        let mut pieces = Pieces { nodes: Vec::new(), separators: Vec::new() };
+        let mut at = 0;
+        for size in even_chunks(entries.len(), B - 1) {
+            let arc = try!(pool.make_new::<Node>());
+            { // Borrow checker
+                let node = arc.deref_as_mut::<Node>();
+                node.init(tx_id, NodeType::Leaf);
+                node.set_checksummed(self.checksummed());
+                for (i, &(ref key, ref value)) in entries[at..at + size].iter().enumerate() {
+                    node.keys[i] = try!(pool.persist(&key));
+                    node.children[i] = try!(pool.persist(&value));
+                    node.checksums[i] = entry_checksum(key, value);
+                }
+                node.set_num_keys(size);
+                node.set_num_children(size);
+            }
+            pool.mark_written(&arc);
+            at += size;
+            if at < entries.len() {
+                pieces.separators.push(entries[at - 1].0.clone());
+            }
+            pieces.nodes.push(arc);
+        }
+        Ok(pieces)
+    }
+
+    fn internal_node_apply<'p>(&self, tx_id: usize, messages: Vec<Message>, capacity: usize, pool: &'p Pool)
+        -> Result<Pieces<'p>, LodestoneError> {
+        try!(self.expect_type(NodeType::Internal));
+        let mut buffer = messages::merge(try!(self.messages(pool)), messages);
+        let mut keys = Vec::with_capacity(self.num_keys());
+        for k in self.keys.iter().take(self.num_keys()) {
+            keys.push(try!(k.clone_to_arc_byte_slice(pool)));
+        }
+        let mut children = Vec::with_capacity(self.num_children());
+        for c in self.children.iter().take(self.num_children()) {
+            children.push(try!(c.clone_to_arc_byte_slice(pool)));
+        }
+        let mut drained = vec![false; children.len()];
+        loop {
+            let targets: Vec<usize> = buffer.iter().map(|m| child_for(&keys, &m.key)).collect();
+            let fullest = if buffer.len() > capacity {
+                let mut counts = vec![0; children.len()];
+                for &t in targets.iter() {
+                    counts[t] += 1;
+                }
+                (0..children.len()).max_by_key(|&i| counts[i])
+            } else if capacity == 0 {
+                // Nothing left here, but there may be further down
+                let mut next = None;
+                for i in 0..children.len() {
+                    if !drained[i] && try!(children[i].deref_as::<Node>().has_messages(pool)) {
+                        next = Some(i);
+                        break;
+                    }
+                }
+                next
+            } else {
+                None
+            };
+            let i = match fullest {
+                Some(i) => i,
+                None => break,
+            };
+            let (batch, rest): (Vec<(usize, Message)>, Vec<(usize, Message)>) =
+                targets.into_iter().zip(buffer).partition(|&(t, _)| t == i);
+            buffer = rest.into_iter().map(|(_, m)| m).collect();
+            let pieces = {
+                let child = children[i].deref_as::<Node>();
+                try!(child.apply_messages(tx_id, batch.into_iter().map(|(_, m)| m).collect(), capacity, pool))
+            };
+            let count = pieces.nodes.len();
+            children.remove(i);
+            drained.remove(i);
+            if count == 0 {
+                // The child was emptied, and its upper bound goes with it
+                // (its lower one if it was the last child)
+                keys.remove(if i < keys.len() { i } else { i - 1 });
+            }
+            for (n, node) in pieces.nodes.into_iter().enumerate() {
+                children.insert(i + n, node);
+                drained.insert(i + n, true);
+            }
+            for (n, key) in pieces.separators.into_iter().enumerate() {
+                keys.insert(i + n, key);
+            }
+        }
+
+        // Rebalance the children the messages left underfull with a
+        // neighbour, as remove does
+        let mut i = 0;
+        while i < children.len() && children.len() > 1 {
+            if !drained[i] || children[i].deref_as::<Node>().num_children() >= B/2 {
+                i += 1;
+                continue;
+            }
+            let (bottom, top) = if i > 0 { (i-1, i) } else { (i, i+1) };
+            let rebalanced = try!(rebalance_buffered(&children[bottom], &keys[bottom], &children[top], tx_id, pool));
+            // The rebalanced nodes took their own references to everything
+            // the new children held, and nothing else refers to those
+            let mut released: Vec<PersistedArcByteSlice> = [bottom, top].iter()
+                .filter(|&&n| drained[n])
+                .map(|&n| children[n].clone_to_persisted())
+                .collect();
+            match rebalanced {
+                Rebalanced::Merged(merged) => {
+                    children[bottom] = merged;
+                    children.remove(top);
+                    keys.remove(bottom);
+                    drained[bottom] = true;
+                    drained.remove(top);
+                    // It may still be underfull
+                    i = bottom;
+                },
+                Rebalanced::Shared(split) => {
+                    children[bottom] = split.bottom_half;
+                    children[top] = split.top_half;
+                    keys[bottom] = split.mid_key;
+                    drained[bottom] = true;
+                    drained[top] = true;
+                    i = top + 1;
+                },
+            }
+            for persisted in released.iter_mut() {
+                try!(release_node(persisted, pool));
+            }
+        }
+
+        // Split into as many nodes as the children need, each with the
+        // messages bound for its own children
+        let mut pieces = Pieces { nodes: Vec::new(), separators: Vec::new() };
+        let mut buffer = buffer.into_iter().peekable();
+        let mut at = 0;
+        for size in even_chunks(children.len(), B - 1) {
+            let end = at + size;
+            let mut bound = Vec::new();
+            while buffer.peek().map_or(false, |m| end == children.len() || &m.key[..] <= &keys[end - 1][..]) {
+                bound.push(buffer.next().unwrap());
+            }
+            let arc = try!(self.internal_node_from(tx_id, &keys[at..end - 1], &children[at..end], pool));
+            try!(arc.deref_as_mut::<Node>().set_messages(&bound, pool));
+            pool.mark_written(&arc);
+            if end < children.len() {
+                pieces.separators.push(keys[end - 1].clone());
+            }
+            pieces.nodes.push(arc);
+            at = end;
+        }
+        Ok(pieces)
+    }
+
+    fn messages<'p>(&self, pool: &'p Pool) -> Result<Vec<Message<'p>>, LodestoneError> {
+        if !self.buffered() {
+            return Ok(Vec::new());
+        }
+        let buffer = try!(self.keys[BUFFER_SLOT].clone_to_arc_byte_slice(pool));
+        messages::decode(&buffer, pool)
+    }
+
+    /// Give a node that doesn't have a buffer yet its own, none if
+    /// messages is empty
+    fn set_messages(&mut self, messages: &[Message], pool: &Pool) -> Result<(), LodestoneError> {
+        debug_assert!(!self.buffered());
+        if messages.is_empty() {
+            return Ok(());
+        }
+        let buffer = try!(pool.malloc(&try!(messages::encode(messages, pool))));
+        self.keys[BUFFER_SLOT] = try!(pool.persist(&buffer));
+        self.set_buffered(true);
+        Ok(())
+    }
+}
+
+/// Node::rebalance for nodes that may buffer messages. Their buffers
+/// are set aside and handed to whichever node ends up with their keys.
+fn rebalance_buffered<'p>(bottom: &ArcByteSlice<'p>, separator: &ArcByteSlice, top: &ArcByteSlice<'p>, tx_id: usize, pool: &'p Pool)
+    -> Result<Rebalanced<'p>, LodestoneError> {
+    let (bottom, top) = (bottom.deref_as::<Node>(), top.deref_as::<Node>());
+    // The bottom node's keys all come first
+    let mut messages = try!(bottom.messages(pool));
+    messages.extend(try!(top.messages(pool)));
+    let mut held = separator.clone_to_persisted();
+    let rebalanced = Node::rebalance(bottom, &held, top, tx_id, pool);
+    try!(held.release(pool));
+    let rebalanced = try!(rebalanced);
+    match rebalanced {
+        Rebalanced::Merged(ref merged) => {
+            try!(merged.deref_as_mut::<Node>().set_messages(&messages, pool));
+            pool.mark_written(merged);
+        },
+        Rebalanced::Shared(ref split) => {
+            let (low, high): (Vec<Message>, Vec<Message>) =
+                messages.into_iter().partition(|m| &m.key[..] <= &split.mid_key[..]);
+            try!(split.bottom_half.deref_as_mut::<Node>().set_messages(&low, pool));
+            try!(split.top_half.deref_as_mut::<Node>().set_messages(&high, pool));
+            pool.mark_written(&split.bottom_half);
+            pool.mark_written(&split.top_half);
+        },
+    }
+    Ok(rebalanced)
+}
+
+/// The child of an internal node with these keys that key belongs under
+fn child_for(keys: &[ArcByteSlice], key: &[u8]) -> usize {
+    match keys.binary_search_by(|k| (&k[..]).cmp(key)) {
+        Ok(i) => i,
+        Err(i) => i,
+    }
+}
+
+/// Give up a reference to a message buffer, releasing the values it
+/// holds if it was the last one
+fn release_buffer<F>(persist: &mut PersistedArcByteSlice, pool: &Pool, extract: &F) -> Result<(), LodestoneError>
+    where F: Fn(&[u8]) -> Vec<Reference> {
+    let arc = try!(persist.clone_to_arc_byte_slice(pool));
+    try!(persist.release(pool));
+    if arc.get_ref_count() == 1 {
+        for reference in try!(messages::read(&arc)).into_iter().filter_map(|(_, value)| value) {
+            let mut value = try!(pool.take_reference(&reference));
+            try!(release_value(&mut value, pool, extract));
+        }
+    }
+    Ok(())
+}
+
+/// Precondition: The node must have enough space
+/// The memory should already be allocated, this
+/// just inserts the reference in the correct location.
+fn insert_into(array: &mut [PersistedArcByteSlice; B],
+          array_size: usize,
+                 arc: &ArcByteSlice,
+               index: usize,
+                pool: &Pool) -> Result<(), LodestoneError> {
+    // Shift everything after the index where we're inserting down
+    for i in (index+1..array_size).rev() {
+        array[i] = try!(array[i-1].clone(pool));
+        try!(array[i-1].release(pool));
+    }
+    array[index] = try!(pool.persist(&arc));
+    Ok(())
+}
+
+/// Two neighboring leaves are worth merging if either one is
+/// underfull and the result still leaves room to insert.
+fn should_merge_leaves(bottom: &Node, top: &Node) -> bool {
+    bottom.node_type() == NodeType::Leaf
+        && top.node_type() == NodeType::Leaf
+        && (bottom.num_children() < B/2 || top.num_children() < B/2)
+        && bottom.num_children() + top.num_children() < B
+}
+
+fn insert_checksum(array: &mut [u32; B], array_size: usize, checksum: u32, index: usize) {
+    for i in (index+1..array_size).rev() {
+        array[i] = array[i-1];
+    }
+    array[index] = checksum;
+}
+
+fn entry_checksum(key: &[u8], value: &[u8]) -> u32 {
+    crc32_update(crc32(key), value)
+}
+
+/// A write's new root, with the underfull leaves along key's path
+/// merged, see Node::internal_node_compact_leaves. Whatever the merge
+/// replaced, root included, is released.
+pub fn compact_leaves_on_path<'p>(root: ArcByteSlice<'p>, tx_id: usize, key: &[u8], pool: &'p Pool)
+    -> Result<ArcByteSlice<'p>, LodestoneError> {
+    let compacted = try!(root.deref_as::<Node>().compact_leaves_on_path(tx_id, key, pool, &mut Descent::for_pool(pool)));
+    match compacted {
+        Some(new_root) => {
+            let mut released = root.clone_to_persisted();
+            drop(root);
+            try!(release_node(&mut released, pool));
+            collapse_root(new_root, pool)
+        },
+        None => Ok(root),
+    }
+}
+
+/// An internal root that a remove left with a single child gives way to
+/// the child, for as many levels as that holds
+fn collapse_root<'p>(mut root: ArcByteSlice<'p>, pool: &'p Pool) -> Result<ArcByteSlice<'p>, LodestoneError> {
+    loop {
+        let only_child = {
+            let node = root.deref_as::<Node>();
+            if node.is_leaf() || node.num_children() != 1 || node.buffered() {
+                return Ok(root);
+            }
+            try!(node.children[0].clone_to_arc_byte_slice(pool))
+        };
+        let mut released = root.clone_to_persisted();
+        drop(root);
+        try!(release_node(&mut released, pool));
+        root = only_child;
+    }
+}
+
+/// Give up a reference to a node, releasing its keys and children
+/// (recursively) if it was the last one. A reference that can't be
+/// released means the tree is corrupt, and stops the release there.
+pub fn release_node(persist: &mut PersistedArcByteSlice, pool: &Pool) -> Result<(), LodestoneError> {
+    release_node_traced(persist, pool, &|_: &[u8]| Vec::new())
+}
+
+/// Like release_node, but values are handed to `extract` to find the
+/// references they hold to other blocks. When a value is released for
+/// the last time, everything it refers to is released too.
+pub fn release_node_traced<F>(persist: &mut PersistedArcByteSlice, pool: &Pool, extract: &F)
+    -> Result<(), LodestoneError>
+    where F: Fn(&[u8]) -> Vec<Reference> {
+    let arc = try!(persist.clone_to_arc_byte_slice(pool));
+    // Nodes are shared between versions of the tree, so what's under
+    // the node is only released along with its last reference. Checked
+    // once persist is released, as a logical persist may share its hold
+    // on the node with other references to the same slot
+    try!(persist.release(pool));
+    if arc.get_ref_count() == 1 {
+        let node = arc.deref_as_mut::<Node>();
+        let (num_keys, num_children) = (node.num_keys(), node.num_children());
+        match node.node_type() {
+            NodeType::Root | NodeType::Internal => {
+                for p in node.children.iter_mut().take(num_children) {
+                    try!(release_node_traced(p, pool, extract));
+                }
+            },
+            NodeType::Leaf => {
+                for p in node.children.iter_mut().take(num_children) {
+                    try!(release_value(p, pool, extract));
+                }
+            },
+        }
+        // Release the keys mem
+        for p in node.keys.iter_mut().take(num_keys) {
+            try!(p.release(pool));
+        }
+        if node.buffered() {
+            try!(release_buffer(&mut node.keys[BUFFER_SLOT], pool, extract));
+        }
+    }
+    // Dropping the last arc frees the node itself
+    Ok(())
+}
+
+/// Offset independent picture of the tree under persist, for golden
+/// tests that should survive changes to block placement
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeSnapshot {
+    pub leaf: bool,
+    pub keys: Vec<Vec<u8>>,
+    /// Percent of the B slots in use
+    pub fill: usize,
+    /// Filled in for leaves
+    pub values: Vec<Vec<u8>>,
+    /// Filled in for internal nodes
+    pub children: Vec<TreeSnapshot>,
+}
+
+pub fn snapshot(persist: &PersistedArcByteSlice, pool: &Pool) -> Result<TreeSnapshot, LodestoneError> {
+    let arc = try!(persist.clone_to_arc_byte_slice(pool));
+    let node = arc.deref_as::<Node>();
+    try!(node.check_counts());
+    let leaf = node.node_type() == NodeType::Leaf;
+    let mut picture = TreeSnapshot {
+        leaf: leaf,
+        keys: Vec::new(),
+        fill: node.num_children() * 100 / B,
+        values: Vec::new(),
+        children: Vec::new(),
+    };
+    for k in node.keys.iter().take(node.num_keys()) {
+        picture.keys.push(try!(k.clone_to_arc_byte_slice(pool)).to_vec());
+    }
+    for c in node.children.iter().take(node.num_children()) {
+        if leaf {
+            picture.values.push(try!(c.clone_to_arc_byte_slice(pool)).to_vec());
+        } else {
+            picture.children.push(try!(snapshot(c, pool)));
+        }
+    }
+    Ok(picture)
+}
+
+/// Every block in the tree under persist: nodes, keys and values, as
+/// roots for Pool::sweep_unreachable. References held inside values are
+/// left to the sweep's tracer.
+pub fn tree_references(persist: &PersistedArcByteSlice, pool: &Pool) -> Result<Vec<Reference>, LodestoneError> {
+    let mut found = vec![Reference::from_persisted(persist)];
+    let arc = try!(persist.clone_to_arc_byte_slice(pool));
+    let node = arc.deref_as::<Node>();
+    try!(node.check_counts());
+    for k in node.keys.iter().take(node.num_keys()) {
+        found.push(Reference::from_persisted(k));
+    }
+    for c in node.children.iter().take(node.num_children()) {
+        if node.node_type() == NodeType::Leaf {
+            found.push(Reference::from_persisted(c));
+        } else {
+            found.extend(try!(tree_references(c, pool)));
+        }
+    }
+    if node.buffered() {
+        let buffer = try!(node.keys[BUFFER_SLOT].clone_to_arc_byte_slice(pool));
+        found.push(Reference::from_persisted(&node.keys[BUFFER_SLOT]));
+        found.extend(try!(messages::read(&buffer)).into_iter().filter_map(|(_, value)| value));
+    }
+    Ok(found)
+}
+
+/// The value stored under key in the tree under root. Child i of an
+/// internal node holds the keys up to and including keys[i], and the
+/// last child everything after.
+pub fn find_value<'p>(root: &ArcByteSlice, pool: &'p Pool, key: &[u8]) -> Result<Option<ArcByteSlice<'p>>, LodestoneError> {
+    let mut arc = root.clone();
+    loop {
+        let next = {
+            let node = arc.deref_as::<Node>();
+            try!(node.check_counts());
+            if let Some(buffered) = try!(node.buffered_value(key, pool)) {
+                return Ok(buffered);
+            }
+            let mut found = None;
+            for i in 0..node.num_keys() {
+                let k = try!(node.keys[i].clone_to_arc_byte_slice(pool));
+                if node.node_type() == NodeType::Leaf && &k[..] == key {
+                    return node.children[i].clone_to_arc_byte_slice(pool).map(Some);
+                }
+                if node.node_type() != NodeType::Leaf && key <= &k[..] {
+                    found = Some(i);
+                    break;
+                }
+            }
+            if node.node_type() == NodeType::Leaf || node.num_children() == 0 {
+                return Ok(None);
+            }
+            let child = found.unwrap_or(node.num_children() - 1);
+            try!(node.children[child].clone_to_arc_byte_slice(pool))
+        };
+        arc = next;
+    }
+}
+
+/// The first entry under persist with a key at or after key, if any
+pub fn seek<'p>(persist: &PersistedArcByteSlice, pool: &'p Pool, key: &[u8])
+    -> Result<Option<(ArcByteSlice<'p>, ArcByteSlice<'p>)>, LodestoneError> {
+    let arc = try!(persist.clone_to_arc_byte_slice(pool));
+    let node = arc.deref_as::<Node>();
+    try!(node.check_counts());
+    // The fences rule out subtrees ending before key, such as a child
+    // emptied by removes, without descending into them
+    if !node.may_reach(key, pool) {
+        return Ok(None);
+    }
+    let (_, at) = try!(node.index_or_insertion_of(key, pool));
+    if node.node_type() == NodeType::Leaf {
+        if at == node.num_keys() {
+            return Ok(None);
+        }
+        let found_key = try!(node.keys[at].clone_to_arc_byte_slice(pool));
+        let value = try!(node.children[at].clone_to_arc_byte_slice(pool));
+        return Ok(Some((found_key, value)));
+    }
+    // Removes leave separators standing, so a child can come up empty
+    // and the next one over has the answer
+    for i in at..node.num_children() {
+        if let Some(found) = try!(seek(&node.children[i], pool, key)) {
+            return Ok(Some(found));
+        }
+    }
+    Ok(None)
+}
+
+/// A depth-first walk of the entries under a node, in key order. Every
+/// node on the way down to the current leaf is held by an Arc, with the
+/// next child to visit in it. The cursor doesn't keep the tree alive
+/// beyond those: whoever walks it holds the root.
+pub struct Cursor<'p> {
+    stack: Vec<(ArcByteSlice<'p>, usize)>,
+    max_depth: usize,
+}
+
+impl<'p> Cursor<'p> {
+    pub fn new(root: &PersistedArcByteSlice, pool: &'p Pool) -> Result<Cursor<'p>, LodestoneError> {
+        Ok(Cursor {
+            stack: vec![(try!(root.clone_to_arc_byte_slice(pool)), 0)],
+            max_depth: max_depth_for(pool.size()),
+        })
+    }
+
+    /// A cursor whose first entry is the first one at or after key. The
+    /// descent is seek's, taking the path key would be found on.
+    pub fn seek(root: &PersistedArcByteSlice, pool: &'p Pool, key: &[u8]) -> Result<Cursor<'p>, LodestoneError> {
+        let mut cursor = try!(Cursor::new(root, pool));
+        loop {
+            let child = match cursor.stack.last_mut() {
+                None => return Ok(cursor),
+                Some(&mut (ref arc, ref mut at)) => {
+                    let node = arc.deref_as::<Node>();
+                    try!(node.check_counts());
+                    let (_, i) = try!(node.index_or_insertion_of(key, pool));
+                    *at = i;
+                    if node.node_type() == NodeType::Leaf || i >= node.num_children() {
+                        return Ok(cursor);
+                    }
+                    // Whatever comes up empty in the child, its
+                    // siblings after it are next
+                    *at = i + 1;
+                    try!(node.children[i].clone_to_arc_byte_slice(pool))
+                },
+            };
+            if cursor.stack.len() >= cursor.max_depth {
+                return Err(LodestoneError::StructureCorrupt("Tree is deeper than the maximum allowed depth"));
+            }
+            cursor.stack.push((child, 0));
+        }
+    }
+
+    /// A cursor with nothing left to visit
+    pub fn empty() -> Cursor<'p> {
+        Cursor {
+            stack: Vec::new(),
+            max_depth: 0,
+        }
+    }
+
+    /// The next entry, None once every one has been visited
+    pub fn next(&mut self, pool: &'p Pool) -> Result<Option<(ArcByteSlice<'p>, ArcByteSlice<'p>)>, LodestoneError> {
+        loop {
+            let child = match self.stack.last_mut() {
+                None => return Ok(None),
+                Some(&mut (ref arc, ref mut at)) => {
+                    let node = arc.deref_as::<Node>();
+                    try!(node.check_counts());
+                    if *at >= node.num_children() {
+                        None
+                    } else {
+                        let i = *at;
+                        *at += 1;
+                        if node.node_type() == NodeType::Leaf {
+                            let key = try!(node.keys[i].clone_to_arc_byte_slice(pool));
+                            let value = try!(node.children[i].clone_to_arc_byte_slice(pool));
+                            return Ok(Some((key, value)));
+                        }
+                        Some(try!(node.children[i].clone_to_arc_byte_slice(pool)))
+                    }
+                },
+            };
+            match child {
+                Some(child) => {
+                    if self.stack.len() >= self.max_depth {
+                        return Err(LodestoneError::StructureCorrupt("Tree is deeper than the maximum allowed depth"));
+                    }
+                    self.stack.push((child, 0));
+                },
+                // Done with the node, carry on in its parent
+                None => { self.stack.pop(); },
+            }
+        }
+    }
+}
+
+/// Subtree digests by node block and id tag. Nodes are never changed
+/// once written, so a digest stays good for as long as its node lives,
+/// and after a commit only the nodes it copied need digesting again.
+#[derive(Debug, Default)]
+pub struct DigestCache {
+    digests: HashMap<(usize, usize), u64>,
+}
+
+impl DigestCache {
+    pub fn new() -> DigestCache {
+        DigestCache::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.digests.len()
+    }
+
+    /// Forget everything, e.g. once freed nodes have piled up
+    pub fn clear(&mut self) {
+        self.digests.clear();
+    }
+}
+
+/// Entries are hashed one by one and summed, so a digest depends only
+/// on the entries and not on how the tree happens to be split into
+/// nodes, and two replicas holding the same data agree on it.
+fn entry_digest(key: &[u8], value: &[u8]) -> u64 {
+    let low = crc32_update(crc32(key), value);
+    let high = crc32_update(crc32(value), key);
+    (high as u64) << 32 | low as u64
+}
+
+/// Digest of every entry under persist
+pub fn subtree_digest(persist: &PersistedArcByteSlice, pool: &Pool, cache: &mut DigestCache)
+    -> Result<u64, LodestoneError> {
+    let id = (persist._arc_inner_index(), persist.get_id_tag());
+    if let Some(&digest) = cache.digests.get(&id) {
+        return Ok(digest);
+    }
+    let arc = try!(persist.clone_to_arc_byte_slice(pool));
+    let node = arc.deref_as::<Node>();
+    try!(node.check_counts());
+    let mut digest = 0u64;
+    for i in 0..node.num_children() {
+        digest = digest.wrapping_add(if node.node_type() == NodeType::Leaf {
+            let key = try!(node.keys[i].clone_to_arc_byte_slice(pool));
+            let value = try!(node.children[i].clone_to_arc_byte_slice(pool));
+            entry_digest(&key, &value)
+        } else {
+            try!(subtree_digest(&node.children[i], pool, cache))
+        });
+    }
+    cache.digests.insert(id, digest);
+    Ok(digest)
+}
+
+/// How many entries there are under persist, counted leaf by leaf
+pub fn count_entries(persist: &PersistedArcByteSlice, pool: &Pool) -> Result<usize, LodestoneError> {
+    let arc = try!(persist.clone_to_arc_byte_slice(pool));
+    let node = arc.deref_as::<Node>();
+    try!(node.check_counts());
+    if node.node_type() == NodeType::Leaf {
+        return Ok(node.num_keys());
+    }
+    let mut count = 0;
+    for i in 0..node.num_children() {
+        count += try!(count_entries(&node.children[i], pool));
+    }
+    Ok(count)
+}
+
+/// How many entries under persist have start <= key < end. Subtrees
+/// whose fences lie outside the range are skipped without reading them.
+pub fn count_range(persist: &PersistedArcByteSlice, pool: &Pool, start: &[u8], end: &[u8])
+    -> Result<usize, LodestoneError> {
+    let arc = try!(persist.clone_to_arc_byte_slice(pool));
+    let node = arc.deref_as::<Node>();
+    try!(node.check_counts());
+    if !node.may_overlap(start, end, pool) {
+        return Ok(0);
+    }
+    if node.node_type() == NodeType::Leaf {
+        let (_, first) = try!(node.index_or_insertion_of(start, pool));
+        let (_, last) = try!(node.index_or_insertion_of(end, pool));
+        return Ok(last.saturating_sub(first));
+    }
+    let mut count = 0;
+    for i in 0..node.num_children() {
+        count += try!(count_range(&node.children[i], pool, start, end));
+    }
+    Ok(count)
+}
+
+/// Digest of the entries with start <= key < end (no end means to the
+/// last key) under persist. Subtrees that fall wholly in the range use
+/// their cached digest, so only the edges of the range are walked.
+/// Replicas compare digests and only look closer at ranges that differ.
+pub fn digest_range(persist: &PersistedArcByteSlice, pool: &Pool, start: &[u8], end: Option<&[u8]>,
+    cache: &mut DigestCache) -> Result<u64, LodestoneError> {
+    digest_range_within(persist, pool, start, end, None, None, cache)
+}
+
+/// above is exclusive and up_to inclusive, the bounds the parent's
+/// separators put on persist's keys
+fn digest_range_within(persist: &PersistedArcByteSlice, pool: &Pool, start: &[u8], end: Option<&[u8]>,
+    above: Option<&[u8]>, up_to: Option<&[u8]>, cache: &mut DigestCache) -> Result<u64, LodestoneError> {
+    let starts_inside = above.map_or(start.is_empty(), |a| a >= start);
+    let ends_inside = match (up_to, end) {
+        (_, None) => true,
+        (Some(u), Some(e)) => u < e,
+        (None, Some(_)) => false,
+    };
+    if starts_inside && ends_inside {
+        return subtree_digest(persist, pool, cache);
+    }
+    let arc = try!(persist.clone_to_arc_byte_slice(pool));
+    let node = arc.deref_as::<Node>();
+    try!(node.check_counts());
+    let mut digest = 0u64;
+    if node.node_type() == NodeType::Leaf {
+        for i in 0..node.num_children() {
+            let key = try!(node.keys[i].clone_to_arc_byte_slice(pool));
+            if &key[..] >= start && end.map_or(true, |e| &key[..] < e) {
+                let value = try!(node.children[i].clone_to_arc_byte_slice(pool));
+                digest = digest.wrapping_add(entry_digest(&key, &value));
+            }
+        }
+        return Ok(digest);
+    }
+    let mut separators = Vec::new();
+    for k in node.keys.iter().take(node.num_keys()) {
+        separators.push(try!(k.clone_to_arc_byte_slice(pool)));
+    }
+    for i in 0..node.num_children() {
+        let child_above = if i == 0 { above } else { Some(&separators[i - 1][..]) };
+        let child_up_to = if i < separators.len() { Some(&separators[i][..]) } else { up_to };
+        // Skip children wholly before or after the range
+        if child_up_to.map_or(false, |u| u < start) || child_above.map_or(false, |a| end.map_or(false, |e| a >= e)) {
+            continue;
+        }
+        digest = digest.wrapping_add(try!(digest_range_within(&node.children[i], pool, start, end,
+            child_above, child_up_to, cache)));
+    }
+    Ok(digest)
+}
+
+/// Build a tree bottom up out of pairs already in ascending key order,
+/// with up to fill entries per node, instead of inserting them one by one.
+/// Nodes on a level are filled evenly, so none ends up underfull. Returns
+/// the root, or None if there were no pairs.
+pub fn bulk_build<'p, I>(pairs: I, tx_id: usize, fill: usize, pool: &'p Pool) -> Result<Option<ArcByteSlice<'p>>, LodestoneError>
+    where I: IntoIterator<Item=Result<(Vec<u8>, Vec<u8>), LodestoneError>> {
+    if fill < 2 || fill > B {
+        return Err(LodestoneError::UserError("Bulk build fill must be between 2 and B"));
+    }
+    let mut entries: Vec<(ArcByteSlice, ArcByteSlice)> = Vec::new();
+    for pair in pairs {
+        let (key, value) = try!(pair);
+        if entries.last().map_or(false, |&(ref last, _)| **last >= key[..]) {
+            return Err(LodestoneError::UserError("Bulk build input isn't in ascending key order"));
+        }
+        entries.push((try!(pool.malloc(&key)), try!(pool.malloc(&value))));
+    }
+
+    // Each level is a list of (node, largest key beneath it)
+    let mut level = Vec::new();
+    let mut at = 0;
+    for size in even_chunks(entries.len(), fill) {
+        let arc = try!(pool.make_new::<Node>());
+        {
+            let node = arc.deref_as_mut::<Node>();
+            node.init(tx_id, NodeType::Leaf);
+            for (i, &(ref key, ref value)) in entries[at..at + size].iter().enumerate() {
+                node.keys[i] = try!(pool.persist(&key));
+                node.children[i] = try!(pool.persist(&value));
+            }
+            node.set_num_keys(size);
+            node.set_num_children(size);
+        }
+        pool.mark_written(&arc);
+        at += size;
+        level.push((arc, entries[at - 1].0.clone()));
+    }
+    build_levels(level, tx_id, fill, pool)
+}
+
+/// Build the internal levels over a level of nodes in key order, each
+/// given with the largest key beneath it, up to a single root
+pub fn build_levels<'p>(mut level: Vec<(ArcByteSlice<'p>, ArcByteSlice<'p>)>, tx_id: usize, fill: usize, pool: &'p Pool)
+    -> Result<Option<ArcByteSlice<'p>>, LodestoneError> {
+    if fill < 2 || fill > B {
+        return Err(LodestoneError::UserError("Bulk build fill must be between 2 and B"));
+    }
+    while level.len() > 1 {
+        let mut above = Vec::new();
+        let mut at = 0;
+        for size in even_chunks(level.len(), fill) {
+            let arc = try!(pool.make_new::<Node>());
+            {
+                let node = arc.deref_as_mut::<Node>();
+                node.init(tx_id, NodeType::Internal);
+                // Each key is the largest beneath the child to its left
+                for (i, &(ref child, ref max)) in level[at..at + size].iter().enumerate() {
+                    node.children[i] = try!(pool.persist(&child));
+                    if i + 1 < size {
+                        node.keys[i] = try!(pool.persist(&max));
+                    }
+                }
+                node.set_num_keys(size - 1);
+                node.set_num_children(size);
+                try!(node.refresh_fences(pool));
+            }
+            pool.mark_written(&arc);
+            at += size;
+            above.push((arc, level[at - 1].1.clone()));
+        }
+        level = above;
+    }
+    Ok(level.pop().map(|(root, _)| root))
+}
+
+/// Copy the leaves of the subtree under persist from one pool into
+/// another, in key order, appending each with its largest key to out,
+/// ready for build_levels. Fails if the subtree's keys don't all come
+/// after those of the leaves already in out.
+pub fn copy_leaves<'t>(persist: &PersistedArcByteSlice, from: &Pool, to: &'t Pool, tx_id: usize,
+    out: &mut Vec<(ArcByteSlice<'t>, ArcByteSlice<'t>)>) -> Result<(), LodestoneError> {
+    let arc = try!(persist.clone_to_arc_byte_slice(from));
+    let node = arc.deref_as::<Node>();
+    try!(node.check_counts());
+    if node.node_type() != NodeType::Leaf {
+        for i in 0..node.num_children() {
+            try!(copy_leaves(&node.children[i], from, to, tx_id, out));
+        }
+        return Ok(());
+    }
+    if node.num_keys() == 0 {
+        return Ok(());
+    }
+    let first = try!(node.keys[0].clone_to_arc_byte_slice(from));
+    if out.last().map_or(false, |&(_, ref max)| **max >= *first) {
+        return Err(LodestoneError::UserError("Stitched subtrees overlap or are out of order"));
+    }
+    let copy = try!(to.make_new::<Node>());
+    let mut max = None;
+    {
+        let leaf = copy.deref_as_mut::<Node>();
+        leaf.init(tx_id, NodeType::Leaf);
+        leaf.set_checksummed(node.checksummed());
+        for i in 0..node.num_keys() {
+            let key = try!(from.copy_block(&try!(node.keys[i].clone_to_arc_byte_slice(from)), to));
+            let value = try!(from.copy_block(&try!(node.children[i].clone_to_arc_byte_slice(from)), to));
+            leaf.keys[i] = try!(to.persist(&key));
+            leaf.children[i] = try!(to.persist(&value));
+            leaf.checksums[i] = node.checksums[i];
+            max = Some(key);
+        }
+        leaf.set_num_keys(node.num_keys());
+        leaf.set_num_children(node.num_keys());
+    }
+    to.mark_written(&copy);
+    out.push((copy, max.unwrap()));
+    Ok(())
+}
+
+/// The block a leaf entry stores value in: block, when the caller
+/// already has value in one, or else a new one
+fn value_block<'p>(value: &[u8], block: Option<&ArcByteSlice<'p>>, pool: &'p Pool) -> Result<ArcByteSlice<'p>, LodestoneError> {
+    match block {
+        Some(block) => Ok(block.clone()),
+        None => pool.malloc(value),
+    }
+}
+
+/// The non-empty leaves under persist in key order, each with its
+/// smallest and largest key. The leaves are shared, not copied.
+fn collect_leaves<'p>(persist: &PersistedArcByteSlice, pool: &'p Pool,
+    out: &mut Vec<(ArcByteSlice<'p>, ArcByteSlice<'p>, ArcByteSlice<'p>)>) -> Result<(), LodestoneError> {
+    let arc = try!(persist.clone_to_arc_byte_slice(pool));
+    let (min, max) = {
+        let node = arc.deref_as::<Node>();
+        try!(node.check_counts());
+        if node.node_type() != NodeType::Leaf {
+            for i in 0..node.num_children() {
+                try!(collect_leaves(&node.children[i], pool, out));
+            }
+            return Ok(());
+        }
+        if node.num_keys() == 0 {
+            return Ok(());
+        }
+        (try!(node.keys[0].clone_to_arc_byte_slice(pool)),
+         try!(node.keys[node.num_keys()-1].clone_to_arc_byte_slice(pool)))
+    };
+    out.push((arc, min, max));
+    Ok(())
+}
+
+/// The leaves of a tree and of a subtree to merge into it, see splice_leaves
+pub struct Splice<'p> {
+    /// Every leaf kept, in key order with its largest key, for build_levels
+    pub leaves: Vec<(ArcByteSlice<'p>, ArcByteSlice<'p>)>,
+    /// How many entries the subtree's leaves that were kept hold
+    pub linked: usize,
+    /// The entries of the subtree's leaves whose key range overlaps one
+    /// of the tree's leaves, which have to be inserted one by one
+    pub overlapping: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Interleave the leaves of subtree with those of the tree under root,
+/// keeping every leaf of the tree and each leaf of the subtree that falls
+/// between them
+pub fn splice_leaves<'p>(root: &PersistedArcByteSlice, subtree: &PersistedArcByteSlice, pool: &'p Pool)
+    -> Result<Splice<'p>, LodestoneError> {
+    let (mut old, mut new) = (Vec::new(), Vec::new());
+    try!(collect_leaves(root, pool, &mut old));
+    try!(collect_leaves(subtree, pool, &mut new));
+    let mut old = old.into_iter().peekable();
+    let mut splice = Splice { leaves: Vec::new(), linked: 0, overlapping: Vec::new() };
+    for (leaf, min, max) in new {
+        // The tree's leaves wholly before this one go first
+        while old.peek().map_or(false, |&(_, _, ref old_max)| **old_max < *min) {
+            let (old_leaf, _, old_max) = old.next().unwrap();
+            splice.leaves.push((old_leaf, old_max));
+        }
+        let node = leaf.deref_as::<Node>();
+        if old.peek().map_or(false, |&(_, ref old_min, _)| **old_min <= *max) {
+            for i in 0..node.num_keys() {
+                let key = try!(node.keys[i].clone_to_arc_byte_slice(pool));
+                let value = try!(node.children[i].clone_to_arc_byte_slice(pool));
+                splice.overlapping.push((key.to_vec(), value.to_vec()));
+            }
+        } else {
+            splice.linked += node.num_keys();
+            splice.leaves.push((leaf.clone(), max));
+        }
+    }
+    splice.leaves.extend(old.map(|(old_leaf, _, old_max)| (old_leaf, old_max)));
+    Ok(splice)
+}
+
+/// Split n items into as few chunks of at most max as possible,
+/// with sizes differing by at most one
+fn even_chunks(n: usize, max: usize) -> Vec<usize> {
+    let count = (n + max - 1) / max;
+    (0..count).map(|i| n / count + if i < n % count { 1 } else { 0 }).collect()
+}
+
+/// What verify_quick checked, and how much of the tree that covers
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuickVerifyReport {
+    /// Levels verified node by node, from the root down
+    pub levels_verified: usize,
+    pub nodes_verified: usize,
+    /// Random paths verified below those levels, one leaf each
+    pub leaves_sampled: usize,
+    /// Leaves below the verified levels, estimated from the fan-out
+    /// along the sampled paths
+    pub leaves_estimated: usize,
+    /// The whole tree fit in the verified levels
+    pub complete: bool,
+}
+
+impl QuickVerifyReport {
+    /// Roughly what fraction of the leaves were looked at
+    pub fn leaf_coverage(&self) -> f64 {
+        if self.complete {
+            1.0
+        } else if self.leaves_estimated == 0 {
+            0.0
+        } else {
+            (self.leaves_sampled as f64 / self.leaves_estimated as f64).min(1.0)
+        }
+    }
+}
+
+/// A cheaper verify for big trees, e.g. on every open: every node in
+/// the top depth_limit levels, then sample_leaves random paths from there
+/// down to a leaf. Fails on the first bad node found.
+pub fn verify_quick(persist: &PersistedArcByteSlice, pool: &Pool, depth_limit: usize, sample_leaves: usize, seed: usize)
+    -> Result<QuickVerifyReport, LodestoneError> {
+    let mut report = QuickVerifyReport {
+        levels_verified: 0,
+        nodes_verified: 0,
+        leaves_sampled: 0,
+        leaves_estimated: 0,
+        complete: false,
+    };
+    let mut level = vec![try!(persist.clone_to_arc_byte_slice(pool))];
+    while !level.is_empty() && report.levels_verified < depth_limit {
+        let mut below = Vec::new();
+        for arc in level.iter() {
+            let node = arc.deref_as::<Node>();
+            try!(node.verify(pool));
+            report.nodes_verified += 1;
+            if node.node_type() != NodeType::Leaf {
+                for c in node.children.iter().take(node.num_children()) {
+                    below.push(try!(c.clone_to_arc_byte_slice(pool)));
+                }
+            }
+        }
+        report.levels_verified += 1;
+        level = below;
+    }
+    if level.is_empty() {
+        report.complete = true;
+        return Ok(report);
+    }
+
+    let state = AtomicUsize::new(xorshift_seed(seed));
+    let mut estimates = 0;
+    for _ in 0..sample_leaves {
+        let mut arc = level[roll(&state) % level.len()].clone();
+        // Each path estimates the leaf count as the product of the fan-outs along it
+        let mut estimate = level.len();
+        loop {
+            let next = {
+                let node = arc.deref_as::<Node>();
+                try!(node.verify(pool));
+                report.nodes_verified += 1;
+                if node.node_type() == NodeType::Leaf || node.num_children() == 0 {
+                    break;
+                }
+                estimate *= node.num_children();
+                try!(node.children[roll(&state) % node.num_children()].clone_to_arc_byte_slice(pool))
+            };
+            arc = next;
+        }
+        report.leaves_sampled += 1;
+        estimates += estimate;
+    }
+    if sample_leaves > 0 {
+        report.leaves_estimated = estimates / sample_leaves;
+    }
+    Ok(report)
+}
+
+/// Everything in a node ahead of its arrays: the header, fences and
+/// tx_id. It reads the same whatever B the node was written with, only
+/// the keys, children and checksums after it change length.
+const NODE_PREFIX_SIZE: usize = mem::size_of::<Node>() - B * NODE_SLOT_SIZE;
+/// A key, a child and a checksum
+const NODE_SLOT_SIZE: usize = 2 * mem::size_of::<PersistedArcByteSlice>() + 4;
+
+/// The size of a node block written with room for capacity keys
+pub fn node_size(capacity: usize) -> usize {
+    let word = mem::size_of::<usize>();
+    NODE_PREFIX_SIZE + (capacity * NODE_SLOT_SIZE + word - 1) / word * word
+}
+
+/// The B a node block of len bytes was written with, if any
+pub fn node_capacity(len: usize) -> Option<usize> {
+    (1..HEADER_COUNT_MASK as usize + 1).find(|&capacity| node_size(capacity) == len)
+}
+
+/// Slot i of the keys (array 0) or children (array 1) of a node block
+/// written with room for capacity keys
+fn slot_at(block: &ArcByteSlice, capacity: usize, array: usize, i: usize) -> PersistedArcByteSlice {
+    let offset = NODE_PREFIX_SIZE + (array * capacity + i) * mem::size_of::<PersistedArcByteSlice>();
+    assert!(i < capacity && offset + mem::size_of::<PersistedArcByteSlice>() <= block.len());
+    unsafe { ptr::read(block.as_ptr().offset(offset as isize) as *const PersistedArcByteSlice) }
+}
+
+fn checksum_at(block: &ArcByteSlice, capacity: usize, i: usize) -> u32 {
+    let offset = NODE_PREFIX_SIZE + 2 * capacity * mem::size_of::<PersistedArcByteSlice>() + 4 * i;
+    assert!(i < capacity && offset + 4 <= block.len());
+    unsafe { ptr::read(block.as_ptr().offset(offset as isize) as *const u32) }
+}
+
+/// A node migrate_capacity replaced, held until the new tree is current
+struct RetiredNode {
+    persist: PersistedArcByteSlice,
+    capacity: usize,
+    num_keys: usize,
+    num_children: usize,
+}
+
+/// A tree rewritten by migrate_capacity, and the nodes of the old tree
+/// it replaced. The old tree is whole until finish releases them.
+pub struct Migration<'p> {
+    pub root: ArcByteSlice<'p>,
+    retired: Vec<RetiredNode>,
+}
+
+impl<'p> Migration<'p> {
+    /// The new root is current: release the replaced nodes. Everything
+    /// they held is held by the new tree too, so this only gives up
+    /// their counts and frees the node blocks themselves.
+    pub fn finish(self, pool: &Pool) -> Result<(), LodestoneError> {
+        // Children were retired before their parents, and are freed
+        // along with their parent's reference to them
+        for mut retired in self.retired {
+            let block = try!(retired.persist.clone_to_arc_byte_slice(pool));
+            for i in 0..retired.num_keys {
+                try!(slot_at(&block, retired.capacity, 0, i).release(pool));
+            }
+            for i in 0..retired.num_children {
+                try!(slot_at(&block, retired.capacity, 1, i).release(pool));
+            }
+            try!(retired.persist.release(pool));
+        }
+        Ok(())
+    }
+
+    /// The new root won't be used: release it, leaving the old tree as
+    /// it was
+    pub fn abandon(self, pool: &Pool) -> Result<(), LodestoneError> {
+        let mut root = self.root.clone_to_persisted();
+        drop(self.root);
+        try!(release_node(&mut root, pool));
+        abandon_retired(self.retired, pool)
+    }
+}
+
+fn abandon_retired(retired: Vec<RetiredNode>, pool: &Pool) -> Result<(), LodestoneError> {
+    for mut retired in retired {
+        try!(retired.persist.release(pool));
+    }
+    Ok(())
+}
+
+/// Rewrite the tree under root in this build's layout, where any of it
+/// was written with another B. Nodes already in the layout are shared
+/// with the old tree unless something under them was rewritten, and the
+/// old tree is left whole, so the migration commits in one step or not
+/// at all. Returns None if nothing needed rewriting. Nodes holding more
+/// entries than B can't be rewritten without splitting them, and fail
+/// the migration. A failed migration leaves the old tree as it was, but
+/// what it had copied so far isn't reclaimed.
+pub fn migrate_capacity<'p>(root: &ArcByteSlice, pool: &'p Pool, tx_id: usize) -> Result<Option<Migration<'p>>, LodestoneError> {
+    let mut retired = Vec::new();
+    match migrate_node(root, pool, tx_id, &mut retired) {
+        Ok(Some(new_root)) => Ok(Some(Migration { root: new_root, retired: retired })),
+        Ok(None) => Ok(None),
+        Err(e) => {
+            try!(abandon_retired(retired, pool));
+            Err(e)
+        },
+    }
+}
+
+fn migrate_node<'p>(block: &ArcByteSlice, pool: &'p Pool, tx_id: usize, retired: &mut Vec<RetiredNode>)
+    -> Result<Option<ArcByteSlice<'p>>, LodestoneError> {
+    let capacity = match node_capacity(block.len()) {
+        Some(capacity) => capacity,
+        None => return Err(LodestoneError::StructureCorrupt("Node block doesn't have the size of any node layout")),
+    };
+    let new = try!(pool.make_new::<Node>());
+    let node = new.deref_as_mut::<Node>();
+    unsafe {
+        ptr::copy_nonoverlapping(block.as_ptr(), node as *mut Node as *mut u8, NODE_PREFIX_SIZE);
+    }
+    if node.num_keys() > cmp::min(capacity, B) || node.num_children() > cmp::min(capacity, B) {
+        return Err(LodestoneError::StructureCorrupt("Node holds more entries than fit in this build's nodes"));
+    }
+    try!(node.check_counts());
+
+    let mut moved = Vec::new();
+    if node.node_type() != NodeType::Leaf {
+        for i in 0..node.num_children() {
+            let child = try!(slot_at(block, capacity, 1, i).clone_to_arc_byte_slice(pool));
+            moved.push(try!(migrate_node(&child, pool, tx_id, retired)));
+        }
+    }
+    if capacity == B && moved.iter().all(|m| m.is_none()) {
+        // The node being dropped was never linked or given slots
+        return Ok(None);
+    }
+
+    node.tx_id = tx_id;
+    for i in 0..node.num_keys() {
+        node.keys[i] = try!(slot_at(block, capacity, 0, i).clone(pool));
+        node.checksums[i] = checksum_at(block, capacity, i);
+    }
+    for i in 0..node.num_children() {
+        node.children[i] = match moved.get_mut(i).and_then(|m| m.take()) {
+            Some(child) => try!(pool.persist(&child)),
+            None => try!(slot_at(block, capacity, 1, i).clone(pool)),
+        };
     }
-    array[index] = arc.clone_to_persisted();
+    retired.push(RetiredNode {
+        persist: block.clone_to_persisted(),
+        capacity: capacity,
+        num_keys: node.num_keys(),
+        num_children: node.num_children(),
+    });
+    pool.mark_written(&new);
+    Ok(Some(new))
 }
 
-pub fn release_node(persist: &mut PersistedArcByteSlice, pool: &Pool) {
-    { // Borrow checker
-        let arc = recover_but_panic_in_debug!(persist.clone_to_arc_byte_slice(pool), ());
-        let node = arc.deref_as_mut::<Node>();
-        match node.node_type {
-            NodeType::Root | NodeType::Internal => {
-                for p in node.children.iter_mut().take(node.num_children) {
-                    release_node(p, pool);
-                }
-            },
-            NodeType::Leaf => {
-                for p in node.children.iter_mut().take(node.num_children) {
-                    let ok = p.release(pool).is_ok();
-                    debug_assert!(ok);
-                }
-            },
-        }
-        // Release the keys mem
-        for p in node.keys.iter_mut().take(node.num_keys) {
-            let ok = p.release(pool).is_ok();
-            debug_assert!(ok);
+fn release_value<F>(persist: &mut PersistedArcByteSlice, pool: &Pool, extract: &F) -> Result<(), LodestoneError>
+    where F: Fn(&[u8]) -> Vec<Reference> {
+    let arc = try!(persist.clone_to_arc_byte_slice(pool));
+    try!(persist.release(pool));
+    // If ours is the only reference left, the value is freed once we drop it
+    if arc.get_ref_count() == 1 {
+        // References can form cycles, which will never be released
+        for reference in extract(&*arc) {
+            let mut target = try!(pool.take_reference(&reference));
+            try!(release_value(&mut target, pool, extract));
         }
     }
-    // Finally, release the pointer itself
-    let ok = persist.release(pool).is_ok();
-    debug_assert!(ok);
+    Ok(())
 }
 
 pub struct DebuggableNode<'a> {
     node: &'a Node,
-    pool: &'a Pool,
+    pool: &'a Pool<'a>,
 }
 
 impl <'a> fmt::Debug for DebuggableNode<'a> {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         let key_vec: Vec<String> = self.node.keys.iter()
-            .take(self.node.num_keys)
+            .take(self.node.num_keys())
             .map(|persist| {
                 str::from_utf8(
                     &*persist.clone_to_arc_byte_slice(self.pool).unwrap()
@@ -475,7 +2562,7 @@ impl <'a> fmt::Debug for DebuggableNode<'a> {
             })
             .collect();
         let child_vec: Vec<String> = self.node.children.iter()
-            .take(self.node.num_children)
+            .take(self.node.num_children())
             .map(|persist| {
                 str::from_utf8(
                     &*persist.clone_to_arc_byte_slice(self.pool).unwrap()
@@ -484,7 +2571,7 @@ impl <'a> fmt::Debug for DebuggableNode<'a> {
                 .to_string()
             })
             .collect();
-        fmt.debug_struct(&format!("{:?}", self.node.node_type))
+        fmt.debug_struct(&format!("{:?}", self.node.node_type()))
             .field("tx_id", &self.node.tx_id)
             .field("keys", &key_vec.join(", "))
             .field("children", &child_vec.join(", "))
@@ -500,6 +2587,8 @@ mod tests {
     use super::super::*;
     use super::NodeType::*;
     use super::InsertionResult::*;
+    use std::sync::atomic::Ordering::Relaxed;
+    use LodestoneError;
 
     lazy_static! {
         static ref HELLO: Vec<u8> = String::from("hello").into_bytes();
@@ -518,7 +2607,8 @@ mod tests {
 
     #[test]
     fn test_internal_node_insert_with_leaf_split() {
-        let mut buf = [0u8; 0x8000];
+        // Room for both versions of the path while the leaf splits
+        let mut buf = vec![0u8; 0x10000];
         let pool = Pool::new(&mut buf);
 
         let mut child = pool.make_new::<Node>().unwrap();
@@ -527,36 +2617,403 @@ mod tests {
         center_arc.deref_as_mut::<Node>().init(0, Internal);
         {
             let mut center = center_arc.deref_as_mut::<Node>();
-            center.num_keys = 0;
-            center.num_children = 1;
+            center.set_num_keys(0);
+            center.set_num_children(1);
             center.children[0] = child.clone_to_persisted();
         }
         for i in 0..B {
             let key: Vec<u8> = format!("{} key", i).into_bytes();
             let value: Vec<u8> = format!("{} value", i).into_bytes();
-            release_node(&mut center_arc.clone_to_persisted(), &pool);
-            match center_arc.deref_as::<Node>()
+            let new_center = match center_arc.deref_as::<Node>()
                 .internal_node_insert(i, &key[..], &value[..], &pool)
                 .unwrap() {
-                HadRoom(arc) => center_arc = arc,
+                HadRoom(arc) => arc,
                 NoRoom(_) => panic!("Ran out of room {}/{}", i, B),
-            }
+            };
+            // The old version shares everything but the path to the new key
+            let mut old_center = center_arc.clone_to_persisted();
+            center_arc = new_center;
+            release_node(&mut old_center, &pool).unwrap();
         }
         {
             let center = center_arc.deref_as::<Node>();
-            assert_eq!(2, center.num_children);
-            assert_eq!(1, center.num_keys);
+            assert_eq!(2, center.num_children());
+            assert_eq!(1, center.num_keys());
             let mid_key = center.keys[0].clone_to_arc_byte_slice(&pool).unwrap();
-            assert_eq!("", str::from_utf8(&*mid_key).unwrap());
+            assert_eq!("53 key", str::from_utf8(&*mid_key).unwrap());
 
             let left_node_arc = center.children[0].clone_to_arc_byte_slice(&pool).unwrap();
             let left_node = left_node_arc.deref_as::<Node>();
-            assert_eq!(B/2, left_node.num_keys);
-            assert_eq!(B/2, left_node.num_children);
+            assert_eq!(B/2, left_node.num_keys());
+            assert_eq!(B/2, left_node.num_children());
             let right_node_arc = center.children[1].clone_to_arc_byte_slice(&pool).unwrap();
             let right_node = right_node_arc.deref_as::<Node>();
-            assert_eq!(B/2, right_node.num_keys);
-            assert_eq!(B/2, right_node.num_children);
+            assert_eq!(B/2, right_node.num_keys());
+            assert_eq!(B/2, right_node.num_children());
+        }
+    }
+
+    #[test]
+    fn test_internal_node_split_grows_the_root() {
+        let mut buf = vec![0u8; 0x200000];
+        let pool = Pool::new(&mut buf);
+        let mut root = Node::new_leaf(0, false, &pool).unwrap();
+        let mut root_splits = 0;
+        // Sequential inserts leave half full leaves behind, so the root
+        // runs out of children and splits after about B * B / 2 entries
+        for i in 0..6000 {
+            let key = format!("key {:04}", i).into_bytes();
+            let new_root = match root.deref_as::<Node>().insert(i + 1, &key, &key[4..], &pool).unwrap() {
+                HadRoom(arc) => arc,
+                NoRoom(split) => {
+                    root_splits += 1;
+                    Node::new_root(i + 1, split, &pool).unwrap()
+                },
+            };
+            let mut old_root = root.clone_to_persisted();
+            root = new_root;
+            release_node(&mut old_root, &pool).unwrap();
+        }
+        // Once for the first leaf, once for the first internal root
+        assert_eq!(2, root_splits);
+
+        let persisted = root.clone_to_persisted();
+        let picture = snapshot(&persisted, &pool).unwrap();
+        assert_eq!(2, picture.children.len());
+        assert!(picture.children.iter().all(|c| !c.leaf && c.children.iter().all(|l| l.leaf)));
+        assert!(verify_quick(&persisted, &pool, 3, 0, 1).unwrap().complete);
+        assert_eq!(6000, count_entries(&persisted, &pool).unwrap());
+        for i in (0..6000).filter(|i| i % 7 == 0) {
+            let key = format!("key {:04}", i).into_bytes();
+            assert_eq!(&key[4..], &find_value(&root, &pool, &key).unwrap().unwrap()[..]);
+        }
+    }
+
+    #[test]
+    fn test_remove_rebalances() {
+        /// Depth of the tree, checking every node but the root is at
+        /// least half full and every leaf is at the same depth
+        fn checked_depth(picture: &TreeSnapshot, root: bool) -> usize {
+            let entries = if picture.leaf { picture.values.len() } else { picture.children.len() };
+            assert!(root || entries >= B/2, "Underfull node with {} entries", entries);
+            assert!(picture.leaf || entries >= 2);
+            let depths: Vec<usize> = picture.children.iter().map(|c| checked_depth(c, false)).collect();
+            assert!(depths.windows(2).all(|w| w[0] == w[1]));
+            1 + depths.first().cloned().unwrap_or(0)
+        }
+        fn entries(picture: &TreeSnapshot) -> usize {
+            picture.values.len() + picture.children.iter().map(entries).sum::<usize>()
+        }
+        fn replace_root<'p>(root: &mut ArcByteSlice<'p>, new_root: ArcByteSlice<'p>, pool: &Pool) {
+            let mut old_root = root.clone_to_persisted();
+            *root = new_root;
+            release_node(&mut old_root, pool).unwrap();
+        }
+
+        let mut buf = vec![0u8; 0x200000];
+        let pool = Pool::new(&mut buf);
+        let empty = pool.lifetime_stats().live_blocks;
+        let n = 1500;
+        let key = |i: usize| format!("key {:04}", i).into_bytes();
+        let mut root = Node::new_leaf(0, false, &pool).unwrap();
+        for i in 0..n {
+            let new_root = match root.deref_as::<Node>().insert(i + 1, &key(i), b"value", &pool).unwrap() {
+                HadRoom(arc) => arc,
+                NoRoom(split) => Node::new_root(i + 1, split, &pool).unwrap(),
+            };
+            replace_root(&mut root, new_root, &pool);
+        }
+        let picture = |root: &ArcByteSlice| {
+            let mut persisted = root.clone_to_persisted();
+            let picture = snapshot(&persisted, &pool).unwrap();
+            persisted.release(&pool).unwrap();
+            picture
+        };
+        assert_eq!(2, checked_depth(&picture(&root), true));
+
+        // Scattered removes, so nodes run into neighbours on either side
+        let mut removed = vec![false; n];
+        for (done, i) in (0..n).map(|i| (i * 7919) % n).enumerate() {
+            let new_root = root.deref_as::<Node>().remove(n + done, &key(i), &pool).unwrap().unwrap();
+            replace_root(&mut root, new_root, &pool);
+            removed[i] = true;
+            if done % 100 == 0 {
+                let picture = picture(&root);
+                checked_depth(&picture, true);
+                assert_eq!(n - done - 1, entries(&picture));
+                for j in (0..n).filter(|j| j % 7 == done % 7) {
+                    assert_eq!(!removed[j], find_value(&root, &pool, &key(j)).unwrap().is_some());
+                }
+            }
+        }
+        {
+            let node = root.deref_as::<Node>();
+            assert!(node.is_leaf());
+            assert_eq!(0, node.num_keys());
+        }
+        let mut persisted = root.clone_to_persisted();
+        drop(root);
+        release_node(&mut persisted, &pool).unwrap();
+        assert_eq!(empty, pool.lifetime_stats().live_blocks);
+    }
+
+    #[test]
+    fn test_rebalance_internal_nodes() {
+        let mut buf = vec![0u8; 0x100000];
+        let pool = Pool::new(&mut buf);
+        let name = |i: usize| format!("k{:03}", i).into_bytes();
+        // A leaf per key, under separators of the same name
+        let leaves: Vec<ArcByteSlice> = (0..110).map(|i| {
+            let empty = Node::new_leaf(0, false, &pool).unwrap();
+            let result = empty.deref_as::<Node>().insert(0, &name(i), b"value", &pool).unwrap();
+            match result {
+                HadRoom(leaf) => leaf,
+                NoRoom(_) => panic!("Ran out of room in an empty leaf"),
+            }
+        }).collect();
+        let internal = |from: usize, to: usize| {
+            let arc = pool.make_new::<Node>().unwrap();
+            {
+                let node = arc.deref_as_mut::<Node>();
+                node.init(0, Internal);
+                for i in from..to {
+                    node.children[i - from] = leaves[i].clone_to_persisted();
+                }
+                for i in from..to - 1 {
+                    node.keys[i - from] = pool.malloc(&name(i)).unwrap().clone_to_persisted();
+                }
+                node.set_num_children(to - from);
+                node.set_num_keys(to - from - 1);
+                node.refresh_fences(&pool).unwrap();
+            }
+            arc
+        };
+        let keys_of = |arc: &ArcByteSlice| {
+            let node = arc.deref_as::<Node>();
+            node.verify(&pool).unwrap();
+            node.keys.iter().take(node.num_keys())
+                .map(|k| k.clone_to_arc_byte_slice(&pool).unwrap().to_vec())
+                .collect::<Vec<_>>()
+        };
+        let separator = pool.malloc(&name(39)).unwrap().clone_to_persisted();
+
+        // Too many children for one node: 55 each, and the key between
+        // them moves up
+        let (bottom, top) = (internal(0, 40), internal(40, 110));
+        match Node::rebalance(bottom.deref_as::<Node>(), &separator, top.deref_as::<Node>(), 1, &pool).unwrap() {
+            Rebalanced::Shared(split) => {
+                assert_eq!(name(54), split.mid_key.to_vec());
+                assert_eq!((0..54).map(&name).collect::<Vec<_>>(), keys_of(&split.bottom_half));
+                assert_eq!((55..109).map(&name).collect::<Vec<_>>(), keys_of(&split.top_half));
+                assert_eq!(55, split.top_half.deref_as::<Node>().num_children());
+            },
+            Rebalanced::Merged(_) => panic!("110 children can't share a node"),
+        }
+
+        // Few enough for one: the separator comes down between them
+        let (bottom, top) = (internal(0, 40), internal(40, 85));
+        match Node::rebalance(bottom.deref_as::<Node>(), &separator, top.deref_as::<Node>(), 1, &pool).unwrap() {
+            Rebalanced::Merged(merged) => {
+                assert_eq!((0..84).map(&name).collect::<Vec<_>>(), keys_of(&merged));
+                assert_eq!(85, merged.deref_as::<Node>().num_children());
+            },
+            Rebalanced::Shared(_) => panic!("85 children fit in one node"),
+        }
+
+        assert!(Node::rebalance(leaves[0].deref_as::<Node>(), &separator, top.deref_as::<Node>(), 1, &pool).is_err());
+    }
+
+    #[test]
+    fn test_internal_node_insert_detects_cycle() {
+        let mut buf = [0u8; 0x8000];
+        let pool = Pool::new(&mut buf);
+
+        let n_arc = pool.make_new::<Node>().unwrap();
+        {
+            let n = n_arc.deref_as_mut::<Node>();
+            n.init(0, Internal);
+            // Corrupt the node by pointing it at itself
+            n.set_num_children(1);
+            n.children[0] = n_arc.clone_to_persisted();
+        }
+        match n_arc.deref_as::<Node>().internal_node_insert(1, &HELLO, &WORLD, &pool) {
+            Err(LodestoneError::StructureCorrupt(_)) => (),
+            Err(e) => panic!("Wrong error for a cyclic tree: {:?}", e),
+            Ok(_) => panic!("Insert into a cyclic tree succeeded"),
+        };
+    }
+
+    #[test]
+    fn test_internal_node_compact_leaves() {
+        let mut buf = [0u8; 0x8000];
+        let pool = Pool::new(&mut buf);
+
+        let leaf_with = |k: &[u8], v: &[u8]| {
+            let arc = pool.make_new::<Node>().unwrap();
+            arc.deref_as_mut::<Node>().init(0, Leaf);
+            let arc = arc.deref_as::<Node>().leaf_node_insert_non_full(1, k, v, &pool).unwrap();
+            arc
+        };
+        let apple = leaf_with(&APPLE, &BAR);
+        let cherry = leaf_with(&CHERRY, &BAR);
+        let hello = leaf_with(&HELLO, &WORLD);
+
+        let center_arc = pool.make_new::<Node>().unwrap();
+        {
+            let center = center_arc.deref_as_mut::<Node>();
+            center.init(1, Internal);
+            center.keys[0] = pool.malloc(&CHERRY).unwrap().clone_to_persisted();
+            center.keys[1] = pool.malloc(&HELLO).unwrap().clone_to_persisted();
+            center.set_num_keys(2);
+            center.children[0] = apple.clone_to_persisted();
+            center.children[1] = cherry.clone_to_persisted();
+            center.children[2] = hello.clone_to_persisted();
+            center.set_num_children(3);
+        }
+
+        let compacted = center_arc.deref_as::<Node>().internal_node_compact_leaves(2, &pool).unwrap().unwrap();
+        let node = compacted.deref_as::<Node>();
+        assert_eq!(0, node.num_keys());
+        assert_eq!(1, node.num_children());
+        assert!(node.internal_node_contains_key(&APPLE, &pool).unwrap());
+        assert!(node.internal_node_contains_key(&CHERRY, &pool).unwrap());
+        assert!(node.internal_node_contains_key(&HELLO, &pool).unwrap());
+
+        let leaf_arc = node.children[0].clone_to_arc_byte_slice(&pool).unwrap();
+        assert_eq!(
+            "Leaf { tx_id: 2, keys: \"apple, cherry, hello\", children: \"bar, bar, world\" }",
+            format!("{:?}", DebuggableNode {
+                node: leaf_arc.deref_as::<Node>(),
+                pool: &pool,
+            })
+        );
+
+        // Nothing left to merge
+        assert!(node.internal_node_compact_leaves(3, &pool).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_internal_node_numeric_update() {
+        use super::super::numeric::*;
+        let mut buf = [0u8; 0x8000];
+        let pool = Pool::new(&mut buf);
+
+        let leaf_arc = pool.make_new::<Node>().unwrap();
+        leaf_arc.deref_as_mut::<Node>().init(0, Leaf);
+        let leaf_arc = leaf_arc.deref_as::<Node>()
+            .leaf_node_insert_non_full(1, &HELLO, &encode_u64(5), &pool).unwrap();
+        let center_arc = pool.make_new::<Node>().unwrap();
+        {
+            let center = center_arc.deref_as_mut::<Node>();
+            center.init(1, Internal);
+            center.set_num_children(1);
+            center.children[0] = leaf_arc.clone_to_persisted();
+        }
+        let value_of = |arc: &ArcByteSlice, key: &[u8]| {
+            let node = arc.deref_as::<Node>();
+            let leaf = node.children[0].clone_to_arc_byte_slice(&pool).unwrap();
+            let value = leaf.deref_as::<Node>().leaf_node_value_for_key(key, &pool).unwrap();
+            value.map(|v| decode_u64(&*v).unwrap())
+        };
+
+        let center = center_arc.deref_as::<Node>();
+        let raised = match center.internal_node_update(2, &HELLO, &|cur| if_greater(cur, 7),
+                &pool, &mut Descent::for_pool(&pool)).unwrap() {
+            Some(HadRoom(arc)) => arc,
+            _ => panic!("Expected the high water mark to move"),
+        };
+        assert_eq!(Some(7), value_of(&raised, &HELLO));
+        assert_eq!(Some(5), value_of(&center_arc, &HELLO));
+
+        // A lower candidate leaves the tree alone
+        assert!(raised.deref_as::<Node>().internal_node_update(3, &HELLO, &|cur| if_greater(cur, 3),
+            &pool, &mut Descent::for_pool(&pool)).unwrap().is_none());
+
+        let counted = match raised.deref_as::<Node>().internal_node_update(4, &FOO, &|cur| add(cur, 2),
+                &pool, &mut Descent::for_pool(&pool)).unwrap() {
+            Some(HadRoom(arc)) => arc,
+            _ => panic!("Expected the counter to be created"),
+        };
+        assert_eq!(Some(2), value_of(&counted, &FOO));
+    }
+
+    #[test]
+    fn test_malformed_input_returns_errors() {
+        let mut buf = [0u8; 0x8000];
+        let pool = Pool::new(&mut buf);
+
+        let leaf_arc = pool.make_new::<Node>().unwrap();
+        leaf_arc.deref_as_mut::<Node>().init(0, Leaf);
+        let internal_arc = pool.make_new::<Node>().unwrap();
+        internal_arc.deref_as_mut::<Node>().init(0, Internal);
+        let root_arc = pool.make_new::<Node>().unwrap();
+        root_arc.deref_as_mut::<Node>().init(0, Root);
+        let leaf = leaf_arc.deref_as::<Node>();
+        let internal = internal_arc.deref_as::<Node>();
+
+        assert!(leaf.split(1, &pool).is_err());
+        assert!(Node::join(leaf, internal, 1, &pool).is_err());
+        assert!(leaf.internal_node_insert(1, &HELLO, &WORLD, &pool).is_err());
+        assert!(internal.leaf_node_insert_non_full(1, &HELLO, &WORLD, &pool).is_err());
+        assert!(internal.leaf_node_insert_or_set(1, &HELLO, &WORLD, &pool).is_err());
+        assert!(leaf.leaf_node_remove(1, &HELLO, &pool).is_err());
+
+        // An internal node pointing at a Root
+        {
+            let n = internal_arc.deref_as_mut::<Node>();
+            n.set_num_children(1);
+            n.children[0] = root_arc.clone_to_persisted();
+        }
+        match internal.internal_node_insert(1, &HELLO, &WORLD, &pool) {
+            Err(LodestoneError::StructureCorrupt(_)) => (),
+            Err(e) => panic!("Wrong error for a misplaced Root: {:?}", e),
+            Ok(_) => panic!("Insert below a misplaced Root succeeded"),
+        }
+        match internal.internal_node_contains_key(&HELLO, &pool) {
+            Err(LodestoneError::StructureCorrupt(_)) => (),
+            other => panic!("Expected a misplaced Root to be reported, got {:?}", other),
+        }
+
+        // An internal node pointing at itself
+        internal_arc.deref_as_mut::<Node>().children[0] = internal_arc.clone_to_persisted();
+        match internal.internal_node_contains_key(&HELLO, &pool) {
+            Err(LodestoneError::StructureCorrupt(_)) => (),
+            other => panic!("Expected the cycle to be reported, got {:?}", other),
+        }
+
+        // A leaf whose key was freed from under it
+        let keyed = leaf.leaf_node_insert_non_full(1, &HELLO, &WORLD, &pool).unwrap();
+        {
+            let junk = pool.malloc(&APPLE).unwrap();
+            let n = keyed.deref_as_mut::<Node>();
+            n.keys[0].release(&pool).unwrap();
+            n.keys[0] = junk.clone_to_persisted();
+            // Give up the node's count, leaving the node naming the block
+            let reference = Reference::from_persisted(&n.keys[0]);
+            pool.take_reference(&reference).unwrap().release(&pool).unwrap();
+        }
+        // And one with garbage where a key reference should be
+        let scribbled = leaf.leaf_node_insert_non_full(1, &HELLO, &WORLD, &pool).unwrap();
+        {
+            let n = scribbled.deref_as_mut::<Node>();
+            n.keys[0].release(&pool).unwrap();
+            n.keys[0] = Reference::new(usize::max_value() - 7, 1)._to_persisted();
+        }
+        match scribbled.deref_as::<Node>().index_or_insertion_of(&HELLO, &pool) {
+            Err(LodestoneError::StructureCorrupt(_)) => (),
+            other => panic!("Expected the scribbled key to be reported, got {:?}", other),
+        }
+        let keyed = keyed.deref_as::<Node>();
+        for result in vec![
+            keyed.index_or_insertion_of(&HELLO, &pool).map(|_| ()),
+            keyed.leaf_node_contains_key(&HELLO, &pool).map(|_| ()),
+            keyed.leaf_node_value_for_key(&HELLO, &pool).map(|_| ()),
+            keyed.leaf_node_insert_or_set(2, &FOO, &BAR, &pool).map(|_| ()),
+        ] {
+            match result {
+                Err(LodestoneError::StructureCorrupt(_)) => (),
+                other => panic!("Expected the freed key to be reported, got {:?}", other),
+            }
         }
     }
 
@@ -589,14 +3046,14 @@ mod tests {
         ).unwrap();
         match should_be_split {
             HadRoom(arc) => {
-                panic!("Did not split when it should have! {}/{}", arc.deref_as::<Node>().num_children, B);
+                panic!("Did not split when it should have! {}/{}", arc.deref_as::<Node>().num_children(), B);
             },
             NoRoom(split) => {
                 let bottom_node = split.bottom_half.deref_as::<Node>();
                 let top_node = split.top_half.deref_as::<Node>();
 
-                assert_eq!(50, bottom_node.num_children);
-                assert_eq!(50, top_node.num_children);
+                assert_eq!(50, bottom_node.num_children());
+                assert_eq!(50, top_node.num_children());
 
                 println!("BOTTOM: {:?}", DebuggableNode {
                     node: bottom_node,
@@ -607,8 +3064,8 @@ mod tests {
                     pool: &pool,
                 });
 
-                // "55 key" is the lexicographic middle
-                assert_eq!("55 key", str::from_utf8(&*split.mid_key).unwrap());
+                // "53 key" is the last of the lexicographic bottom half
+                assert_eq!("53 key", str::from_utf8(&*split.mid_key).unwrap());
             },
         };
     }
@@ -674,28 +3131,30 @@ mod tests {
 
         let bottom = split.bottom_half.deref_as::<Node>();
         let top = split.top_half.deref_as::<Node>();
-        assert_eq!(1, bottom.num_keys);
-        assert_eq!(1, bottom.num_children);
-        assert_eq!(2, top.num_keys);
-        assert_eq!(2, top.num_children);
+        assert_eq!(1, bottom.num_keys());
+        assert_eq!(1, bottom.num_children());
+        assert_eq!(2, top.num_keys());
+        assert_eq!(2, top.num_children());
 
-        assert_eq!(*FOO, &*split.mid_key);
+        assert_eq!(*CHERRY, &*split.mid_key);
 
-        assert!(top.leaf_node_contains_key(&HELLO, &pool));
-        assert!(bottom.leaf_node_contains_key(&CHERRY, &pool));
+        assert!(top.leaf_node_contains_key(&HELLO, &pool).unwrap());
+        assert!(bottom.leaf_node_contains_key(&CHERRY, &pool).unwrap());
 
-        assert!(!bottom.leaf_node_contains_key(&HELLO, &pool));
-        assert!(!top.leaf_node_contains_key(&CHERRY, &pool));
+        assert!(!bottom.leaf_node_contains_key(&HELLO, &pool).unwrap());
+        assert!(!top.leaf_node_contains_key(&CHERRY, &pool).unwrap());
 
-        assert!(top.leaf_node_contains_key(&FOO, &pool));
+        assert!(top.leaf_node_contains_key(&FOO, &pool).unwrap());
 
-        assert_eq!(*BAR, &*top.leaf_node_value_for_key(&FOO, &pool).unwrap());
-        assert_eq!(*WORLD, &*top.leaf_node_value_for_key(&HELLO, &pool).unwrap());
-        assert_eq!(*BLUEBERRY, &*bottom.leaf_node_value_for_key(&CHERRY, &pool).unwrap());
+        assert_eq!(*BAR, &*top.leaf_node_value_for_key(&FOO, &pool).unwrap().unwrap());
+        assert_eq!(*WORLD, &*top.leaf_node_value_for_key(&HELLO, &pool).unwrap().unwrap());
+        assert_eq!(*BLUEBERRY, &*bottom.leaf_node_value_for_key(&CHERRY, &pool).unwrap().unwrap());
     }
 
     #[test]
     fn test_release_leaf_node() {
+        use std::mem;
+        use allocator::SnapshotBlock::*;
         let mut buf = [0u8; 0x5000];
         let pool = Pool::new(&mut buf);
 
@@ -713,7 +3172,7 @@ mod tests {
             assert_eq!(1, n3.get_ref_count());
 
             // 'hello' should have 2 node refs and 'foo' should have 1 ref
-            assert_eq!((true, 1), n3.deref_as::<Node>().index_or_insertion_of(&HELLO, &pool));
+            assert_eq!((true, 1), n3.deref_as::<Node>().index_or_insertion_of(&HELLO, &pool).unwrap());
             assert_eq!(2, get_ref_count(&n2.deref_as::<Node>().keys[0], &pool));
             assert_eq!(2, get_ref_count(&n3.deref_as::<Node>().keys[1], &pool));
             assert_eq!(1, get_ref_count(&n3.deref_as::<Node>().keys[0], &pool));
@@ -724,28 +3183,147 @@ mod tests {
             assert_eq!(1, get_ref_count(&n3.deref_as::<Node>().children[0], &pool));
 
             // Now, we'll free the last node, and watch the ref counts go down
-            release_node(&mut n3.clone_to_persisted(), &pool);
+            let mut n3_persisted = n3.clone_to_persisted();
+            drop(n3);
+            release_node(&mut n3_persisted, &pool).unwrap();
             // 'hello' and 'world' should have 1 node ref left
             assert_eq!(1, get_ref_count(&n2.deref_as::<Node>().keys[0], &pool));
             assert_eq!(1, get_ref_count(&n2.deref_as::<Node>().children[0], &pool));
         }
         // n3 should be totally released now, as should 'foo' and 'bar'
         // The memory from 'foo' and 'bar' should have been reclaimed and merged
+        let node_size = mem::size_of::<Node>();
         assert_eq!(
-            "Pool { buffer_size: 20480, \
-                metadata: Metadata { lowest_known_free_index: 6672, next_id_tag: AtomicUsize(9) }, \
-                blocks: [\
-                    _B { start: 0, capacity: 3232, next: 3280, prev: 18446744073709551615, is_free: false }, \
-                    _B { start: 3280, capacity: 8, next: 3336, prev: 0, is_free: false }, \
-                    _B { start: 3336, capacity: 8, next: 3392, prev: 3280, is_free: false }, \
-                    _B { start: 3392, capacity: 3232, next: 6672, prev: 3336, is_free: false }, \
-                    _B { start: 6672, capacity: 9664, next: 16384, prev: 3392, is_free: true }\
-                    ] \
-                }",
-            format!("{:?}", &pool)
+            vec![
+                Live { id: 0, size: node_size, ref_count: 1 },
+                Live { id: 1, size: 5, ref_count: 1 },
+                Live { id: 2, size: 5, ref_count: 1 },
+                Live { id: 3, size: node_size, ref_count: 1 },
+                Free,
+            ],
+            pool.snapshot().blocks
         );
     }
 
+    #[test]
+    fn test_checked_value_for_key() {
+        let mut buf = [0u8; 0x5000];
+        let pool = Pool::new(&mut buf);
+        let stats = Stats::default();
+
+        let n_arc = pool.make_new::<Node>().unwrap();
+        let n = n_arc.deref_as_mut::<Node>();
+        n.init(0, Leaf);
+        n.set_checksummed(true);
+
+        let n = n.leaf_node_insert_non_full(1, &HELLO, &WORLD, &pool).unwrap();
+        let n = n.deref_as::<Node>().leaf_node_insert_non_full(2, &APPLE, &BANANA, &pool).unwrap();
+        let node = n.deref_as::<Node>();
+        assert!(node.checksummed());
+
+        let value = node.leaf_node_checked_value_for_key(&HELLO, &pool, &stats).unwrap().unwrap();
+        assert_eq!(*WORLD, &*value);
+        assert!(node.leaf_node_checked_value_for_key(&FOO, &pool, &stats).unwrap().is_none());
+        assert_eq!(1, stats.checksums_verified.load(Relaxed));
+
+        // Flip a byte of "world" behind the tree's back
+        value.deref_as_mut::<[u8; 5]>()[0] = b'W';
+        match node.leaf_node_checked_value_for_key(&HELLO, &pool, &stats) {
+            Err(LodestoneError::Corruption(_)) => (),
+            other => panic!("Corruption went unnoticed: {:?}", other.map(|o| o.is_some())),
+        }
+        assert_eq!(1, stats.checksums_failed.load(Relaxed));
+        // The other entry is still fine
+        assert!(node.leaf_node_checked_value_for_key(&APPLE, &pool, &stats).is_ok());
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut buf = vec![0u8; 0x5000];
+        let pool = Pool::new(&mut buf);
+
+        let n_arc = pool.make_new::<Node>().unwrap();
+        let n = n_arc.deref_as_mut::<Node>();
+        n.init(0, Leaf);
+        n.set_checksummed(true);
+        let n = n.leaf_node_insert_non_full(1, &HELLO, &WORLD, &pool).unwrap();
+        let n = n.deref_as::<Node>().leaf_node_insert_non_full(2, &APPLE, &BANANA, &pool).unwrap();
+        assert!(n.deref_as::<Node>().verify(&pool).is_ok());
+
+        // A value changed behind the tree's back
+        n.deref_as::<Node>().leaf_node_value_for_key(&APPLE, &pool).unwrap().unwrap()
+            .deref_as_mut::<[u8; 6]>()[0] = b'B';
+        match n.deref_as::<Node>().verify(&pool) {
+            Err(LodestoneError::Corruption(_)) => (),
+            other => panic!("Expected Corruption, got {:?}", other),
+        }
+
+        // Keys out of order
+        let node = n.deref_as_mut::<Node>();
+        node.set_checksummed(false);
+        node.keys.swap(0, 1);
+        match node.verify(&pool) {
+            Err(LodestoneError::StructureCorrupt(_)) => (),
+            other => panic!("Expected StructureCorrupt, got {:?}", other),
+        }
+        node.keys.swap(0, 1);
+        assert!(node.verify(&pool).is_ok());
+
+        // A leaf with more keys than values
+        node.set_num_keys(3);
+        assert!(node.verify(&pool).is_err());
+        node.set_num_keys(2);
+    }
+
+    #[test]
+    fn test_integrity_sampling() {
+        use std::sync::Arc;
+        use std::sync::atomic::AtomicUsize;
+        let mut buf = vec![0u8; 0x8000];
+        let mut tree = BTree::with_options(&mut buf, TreeOptions {
+            integrity_sample_one_in: 1,
+            ..TreeOptions::default()
+        });
+        let failures = Arc::new(AtomicUsize::new(0));
+        let seen = failures.clone();
+        tree.on_integrity_failure(move |_| { seen.fetch_add(1, Relaxed); });
+
+        let node_arc = tree.page_pool.make_new::<Node>().unwrap();
+        let node = node_arc.deref_as_mut::<Node>();
+        node.init(0, Leaf);
+        tree.sample_integrity(node);
+        assert_eq!(1, tree.stats().samples_verified.load(Relaxed));
+
+        node.set_num_keys(2);
+        tree.sample_integrity(node);
+        assert_eq!(1, tree.stats().samples_failed.load(Relaxed));
+        assert_eq!(1, failures.load(Relaxed));
+    }
+
+    #[test]
+    fn test_release_leaf_node_traces_references() {
+        let mut buf = [0u8; 0x5000];
+        let pool = Pool::new(&mut buf);
+
+        let target = pool.malloc(&BANANA).unwrap();
+        let target_reference = pool.make_reference(&target);
+        let value = target_reference.to_bytes();
+        drop(target);
+
+        let n_arc = pool.make_new::<Node>().unwrap();
+        n_arc.deref_as_mut::<Node>().init(0, Leaf);
+        let n2 = n_arc.deref_as::<Node>().leaf_node_insert_non_full(1, &HELLO, &value, &pool).unwrap();
+        assert_eq!(*BANANA, &*pool.resolve(&target_reference).unwrap());
+
+        let mut n2_persisted = n2.clone_to_persisted();
+        drop(n2);
+        release_node_traced(&mut n2_persisted, &pool, &|v: &[u8]| {
+            Reference::from_bytes(v).into_iter().collect()
+        }).unwrap();
+        // Releasing the leaf released the value, which released its target
+        assert!(pool.resolve(&target_reference).is_err());
+    }
+
     #[test]
     fn test_insert_remove() {
         let mut buf: [u8; 0x5000] = [0; 0x5000];
@@ -789,6 +3367,357 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_leaf_node_remove_where() {
+        let mut buf = vec![0u8; 0x8000];
+        let p = Pool::new(&mut buf);
+        let mut n_arc = p.make_new::<Node>().unwrap();
+        n_arc.deref_as_mut::<Node>().init(0, Leaf);
+        for &(k, v) in [(&APPLE[..], &FOO[..]), (&BANANA[..], &BAR[..]), (&BLUEBERRY[..], &FOO[..]), (&CHERRY[..], &FOO[..])].iter() {
+            n_arc = n_arc.deref_as::<Node>().leaf_node_insert_non_full(1, k, v, &p).unwrap();
+        }
+        let is_foo = |_: &[u8], v: &[u8]| v == &FOO[..];
+
+        // Stops after 1, and says where to pick up
+        let r = n_arc.deref_as::<Node>().leaf_node_remove_where(2, &BANANA, &is_foo, 1, &p).unwrap();
+        assert_eq!(1, r.removed);
+        assert_eq!(&CHERRY[..], &*r.resume_from.unwrap());
+        let n2_arc = r.node.unwrap();
+        assert_eq!(
+            "Leaf { tx_id: 2, keys: \"apple, banana, cherry\", children: \"foo, bar, foo\" }",
+            format!("{:?}", DebuggableNode {
+                node: n2_arc.deref_as::<Node>(),
+                pool: &p,
+            })
+        );
+
+        let r = n2_arc.deref_as::<Node>().leaf_node_remove_where(3, &CHERRY, &is_foo, 10, &p).unwrap();
+        assert_eq!(1, r.removed);
+        assert!(r.resume_from.is_none());
+        assert_eq!(
+            "Leaf { tx_id: 3, keys: \"apple, banana\", children: \"foo, bar\" }",
+            format!("{:?}", DebuggableNode {
+                node: r.node.unwrap().deref_as::<Node>(),
+                pool: &p,
+            })
+        );
+
+        // Nothing left to remove past banana
+        let r = n2_arc.deref_as::<Node>().leaf_node_remove_where(3, &BANANA, &|_: &[u8], _: &[u8]| false, 10, &p).unwrap();
+        assert!(r.node.is_none());
+        assert_eq!(0, r.removed);
+    }
+
+    #[test]
+    fn test_fences() {
+        let mut buf = vec![0u8; 0x10000];
+        let pool = Pool::new(&mut buf);
+        let long_key = [b'p'; 30];
+
+        let left = pool.make_new::<Node>().unwrap();
+        left.deref_as_mut::<Node>().init(0, Leaf);
+        let left = left.deref_as::<Node>().leaf_node_insert_non_full(1, &BANANA, &BAR, &pool).unwrap();
+        let left = left.deref_as::<Node>().leaf_node_insert_non_full(1, &CHERRY, &BAR, &pool).unwrap();
+        let right = pool.make_new::<Node>().unwrap();
+        right.deref_as_mut::<Node>().init(0, Leaf);
+        let right = right.deref_as::<Node>().leaf_node_insert_non_full(1, &HELLO, &BAR, &pool).unwrap();
+        let right = right.deref_as::<Node>().leaf_node_insert_non_full(1, &long_key, &BAR, &pool).unwrap();
+
+        let internal_arc = pool.make_new::<Node>().unwrap();
+        {
+            let internal = internal_arc.deref_as_mut::<Node>();
+            internal.init(0, Internal);
+            internal.keys[0] = pool.malloc(&HELLO).unwrap().clone_to_persisted();
+            internal.children[0] = left.clone_to_persisted();
+            internal.children[1] = right.clone_to_persisted();
+            internal.set_num_keys(1);
+            internal.set_num_children(2);
+            // Without fences nothing can be ruled out
+            assert!(internal.may_overlap(b"x", b"y", &pool));
+            internal.refresh_fences(&pool).unwrap();
+            assert!(internal.verify(&pool).is_ok());
+        }
+        let internal = internal_arc.deref_as::<Node>();
+        assert!(internal.may_overlap(b"a", b"c", &pool));
+        assert!(!internal.may_overlap(b"a", b"banana", &pool));
+        // The max fence is truncated, so anything starting with its prefix might be there
+        assert!(internal.may_overlap(&[b'p'; 40], b"q", &pool));
+        assert!(!internal.may_overlap(b"q", b"z", &pool));
+        assert!(!left.deref_as::<Node>().may_overlap(b"d", b"e", &pool));
+        let persisted = internal_arc.clone_to_persisted();
+        assert_eq!(2, count_range(&persisted, &pool, b"a", b"d").unwrap());
+        assert_eq!(3, count_range(&persisted, &pool, b"c", b"q").unwrap());
+        assert_eq!(0, count_range(&persisted, &pool, b"q", b"z").unwrap());
+        assert!(seek(&persisted, &pool, b"q").unwrap().is_none());
+
+        // Fences are kept up to date as children are replaced
+        let newer = left.deref_as::<Node>().leaf_node_insert_non_full(2, &APPLE, &BAR, &pool).unwrap();
+        let updated = internal.internal_node_set(2, 0, &newer, &pool).unwrap();
+        assert!(updated.deref_as::<Node>().may_overlap(b"a", b"b", &pool));
+        assert!(updated.deref_as::<Node>().verify(&pool).is_ok());
+
+        // Stale fences are caught by verify
+        internal_arc.deref_as_mut::<Node>().children[0] = newer.clone_to_persisted();
+        match internal.verify(&pool) {
+            Err(LodestoneError::StructureCorrupt(_)) => (),
+            other => panic!("Expected StructureCorrupt, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_sweep_tree() {
+        use std::mem;
+        let mut buf = vec![0u8; 0x10000];
+        let pool = Pool::new(&mut buf);
+
+        let leaf = pool.make_new::<Node>().unwrap();
+        leaf.deref_as_mut::<Node>().init(0, Leaf);
+        let leaf = leaf.deref_as::<Node>().leaf_node_insert_non_full(1, &APPLE, &BANANA, &pool).unwrap();
+        let leaf = leaf.deref_as::<Node>().leaf_node_insert_non_full(1, &HELLO, &WORLD, &pool).unwrap();
+        let internal_arc = pool.make_new::<Node>().unwrap();
+        {
+            let internal = internal_arc.deref_as_mut::<Node>();
+            internal.init(1, Internal);
+            internal.children[0] = leaf.clone_to_persisted();
+            internal.set_num_children(1);
+        }
+        let root = internal_arc.clone_to_persisted();
+        let picture = snapshot(&root, &pool).unwrap();
+        assert_eq!(TreeSnapshot {
+            leaf: false,
+            keys: vec![],
+            fill: 1,
+            values: vec![],
+            children: vec![TreeSnapshot {
+                leaf: true,
+                keys: vec![APPLE.clone(), HELLO.clone()],
+                fill: 2,
+                values: vec![BANANA.clone(), WORLD.clone()],
+                children: vec![],
+            }],
+        }, picture);
+        let roots = tree_references(&root, &pool).unwrap();
+        // internal, leaf, 2 keys and 2 values
+        assert_eq!(6, roots.len());
+
+        // Everything else, like the leaf the tree started from, is garbage
+        let before = pool.usage().allocated;
+        mem::forget(leaf);
+        mem::forget(internal_arc);
+        let report = pool.sweep_unreachable(&roots, |_| Vec::new());
+        assert_eq!(6, report.reachable_blocks);
+        assert_eq!(before - report.freed_bytes, pool.usage().allocated);
+        assert!(report.freed_blocks > 0);
+        assert!(pool.clone_persisted_to_arc(&root).unwrap().deref_as::<Node>().verify(&pool).is_ok());
+        // Nothing the tree needs was touched
+        assert_eq!(picture, snapshot(&root, &pool).unwrap());
+        mem::forget(root);
+    }
+
+    #[test]
+    fn test_verify_quick() {
+        use std::mem;
+        let mut buf = vec![0u8; 0x10000];
+        let pool = Pool::new(&mut buf);
+
+        let mut leaves = Vec::new();
+        for _ in 0..3 {
+            let leaf = pool.make_new::<Node>().unwrap();
+            leaf.deref_as_mut::<Node>().init(0, Leaf);
+            let leaf = leaf.deref_as::<Node>().leaf_node_insert_non_full(1, &APPLE, &BANANA, &pool).unwrap();
+            leaves.push(leaf.deref_as::<Node>().leaf_node_insert_non_full(1, &HELLO, &WORLD, &pool).unwrap());
+        }
+        let internal_arc = pool.make_new::<Node>().unwrap();
+        {
+            let internal = internal_arc.deref_as_mut::<Node>();
+            internal.init(1, Internal);
+            for (i, leaf) in leaves.iter().enumerate() {
+                internal.children[i] = leaf.clone_to_persisted();
+            }
+            internal.set_num_children(3);
+            let keys = leaves[0].deref_as::<Node>();
+            internal.keys[0] = keys.keys[0].clone(&pool).unwrap();
+            internal.keys[1] = keys.keys[1].clone(&pool).unwrap();
+            internal.set_num_keys(2);
+        }
+        let root = internal_arc.clone_to_persisted();
+
+        let report = verify_quick(&root, &pool, 2, 10, 7).unwrap();
+        assert_eq!(QuickVerifyReport {
+            levels_verified: 2,
+            nodes_verified: 4,
+            leaves_sampled: 0,
+            leaves_estimated: 0,
+            complete: true,
+        }, report);
+        assert_eq!(1.0, report.leaf_coverage());
+
+        // Only the root level, then sampled paths
+        let report = verify_quick(&root, &pool, 1, 2, 7).unwrap();
+        assert_eq!((1, 3, 2, 3), (report.levels_verified, report.nodes_verified, report.leaves_sampled, report.leaves_estimated));
+        assert!(!report.complete);
+        assert!(report.leaf_coverage() > 0.5);
+
+        // A bad leaf is caught by the full levels, and by enough samples
+        leaves[1].deref_as_mut::<Node>().keys.swap(0, 1);
+        assert!(verify_quick(&root, &pool, 2, 0, 7).is_err());
+        assert!(verify_quick(&root, &pool, 1, 30, 7).is_err());
+        assert!(verify_quick(&root, &pool, 0, 0, 7).unwrap().leaf_coverage() == 0.0);
+        mem::forget(root);
+    }
+
+    #[test]
+    fn test_bulk_build() {
+        let mut buf = vec![0u8; 0x100000];
+        let pool = Pool::new(&mut buf);
+        let pairs = (0..250).map(|i| Ok((format!("key{:03}", i).into_bytes(), vec![i as u8])));
+        let root = bulk_build(pairs, 1, 50, &pool).unwrap().unwrap();
+        let persisted = root.clone_to_persisted();
+        let picture = snapshot(&persisted, &pool).unwrap();
+        // 5 full leaves under one root, split at the last key of each leaf
+        assert!(!picture.leaf);
+        assert_eq!(5, picture.children.len());
+        assert!(picture.children.iter().all(|c| c.leaf && c.keys.len() == 50));
+        assert_eq!(b"key049".to_vec(), picture.keys[0]);
+        assert_eq!(b"key050".to_vec(), picture.children[1].keys[0]);
+        assert!(verify_quick(&persisted, &pool, 2, 0, 1).unwrap().complete);
+        assert_eq!(vec![249u8], picture.children[4].values[49]);
+
+        // Levels are spread evenly, 101 entries in 3 leaves of 34, 34 and 33
+        let pairs = (0..101).map(|i| Ok((vec![i as u8], vec![])));
+        let root = bulk_build(pairs, 1, 50, &pool).unwrap().unwrap();
+        let picture = snapshot(&root.clone_to_persisted(), &pool).unwrap();
+        assert_eq!(vec![34, 34, 33], picture.children.iter().map(|c| c.keys.len()).collect::<Vec<_>>());
+
+        let unsorted = vec![Ok((b"b".to_vec(), vec![])), Ok((b"a".to_vec(), vec![]))];
+        assert!(bulk_build(unsorted, 1, 50, &pool).is_err());
+        assert!(bulk_build(Vec::new(), 1, 50, &pool).unwrap().is_none());
+        assert!(bulk_build(Vec::new(), 1, 1, &pool).is_err());
+    }
+
+    #[test]
+    fn test_digest_range() {
+        let mut buf = vec![0u8; 0x100000];
+        let pool = Pool::new(&mut buf);
+        let pairs = |changed: Option<usize>| (0..200).map(move |i| {
+            let value = if Some(i) == changed { vec![0xff] } else { vec![i as u8] };
+            Ok((format!("key{:03}", i).into_bytes(), value))
+        });
+        // Same entries split into different nodes
+        let a = bulk_build(pairs(None), 1, 20, &pool).unwrap().unwrap().clone_to_persisted();
+        let b = bulk_build(pairs(None), 1, 30, &pool).unwrap().unwrap().clone_to_persisted();
+        // One value differs
+        let c = bulk_build(pairs(Some(150)), 1, 20, &pool).unwrap().unwrap().clone_to_persisted();
+        let mut cache = DigestCache::new();
+
+        let whole = subtree_digest(&a, &pool, &mut cache).unwrap();
+        assert_eq!(whole, subtree_digest(&b, &pool, &mut cache).unwrap());
+        assert!(whole != subtree_digest(&c, &pool, &mut cache).unwrap());
+        assert_eq!(whole, digest_range(&a, &pool, b"", None, &mut cache).unwrap());
+
+        // Ranges split the whole, at node boundaries or not
+        for split in [&b"key000"[..], b"key057", b"key060", b"key1", b"key199", b"zzz"].iter() {
+            let low = digest_range(&a, &pool, b"", Some(split), &mut cache).unwrap();
+            let high = digest_range(&b, &pool, split, None, &mut cache).unwrap();
+            assert_eq!(whole, low.wrapping_add(high));
+        }
+
+        // Narrowing in on the difference
+        let range = |tree: &PersistedArcByteSlice, start: &[u8], end: &[u8], cache: &mut DigestCache|
+            digest_range(tree, &pool, start, Some(end), cache).unwrap();
+        assert_eq!(range(&a, b"key000", b"key100", &mut cache), range(&c, b"key000", b"key100", &mut cache));
+        assert!(range(&a, b"key100", b"key200", &mut cache) != range(&c, b"key100", b"key200", &mut cache));
+        assert!(range(&a, b"key150", b"key151", &mut cache) != range(&c, b"key150", b"key151", &mut cache));
+        assert_eq!(range(&a, b"key151", b"key200", &mut cache), range(&c, b"key151", b"key200", &mut cache));
+        assert_eq!(0, range(&a, b"key5", b"key6", &mut cache));
+        // Roots and leaves of three trees, no more
+        assert_eq!(3 + 10 + 7 + 10, cache.len());
+    }
+
+    #[test]
+    fn test_packed_header() {
+        let mut buf = vec![0u8; 0x2000];
+        let pool = Pool::new(&mut buf);
+        let arc = pool.make_new::<Node>().unwrap();
+        let node = arc.deref_as_mut::<Node>();
+        node.init(7, Internal);
+        node.set_num_children(B);
+        node.set_num_keys(B - 1);
+        node.set_fenced(true);
+        assert_eq!((Internal, false, true), (node.node_type(), node.checksummed(), node.fenced()));
+        assert_eq!((B - 1, B), (node.num_keys(), node.num_children()));
+        assert_eq!(NODE_LAYOUT_VERSION, node.layout_version());
+
+        // Fields don't bleed into each other
+        node.set_num_keys(0);
+        node.set_node_type(Leaf);
+        node.set_checksummed(true);
+        assert_eq!((Leaf, true, true), (node.node_type(), node.checksummed(), node.fenced()));
+        assert_eq!((0, B, 7), (node.num_keys(), node.num_children(), node.tx_id));
+
+        // A node from another layout isn't read as this one
+        node.set_num_children(0);
+        assert!(node.check_counts().is_ok());
+        node.header ^= 1 << HEADER_VERSION_SHIFT;
+        assert!(node.check_counts().is_err());
+    }
+
+    #[test]
+    fn test_node_cache() {
+        use super::super::node_cache::*;
+        let mut buf = vec![0u8; 0x8000];
+        let pool = Pool::new(&mut buf);
+
+        let children: Vec<ArcByteSlice> = (0..3).map(|_| {
+            let c = pool.make_new::<Node>().unwrap();
+            c.deref_as_mut::<Node>().init(0, Leaf);
+            c
+        }).collect();
+        let internal_arc = pool.make_new::<Node>().unwrap();
+        {
+            let internal = internal_arc.deref_as_mut::<Node>();
+            internal.init(0, Internal);
+            internal.keys[0] = pool.malloc(&BANANA).unwrap().clone_to_persisted();
+            internal.keys[1] = pool.malloc(&HELLO).unwrap().clone_to_persisted();
+            for (i, c) in children.iter().enumerate() {
+                internal.children[i] = c.clone_to_persisted();
+            }
+            internal.set_num_keys(2);
+            internal.set_num_children(3);
+        }
+        let internal = internal_arc.deref_as::<Node>();
+        let persisted = Reference::from_persisted(&internal_arc.clone_to_persisted());
+
+        // As a descent does it: the cached copy, or decode and cache it
+        let get_or_decode = |cache: &mut NodeCache, node: &Reference| -> Result<_, LodestoneError> {
+            if let Some(decoded) = cache.get(node) {
+                return Ok(decoded);
+            }
+            let arc = try!(pool.resolve(node));
+            let decoded = try!(arc.deref_as::<Node>().internal_node_decode(&pool));
+            Ok(cache.insert(node, decoded))
+        };
+        let mut cache = NodeCache::new(1000);
+        let decoded = get_or_decode(&mut cache, &persisted).unwrap();
+        assert_eq!(vec![BANANA.clone(), HELLO.clone()], decoded.keys);
+        // Same child as the node itself would pick
+        for key in [&APPLE[..], &BANANA[..], &CHERRY[..], &HELLO[..], &WORLD[..]].iter() {
+            let (_, i) = internal.index_or_insertion_of(key, &pool).unwrap();
+            assert_eq!(&Reference::from_persisted(&internal.children[i]), decoded.child_for(key).unwrap());
+        }
+        get_or_decode(&mut cache, &persisted).unwrap();
+        assert_eq!(CacheStats { hits: 1, misses: 1, evictions: 0 }, *cache.stats());
+
+        // Too small to hold anything, so each decode evicts the last
+        let mut tiny = NodeCache::new(10);
+        get_or_decode(&mut tiny, &persisted).unwrap();
+        get_or_decode(&mut tiny, &persisted).unwrap();
+        assert_eq!(CacheStats { hits: 0, misses: 2, evictions: 2 }, *tiny.stats());
+
+        // Leaves aren't cached
+        assert!(get_or_decode(&mut cache, &Reference::from_persisted(&children[0].clone_to_persisted())).is_err());
+    }
+
     #[test]
     fn test_insertion_ordering() {
         let mut buf = [0u8; 0x7000];
@@ -800,19 +3729,19 @@ mod tests {
 
         let n = n.leaf_node_insert_non_full(1, &BANANA, &BANANA, &pool).unwrap();
         let n = n.deref_as::<Node>().leaf_node_insert_non_full(2, &APPLE, &APPLE, &pool).unwrap();
-        assert_eq!((true, 1), n.deref_as::<Node>().index_or_insertion_of(&BANANA, &pool));
-        assert_eq!((true, 0), n.deref_as::<Node>().index_or_insertion_of(&APPLE, &pool));
+        assert_eq!((true, 1), n.deref_as::<Node>().index_or_insertion_of(&BANANA, &pool).unwrap());
+        assert_eq!((true, 0), n.deref_as::<Node>().index_or_insertion_of(&APPLE, &pool).unwrap());
 
         let n = n.deref_as::<Node>().leaf_node_insert_non_full(3, &CHERRY, &CHERRY, &pool).unwrap();
-        assert_eq!((true, 1), n.deref_as::<Node>().index_or_insertion_of(&BANANA, &pool));
-        assert_eq!((true, 0), n.deref_as::<Node>().index_or_insertion_of(&APPLE, &pool));
-        assert_eq!((true, 2), n.deref_as::<Node>().index_or_insertion_of(&CHERRY, &pool));
+        assert_eq!((true, 1), n.deref_as::<Node>().index_or_insertion_of(&BANANA, &pool).unwrap());
+        assert_eq!((true, 0), n.deref_as::<Node>().index_or_insertion_of(&APPLE, &pool).unwrap());
+        assert_eq!((true, 2), n.deref_as::<Node>().index_or_insertion_of(&CHERRY, &pool).unwrap());
 
         let n = n.deref_as::<Node>().leaf_node_insert_non_full(4, &BLUEBERRY, &BLUEBERRY, &pool).unwrap();
-        assert_eq!((true, 1), n.deref_as::<Node>().index_or_insertion_of(&BANANA, &pool));
-        assert_eq!((true, 0), n.deref_as::<Node>().index_or_insertion_of(&APPLE, &pool));
-        assert_eq!((true, 3), n.deref_as::<Node>().index_or_insertion_of(&CHERRY, &pool));
-        assert_eq!((true, 2), n.deref_as::<Node>().index_or_insertion_of(&BLUEBERRY, &pool));
+        assert_eq!((true, 1), n.deref_as::<Node>().index_or_insertion_of(&BANANA, &pool).unwrap());
+        assert_eq!((true, 0), n.deref_as::<Node>().index_or_insertion_of(&APPLE, &pool).unwrap());
+        assert_eq!((true, 3), n.deref_as::<Node>().index_or_insertion_of(&CHERRY, &pool).unwrap());
+        assert_eq!((true, 2), n.deref_as::<Node>().index_or_insertion_of(&BLUEBERRY, &pool).unwrap());
 
         assert_eq!(
             "Leaf { tx_id: 4, \
@@ -833,4 +3762,125 @@ mod tests {
         println!("CHECK {:?} < {:?}?", mem::size_of::<Node>(), *FIRST_OR_SINGLE_CONTENT_SIZE);
         assert!(mem::size_of::<Node>() < *FIRST_OR_SINGLE_CONTENT_SIZE);
     }
+
+    /// Copy the tree under block into nodes laid out for capacity, as a
+    /// build with that B would have written it. The copy takes its own
+    /// references to the keys and values.
+    fn rewrite_with_capacity<'p>(block: &ArcByteSlice, capacity: usize, pool: &'p Pool) -> ArcByteSlice<'p> {
+        let node = block.deref_as::<Node>();
+        let slot = mem::size_of::<PersistedArcByteSlice>();
+        let mut bytes = vec![0u8; node_size(capacity)];
+        bytes[..NODE_PREFIX_SIZE].copy_from_slice(&block[..NODE_PREFIX_SIZE]);
+        let at = |bytes: &mut Vec<u8>, offset: usize| unsafe { bytes.as_mut_ptr().offset(offset as isize) };
+        for i in 0..node.num_keys() {
+            let key = node.keys[i].clone(pool).unwrap();
+            unsafe {
+                ptr::write_unaligned(at(&mut bytes, NODE_PREFIX_SIZE + i * slot) as *mut PersistedArcByteSlice, key);
+                ptr::write_unaligned(at(&mut bytes, NODE_PREFIX_SIZE + 2 * capacity * slot + 4 * i) as *mut u32,
+                    node.checksums[i]);
+            }
+        }
+        for i in 0..node.num_children() {
+            let child = if node.is_leaf() {
+                node.children[i].clone(pool).unwrap()
+            } else {
+                let child = node.children[i].clone_to_arc_byte_slice(pool).unwrap();
+                rewrite_with_capacity(&child, capacity, pool).clone_to_persisted()
+            };
+            unsafe {
+                ptr::write_unaligned(at(&mut bytes, NODE_PREFIX_SIZE + (capacity + i) * slot) as *mut PersistedArcByteSlice,
+                    child);
+            }
+        }
+        pool.malloc(&bytes).unwrap()
+    }
+
+    #[test]
+    fn test_migrate_capacity() {
+        assert_eq!(Some(B), node_capacity(mem::size_of::<Node>()));
+        assert_eq!(Some(7), node_capacity(node_size(7)));
+        assert_eq!(None, node_capacity(NODE_PREFIX_SIZE + 1));
+
+        let mut buf = vec![0u8; 0x100000];
+        let pool = Pool::new(&mut buf);
+        let empty = pool.lifetime_stats().live_blocks;
+        let pairs = (0..300u32).map(|i| Ok((format!("key{:03}", i).into_bytes(), vec![i as u8; 3])));
+        let current = bulk_build(pairs, 1, 20, &pool).unwrap().unwrap();
+        assert!(migrate_capacity(&current, &pool, 2).unwrap().is_none());
+        let live = pool.lifetime_stats().live_blocks;
+
+        // As written by a build with B = 30, which this one has to grow
+        let old = rewrite_with_capacity(&current, 30, &pool);
+        let mut current_root = current.clone_to_persisted();
+        drop(current);
+        release_node(&mut current_root, &pool).unwrap();
+        assert_eq!(live, pool.lifetime_stats().live_blocks);
+
+        let migration = migrate_capacity(&old, &pool, 2).unwrap().unwrap();
+        let root = migration.root.clone();
+        // Both trees are whole until the migration finishes
+        assert!(pool.lifetime_stats().live_blocks > live);
+        migration.finish(&pool).unwrap();
+        drop(old);
+        assert_eq!(live, pool.lifetime_stats().live_blocks);
+
+        let persisted = root.clone_to_persisted();
+        assert!(verify_quick(&persisted, &pool, 3, 0, 1).unwrap().complete);
+        assert_eq!(2, root.deref_as::<Node>().tx_id);
+        for i in 0..300u32 {
+            let value = find_value(&root, &pool, format!("key{:03}", i).as_bytes()).unwrap().unwrap();
+            assert_eq!(&[i as u8; 3][..], &value[..]);
+        }
+        let mut persisted = persisted;
+        drop(root);
+        release_node(&mut persisted, &pool).unwrap();
+        assert_eq!(empty, pool.lifetime_stats().live_blocks);
+
+        // A block of no node size isn't a node
+        let junk = pool.malloc(&[0u8; 10]).unwrap();
+        assert!(migrate_capacity(&junk, &pool, 2).is_err());
+    }
+
+    #[test]
+    fn test_open_tree_written_with_another_b() {
+        let mut buf = vec![0u8; 0x100000];
+        let live = {
+            let tree = BTree::new(&mut buf);
+            tree.describe().unwrap();
+            for i in 0..300 {
+                tree.insert(format!("key {:03}", i).as_bytes(), b"value").unwrap();
+            }
+            let live = tree.page_pool.lifetime_stats().live_blocks;
+            // As written by a build with B = 120, which this one can
+            // read as long as no node is fuller than B
+            let root = tree.root().unwrap().unwrap().clone_to_arc_byte_slice(&tree.page_pool).unwrap();
+            let old = rewrite_with_capacity(&root, 120, &tree.page_pool);
+            drop(root);
+            tree.commit_root(tree.len(), None, |_, _, _| Ok(old)).unwrap();
+            TreeDescriptor::record_capacity(&tree.page_pool, 120).unwrap();
+            live
+        };
+        match BTree::open(&mut buf, PoolDefaults::default()) {
+            Err(LodestoneError::Format(m)) => {
+                assert_eq!("node capacity", m.field);
+                assert_eq!((B as u64, 120), (m.expected, m.found));
+            },
+            _ => panic!("Opened a tree written with another B without migrating it"),
+        }
+        let migrating = PoolDefaults { migrate_capacity: true, ..PoolDefaults::default() };
+        let tx_id = {
+            let tree = BTree::open(&mut buf, migrating).unwrap();
+            assert_eq!(live, tree.page_pool.lifetime_stats().live_blocks);
+            assert_eq!(Some(B), TreeDescriptor::load(&tree.page_pool).unwrap().unwrap().node_capacity());
+            assert_eq!(300, tree.len());
+            tree.verify_counts().unwrap();
+            assert_eq!(&b"value"[..], &tree.get(b"key 123").unwrap().unwrap()[..]);
+            tree.tx_id.load(Relaxed)
+        };
+        // Only migrated once
+        let tree = BTree::open(&mut buf, PoolDefaults::default()).unwrap();
+        assert_eq!(tx_id, tree.tx_id.load(Relaxed));
+        tree.insert(b"key 300", b"value").unwrap();
+        assert_eq!(301, tree.len());
+    }
 }
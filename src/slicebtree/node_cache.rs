@@ -0,0 +1,123 @@
+/// Internal nodes are visited by every descent, and reading one means
+/// taking a reference on each key block it compares against. The cache
+/// keeps a decoded copy (key bytes and child references) on the heap,
+/// keyed by the reference to the node block (its index and id tag, or
+/// its block table slot and generation). Copy-on-Write never modifies a
+/// node in place, and a freed block or slot gets a new id tag or
+/// generation when it's reused, so entries never need invalidating:
+/// stale ones just stop being asked for and age out.
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use allocator::*;
+
+pub const DEFAULT_NODE_CACHE_BYTES: usize = 1 << 20;
+
+pub struct DecodedNode {
+    pub keys: Vec<Vec<u8>>,
+    pub children: Vec<Reference>,
+}
+
+impl DecodedNode {
+    /// The child that would hold key, matching Node::index_or_insertion_of.
+    /// None if the node had too few children for its keys.
+    pub fn child_for(&self, key: &[u8]) -> Option<&Reference> {
+        let i = match self.keys.binary_search_by(|k| (&k[..]).cmp(key)) {
+            Ok(i) => i,
+            Err(i) => i,
+        };
+        self.children.get(i)
+    }
+
+    /// Rough heap footprint, used to enforce the cache's size limit
+    fn size(&self) -> usize {
+        self.keys.iter().map(|k| k.len()).sum::<usize>() + self.children.len() * REFERENCE_SIZE
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    pub evictions: usize,
+}
+
+struct Entry {
+    node: Arc<DecodedNode>,
+    last_used: u64,
+}
+
+pub struct NodeCache {
+    max_bytes: usize,
+    bytes: usize,
+    clock: u64,
+    entries: HashMap<(usize, usize), Entry>,
+    // last_used -> entry key, oldest first
+    recency: BTreeMap<u64, (usize, usize)>,
+    stats: CacheStats,
+}
+
+impl NodeCache {
+    pub fn new(max_bytes: usize) -> NodeCache {
+        NodeCache {
+            max_bytes: max_bytes,
+            bytes: 0,
+            clock: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> &CacheStats {
+        &self.stats
+    }
+
+    /// The cached copy of the node, if there is one
+    pub fn get(&mut self, node: &Reference) -> Option<Arc<DecodedNode>> {
+        let key = (node.arc_inner_index(), node.generation());
+        self.clock += 1;
+        match self.entries.get_mut(&key) {
+            Some(entry) => {
+                self.stats.hits += 1;
+                self.recency.remove(&entry.last_used);
+                self.recency.insert(self.clock, key);
+                entry.last_used = self.clock;
+                Some(entry.node.clone())
+            },
+            None => {
+                self.stats.misses += 1;
+                None
+            },
+        }
+    }
+
+    /// Cache the decoded copy of the node, see Node::internal_node_decode
+    pub fn insert(&mut self, node: &Reference, decoded: DecodedNode) -> Arc<DecodedNode> {
+        let key = (node.arc_inner_index(), node.generation());
+        let decoded = Arc::new(decoded);
+        self.bytes += decoded.size();
+        if let Some(old) = self.entries.insert(key, Entry { node: decoded.clone(), last_used: self.clock }) {
+            self.recency.remove(&old.last_used);
+            self.bytes -= old.node.size();
+        }
+        self.recency.insert(self.clock, key);
+        self.evict();
+        decoded
+    }
+
+    /// Drop the least recently used entries until we're under the limit
+    fn evict(&mut self) {
+        while self.bytes > self.max_bytes {
+            let oldest = match self.recency.iter().next() {
+                Some((&tick, &key)) => (tick, key),
+                None => break,
+            };
+            self.recency.remove(&oldest.0);
+            if let Some(entry) = self.entries.remove(&oldest.1) {
+                self.bytes -= entry.node.size();
+                self.stats.evictions += 1;
+            }
+        }
+    }
+}
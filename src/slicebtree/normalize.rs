@@ -0,0 +1,32 @@
+/// Keys can be normalized before they're stored or looked up, giving
+/// case-insensitive or otherwise canonical keyspaces without every caller
+/// having to normalize the same way. Normalizers return the key as is
+/// when it's already normal, so the common case doesn't allocate.
+use std::borrow::Cow;
+
+pub type KeyNormalizer = Box<Fn(&[u8]) -> Cow<[u8]> + Send>;
+
+/// ASCII letters compare without regard to case. Other bytes, including
+/// non-ASCII UTF-8, are left alone.
+pub fn ascii_case_insensitive<'a>(key: &'a [u8]) -> Cow<'a, [u8]> {
+    if key.iter().any(|b| b.is_ascii_uppercase()) {
+        Cow::Owned(key.to_ascii_lowercase())
+    } else {
+        Cow::Borrowed(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+
+    #[test]
+    fn test_ascii_case_insensitive() {
+        assert_eq!(b"hello world".to_vec(), ascii_case_insensitive(b"Hello WORLD").into_owned());
+        match ascii_case_insensitive(b"already lower \xc3\x89") {
+            Cow::Borrowed(k) => assert_eq!(b"already lower \xc3\x89", k),
+            Cow::Owned(_) => panic!("Normal keys shouldn't be copied"),
+        }
+    }
+}
@@ -0,0 +1,83 @@
+/// Update rules for values holding 8 byte big-endian integers.
+/// Each one has the shape expected by Node::leaf_node_update: given
+/// the current value (if any) it returns the value to store, or None
+/// to leave the entry alone.
+use LodestoneError;
+
+pub const NUMERIC_SIZE: usize = 8;
+
+pub fn encode_u64(n: u64) -> Vec<u8> {
+    (0..NUMERIC_SIZE).rev().map(|i| (n >> (i * 8)) as u8).collect()
+}
+
+pub fn decode_u64(bytes: &[u8]) -> Result<u64, LodestoneError> {
+    if bytes.len() != NUMERIC_SIZE {
+        return Err(LodestoneError::UserError("Value is not an 8 byte integer"));
+    }
+    Ok(bytes.iter().fold(0u64, |n, b| n << 8 | *b as u64))
+}
+
+/// Store candidate if there is no value yet or it is greater than the current one
+pub fn if_greater(current: Option<&[u8]>, candidate: u64) -> Result<Option<Vec<u8>>, LodestoneError> {
+    match current {
+        Some(bytes) if try!(decode_u64(bytes)) >= candidate => Ok(None),
+        _ => Ok(Some(encode_u64(candidate))),
+    }
+}
+
+/// Store candidate if there is no value yet or it is less than the current one
+pub fn if_less(current: Option<&[u8]>, candidate: u64) -> Result<Option<Vec<u8>>, LodestoneError> {
+    match current {
+        Some(bytes) if try!(decode_u64(bytes)) <= candidate => Ok(None),
+        _ => Ok(Some(encode_u64(candidate))),
+    }
+}
+
+/// Add delta to the current value, treating a missing value as 0.
+/// Over or underflowing the counter is an error.
+pub fn add(current: Option<&[u8]>, delta: i64) -> Result<Option<Vec<u8>>, LodestoneError> {
+    let n = match current {
+        Some(bytes) => try!(decode_u64(bytes)),
+        None => 0,
+    };
+    let result = if delta >= 0 {
+        n.checked_add(delta as u64)
+    } else {
+        n.checked_sub(delta.wrapping_neg() as u64)
+    };
+    match result {
+        Some(r) => Ok(Some(encode_u64(r))),
+        None => Err(LodestoneError::UserError("Counter overflowed")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encoding_orders_like_integers() {
+        assert_eq!(vec![0, 0, 0, 0, 0, 0, 1, 2], encode_u64(0x102));
+        assert_eq!(0x102, decode_u64(&encode_u64(0x102)).unwrap());
+        assert!(encode_u64(255) < encode_u64(256));
+        assert!(decode_u64(&[1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn test_conditional_updates() {
+        let five = encode_u64(5);
+        assert_eq!(Some(encode_u64(6)), if_greater(Some(&five), 6).unwrap());
+        assert_eq!(None, if_greater(Some(&five), 5).unwrap());
+        assert_eq!(Some(encode_u64(1)), if_greater(None, 1).unwrap());
+
+        assert_eq!(Some(encode_u64(4)), if_less(Some(&five), 4).unwrap());
+        assert_eq!(None, if_less(Some(&five), 9).unwrap());
+
+        assert_eq!(Some(encode_u64(8)), add(Some(&five), 3).unwrap());
+        assert_eq!(Some(encode_u64(2)), add(Some(&five), -3).unwrap());
+        assert_eq!(Some(encode_u64(3)), add(None, 3).unwrap());
+        assert!(add(Some(&five), -6).is_err());
+        assert!(add(Some(&encode_u64(!0)), 1).is_err());
+        assert!(add(Some(b"nope"), 1).is_err());
+    }
+}
@@ -0,0 +1,137 @@
+/// Configuration is layered: defaults for the whole pool, overridden by
+/// the options a tree was created with, overridden by the options passed
+/// to a single operation. A None at any layer falls through to the one below.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Durability {
+    /// Visible to readers as soon as the write returns,
+    /// but may be lost if the process dies
+    Buffered,
+    /// Flushed to the backing storage before the write returns
+    Synced,
+}
+
+/// The bottom layer, shared by every tree in a pool
+#[derive(Debug, Clone)]
+pub struct PoolDefaults {
+    pub verify_checksums: bool,
+    pub fill_cache: bool,
+    pub durability: Durability,
+    /// Rewrite a tree written with another B when it's opened, in one
+    /// commit. Otherwise opening it fails with a node capacity mismatch.
+    pub migrate_capacity: bool,
+}
+
+impl Default for PoolDefaults {
+    fn default() -> PoolDefaults {
+        PoolDefaults {
+            verify_checksums: true,
+            fill_cache: true,
+            durability: Durability::Buffered,
+            migrate_capacity: false,
+        }
+    }
+}
+
+/// Knobs that are set when a tree is created
+#[derive(Debug, Clone, Default)]
+pub struct TreeOptions {
+    /// Store a checksum of every key+value pair in its leaf
+    /// and verify it whenever the pair is read
+    pub entry_checksums: bool,
+    pub verify_checksums: Option<bool>,
+    pub fill_cache: Option<bool>,
+    pub durability: Option<Durability>,
+    /// Fully verify roughly one in this many nodes touched by reads.
+    /// 0 turns sampling off.
+    pub integrity_sample_one_in: usize,
+    /// Count roughly one in this many leaf reads towards
+    /// BTree::hot_ranges. 0 turns access statistics off.
+    pub access_sample_one_in: usize,
+    /// Buffer up to this many writes in each internal node before
+    /// flushing them towards the leaves (a B-epsilon tree, see messages),
+    /// at most 255. Random writes copy fewer nodes, more so the deeper
+    /// the tree, for rewriting a buffer on every write. Point reads check
+    /// the buffers on their way down, and scans flush every buffer first.
+    /// 0 writes straight to the leaves.
+    pub message_buffer: usize,
+    /// Hold children, keys and values through logical references (see
+    /// Pool::set_logical_references), so Pool::compact can move every
+    /// block of the tree but its root. Costs a block table slot per
+    /// reference and a lookup whenever one is followed.
+    pub relocatable: bool,
+}
+
+/// Per-call overrides for reads
+#[derive(Debug, Clone, Default)]
+pub struct ReadOptions {
+    pub verify_checksums: Option<bool>,
+    pub fill_cache: Option<bool>,
+}
+
+/// Per-call overrides for writes
+#[derive(Debug, Clone, Default)]
+pub struct WriteOptions {
+    pub durability: Option<Durability>,
+}
+
+/// What a single read should actually do, after resolving every layer
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadSettings {
+    pub verify_checksums: bool,
+    pub fill_cache: bool,
+}
+
+/// What a single write should actually do, after resolving every layer
+#[derive(Debug, Clone, PartialEq)]
+pub struct WriteSettings {
+    pub durability: Durability,
+}
+
+impl ReadOptions {
+    pub fn resolve(&self, tree: &TreeOptions, pool: &PoolDefaults) -> ReadSettings {
+        ReadSettings {
+            verify_checksums: self.verify_checksums
+                .or(tree.verify_checksums)
+                .unwrap_or(pool.verify_checksums),
+            fill_cache: self.fill_cache
+                .or(tree.fill_cache)
+                .unwrap_or(pool.fill_cache),
+        }
+    }
+}
+
+impl WriteOptions {
+    pub fn resolve(&self, tree: &TreeOptions, pool: &PoolDefaults) -> WriteSettings {
+        WriteSettings {
+            durability: self.durability
+                .or(tree.durability)
+                .unwrap_or(pool.durability),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layered_resolution() {
+        let pool = PoolDefaults::default();
+        let mut tree = TreeOptions::default();
+        let read = ReadOptions::default();
+        assert_eq!(ReadSettings { verify_checksums: true, fill_cache: true }, read.resolve(&tree, &pool));
+
+        // The tree overrides the pool
+        tree.verify_checksums = Some(false);
+        tree.durability = Some(Durability::Synced);
+        assert_eq!(ReadSettings { verify_checksums: false, fill_cache: true }, read.resolve(&tree, &pool));
+        assert_eq!(Durability::Synced, WriteOptions::default().resolve(&tree, &pool).durability);
+
+        // The operation overrides the tree
+        let read = ReadOptions { verify_checksums: Some(true), fill_cache: Some(false) };
+        assert_eq!(ReadSettings { verify_checksums: true, fill_cache: false }, read.resolve(&tree, &pool));
+        let write = WriteOptions { durability: Some(Durability::Buffered) };
+        assert_eq!(Durability::Buffered, write.resolve(&tree, &pool).durability);
+    }
+}
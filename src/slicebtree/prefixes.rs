@@ -0,0 +1,101 @@
+/// Listing the next path components under a prefix, like listing a
+/// directory when keys are paths. Rather than scanning every key below
+/// a component, the listing seeks straight past it, so it costs one seek
+/// per distinct component however many keys share it.
+use LodestoneError;
+
+/// seek returns the first key at or after the given one, if any.
+/// Components that continue past the delimiter include it, so "a/" and
+/// a plain key "a" are told apart.
+pub fn distinct_prefixes<F>(delimiter: u8, under: &[u8], mut seek: F) -> Result<Vec<Vec<u8>>, LodestoneError>
+    where F: FnMut(&[u8]) -> Result<Option<Vec<u8>>, LodestoneError> {
+    let mut found = Vec::new();
+    let mut cursor = under.to_vec();
+    loop {
+        let key = match try!(seek(&cursor)) {
+            Some(key) => key,
+            None => break,
+        };
+        if !key.starts_with(under) {
+            break;
+        }
+        if key.len() == under.len() {
+            // The prefix itself is a key, but not a component under it
+            cursor.push(0);
+            continue;
+        }
+        let prefix = match key[under.len()..].iter().position(|&b| b == delimiter) {
+            Some(at) => key[..under.len() + at + 1].to_vec(),
+            None => key.clone(),
+        };
+        cursor = if prefix.len() == key.len() && prefix.last() != Some(&delimiter) {
+            // A key of its own, the next one is right after it
+            let mut next = key;
+            next.push(0);
+            next
+        } else {
+            match successor(&prefix) {
+                Some(next) => next,
+                None => {
+                    found.push(prefix);
+                    break;
+                },
+            }
+        };
+        found.push(prefix);
+    }
+    Ok(found)
+}
+
+/// The smallest key greater than every key starting with prefix,
+/// None if there is none (the prefix is all 0xFF)
+fn successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut next = prefix.to_vec();
+    while let Some(last) = next.pop() {
+        if last < 0xFF {
+            next.push(last + 1);
+            return Some(next);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_distinct_prefixes() {
+        let mut keys = BTreeSet::new();
+        for i in 0..100 {
+            keys.insert(format!("photos/2016/{:03}.jpg", i).into_bytes());
+        }
+        for key in ["photos/2015/a.jpg", "photos/index", "photos/zz/", "music/a.mp3", "photos", "photos/"].iter() {
+            keys.insert(key.as_bytes().to_vec());
+        }
+        keys.insert(b"photos/\xff\xff".to_vec());
+
+        let mut seeks = 0;
+        let listed = distinct_prefixes(b'/', b"photos/", |from| {
+            seeks += 1;
+            Ok(keys.range(from.to_vec()..).next().cloned())
+        }).unwrap();
+        let expected: Vec<Vec<u8>> = vec![
+            b"photos/2015/".to_vec(),
+            b"photos/2016/".to_vec(),
+            b"photos/index".to_vec(),
+            b"photos/zz/".to_vec(),
+            b"photos/\xff\xff".to_vec(),
+        ];
+        assert_eq!(expected, listed);
+        // One per component, one past "photos/" and one to find the end,
+        // not one per key
+        assert_eq!(7, seeks);
+
+        let top = distinct_prefixes(b'/', b"", |from| Ok(keys.range(from.to_vec()..).next().cloned())).unwrap();
+        assert_eq!(vec![b"music/".to_vec(), b"photos".to_vec(), b"photos/".to_vec()], top);
+        assert!(distinct_prefixes(b'/', b"videos/", |from| Ok(keys.range(from.to_vec()..).next().cloned()))
+            .unwrap().is_empty());
+    }
+}
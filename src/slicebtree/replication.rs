@@ -0,0 +1,140 @@
+/// The follower half of replication: applying a stream of changes shipped
+/// from another tree. Every record carries the stamp of the transaction
+/// that made it, and the follower remembers the highest stamp it has
+/// committed (its high water mark). Records at or below the mark were
+/// already applied and are skipped, so a stream can be replayed from any
+/// earlier point after a crash. Batches are only cut between transactions,
+/// so a transaction is never half applied.
+use LodestoneError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChangeOp {
+    Put(Vec<u8>),
+    Delete,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeRecord {
+    pub tx_stamp: usize,
+    pub key: Vec<u8>,
+    pub op: ChangeOp,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AppliedSummary {
+    pub applied: usize,
+    /// Records at or below the high water mark
+    pub skipped: usize,
+    pub batches: usize,
+    pub high_water: usize,
+}
+
+/// Feed the records above high_water to commit in batches of at least
+/// batch_size records (and whole transactions). commit gets each batch
+/// along with the high water mark to store with it, in the same commit.
+/// Stamps going backwards mean the stream is broken, and nothing after
+/// that point is applied.
+pub fn apply_changes<I, F>(high_water: usize, stream: I, batch_size: usize, mut commit: F)
+    -> Result<AppliedSummary, LodestoneError>
+    where I: IntoIterator<Item=ChangeRecord>,
+          F: FnMut(&[ChangeRecord], usize) -> Result<(), LodestoneError> {
+    let mut summary = AppliedSummary {
+        high_water: high_water,
+        ..AppliedSummary::default()
+    };
+    let mut batch: Vec<ChangeRecord> = Vec::new();
+    let mut last_stamp = 0;
+    for record in stream {
+        if record.tx_stamp < last_stamp {
+            return Err(LodestoneError::UserError("Change stream stamps went backwards"));
+        }
+        last_stamp = record.tx_stamp;
+        if record.tx_stamp <= summary.high_water {
+            summary.skipped += 1;
+            continue;
+        }
+        let new_tx = batch.last().map_or(false, |last| last.tx_stamp != record.tx_stamp);
+        if new_tx && batch.len() >= batch_size {
+            try!(commit_batch(&mut batch, &mut summary, &mut commit));
+        }
+        batch.push(record);
+    }
+    if !batch.is_empty() {
+        try!(commit_batch(&mut batch, &mut summary, &mut commit));
+    }
+    Ok(summary)
+}
+
+fn commit_batch<F>(batch: &mut Vec<ChangeRecord>, summary: &mut AppliedSummary, commit: &mut F)
+    -> Result<(), LodestoneError>
+    where F: FnMut(&[ChangeRecord], usize) -> Result<(), LodestoneError> {
+    // Stamps only go up, so the last one is the highest
+    let high_water = batch[batch.len() - 1].tx_stamp;
+    try!(commit(&batch[..], high_water));
+    summary.applied += batch.len();
+    summary.batches += 1;
+    summary.high_water = high_water;
+    batch.clear();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use LodestoneError;
+
+    fn put(tx_stamp: usize, key: &[u8], value: &[u8]) -> ChangeRecord {
+        ChangeRecord { tx_stamp: tx_stamp, key: key.to_vec(), op: ChangeOp::Put(value.to_vec()) }
+    }
+
+    #[test]
+    fn test_apply_changes_idempotently() {
+        let stream = vec![
+            put(1, b"a", b"1"),
+            put(2, b"b", b"2"),
+            put(2, b"c", b"3"),
+            ChangeRecord { tx_stamp: 3, key: b"a".to_vec(), op: ChangeOp::Delete },
+            put(4, b"d", b"4"),
+        ];
+        // A map and its high water mark, standing in for the tree
+        let mut map = BTreeMap::new();
+        let mut stored_high_water = 0;
+        let mut batch_sizes = Vec::new();
+        {
+            let mut apply = |batch: &[ChangeRecord], high_water: usize| {
+                for record in batch {
+                    match record.op {
+                        ChangeOp::Put(ref v) => { map.insert(record.key.clone(), v.clone()); },
+                        ChangeOp::Delete => { map.remove(&record.key); },
+                    }
+                }
+                batch_sizes.push(batch.len());
+                stored_high_water = high_water;
+                Ok(())
+            };
+            let summary = apply_changes(0, stream.clone(), 1, &mut apply).unwrap();
+            assert_eq!(AppliedSummary { applied: 5, skipped: 0, batches: 4, high_water: 4 }, summary);
+
+            // Replaying is a no-op
+            let summary = apply_changes(4, stream.clone(), 1, &mut apply).unwrap();
+            assert_eq!(AppliedSummary { applied: 0, skipped: 5, batches: 0, high_water: 4 }, summary);
+        }
+        // Transaction 2 wasn't split across batches
+        assert_eq!(vec![1, 2, 1, 1], batch_sizes);
+        assert_eq!(4, stored_high_water);
+        assert_eq!(vec![b"b".to_vec(), b"c".to_vec(), b"d".to_vec()], map.keys().cloned().collect::<Vec<_>>());
+
+        // Resuming part way, with one big batch
+        let summary = apply_changes(1, stream.clone(), 10, |batch, high_water| {
+            assert_eq!((4, 4), (batch.len(), high_water));
+            Ok(())
+        }).unwrap();
+        assert_eq!(AppliedSummary { applied: 4, skipped: 1, batches: 1, high_water: 4 }, summary);
+
+        assert!(apply_changes(0, stream.clone(), 1, |_, _| Err(LodestoneError::UserError("full"))).is_err());
+
+        let backwards = vec![put(2, b"a", b"1"), put(1, b"b", b"2")];
+        assert!(apply_changes(0, backwards, 1, |_, _| Ok(())).is_err());
+    }
+}
@@ -0,0 +1,115 @@
+/// Reads can fail transiently: a block may be freed and reused under us
+/// (generation mismatch), or read half-written (checksum mismatch). Rather
+/// than each call site retrying on its own, reads go through with_retries,
+/// which retries a bounded number of times, switches to full checksum
+/// verification once the cheap attempts have failed, and finally gives
+/// up with a ReadFailed describing what happened.
+use LodestoneError;
+use ReadDiagnostics;
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: usize,
+    /// Attempts made before escalating to full verification
+    pub escalate_after: usize,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            escalate_after: 1,
+        }
+    }
+}
+
+/// Passed to each attempt so it knows how careful to be
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attempt {
+    pub number: usize,
+    pub verify_checksums: bool,
+}
+
+/// Errors that another attempt might not see.
+/// Anything else is returned straight away.
+fn is_transient(e: &LodestoneError) -> Option<&'static str> {
+    match *e {
+        LodestoneError::InvalidReference(msg) => Some(msg),
+        LodestoneError::Corruption(msg) => Some(msg),
+        _ => None,
+    }
+}
+
+pub fn with_retries<T, F>(policy: &RetryPolicy, mut read: F) -> Result<T, LodestoneError>
+    where F: FnMut(&Attempt) -> Result<T, LodestoneError> {
+    let mut last_error = "No attempts allowed by the retry policy";
+    for number in 0..policy.max_attempts {
+        let attempt = Attempt {
+            number: number,
+            verify_checksums: number >= policy.escalate_after,
+        };
+        match read(&attempt) {
+            Ok(t) => return Ok(t),
+            Err(e) => match is_transient(&e) {
+                Some(msg) => last_error = msg,
+                None => return Err(e),
+            },
+        }
+    }
+    Err(LodestoneError::ReadFailed(ReadDiagnostics {
+        attempts: policy.max_attempts,
+        escalated: policy.max_attempts > policy.escalate_after,
+        last_error: last_error,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use LodestoneError;
+    use ReadDiagnostics;
+
+    #[test]
+    fn test_retries_then_escalates() {
+        let policy = RetryPolicy::default();
+        let mut seen = Vec::new();
+        let result = with_retries(&policy, |a| {
+            seen.push(a.clone());
+            if a.number < 2 {
+                Err(LodestoneError::InvalidReference("stale"))
+            } else {
+                Ok(a.number)
+            }
+        });
+        assert_eq!(2, result.unwrap());
+        assert_eq!(vec![false, true, true], seen.iter().map(|a| a.verify_checksums).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_gives_up_with_diagnostics() {
+        let policy = RetryPolicy { max_attempts: 4, escalate_after: 2 };
+        let mut calls = 0;
+        let result: Result<(), _> = with_retries(&policy, |_| {
+            calls += 1;
+            Err(LodestoneError::Corruption("checksum"))
+        });
+        assert_eq!(4, calls);
+        match result {
+            Err(LodestoneError::ReadFailed(d)) => assert_eq!(
+                ReadDiagnostics { attempts: 4, escalated: true, last_error: "checksum" }, d),
+            other => panic!("Expected ReadFailed, got {:?}", other),
+        }
+
+        // Permanent errors aren't retried
+        calls = 0;
+        let result: Result<(), _> = with_retries(&policy, |_| {
+            calls += 1;
+            Err(LodestoneError::StructureCorrupt("cycle"))
+        });
+        assert_eq!(1, calls);
+        match result {
+            Err(LodestoneError::StructureCorrupt(_)) => (),
+            other => panic!("Expected StructureCorrupt, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,129 @@
+/// Scans that stop after max_entries or max_duration, whichever comes
+/// first, handing back what they found and a Continuation to pick up
+/// from. A continuation carries the key to resume at and the snapshot
+/// the scan reads, and encodes to an opaque token, so a paginated API
+/// can hand it to its client instead of holding a cursor open between
+/// requests.
+use std::time::{Duration, Instant};
+
+use checksum::crc32;
+use LodestoneError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Continuation {
+    /// The first key not returned yet
+    pub resume_key: Vec<u8>,
+    /// Which version of the tree is being scanned, e.g. its tx id
+    pub snapshot: usize,
+}
+
+impl Continuation {
+    /// Little endian snapshot, the resume key, then a crc32 of both,
+    /// so a token that was mangled on its way through a client is caught
+    pub fn to_token(&self) -> Vec<u8> {
+        let mut token: Vec<u8> = (0..8).map(|i| (self.snapshot >> (i * 8)) as u8).collect();
+        token.extend_from_slice(&self.resume_key);
+        let check = crc32(&token);
+        token.extend((0..4).map(|i| (check >> (i * 8)) as u8));
+        token
+    }
+
+    pub fn from_token(token: &[u8]) -> Result<Continuation, LodestoneError> {
+        if token.len() < 12 {
+            return Err(LodestoneError::UserError("Continuation token is too short"));
+        }
+        let (body, check) = token.split_at(token.len() - 4);
+        let check = check.iter().rev().fold(0u32, |n, &b| n << 8 | b as u32);
+        if crc32(body) != check {
+            return Err(LodestoneError::UserError("Continuation token is corrupt"));
+        }
+        Ok(Continuation {
+            resume_key: body[8..].to_vec(),
+            snapshot: body[..8].iter().rev().fold(0, |n, &b| n << 8 | b as usize),
+        })
+    }
+}
+
+/// Scan [start, end) of the given snapshot, or carry on from a
+/// continuation of an earlier scan of it. seek returns the first entry
+/// at or after a key. At least one entry is returned per call when there
+/// is one, however short max_duration is, so a scan always makes progress.
+pub fn scan_with_limit<F>(start: &[u8], end: &[u8], from: Option<&Continuation>, snapshot: usize,
+    max_entries: usize, max_duration: Duration, mut seek: F)
+    -> Result<(Vec<Entry>, Option<Continuation>), LodestoneError>
+    where F: FnMut(&[u8]) -> Result<Option<(Vec<u8>, Vec<u8>)>, LodestoneError> {
+    let mut cursor = match from {
+        Some(c) if c.snapshot != snapshot =>
+            return Err(LodestoneError::UserError("Continuation is for another snapshot")),
+        Some(c) => c.resume_key.clone(),
+        None => start.to_vec(),
+    };
+    let deadline = Instant::now() + max_duration;
+    let mut found = Vec::new();
+    loop {
+        let (key, value) = match try!(seek(&cursor)) {
+            Some(entry) => entry,
+            None => return Ok((found, None)),
+        };
+        if &key[..] >= end {
+            return Ok((found, None));
+        }
+        // The smallest key after this one
+        cursor = key.clone();
+        cursor.push(0);
+        found.push(Entry { key: key, value: value });
+        if found.len() >= max_entries || Instant::now() >= deadline {
+            let continuation = Continuation { resume_key: cursor, snapshot: snapshot };
+            return Ok((found, Some(continuation)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    #[test]
+    fn test_paginated_scan() {
+        let mut map = BTreeMap::new();
+        for i in 0..25u8 {
+            map.insert(vec![i], vec![i * 2]);
+        }
+        let seek = |from: &[u8]| Ok(map.range(from.to_vec()..).next().map(|(k, v)| (k.clone(), v.clone())));
+        let minute = Duration::from_secs(60);
+
+        // Pages of 10 over [3, 20), passing the continuation along as a token
+        let mut pages = Vec::new();
+        let mut token: Option<Vec<u8>> = None;
+        loop {
+            let from = token.map(|t| Continuation::from_token(&t).unwrap());
+            let (entries, next) = scan_with_limit(&[3], &[20], from.as_ref(), 9, 10, minute, &seek).unwrap();
+            pages.push(entries.iter().map(|e| e.key[0]).collect::<Vec<_>>());
+            match next {
+                Some(c) => token = Some(c.to_token()),
+                None => break,
+            }
+        }
+        assert_eq!(vec![(3..13).collect::<Vec<u8>>(), (13..20).collect()], pages);
+
+        // Out of time after every entry still makes progress
+        let (entries, next) = scan_with_limit(&[0], &[25], None, 9, 100, Duration::from_secs(0), &seek).unwrap();
+        assert_eq!(vec![Entry { key: vec![0], value: vec![0] }], entries);
+        assert_eq!(vec![0, 0], next.as_ref().unwrap().resume_key);
+
+        // Resuming against another snapshot, or from a mangled token
+        assert!(scan_with_limit(&[0], &[25], next.as_ref(), 10, 10, minute, &seek).is_err());
+        let mut token = next.unwrap().to_token();
+        token[8] ^= 1;
+        assert!(Continuation::from_token(&token).is_err());
+        assert!(Continuation::from_token(&[1, 2]).is_err());
+    }
+}
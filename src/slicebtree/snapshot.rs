@@ -0,0 +1,149 @@
+/// A read-only view of one version of a tree. The snapshot holds a count
+/// on the root, and through it every node, key and value of that version,
+/// so writers carry on copying around it and nothing it can reach is
+/// reclaimed until it drops. get_ref can hand out slices borrowed straight
+/// from the pool for as long as the snapshot lives, without a counted Arc
+/// per value.
+use std::borrow::Cow;
+use std::slice;
+
+use super::BTree;
+use super::node::{find_value, release_node, release_node_traced, seek};
+use allocator::{ArcByteSlice, PersistedArcByteSlice, Pool};
+use LodestoneError;
+
+pub struct Snapshot<'p> {
+    pool: &'p Pool<'p>,
+    /// Set for snapshots of a tree, whose keys are normalized and whose
+    /// values may hold references to release
+    tree: Option<&'p BTree<'p>>,
+    /// None for a snapshot of an empty tree
+    root: Option<PersistedArcByteSlice>,
+    tx_id: usize,
+}
+
+impl<'p> Snapshot<'p> {
+    pub fn new(pool: &'p Pool, root: &ArcByteSlice) -> Snapshot<'p> {
+        Snapshot {
+            pool: pool,
+            tree: None,
+            root: Some(root.clone_to_persisted()),
+            tx_id: 0,
+        }
+    }
+
+    /// A snapshot of tree at tx_id. root is the tree's own reference, the
+    /// snapshot takes a count of its own.
+    pub(crate) fn of_tree(tree: &'p BTree<'p>, pool: &'p Pool<'p>, root: Option<PersistedArcByteSlice>, tx_id: usize)
+        -> Result<Snapshot<'p>, LodestoneError> {
+        let root = match root {
+            Some(root) => Some(try!(root.clone(pool))),
+            None => None,
+        };
+        Ok(Snapshot {
+            pool: pool,
+            tree: Some(tree),
+            root: root,
+            tx_id: tx_id,
+        })
+    }
+
+    /// The transaction the snapshot was taken at
+    pub fn tx_id(&self) -> usize {
+        self.tx_id
+    }
+
+    /// The value under key, borrowed from the snapshot
+    pub fn get_ref<'s>(&'s self, key: &[u8]) -> Result<Option<&'s [u8]>, LodestoneError> {
+        let value = match try!(self.get(key)) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        // Dropping value only drops our count, the tree holds the block
+        // until the snapshot's root goes, and the borrow can't outlive that
+        Ok(Some(unsafe { slice::from_raw_parts(value.as_ptr(), value.len()) }))
+    }
+
+    /// The value under key as a counted Arc, which may outlive the snapshot
+    pub fn get(&self, key: &[u8]) -> Result<Option<ArcByteSlice<'p>>, LodestoneError> {
+        let root = match self.root {
+            Some(ref root) => try!(root.clone_to_arc_byte_slice(self.pool)),
+            None => return Ok(None),
+        };
+        find_value(&root, self.pool, &self.normalize(key))
+    }
+
+    /// The entries with start <= key < end, in order. No end reads to the
+    /// last key.
+    pub fn range(&self, start: &[u8], end: Option<&[u8]>)
+        -> Result<Vec<(ArcByteSlice<'p>, ArcByteSlice<'p>)>, LodestoneError> {
+        let mut found = Vec::new();
+        let root = match self.root {
+            Some(ref root) => root,
+            None => return Ok(found),
+        };
+        let end = end.map(|e| self.normalize(e).into_owned());
+        let mut from = self.normalize(start).into_owned();
+        while let Some((key, value)) = try!(seek(root, self.pool, &from)) {
+            if end.as_ref().map_or(false, |e| &key[..] >= &e[..]) {
+                break;
+            }
+            from = key.to_vec();
+            from.push(0);
+            found.push((key, value));
+        }
+        Ok(found)
+    }
+
+    fn normalize<'k>(&self, key: &'k [u8]) -> Cow<'k, [u8]> {
+        match self.tree {
+            Some(tree) => tree.normalize_key(key),
+            None => Cow::Borrowed(key),
+        }
+    }
+}
+
+impl<'p> Drop for Snapshot<'p> {
+    fn drop(&mut self) {
+        let mut root = match self.root.take() {
+            Some(root) => root,
+            None => return,
+        };
+        // If the tree has moved on, the snapshot holds the last count on
+        // this version, and what only it reached goes with it. Drop has
+        // nowhere to report a corrupt version to, verify finds it.
+        let _ = match self.tree {
+            Some(tree) => release_node_traced(&mut root, self.pool, &|value: &[u8]| tree.extract_references(value)),
+            None => release_node(&mut root, self.pool),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use allocator::Pool;
+    use slicebtree::node::bulk_build;
+
+    #[test]
+    fn test_get_ref() {
+        let mut buf = vec![0u8; 0x100000];
+        let pool = Pool::new(&mut buf);
+        let pairs = (0..250u32).map(|i| Ok((format!("key{:03}", i).into_bytes(), vec![i as u8; 3])));
+        let snapshot = {
+            let root = bulk_build(pairs, 1, 20, &pool).unwrap().unwrap();
+            Snapshot::new(&pool, &root)
+        };
+        // The root Arc is gone, the snapshot keeps the tree alive
+        let values: Vec<&[u8]> = (0..250)
+            .map(|i| snapshot.get_ref(format!("key{:03}", i).as_bytes()).unwrap().unwrap())
+            .collect();
+        for (i, value) in values.iter().enumerate() {
+            assert_eq!(&[i as u8; 3][..], *value);
+        }
+        assert!(snapshot.get_ref(b"key250").unwrap().is_none());
+        assert!(snapshot.get_ref(b"a").unwrap().is_none());
+        assert!(snapshot.get_ref(b"key0005").unwrap().is_none());
+        assert_eq!(&[7u8; 3], &snapshot.get(b"key007").unwrap().unwrap()[..]);
+    }
+}
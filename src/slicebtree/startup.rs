@@ -0,0 +1,38 @@
+/// How much opening a tree checks, after a clean shutdown or not.
+/// Every mode checks the pool image's block list and recovers the last
+/// journaled commit, they differ in what they do with the tree on top.
+use std::time::Duration;
+
+use allocator::SweepReport;
+use super::node::QuickVerifyReport;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpenMode {
+    /// Trust the descriptor and root, verify nothing (BTree::open_fast)
+    Fast,
+    /// Verify the top of the tree and a sample of paths below it
+    /// (BTree::open_verified, and BTree::open)
+    Verified,
+    /// Verify every node, recount the entries and sweep what no root
+    /// reaches (BTree::open_repair)
+    Repair,
+}
+
+/// What opening a tree found and did
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpenReport {
+    pub mode: OpenMode,
+    /// The commit the tree was opened at
+    pub tx_id: usize,
+    pub entries: usize,
+    /// None if nothing was verified: in Fast mode, or for an empty tree
+    pub verified: Option<QuickVerifyReport>,
+    /// The other trees in the pool's catalog, verified along with this
+    /// one, none in Fast mode
+    pub catalog_trees: usize,
+    /// How far the stored entry count was off, Repair mode only
+    pub count_drift: isize,
+    /// What was swept, Repair mode only
+    pub swept: Option<SweepReport>,
+    pub elapsed: Duration,
+}
@@ -0,0 +1,47 @@
+/// Keys under SYSTEM_PREFIX are reserved for the tree's own state
+/// (catalog, stats, filters, sequences, ...), kept in the same tree as
+/// user data so new internal features don't each need a new block type.
+/// User writes into the namespace are refused with ReservedKey.
+use LodestoneError;
+
+pub const SYSTEM_PREFIX: &'static [u8] = b"\xffsys";
+/// The first key past the system keys
+pub const SYSTEM_END: &'static [u8] = b"\xffsyt";
+
+/// The key internal state called name is stored under
+pub fn system_key(name: &[u8]) -> Vec<u8> {
+    let mut key = SYSTEM_PREFIX.to_vec();
+    key.extend_from_slice(name);
+    key
+}
+
+pub fn is_system_key(key: &[u8]) -> bool {
+    key.starts_with(SYSTEM_PREFIX)
+}
+
+pub fn check_user_key(key: &[u8]) -> Result<(), LodestoneError> {
+    if is_system_key(key) {
+        return Err(LodestoneError::ReservedKey("Keys starting with 0xFF 'sys' are reserved"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_system_keys() {
+        let key = system_key(b"catalog");
+        assert_eq!(b"\xffsyscatalog".to_vec(), key);
+        assert!(is_system_key(&key));
+        assert!(check_user_key(&key).is_err());
+        assert!(check_user_key(SYSTEM_PREFIX).is_err());
+        assert!(check_user_key(SYSTEM_END).is_ok());
+        assert!(SYSTEM_END > &key[..]);
+        // Close isn't enough
+        for key in [&b"\xffsy"[..], b"\xfesys", b"sys", b"", b"\xff\xff"].iter() {
+            assert!(check_user_key(key).is_ok());
+        }
+    }
+}
@@ -0,0 +1,117 @@
+/// Compile time checks that the structures we write into the pool
+/// still have the layout of the on-disk format. Changing any of these
+/// changes the format, so the build fails instead of existing data
+/// being misread. The thread safety of the public types is checked
+/// here too, so it can't change by accident either.
+use std::mem;
+
+use allocator::*;
+use slicebtree::{B, BTree};
+use slicebtree::iter::Iter;
+use slicebtree::snapshot::Snapshot;
+use slicebtree::consistency::{CommitToken, CommitWatch};
+use slicebtree::node::{Cursor, Fence, Node, FENCE_PREFIX_SIZE, HEADER_COUNT_MASK};
+
+const WORD: usize = 8;
+
+/// prev, id_tag, next
+const SKIP_LIST_ENTRY_SIZE: usize = 3 * WORD;
+/// strong, checksum, size
+const ARC_INNER_SIZE_ON_DISK: usize = 3 * WORD;
+/// arc_inner_index, id_tag
+const PERSISTED_ARC_SIZE: usize = 2 * WORD;
+/// prefix, len, truncated
+const FENCE_SIZE: usize = FENCE_PREFIX_SIZE + 2;
+/// The packed u32 header and both fences (padded to a word), tx_id,
+/// then keys and children, then a u32 checksum per pair
+const NODE_SIZE: usize = round_to_word(4 + 2 * FENCE_SIZE) + WORD
+    + 2 * B * PERSISTED_ARC_SIZE + B * 4;
+
+const fn round_to_word(n: usize) -> usize {
+    (n + WORD - 1) / WORD * WORD
+}
+
+// Offsets and sizes are stored as 8 byte words
+const _: () = assert!(mem::size_of::<usize>() == WORD, "The on-disk format requires 64 bit usize");
+
+// Skip list headers go through the codec, only their size is fixed
+const _: () = assert!(SKIP_LIST_HEADER_SIZE == SKIP_LIST_ENTRY_SIZE, "Skip list header layout changed");
+
+const _: () = assert!(mem::size_of::<ArcByteSliceInner>() == ARC_INNER_SIZE_ON_DISK, "ArcByteSliceInner layout changed");
+const _: () = assert!(mem::align_of::<ArcByteSliceInner>() == WORD, "ArcByteSliceInner alignment changed");
+
+const _: () = assert!(mem::size_of::<PersistedArcByteSlice>() == PERSISTED_ARC_SIZE, "PersistedArcByteSlice layout changed");
+const _: () = assert!(mem::align_of::<PersistedArcByteSlice>() == WORD, "PersistedArcByteSlice alignment changed");
+// References are the encoded form of a PersistedArcByteSlice
+const _: () = assert!(REFERENCE_SIZE == PERSISTED_ARC_SIZE, "Reference encoding no longer matches PersistedArcByteSlice");
+
+const _: () = assert!(mem::size_of::<Fence>() == FENCE_SIZE, "Fence layout changed");
+const _: () = assert!(mem::size_of::<Node>() == NODE_SIZE, "Node layout changed");
+const _: () = assert!(mem::align_of::<Node>() == WORD, "Node alignment changed");
+// Counts are packed into the node header
+const _: () = assert!(B <= HEADER_COUNT_MASK as usize, "B no longer fits in a node header count");
+
+// Headers are word sized, so the block after a header stays aligned
+const _: () = assert!(SKIP_LIST_ENTRY_SIZE % WORD == 0 && ARC_INNER_SIZE_ON_DISK % WORD == 0,
+    "Block headers must keep data word aligned");
+// Every node, with its block headers, fits inside a single page
+const _: () = assert!(NODE_SIZE + SKIP_LIST_ENTRY_SIZE + ARC_INNER_SIZE_ON_DISK <= PAGE_SIZE,
+    "Node no longer fits in a page");
+
+// Pools and trees can be handed to another thread, but not shared:
+// malloc and free update the metadata block without synchronization,
+// unless pools are built thread-safe.
+// ArcByteSlices point back into their pool and free into it on drop,
+// so they stay on the pool's thread. Persist them to hand a block over.
+fn assert_send<T: Send>() {}
+fn assert_sync<T: Sync>() {}
+
+const _: fn() = || {
+    assert_send::<Pool>();
+    assert_send::<BTree>();
+    assert_send::<PersistedArcByteSlice>();
+    assert_sync::<PersistedArcByteSlice>();
+    assert_send::<Reference>();
+    assert_sync::<Reference>();
+    // Polled and cancelled from other threads while an operation runs
+    assert_send::<ProgressHandle>();
+    assert_sync::<ProgressHandle>();
+    // Handed to readers on other threads, see consistency
+    assert_send::<CommitToken>();
+    assert_sync::<CommitWatch>();
+    assert_send::<CommitWatch>();
+};
+
+#[cfg(feature = "thread-safe")]
+const _: fn() = || {
+    assert_sync::<Pool>();
+};
+
+// Naming some_item is ambiguous (and fails the build) when a type
+// picks up the second impl, i.e. when it's Send or Sync
+trait AmbiguousIfSend<A> { fn some_item() {} }
+impl<T: ?Sized> AmbiguousIfSend<()> for T {}
+impl<T: ?Sized + Send> AmbiguousIfSend<u8> for T {}
+
+trait AmbiguousIfSync<A> { fn some_item() {} }
+impl<T: ?Sized> AmbiguousIfSync<()> for T {}
+impl<T: ?Sized + Sync> AmbiguousIfSync<u8> for T {}
+
+const _: fn() = || {
+    let _ = <ArcByteSlice as AmbiguousIfSend<_>>::some_item;
+    let _ = <ArcByteSlice as AmbiguousIfSync<_>>::some_item;
+    let _ = <BTree as AmbiguousIfSync<_>>::some_item;
+    // Snapshots read through their tree's pool, on its thread
+    let _ = <Snapshot as AmbiguousIfSend<_>>::some_item;
+    let _ = <Snapshot as AmbiguousIfSync<_>>::some_item;
+    // Cursors and iterators hold Arcs on the nodes they're walking
+    let _ = <Cursor as AmbiguousIfSend<_>>::some_item;
+    let _ = <Cursor as AmbiguousIfSync<_>>::some_item;
+    let _ = <Iter as AmbiguousIfSend<_>>::some_item;
+    let _ = <Iter as AmbiguousIfSync<_>>::some_item;
+};
+
+#[cfg(not(feature = "thread-safe"))]
+const _: fn() = || {
+    let _ = <Pool as AmbiguousIfSync<_>>::some_item;
+};